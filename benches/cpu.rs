@@ -0,0 +1,40 @@
+//! How fast the core runs a game, to compare builds with and without the `instrumentation`
+//! feature:
+//!
+//!     cargo bench --bench cpu
+//!     cargo bench --bench cpu --no-default-features
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rugby::cart::Cart;
+use rugby::cpu::Cpu;
+
+/// A ROM that copies bytes between work RAM and high RAM and calls a subroutine in a loop, so the
+/// frames are spent on instruction fetches and memory accesses like a game's.
+fn busy_rom() -> Box<[u8]> {
+    let mut rom = vec![0; 0x8000];
+    // nop; jp $0150
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x150..0x162].copy_from_slice(&[
+        0x21, 0x00, 0xC0, // ld hl, $C000
+        0x2A,             // .copy: ld a, [hl+]
+        0xE0, 0x80,       // ldh [$FF80], a
+        0x3C,             // inc a
+        0x77,             // ld [hl], a
+        0xCD, 0x00, 0x02, // call $0200
+        0x7C,             // ld a, h
+        0xFE, 0xD0,       // cp $D0
+        0x20, 0xF3,       // jr nz, .copy
+        0x18, 0xEE,       // jr $0150
+    ]);
+    // push hl; pop hl; ret
+    rom[0x200..0x203].copy_from_slice(&[0xE5, 0xE1, 0xC9]);
+    rom.into_boxed_slice()
+}
+
+fn run_frame(c: &mut Criterion) {
+    let mut cpu = Cpu::new(Cart::from_rom(busy_rom(), None).unwrap());
+    c.bench_function("run_frame", |b| b.iter(|| cpu.run_frame()));
+}
+
+criterion_group!(benches, run_frame);
+criterion_main!(benches);
@@ -0,0 +1,32 @@
+//! Records which commit and profile Rugby was built from, for `rugby::version`.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=10", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=RUGBY_GIT_HASH={}", hash);
+    println!("cargo:rustc-env=RUGBY_PROFILE={}", std::env::var("PROFILE").unwrap_or_default());
+
+    // HEAD usually names a branch, and a commit only changes the branch's ref, or packed-refs once
+    // git packs it. Cargo reruns every build for a missing file, so only watch the ones there are.
+    let mut watched = vec![String::from(".git/HEAD"), String::from(".git/packed-refs")];
+    if let Ok(head) = fs::read_to_string(".git/HEAD") {
+        if let Some(r) = head.strip_prefix("ref: ") {
+            watched.push(format!(".git/{}", r.trim()));
+        }
+    }
+    for path in watched {
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
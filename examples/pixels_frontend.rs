@@ -0,0 +1,129 @@
+//! A minimal windowed frontend built on `pixels` and `winit` instead of SDL, using only the
+//! core's prelude. There is no sound.
+//!
+//! Usage: `cargo run --release --features pixels-frontend --example pixels_frontend -- <ROM>`
+//!
+//! Controls: arrow keys, Z = a, X = b, Return = start, Backspace = select, F5 = save state,
+//! F8 = load state.
+
+use pixels::{Pixels, SurfaceTexture};
+use rugby::prelude::*;
+use std::time::{Duration, Instant};
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, Event, KeyboardInput, StartCause, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::WindowBuilder;
+
+const WINDOW_SCALE: f64 = 4.0;
+
+/// RGBA colors for the four Game Boy shades, lightest first.
+const COLORS: [[u8; 4]; 4] = [
+    [0xE0, 0xF8, 0xD0, 0xFF],
+    [0x88, 0xC0, 0x70, 0xFF],
+    [0x34, 0x68, 0x56, 0xFF],
+    [0x08, 0x18, 0x20, 0xFF],
+];
+
+fn main() -> Result<(), failure::Error> {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: pixels_frontend <ROM>");
+            std::process::exit(1);
+        }
+    };
+    let rom = std::fs::read(&path)?.into_boxed_slice();
+    let mut cpu = Cpu::builder(Cart::from_rom(rom, None)?).build();
+
+    let event_loop = EventLoop::new();
+    let size = LogicalSize::new(SCREEN_WIDTH as f64, SCREEN_HEIGHT as f64);
+    let window = WindowBuilder::new()
+        .with_title("Rugby")
+        .with_inner_size(LogicalSize::new(size.width * WINDOW_SCALE, size.height * WINDOW_SCALE))
+        .with_min_inner_size(size)
+        .build(&event_loop)?;
+    let window_size = window.inner_size();
+    let surface = SurfaceTexture::new(window_size.width, window_size.height, &window);
+    let mut pixels = Pixels::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface)?;
+
+    let frame_time = Duration::from_secs(1) / 60;
+    let mut next_frame = Instant::now();
+    let mut saved_state: Option<Vec<u8>> = None;
+
+    event_loop.run(move |event, _, control_flow| match event {
+        Event::NewEvents(StartCause::Init) | Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
+            cpu.run_frame();
+            window.request_redraw();
+            next_frame += frame_time;
+            *control_flow = ControlFlow::WaitUntil(next_frame);
+        }
+
+        Event::RedrawRequested(_) => {
+            for (pixel, &shade) in pixels.frame_mut().chunks_exact_mut(4).zip(cpu.screen().iter().flatten()) {
+                pixel.copy_from_slice(&COLORS[shade as usize]);
+            }
+            if let Err(e) = pixels.render() {
+                eprintln!("failed to render: {}", e);
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+
+        Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+            if let Err(e) = pixels.resize_surface(size.width, size.height) {
+                eprintln!("failed to resize: {}", e);
+                *control_flow = ControlFlow::Exit;
+            }
+        }
+
+        Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
+            *control_flow = ControlFlow::Exit;
+        }
+
+        Event::WindowEvent {
+            event: WindowEvent::KeyboardInput {
+                input: KeyboardInput { virtual_keycode: Some(key), state, .. },
+                ..
+            },
+            ..
+        } => {
+            let down = state == ElementState::Pressed;
+            match key {
+                VirtualKeyCode::F5 if down => saved_state = Some(cpu.save_state()),
+                VirtualKeyCode::F8 if down => {
+                    if let Some(state) = &saved_state {
+                        if let Err(e) = cpu.load_state(state) {
+                            eprintln!("failed to load state: {}", e);
+                        }
+                    }
+                }
+                _ => set_key(&mut cpu, key, down),
+            }
+        }
+
+        _ => {}
+    });
+}
+
+fn set_key(cpu: &mut Cpu, key: VirtualKeyCode, down: bool) {
+    let button = match key {
+        VirtualKeyCode::Z => Some(ButtonKey::A),
+        VirtualKeyCode::X => Some(ButtonKey::B),
+        VirtualKeyCode::Return => Some(ButtonKey::Start),
+        VirtualKeyCode::Back => Some(ButtonKey::Select),
+        _ => None,
+    };
+    let dir = match key {
+        VirtualKeyCode::Right => Some(DirKey::Right),
+        VirtualKeyCode::Left => Some(DirKey::Left),
+        VirtualKeyCode::Up => Some(DirKey::Up),
+        VirtualKeyCode::Down => Some(DirKey::Down),
+        _ => None,
+    };
+    match (button, dir, down) {
+        (Some(button), _, true) => cpu.joypad_mut().button_key_down(button),
+        (Some(button), _, false) => cpu.joypad_mut().button_key_up(button),
+        (_, Some(dir), true) => cpu.joypad_mut().dir_key_down(dir),
+        (_, Some(dir), false) => cpu.joypad_mut().dir_key_up(dir),
+        (None, None, _) => {}
+    }
+}
@@ -1,24 +1,39 @@
-use log::warn;
-use super::{LENGTH_COUNTER_RATE_CYCLES, EnvelopeDirection};
+use super::{Envelope, EnvelopeDirection, LengthCounter};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 /// Max length for sound data
-const MAX_SOUND_LENGTH: u8 = 64;
+const MAX_SOUND_LENGTH: u16 = 64;
 
 #[derive(Clone)]
 pub struct Channel1 {
-    // TODO(wcarlson): Sweep register
+    /// Sweep period, in 128 Hz ticks. Bits 4-6 of 0xFF10
+    sweep_period: u8,
 
-    /// Wave pattern duty. Bits 6-7 of 0xFF11
-    wave_pattern: u8,
+    /// True if the sweep lowers the frequency. Bit 3 of 0xFF10
+    sweep_negate: bool,
+
+    /// How far each sweep step shifts the frequency. Bits 0-2 of 0xFF10
+    sweep_shift: u8,
+
+    /// Ticks left until the next sweep step
+    sweep_timer: u8,
 
-    /// Length of sound data. Bits 0-5 of 0xFF11
-    length: u8,
+    /// The frequency the sweep works from, copied from `frequency` on restart
+    shadow_frequency: u16,
 
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 64 - length.
-    length_counter: u8,
+    /// True if the sweep is running, set on restart if it has a period or shift
+    sweep_enabled: bool,
+
+    /// True if the sweep has lowered the frequency since the restart. Clearing the negate bit
+    /// after that stops the channel.
+    sweep_negated: bool,
+
+    /// Wave pattern duty. Bits 6-7 of 0xFF11
+    wave_pattern: u8,
 
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
+    /// Length counter. Used to tell when to stop playing audio. Loaded from bits 0-5 of 0xFF11,
+    /// enabled by bit 6 of 0xFF14.
+    pub(super) length_counter: LengthCounter,
 
     /// Volume. Bits 4-7 of 0xFF12
     volume: u8,
@@ -36,15 +51,9 @@ pub struct Channel1 {
     /// True if we are going to restart sound. Bit 7 of 0xFF14
     restart: bool,
 
-    /// True if we should stop after the current sound length. Bit 6 of 0xFF14
-    stop_after_sound_length: bool,
-
     /// Track current cycles for audio output
     curr_cycles: usize,
 
-    /// Track current cycles for length counter
-    curr_length_counter_cycles: usize,
-
     /// Track the wave pattern position
     curr_index: u8,
 
@@ -53,34 +62,43 @@ pub struct Channel1 {
 
     /// True if the channel is enabled
     enabled: bool,
+
+    /// The volume the channel is playing at, stepped by the envelope
+    pub(super) envelope: Envelope,
 }
 
 impl Channel1 {
     pub fn new() -> Channel1 {
         Channel1 {
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+            sweep_enabled: false,
+            sweep_negated: false,
             wave_pattern: 0b10,
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
-            volume: 0b11111,
+            length_counter: LengthCounter::new(MAX_SOUND_LENGTH),
+            volume: 0b1111,
             envelope_direction: EnvelopeDirection::Decrease,
             envelope_sweeps: 0b11,
             frequency: 0,
             restart: false,
-            stop_after_sound_length: false,
             curr_cycles: 0,
-            curr_length_counter_cycles: 0,
             curr_index: 0,
             curr_output: 0,
             enabled: false,
+            envelope: Envelope::new(),
         }
     }
 
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
             0x10 => {
-                warn!("unimplemented read from channel 1 audio sweep register");
-                0x80
+                0b1000_0000 // Unused bit
+                    | self.sweep_period << 4
+                    | (self.sweep_negate as u8) << 3
+                    | self.sweep_shift
             }
             0x11 => (self.wave_pattern << 6) | 0b0011_1111, // Low bits are write-only
             0x12 => {
@@ -91,24 +109,50 @@ impl Channel1 {
             0x13 => 0xFF, // This register is entirely write-only
             0x14 => {
                 0b10111111 // These bits are unused or write-only
-                    | (self.stop_after_sound_length as u8) << 6
+                    | (self.length_counter.enabled as u8) << 6
             }
             _ => panic!("Invalid read address for audio channel 1"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    /// The value last written to a register, as far as the channel keeps it, including the
+    /// write-only bits but not the trigger.
+    pub fn written_reg(&self, addr: u8) -> u8 {
         match addr {
-            0x10 => warn!("unimplemented write to channel 1 audio sweep register"),
+            0x10 => self.read_reg(addr) & 0b0111_1111,
+            0x11 => (self.wave_pattern << 6) | (self.length_counter.length() & 0b0011_1111),
+            0x13 => self.frequency as u8,
+            0x14 => (self.length_counter.enabled as u8) << 6 | (self.frequency >> 8) as u8,
+            _ => self.read_reg(addr),
+        }
+    }
+
+    /// Write to a register. `extra_length_clock` is whether enabling the length counter clocks it.
+    pub fn write_reg(&mut self, addr: u8, val: u8, extra_length_clock: bool) {
+        match addr {
+            0x10 => {
+                self.sweep_period = (val >> 4) & 0b111;
+                self.sweep_shift = val & 0b111;
+                let negate = val & (1 << 3) != 0;
+                if self.sweep_negate && !negate && self.sweep_negated {
+                    self.enabled = false;
+                }
+                self.sweep_negate = negate;
+            },
             0x11 => {
                 self.wave_pattern = val >> 6;
-                self.length = val & 0b0011_1111;
-                self.length_counter = MAX_SOUND_LENGTH - self.length;
+                self.length_counter.load(val & 0b0011_1111);
             },
             0x12 => {
+                let (old_direction, old_sweeps) = (self.envelope_direction, self.envelope_sweeps);
                 self.envelope_sweeps = val & 0b0111;
                 self.envelope_direction = EnvelopeDirection::from((val >> 3) & 1);
                 self.volume = val >> 4;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                } else if self.enabled {
+                    self.envelope.write_while_playing(old_direction, old_sweeps, self.envelope_direction);
+                }
             },
             0x13 => {
                 self.frequency &= !0 << 8;
@@ -116,23 +160,17 @@ impl Channel1 {
             },
             0x14 => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
                 self.frequency &= 0xFF;
                 self.frequency |= ((val & 0b111) as u16) << 8;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = MAX_SOUND_LENGTH;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = MAX_SOUND_LENGTH
+                if self.length_counter.write_control(val, extra_length_clock) {
+                    self.enabled = false;
                 }
                 if self.restart {
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = MAX_SOUND_LENGTH
-                    }
+                    self.enabled = self.dac_enabled();
+                    self.restart_envelope();
+                    self.restart_sweep();
                 }
-                self.length_counter_enabled = self.restart;
             },
             _ => panic!("Invalid write address for audio channel 1"),
         }
@@ -151,9 +189,7 @@ impl Channel1 {
             self.curr_index = (self.curr_index + 1) % 8;
         }
 
-        self.update_length_counter(cycles);
-
-        self.curr_output * self.volume
+        self.curr_output * self.envelope.volume
     }
 
     fn get_wave_duty(&self) -> u8 {
@@ -166,16 +202,129 @@ impl Channel1 {
         }
     }
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_length_counter_cycles += cycles;
-        if self.curr_length_counter_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_length_counter_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The DAC is off, silencing the channel, while the initial volume is 0 and the envelope
+    /// lowers it.
+    fn dac_enabled(&self) -> bool {
+        self.volume != 0 || self.envelope_direction == EnvelopeDirection::Increase
+    }
+
+    /// Count down the length counter, on the frame sequencer's 256 Hz clock.
+    pub fn clock_length(&mut self) {
+        if self.length_counter.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Step the frequency sweep, on the frame sequencer's 128 Hz clock.
+    pub fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer > 0 {
+            return;
+        }
+        self.reload_sweep_timer();
+        if !self.sweep_enabled || self.sweep_period == 0 {
+            return;
+        }
+        let frequency = self.next_sweep_frequency();
+        if frequency <= 2047 && self.sweep_shift != 0 {
+            self.shadow_frequency = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked for overflow again right away, without being used.
+            self.next_sweep_frequency();
+        }
+    }
+
+    /// Start the sweep over from the current frequency, as when the channel is triggered.
+    pub fn restart_sweep(&mut self) {
+        self.shadow_frequency = self.frequency;
+        self.reload_sweep_timer();
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        self.sweep_negated = false;
+        if self.sweep_shift != 0 {
+            self.next_sweep_frequency();
+        }
+    }
+
+    /// A period of 0 is treated as 8.
+    fn reload_sweep_timer(&mut self) {
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+    }
+
+    /// Work out the next frequency of the sweep, stopping the channel if it overflows.
+    fn next_sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.sweep_negated = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
         }
+        frequency
+    }
+
+    /// Step the volume envelope, on the frame sequencer's 64 Hz clock.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock(self.envelope_direction, self.envelope_sweeps);
+    }
+
+    /// Start the envelope over from the initial volume, as when the channel is triggered.
+    pub fn restart_envelope(&mut self) {
+        self.envelope.restart(self.volume, self.envelope_sweeps);
+    }
+}
+
+impl SaveState for Channel1 {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.wave_pattern);
+        w.u8(self.length_counter.counter as u8);
+        w.bool(self.length_counter.enabled);
+        w.u8(self.volume);
+        w.u8(self.envelope_direction as u8);
+        w.u8(self.envelope_sweeps);
+        w.u16(self.frequency);
+        w.bool(self.restart);
+        w.usize(self.curr_cycles);
+        w.u8(self.curr_index);
+        w.u8(self.curr_output);
+        w.bool(self.enabled);
+        self.envelope.save(w);
+        w.u8(self.sweep_period);
+        w.bool(self.sweep_negate);
+        w.u8(self.sweep_shift);
+        w.u8(self.sweep_timer);
+        w.u16(self.shadow_frequency);
+        w.bool(self.sweep_enabled);
+        w.bool(self.sweep_negated);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.wave_pattern = r.u8()? & 0b11;
+        self.length_counter.counter = u16::from(r.u8()?).min(MAX_SOUND_LENGTH);
+        self.length_counter.enabled = r.bool()?;
+        self.volume = r.u8()? & 0xF;
+        self.envelope_direction = EnvelopeDirection::from(r.u8()? & 1);
+        self.envelope_sweeps = r.u8()? & 0b111;
+        self.frequency = r.u16()? & 0x7FF;
+        self.restart = r.bool()?;
+        self.curr_cycles = r.usize()?;
+        self.curr_index = r.u8()? % 8;
+        self.curr_output = r.u8()? & 1;
+        self.enabled = r.bool()?;
+        self.envelope.load(r)?;
+        self.sweep_period = r.u8()? & 0b111;
+        self.sweep_negate = r.bool()?;
+        self.sweep_shift = r.u8()? & 0b111;
+        self.sweep_timer = r.u8()? & 0b1111;
+        self.shadow_frequency = r.u16()? & 0x7FF;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_negated = r.bool()?;
+        Ok(())
     }
 }
@@ -1,21 +1,17 @@
-use super::{LENGTH_COUNTER_RATE_CYCLES, EnvelopeDirection};
+use super::{Envelope, EnvelopeDirection, LengthCounter};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 /// Max length for sound data
-const MAX_SOUND_LENGTH: u8 = 64;
+const MAX_SOUND_LENGTH: u16 = 64;
 
 #[derive(Clone)]
 pub struct Channel2 {
     /// Wave pattern. Bits 6-7 of 0xFF16
     wave_pattern: u8,
 
-    /// Length of sound data. Bits 0-5 of 0xFF16
-    length: u8,
-
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 64 - length.
-    length_counter: u8,
-
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
+    /// Length counter. Used to tell when to stop playing audio. Loaded from bits 0-5 of 0xFF16,
+    /// enabled by bit 6 of 0xFF19.
+    pub(super) length_counter: LengthCounter,
 
     /// Volume. Bits 4-7 of 0xFF17
     volume: u8,
@@ -33,15 +29,9 @@ pub struct Channel2 {
     /// True if we are going to restart sound. Bit 7 of 0xFF19
     restart: bool,
 
-    /// True if we should stop after the current sound length. Bit 6 of 0xFF19
-    stop_after_sound_length: bool,
-
     /// Track current cycles for audio output
     curr_cycles: usize,
 
-    /// Track current cycles for length counter
-    curr_length_counter_cycles: usize,
-
     /// Track the wave pattern position
     curr_index: u8,
 
@@ -50,26 +40,26 @@ pub struct Channel2 {
 
     /// True if the channel is enabled
     enabled: bool,
+
+    /// The volume the channel is playing at, stepped by the envelope
+    pub(super) envelope: Envelope,
 }
 
 impl Channel2 {
     pub fn new() -> Channel2 {
         Channel2 {
             wave_pattern: 0,
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
+            length_counter: LengthCounter::new(MAX_SOUND_LENGTH),
             volume: 0,
             envelope_direction: EnvelopeDirection::Decrease,
             envelope_sweeps: 0,
             frequency: 0,
             restart: false,
-            stop_after_sound_length: false,
             curr_cycles: 0,
-            curr_length_counter_cycles: 0,
             curr_index: 0,
             curr_output: 0,
             enabled: false,
+            envelope: Envelope::new(),
         }
     }
 
@@ -84,23 +74,40 @@ impl Channel2 {
             0x18 => 0xFF, // This register is entirely write-only
             0x19 => {
                 0b10111111 // These bits are unused or write-only
-                    | (self.stop_after_sound_length as u8) << 6
+                    | (self.length_counter.enabled as u8) << 6
             },
             _ => panic!("Invalid read address for audio channel 2"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    /// The value last written to a register, as far as the channel keeps it, including the
+    /// write-only bits but not the trigger.
+    pub fn written_reg(&self, addr: u8) -> u8 {
+        match addr {
+            0x16 => (self.wave_pattern << 6) | (self.length_counter.length() & 0b0011_1111),
+            0x18 => self.frequency as u8,
+            0x19 => (self.length_counter.enabled as u8) << 6 | (self.frequency >> 8) as u8,
+            _ => self.read_reg(addr),
+        }
+    }
+
+    /// Write to a register. `extra_length_clock` is whether enabling the length counter clocks it.
+    pub fn write_reg(&mut self, addr: u8, val: u8, extra_length_clock: bool) {
         match addr {
             0x16 => {
                 self.wave_pattern = val >> 6;
-                self.length = val & 0b0011_1111;
-                self.length_counter = MAX_SOUND_LENGTH - self.length;
+                self.length_counter.load(val & 0b0011_1111);
             },
             0x17 => {
+                let (old_direction, old_sweeps) = (self.envelope_direction, self.envelope_sweeps);
                 self.envelope_sweeps = val & 0b0111;
                 self.envelope_direction = EnvelopeDirection::from((val >> 3) & 1);
                 self.volume = val >> 4;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                } else if self.enabled {
+                    self.envelope.write_while_playing(old_direction, old_sweeps, self.envelope_direction);
+                }
             },
             0x18 => {
                 self.frequency &= !0 << 8;
@@ -108,23 +115,16 @@ impl Channel2 {
             },
             0x19 => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
                 self.frequency &= 0xFF;
                 self.frequency |= ((val & 0b111) as u16) << 8;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = MAX_SOUND_LENGTH;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = MAX_SOUND_LENGTH
+                if self.length_counter.write_control(val, extra_length_clock) {
+                    self.enabled = false;
                 }
                 if self.restart {
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = MAX_SOUND_LENGTH
-                    }
+                    self.enabled = self.dac_enabled();
+                    self.restart_envelope();
                 }
-                self.length_counter_enabled = self.restart;
             },
             _ => panic!("Invalid write address for audio channel 2"),
         }
@@ -143,9 +143,7 @@ impl Channel2 {
             self.curr_index = (self.curr_index + 1) % 8;
         }
 
-        self.update_length_counter(cycles);
-
-        self.curr_output * self.volume
+        self.curr_output * self.envelope.volume
     }
 
     fn get_wave_duty(&self) -> u8 {
@@ -158,16 +156,65 @@ impl Channel2 {
         }
     }
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_length_counter_cycles += cycles;
-        if self.curr_length_counter_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_length_counter_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The DAC is off, silencing the channel, while the initial volume is 0 and the envelope
+    /// lowers it.
+    fn dac_enabled(&self) -> bool {
+        self.volume != 0 || self.envelope_direction == EnvelopeDirection::Increase
+    }
+
+    /// Count down the length counter, on the frame sequencer's 256 Hz clock.
+    pub fn clock_length(&mut self) {
+        if self.length_counter.clock() {
+            self.enabled = false;
         }
     }
+
+    /// Step the volume envelope, on the frame sequencer's 64 Hz clock.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock(self.envelope_direction, self.envelope_sweeps);
+    }
+
+    /// Start the envelope over from the initial volume, as when the channel is triggered.
+    pub fn restart_envelope(&mut self) {
+        self.envelope.restart(self.volume, self.envelope_sweeps);
+    }
+}
+
+impl SaveState for Channel2 {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.wave_pattern);
+        w.u8(self.length_counter.counter as u8);
+        w.bool(self.length_counter.enabled);
+        w.u8(self.volume);
+        w.u8(self.envelope_direction as u8);
+        w.u8(self.envelope_sweeps);
+        w.u16(self.frequency);
+        w.bool(self.restart);
+        w.usize(self.curr_cycles);
+        w.u8(self.curr_index);
+        w.u8(self.curr_output);
+        w.bool(self.enabled);
+        self.envelope.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.wave_pattern = r.u8()? & 0b11;
+        self.length_counter.counter = u16::from(r.u8()?).min(MAX_SOUND_LENGTH);
+        self.length_counter.enabled = r.bool()?;
+        self.volume = r.u8()? & 0xF;
+        self.envelope_direction = EnvelopeDirection::from(r.u8()? & 1);
+        self.envelope_sweeps = r.u8()? & 0b111;
+        self.frequency = r.u16()? & 0x7FF;
+        self.restart = r.bool()?;
+        self.curr_cycles = r.usize()?;
+        self.curr_index = r.u8()? % 8;
+        self.curr_output = r.u8()? & 1;
+        self.enabled = r.bool()?;
+        self.envelope.load(r)?;
+        Ok(())
+    }
 }
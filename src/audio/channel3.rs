@@ -1,4 +1,5 @@
-use super::LENGTH_COUNTER_RATE_CYCLES;
+use super::LengthCounter;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 /// Wave RAM can fit 32 4-bit samples
 const WAVE_RAM_LENGTH: usize = 16;
@@ -8,14 +9,13 @@ const MAX_SOUND_LENGTH: u16 = 256;
 
 #[derive(Clone)]
 pub struct Channel3 {
-    /// Sound Length. Register FF1B
-    length: u8,
+    /// True if the DAC is on. Bit 7 of FF1A. Turning it off stops the channel, and the channel
+    /// can't be restarted until it's back on.
+    pub(super) dac_enabled: bool,
 
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 256 - length.
-    length_counter: u16,
-
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
+    /// Length counter. Used to tell when to stop playing audio. Loaded from register FF1B,
+    /// enabled by bit 6 of FF1E.
+    pub(super) length_counter: LengthCounter,
 
     /// Volume. Register FF1C
     pub volume: Volume,
@@ -27,18 +27,12 @@ pub struct Channel3 {
     /// True if we are going to restart sound.
     restart: bool,
 
-    /// True if we should stop after the current sound length
-    stop_after_sound_length: bool,
-
     /// Wave pattern RAM. Registers FF30-FF3F
     pub wave_ram: Box<[u8]>,
 
     /// Track current cycles for audio output
     curr_cycles: usize,
 
-    /// Track current cycles for length counter
-    curr_length_counter_cycles: usize,
-
     /// Track the current nibble index in wave ram
     curr_index: usize,
 
@@ -47,51 +41,67 @@ pub struct Channel3 {
 
     /// True if the channel is enabled
     enabled: bool,
+
+    /// True if the channel fetched a byte from wave RAM in the last step, which is when the
+    /// Game Boy lets the CPU at wave RAM while the channel plays. Cleared after each CPU step, so
+    /// it only covers the instruction right after the fetch, and not saved.
+    just_fetched: bool,
 }
 
 impl Channel3 {
     pub fn new() -> Channel3 {
         Channel3 {
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
+            dac_enabled: false,
+            length_counter: LengthCounter::new(MAX_SOUND_LENGTH),
             volume: Volume::Zero,
             frequency: 0,
             restart: false,
-            stop_after_sound_length: false,
             wave_ram: vec![0; WAVE_RAM_LENGTH].into_boxed_slice(),
             curr_cycles: 0,
-            curr_length_counter_cycles: 0,
             curr_index: 0,
             curr_output: 0,
             enabled: false,
+            just_fetched: false,
         }
     }
 
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
-            0x1A => ((self.enabled as u8) << 7) | 0b0111_1111, // Lower 7 bits unused
+            0x1A => ((self.dac_enabled as u8) << 7) | 0b0111_1111, // Lower 7 bits unused
             0x1B => 0xFF, // This entire register is write-only
             0x1C => ((self.volume as u8) << 5) | 0b1001_1111, // All other bits unused
             0x1D => 0xFF, // This entire register is write-only
             0x1E => {
                 0b1011_1111 // These bits are unused or write-only
-                | (self.restart as u8) << 7
-                | (self.stop_after_sound_length as u8) << 6
-                | ((self.frequency >> 8) as u8) & 0b111
+                | (self.length_counter.enabled as u8) << 6
             },
-            0x30...0x3F => self.wave_ram[(addr - 0x30) as usize],
             _ => panic!("Invalid read address for audio channel 3"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    /// The value last written to a register, as far as the channel keeps it, including the
+    /// write-only bits but not the trigger.
+    pub fn written_reg(&self, addr: u8) -> u8 {
         match addr {
-            0x1A => self.enabled = (val >> 7) == 1,
-            0x1B => {
-                self.length = val;
-                self.length_counter = MAX_SOUND_LENGTH - self.length as u16;
+            0x1A => (self.dac_enabled as u8) << 7,
+            0x1B => self.length_counter.length(),
+            0x1C => (self.volume as u8) << 5,
+            0x1D => self.frequency as u8,
+            0x1E => (self.length_counter.enabled as u8) << 6 | (self.frequency >> 8) as u8,
+            _ => self.read_reg(addr),
+        }
+    }
+
+    /// Write to a register. `extra_length_clock` is whether enabling the length counter clocks it.
+    pub fn write_reg(&mut self, addr: u8, val: u8, extra_length_clock: bool) {
+        match addr {
+            0x1A => {
+                self.dac_enabled = (val >> 7) == 1;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
             },
+            0x1B => self.length_counter.load(val),
             0x1C => self.volume = Volume::from((val >> 5) & 0b11),
             0x1D => {
                 self.frequency &= !0 << 8;
@@ -99,29 +109,49 @@ impl Channel3 {
             },
             0x1E => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
                 self.frequency &= 0xFF;
                 self.frequency |= ((val & 0b111) as u16) << 8;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = MAX_SOUND_LENGTH;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = MAX_SOUND_LENGTH
+                if self.length_counter.write_control(val, extra_length_clock) {
+                    self.enabled = false;
                 }
                 if self.restart {
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = MAX_SOUND_LENGTH
-                    }
+                    self.enabled = self.dac_enabled;
                 }
-                self.length_counter_enabled = self.restart;
             },
-            0x30...0x3F => self.wave_ram[(addr - 0x30) as usize] = val,
             _ => panic!("Invalid write address for audio channel 3"),
         }
     }
 
+    /// Read wave RAM at `addr` (0xFF30-0xFF3F). While the channel plays, the CPU only reaches the
+    /// byte the channel is playing, whatever the address. On the original Game Boy that's only
+    /// right as the channel fetches it, and otherwise reads give 0xFF.
+    pub fn read_wave_ram(&self, addr: u8, cgb: bool) -> u8 {
+        match self.wave_ram_index(addr, cgb) {
+            Some(i) => self.wave_ram[i],
+            None => 0xFF,
+        }
+    }
+
+    /// Write wave RAM at `addr`, with the same restrictions as `read_wave_ram`. Writes the original
+    /// Game Boy doesn't let through are lost.
+    pub fn write_wave_ram(&mut self, addr: u8, val: u8, cgb: bool) {
+        if let Some(i) = self.wave_ram_index(addr, cgb) {
+            self.wave_ram[i] = val;
+        }
+    }
+
+    fn wave_ram_index(&self, addr: u8, cgb: bool) -> Option<usize> {
+        if !self.enabled {
+            Some((addr - 0x30) as usize)
+        } else if cgb || self.just_fetched {
+            // `curr_index` has already moved on to the next sample.
+            Some((self.curr_index + 31) % 32 / 2)
+        } else {
+            None
+        }
+    }
+
     pub fn step(&mut self, cycles: usize) -> u8 {
         if !self.enabled {
             return 0;
@@ -130,6 +160,7 @@ impl Channel3 {
         self.curr_cycles += cycles;
         let freq = (2048 - self.frequency as usize) * 2;
         if self.curr_cycles > freq && freq > 0 {
+            self.just_fetched = true;
             self.curr_cycles %= freq;
             let mut b = self.wave_ram[self.curr_index / 2];
             if self.curr_index % 2 == 0 {
@@ -147,21 +178,22 @@ impl Channel3 {
             }
         }
 
-        self.update_length_counter(cycles);
-
         self.curr_output
     }
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_length_counter_cycles += cycles;
-        if self.curr_length_counter_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_length_counter_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
+    /// Close the window for the CPU to reach wave RAM opened by the last fetch.
+    pub fn end_cpu_step(&mut self) {
+        self.just_fetched = false;
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Count down the length counter, on the frame sequencer's 256 Hz clock.
+    pub fn clock_length(&mut self) {
+        if self.length_counter.clock() {
+            self.enabled = false;
         }
     }
 }
@@ -185,3 +217,34 @@ impl std::convert::From<u8> for Volume {
         }
     }
 }
+
+impl SaveState for Channel3 {
+    fn save(&self, w: &mut StateWriter) {
+        w.u16(self.length_counter.counter);
+        w.bool(self.length_counter.enabled);
+        w.u8(self.volume as u8);
+        w.u16(self.frequency);
+        w.bool(self.restart);
+        w.bytes(&self.wave_ram);
+        w.usize(self.curr_cycles);
+        w.usize(self.curr_index);
+        w.u8(self.curr_output);
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.length_counter.counter = r.u16()?.min(MAX_SOUND_LENGTH);
+        self.length_counter.enabled = r.bool()?;
+        self.volume = Volume::from(r.u8()? & 0b11);
+        self.frequency = r.u16()? & 0x7FF;
+        self.restart = r.bool()?;
+        r.bytes_into(&mut self.wave_ram)?;
+        self.curr_cycles = r.usize()?;
+        self.curr_index = r.usize()? % (WAVE_RAM_LENGTH * 2);
+        self.curr_output = r.u8()? & 0xF;
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        Ok(())
+    }
+}
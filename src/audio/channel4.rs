@@ -1,18 +1,14 @@
-use super::{EnvelopeDirection, LENGTH_COUNTER_RATE_CYCLES};
+use super::{Envelope, EnvelopeDirection, LengthCounter};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 /// Max length for sound data
-const MAX_SOUND_LENGTH: u8 = 64;
+const MAX_SOUND_LENGTH: u16 = 64;
 
 #[derive(Clone)]
 pub struct Channel4 {
-    /// Sound Length. Bits 0-5 of 0xFF20
-    length: u8,
-
-    /// Length counter. Used to tell when to stop playing audio. Sound length is given by 64 - length.
-    length_counter: u8,
-
-    /// True if the length counter is enabled
-    length_counter_enabled: bool,
+    /// Length counter. Used to tell when to stop playing audio. Loaded from bits 0-5 of 0xFF20,
+    /// enabled by bit 6 of 0xFF23.
+    pub(super) length_counter: LengthCounter,
 
     /// Initial volume of envelope. Bits 4-7 of 0xFF21
     volume: u8,
@@ -38,28 +34,23 @@ pub struct Channel4 {
     /// True if we are going to restart sound.
     restart: bool,
 
-    /// True if we should stop after the current sound length
-    stop_after_sound_length: bool,
-
     /// Track the current step updating audio output
     curr_index: usize,
 
-    /// Track current cycles for audio output
-    curr_cycles: usize,
-
     /// Track the current audio output value
     curr_output: u8,
 
     /// True if the channel is enabled
     enabled: bool,
+
+    /// The volume the channel is playing at, stepped by the envelope
+    pub(super) envelope: Envelope,
 }
 
 impl Channel4 {
     pub fn new() -> Self {
         Self {
-            length: 0,
-            length_counter: MAX_SOUND_LENGTH,
-            length_counter_enabled: true,
+            length_counter: LengthCounter::new(MAX_SOUND_LENGTH),
             volume: 0,
             envelope_direction: EnvelopeDirection::Decrease,
             envelope_sweeps: 0,
@@ -68,11 +59,10 @@ impl Channel4 {
             linear_feedback_shift_register: 0b0111_1111_1111_1111,
             dividing_ratio: 0,
             restart: false,
-            stop_after_sound_length: false,
             curr_index: 0,
-            curr_cycles: 0,
             curr_output: 0,
             enabled: false,
+            envelope: Envelope::new(),
         }
     }
 
@@ -91,23 +81,36 @@ impl Channel4 {
             },
             0x23 => {
                 0b10111111 // These bits are unused or write-only
-                    | (self.restart as u8) << 7
-                    | (self.stop_after_sound_length as u8) << 6
+                    | (self.length_counter.enabled as u8) << 6
             },
             _ => panic!("Invalid read address for audio channel 4"),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    /// The value last written to a register, as far as the channel keeps it, including the
+    /// write-only bits but not the trigger.
+    pub fn written_reg(&self, addr: u8) -> u8 {
         match addr {
-            0x20 => {
-                self.length = val & 0b0011_1111;
-                self.length_counter = MAX_SOUND_LENGTH - self.length;
-            },
+            0x20 => self.length_counter.length() & 0b0011_1111,
+            0x23 => (self.length_counter.enabled as u8) << 6,
+            _ => self.read_reg(addr),
+        }
+    }
+
+    /// Write to a register. `extra_length_clock` is whether enabling the length counter clocks it.
+    pub fn write_reg(&mut self, addr: u8, val: u8, extra_length_clock: bool) {
+        match addr {
+            0x20 => self.length_counter.load(val & 0b0011_1111),
             0x21 => {
+                let (old_direction, old_sweeps) = (self.envelope_direction, self.envelope_sweeps);
                 self.envelope_sweeps = val & 0b0111;
                 self.envelope_direction = EnvelopeDirection::from((val >> 3) & 1);
                 self.volume = val >> 4;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                } else if self.enabled {
+                    self.envelope.write_while_playing(old_direction, old_sweeps, self.envelope_direction);
+                }
             },
             0x22 => {
                 self.dividing_ratio = val & 0b0111;
@@ -116,28 +119,21 @@ impl Channel4 {
             },
             0x23 => {
                 self.restart = (val >> 7) & 1 == 1;
-                self.stop_after_sound_length = (val >> 6) & 1 == 1;
 
-                if self.length_counter == 0 && self.restart {
-                    self.length_counter = 64;
-                }
-                if self.length_counter_enabled {
-                    self.length_counter = 64
+                if self.length_counter.write_control(val, extra_length_clock) {
+                    self.enabled = false;
                 }
                 if self.restart {
                     self.linear_feedback_shift_register = 0b0111_1111_1111_1111;
-                    self.enabled = true;
-                    if self.stop_after_sound_length {
-                        self.length_counter = 64
-                    }
+                    self.enabled = self.dac_enabled();
+                    self.restart_envelope();
                 }
-                self.length_counter_enabled = self.restart;
             },
             _ => panic!("Invalid write address for audio channel 4"),
         }
     }
 
-    pub fn step(&mut self, cycles: usize) -> u8 {
+    pub fn step(&mut self) -> u8 {
         if !self.enabled {
             return 0;
         }
@@ -149,9 +145,7 @@ impl Channel4 {
             self.curr_output = self.get_next_output();
         }
 
-        self.update_length_counter(cycles);
-
-        self.curr_output * self.volume
+        self.curr_output * self.envelope.volume
     }
 
     fn get_divisor(&self, dividing_ratio: u8) -> usize {
@@ -182,16 +176,68 @@ impl Channel4 {
         return (!self.linear_feedback_shift_register & 1) as u8
     }
 
-    fn update_length_counter(&mut self, cycles: usize) {
-        self.curr_cycles += cycles;
-        if self.curr_cycles >= LENGTH_COUNTER_RATE_CYCLES {
-            self.curr_cycles %= LENGTH_COUNTER_RATE_CYCLES;
-            if self.length_counter > 0 && self.length_counter_enabled {
-                self.length_counter -= 1;
-                if self.length_counter == 0 {
-                    self.enabled = false;
-                }
-            }
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The DAC is off, silencing the channel, while the initial volume is 0 and the envelope
+    /// lowers it.
+    fn dac_enabled(&self) -> bool {
+        self.volume != 0 || self.envelope_direction == EnvelopeDirection::Increase
+    }
+
+    /// Count down the length counter, on the frame sequencer's 256 Hz clock.
+    pub fn clock_length(&mut self) {
+        if self.length_counter.clock() {
+            self.enabled = false;
         }
     }
+
+    /// Step the volume envelope, on the frame sequencer's 64 Hz clock.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock(self.envelope_direction, self.envelope_sweeps);
+    }
+
+    /// Start the envelope over from the initial volume, as when the channel is triggered.
+    pub fn restart_envelope(&mut self) {
+        self.envelope.restart(self.volume, self.envelope_sweeps);
+    }
+}
+
+impl SaveState for Channel4 {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.length_counter.counter as u8);
+        w.bool(self.length_counter.enabled);
+        w.u8(self.volume);
+        w.u8(self.envelope_direction as u8);
+        w.u8(self.envelope_sweeps);
+        w.u8(self.shift_clock_frequency);
+        w.u8(self.counter_step);
+        w.u8(self.dividing_ratio);
+        w.u16(self.linear_feedback_shift_register);
+        w.bool(self.restart);
+        w.usize(self.curr_index);
+        w.u8(self.curr_output);
+        w.bool(self.enabled);
+        self.envelope.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.length_counter.counter = u16::from(r.u8()?).min(MAX_SOUND_LENGTH);
+        self.length_counter.enabled = r.bool()?;
+        self.volume = r.u8()? & 0xF;
+        self.envelope_direction = EnvelopeDirection::from(r.u8()? & 1);
+        self.envelope_sweeps = r.u8()? & 0b111;
+        self.shift_clock_frequency = r.u8()? & 0xF;
+        self.counter_step = r.u8()? & 1;
+        self.dividing_ratio = r.u8()? & 0b111;
+        self.linear_feedback_shift_register = r.u16()? & 0x7FFF;
+        self.restart = r.bool()?;
+        let out_freq = self.get_divisor(self.dividing_ratio) << self.shift_clock_frequency as usize;
+        self.curr_index = r.usize()? % out_freq;
+        self.curr_output = r.u8()? & 1;
+        self.enabled = r.bool()?;
+        self.envelope.load(r)?;
+        Ok(())
+    }
 }
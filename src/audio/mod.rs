@@ -8,15 +8,26 @@ use channel2::Channel2;
 use channel3::Channel3;
 use channel4::Channel4;
 use log::warn;
+use std::collections::VecDeque;
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
-/// Number of samples in our audio buffer
+/// Number of samples in our audio buffer, unless configured otherwise
 pub const SAMPLE_BUFFER_SIZE: usize = 1024;
 
-/// Number of cycles between samples to achieve at rate of 44100Hz
-const SAMPLE_RATE_CYCLES: usize = 95;
+/// The output sample rate, unless configured otherwise
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
 
-/// Number of cycles between length counter updates to achieve 256Hz
-const LENGTH_COUNTER_RATE_CYCLES: usize = 16383;
+/// The rate the APU is clocked at, in cycles per second
+const CLOCK_RATE: f64 = 4_194_304.0;
+
+/// How many samples of each channel's output the oscilloscope keeps, about 23 ms worth
+pub const SCOPE_SAMPLES: usize = 1024;
+
+/// Where the APU's samples go as they're made, like a sound card's queue.
+pub trait AudioSink {
+    /// Play interleaved left and right samples, unsigned 8-bit.
+    fn queue(&mut self, samples: &[u8]);
+}
 
 #[derive(Clone)]
 pub struct Audio {
@@ -30,22 +41,47 @@ pub struct Audio {
     left_volume: u8,
     /// Vin to S01 terminal enabled. Bit 3 at 0xFF24
     output_vin_right: bool,
-    /// S02 volume (0-7). Bits 0-2 at 0xFF24
+    /// S01 volume (0-7). Bits 0-2 at 0xFF24
     right_volume: u8,
-    /// Sound channel output selection. register 0xFF25
+    /// Sound channel output selection. register 0xFF25. Bits 4-7 send channels 1-4 to the left
+    /// (SO2), and bits 0-3 to the right (SO1).
     selection: u8,
     /// Sound enabled. Bit 7 at 0xFF26. Cannot access any sound registers besides 0xFF26 while disabled.
+    /// Bits 0-3 are read-only, and tell which channels are playing.
     enabled: bool,
-    /// Sound 4 enabled. Bit 3 at 0xFF26. Read only
-    channel_4_enabled: bool,
-    /// Sound 3 enabled. Bit 2 at 0xFF26. Read only
-    channel_3_enabled: bool,
-    /// Sound 2 enabled. Bit 1 at 0xFF26. Read only
-    channel_2_enabled: bool,
-    /// Sound 1 enabled. Bit 0 at 0xFF26. Read only
-    channel_1_enabled: bool,
+    /// The frame sequencer's position, 0-7. It's clocked at 512 Hz by DIV, see
+    /// `clock_frame_sequencer`.
+    frame_sequencer_step: u8,
     /// Cycle counter for outputting sound data to the audio queue at the proper rate.
-    queue_cycles: usize,
+    queue_cycles: f64,
+    /// Cycle counter for `capture`, which always gets samples at the nominal sample period, so
+    /// recordings and hashes don't depend on how the frontend paces the audio queue.
+    capture_cycles: f64,
+    /// The output sample rate, which sets how many cycles pass between samples. Not part of the
+    /// emulated state.
+    sample_rate: u32,
+    /// How many cycles pass between samples, nominally the clock rate over the sample rate. The
+    /// frontend stretches or shrinks it slightly to keep the audio queue from running dry or
+    /// filling up. Not part of the emulated state.
+    sample_period: f64,
+    /// The number of samples output so far, for the frontend to tell whether it can pace itself by
+    /// the audio queue.
+    samples_output: u64,
+    /// Each channel's digital output (0-15) from the last step, for PCM12 and PCM34. Not part of
+    /// the saved state, since the next step works it out again.
+    outputs: [u8; 4],
+    /// If set, samples are also appended here as interleaved left/right bytes, whether or not
+    /// there is an audio queue, at exactly the sample rate.
+    pub capture: Option<Vec<u8>>,
+    /// If set, the latest samples of each channel's output are kept here for an oscilloscope.
+    pub scope: Option<Scope>,
+    /// If set, every write to the sound registers is also logged here, e.g. to export the music.
+    pub register_log: Option<RegisterLog>,
+    /// Whether this is a Game Boy Color, whose APU has fewer quirks.
+    pub cgb: bool,
+    /// Channels left out of the mix, indexed from channel 1, for listening to parts of a song on
+    /// their own. A user setting, not part of the emulated state.
+    pub muted: [bool; 4],
 }
 
 impl Audio {
@@ -55,20 +91,38 @@ impl Audio {
             channel2: Channel2::new(),
             channel3: Channel3::new(),
             channel4: Channel4::new(),
-            queue_cycles: 0,
+            frame_sequencer_step: 0,
+            queue_cycles: 0.0,
+            capture_cycles: 0.0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_period: CLOCK_RATE / f64::from(DEFAULT_SAMPLE_RATE),
+            samples_output: 0,
+            outputs: [0; 4],
+            capture: None,
+            muted: [false; 4],
+            scope: None,
+            register_log: None,
+            cgb: false,
             output_vin_left: false,
             left_volume: 7,
             output_vin_right: false,
             right_volume: 7,
             selection: 0xF3,
             enabled: true,
-            channel_4_enabled: true,
-            channel_3_enabled: true,
-            channel_2_enabled: true,
-            channel_1_enabled: true,
         }
     }
 
+    /// The channel registers NR10 to NR44 as they were last written, for putting another APU in
+    /// the same state. The NRx4 values don't trigger the channels.
+    pub fn channel_registers(&self) -> Vec<(u8, u8)> {
+        let mut regs = Vec::new();
+        regs.extend((0x10..=0x14).map(|addr| (addr, self.channel1.written_reg(addr))));
+        regs.extend((0x16..=0x19).map(|addr| (addr, self.channel2.written_reg(addr))));
+        regs.extend((0x1A..=0x1E).map(|addr| (addr, self.channel3.written_reg(addr))));
+        regs.extend((0x20..=0x23).map(|addr| (addr, self.channel4.written_reg(addr))));
+        regs
+    }
+
     pub fn read_reg(&self, addr: u8) -> u8 {
         match addr {
             0x10...0x14 => self.channel1.read_reg(addr),
@@ -87,22 +141,39 @@ impl Audio {
             0x26 => {
                 (self.enabled as u8) << 7
                 | 0b0111_0000 // Unused bits
-                | (self.channel_4_enabled as u8) << 3
-                | (self.channel_3_enabled as u8) << 2
-                | (self.channel_2_enabled as u8) << 1
-                | (self.channel_1_enabled as u8)
+                | (self.channel4.enabled() as u8) << 3
+                | (self.channel3.enabled() as u8) << 2
+                | (self.channel2.enabled() as u8) << 1
+                | (self.channel1.enabled() as u8)
             }
-            0x30...0x3F => self.channel3.read_reg(addr),
+            0x30...0x3F => self.channel3.read_wave_ram(addr, self.cgb),
             _ => panic!("Unimplemented audio register read"),
         }
     }
 
     pub fn write_reg(&mut self, addr: u8, val: u8) {
+        if let Some(log) = &mut self.register_log {
+            log.writes.push(RegisterWrite { cycle: log.cycles, addr, val });
+        }
+        let mut val = val;
+        if !self.enabled {
+            match addr {
+                0x26 | 0x30...0x3F => {}
+                // The original Game Boy's length counters stay powered, so they can still be
+                // loaded. The duty bits next to them can't.
+                0x11 | 0x16 | 0x20 if !self.cgb => val &= 0b0011_1111,
+                0x1B if !self.cgb => {}
+                _ => return,
+            }
+        }
+        // Enabling a length counter clocks it right away if the frame sequencer's last step
+        // clocked the lengths, that is if the next one won't.
+        let extra_length_clock = self.frame_sequencer_step % 2 == 1;
         match addr {
-            0x10...0x14 => self.channel1.write_reg(addr, val),
-            0x16...0x19 => self.channel2.write_reg(addr, val),
-            0x1A...0x1E => self.channel3.write_reg(addr, val),
-            0x20...0x23 => self.channel4.write_reg(addr, val),
+            0x10...0x14 => self.channel1.write_reg(addr, val, extra_length_clock),
+            0x16...0x19 => self.channel2.write_reg(addr, val, extra_length_clock),
+            0x1A...0x1E => self.channel3.write_reg(addr, val, extra_length_clock),
+            0x20...0x23 => self.channel4.write_reg(addr, val, extra_length_clock),
             0x24 => {
                 self.output_vin_left = val & (1 << 7) != 0;
                 self.left_volume = (val >> 4) & 0b111;
@@ -117,90 +188,279 @@ impl Audio {
                 self.selection = val;
             }
             0x26 => {
-                // Should destroy all sound register contents upon disabled.
-                self.enabled = val & (1 << 7) != 0;
+                let enabled = val & (1 << 7) != 0;
+                if self.enabled && !enabled {
+                    self.power_off();
+                } else if !self.enabled && enabled {
+                    self.frame_sequencer_step = 0;
+                }
+                self.enabled = enabled;
             }
-            0x30...0x3F => self.channel3.write_reg(addr, val),
+            0x30...0x3F => self.channel3.write_wave_ram(addr, val, self.cgb),
             _ => panic!("Unimplemented audio register write"),
         }
     }
 
-    pub fn step(&mut self, cycles: usize, audio_queue: &mut sdl2::audio::AudioQueue<u8>) {
+    /// Turning the APU off clears every sound register but wave RAM, and on the original Game Boy
+    /// the length counters.
+    fn power_off(&mut self) {
+        let lengths = [
+            self.channel1.length_counter.counter,
+            self.channel2.length_counter.counter,
+            self.channel3.length_counter.counter,
+            self.channel4.length_counter.counter,
+        ];
+        let wave_ram = std::mem::replace(&mut self.channel3.wave_ram, Box::new([]));
+        self.channel1 = Channel1::new();
+        self.channel2 = Channel2::new();
+        self.channel3 = Channel3::new();
+        self.channel4 = Channel4::new();
+        self.channel3.wave_ram = wave_ram;
+        if !self.cgb {
+            self.channel1.length_counter.counter = lengths[0];
+            self.channel2.length_counter.counter = lengths[1];
+            self.channel3.length_counter.counter = lengths[2];
+            self.channel4.length_counter.counter = lengths[3];
+        }
+        self.output_vin_left = false;
+        self.left_volume = 0;
+        self.output_vin_right = false;
+        self.right_volume = 0;
+        self.selection = 0;
+    }
+
+    /// Call after each CPU step, before stepping the APU for it. See `Channel3::end_cpu_step`.
+    pub fn end_cpu_step(&mut self) {
+        self.channel3.end_cpu_step();
+    }
+
+    /// Output samples `scale` times as far apart as normal, e.g. 1.005 for 0.5% fewer samples. Only
+    /// the audio queue is affected, not `capture`.
+    pub fn set_sample_period_scale(&mut self, scale: f64) {
+        self.sample_period = CLOCK_RATE / f64::from(self.sample_rate) * scale;
+    }
+
+    /// Output `rate` samples per second, e.g. to match the sound card.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.set_sample_period_scale(1.0);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// PCM12 (0xFF76) and PCM34 (0xFF77), the Game Boy Color's readback of the channels' digital
+    /// outputs, with the lower numbered channel in the low nibble.
+    pub fn read_pcm(&self, port: u8) -> u8 {
+        let first = if port == 0x76 { 0 } else { 2 };
+        self.outputs[first] | self.outputs[first + 1] << 4
+    }
+
+    pub fn samples_output(&self) -> u64 {
+        self.samples_output
+    }
+
+    /// Mute every channel but the one at `index` in `muted`, or unmute them all if it was already
+    /// the only one playing.
+    pub fn toggle_solo(&mut self, index: usize) {
+        let soloed = self.muted.iter().enumerate().all(|(i, &muted)| muted == (i != index));
+        for (i, muted) in self.muted.iter_mut().enumerate() {
+            *muted = !soloed && i != index;
+        }
+    }
+
+    /// Clock the frame sequencer, on each falling edge of DIV's bit 4 (bit 5 in double speed mode),
+    /// so resetting DIV delays the next length and envelope steps like on hardware. Even steps
+    /// clock the length counters, steps 2 and 6 channel 1's frequency sweep, and step 7 the volume
+    /// envelopes.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.frame_sequencer_step % 2 == 0 {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if self.frame_sequencer_step % 4 == 2 {
+            self.channel1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    pub fn step(&mut self, cycles: usize, sink: Option<&mut dyn AudioSink>) {
+        if let Some(log) = &mut self.register_log {
+            log.cycles += cycles;
+        }
         let channel1_val = self.channel1.step(cycles);
         let channel2_val = self.channel2.step(cycles);
         let channel3_val = self.channel3.step(cycles);
-        let channel4_val = self.channel4.step(cycles);
+        let channel4_val = self.channel4.step();
+        let unmuted = [channel1_val, channel2_val, channel3_val, channel4_val];
+        self.outputs = unmuted;
+        let vals = self.apply_mutes(unmuted);
+
+        // NR50 scales each side by (volume + 1) / 8, so even volume 0 is audible. Four channels at
+        // their loudest with NR50 at 7 make 240.
+        let (left, right) = self.get_left_and_right_audio(vals);
+        let left = (left * (self.left_volume as u16 + 1) / 2) as u8;
+        let right = (right * (self.right_volume as u16 + 1) / 2) as u8;
 
-        let (mut left, mut right) = self.get_left_and_right_audio(channel1_val, channel2_val, channel3_val, channel4_val);
-        left *= self.left_volume;
-        right *= self.right_volume;
+        if self.output_to_queue(left, right, sink, cycles) {
+            if let Some(scope) = &mut self.scope {
+                scope.push(unmuted);
+            }
+        }
+    }
 
-        self.output_to_queue(left, right, audio_queue, cycles);
+    fn apply_mutes(&self, mut vals: [u8; 4]) -> [u8; 4] {
+        for (val, &muted) in vals.iter_mut().zip(&self.muted) {
+            if muted {
+                *val = 0;
+            }
+        }
+        vals
     }
 
-    fn get_left_and_right_audio(&self, channel1_val: u8, channel2_val: u8, channel3_val: u8, channel4_val: u8) -> (u8, u8) {
+    /// Route each channel to the left and right outputs as NR51 says, returning the sum on each
+    /// side.
+    fn get_left_and_right_audio(&self, vals: [u8; 4]) -> (u16, u16) {
         let mut left: u16 = 0;
         let mut right: u16 = 0;
         if !self.enabled {
             return (0, 0);
         }
 
-        if self.channel_4_enabled {
-            if self.selection & (1 << 7) != 0 {
-                left += channel4_val as u16;
-            }
-        }
-        if self.channel_3_enabled {
-            if self.selection & (1 << 6) != 0 {
-                left += channel3_val as u16;
-            }
-        }
-        if self.channel_2_enabled {
-            if self.selection & (1 << 5) != 0 {
-                left += channel2_val as u16;
+        for (i, &val) in vals.iter().enumerate() {
+            if self.selection & (1 << (4 + i)) != 0 {
+                left += val as u16;
             }
-        }
-        if self.channel_1_enabled {
-            if self.selection & (1 << 4) != 0 {
-                left += channel1_val as u16;
+            if self.selection & (1 << i) != 0 {
+                right += val as u16;
             }
         }
+        (left, right)
+    }
 
-        if self.channel_4_enabled {
-            if self.selection & (1 << 3) != 0 {
-                right += channel4_val as u16;
-            }
-        }
-        if self.channel_3_enabled {
-            if self.selection & (1 << 2) != 0 {
-                right += channel3_val as u16;
+    /// Output a sample if it's time to. Returns whether one was output.
+    fn output_to_queue(&mut self, left: u8, right: u8, sink: Option<&mut dyn AudioSink>, cycles: usize) -> bool {
+        if let Some(capture) = &mut self.capture {
+            let period = CLOCK_RATE / f64::from(self.sample_rate);
+            self.capture_cycles += cycles as f64;
+            if self.capture_cycles >= period {
+                self.capture_cycles %= period;
+                capture.extend_from_slice(&[left, right]);
             }
         }
-        if self.channel_2_enabled {
-            if self.selection & (1 << 1) != 0 {
-                right += channel2_val as u16;
+        self.queue_cycles += cycles as f64;
+        if self.queue_cycles >= self.sample_period {
+            self.queue_cycles %= self.sample_period;
+            self.samples_output += 1;
+            // Need to verify that this is the right way to do left and right audio
+            if let Some(sink) = sink {
+                sink.queue(&[left, right]);
             }
+            return true;
         }
-        if self.channel_1_enabled {
-            if self.selection & 1 != 0 {
-                right += channel1_val as u16;
-            }
+        false
+    }
+}
+
+impl SaveState for Audio {
+    fn save(&self, w: &mut StateWriter) {
+        self.channel1.save(w);
+        self.channel2.save(w);
+        self.channel3.save(w);
+        self.channel4.save(w);
+        w.u8(self.read_reg(0x24));
+        w.u8(self.selection);
+        w.bool(self.enabled);
+        w.u64(self.queue_cycles.to_bits());
+        w.u8(self.frame_sequencer_step);
+        w.u8(self.read_pcm(0x76));
+        w.u8(self.read_pcm(0x77));
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.channel1.load(r)?;
+        self.channel2.load(r)?;
+        self.channel3.load(r)?;
+        self.channel4.load(r)?;
+        let volume = r.u8()?;
+        self.output_vin_left = volume & (1 << 7) != 0;
+        self.left_volume = (volume >> 4) & 0b111;
+        self.output_vin_right = volume & (1 << 3) != 0;
+        self.right_volume = volume & 0b111;
+        self.selection = r.u8()?;
+        self.enabled = r.bool()?;
+        self.queue_cycles = f64::from_bits(r.u64()?);
+        if !(self.queue_cycles >= 0.0 && self.queue_cycles.is_finite()) {
+            return Err(SaveStateError::InvalidValue("audio queue cycles"));
         }
+        self.capture_cycles = self.queue_cycles;
+        self.frame_sequencer_step = r.u8()? % 8;
+        let (pcm12, pcm34) = (r.u8()?, r.u8()?);
+        self.outputs = [pcm12 & 0xF, pcm12 >> 4, pcm34 & 0xF, pcm34 >> 4];
+        Ok(())
+    }
+}
+
+/// The latest output of each channel, sampled along with the mixed output.
+#[derive(Clone, Default)]
+pub struct Scope {
+    /// Each channel's samples (0-15), oldest first, before muting. At most `SCOPE_SAMPLES`.
+    pub channels: [VecDeque<u8>; 4],
+}
 
-        ((left / 4) as u8, (right / 4) as u8)
+impl Scope {
+    pub fn new() -> Scope {
+        Scope::default()
     }
 
-    fn output_to_queue(&mut self, left: u8, right: u8, queue: &mut sdl2::audio::AudioQueue<u8>, cycles: usize) {
-        self.queue_cycles += cycles;
-        if self.queue_cycles >= SAMPLE_RATE_CYCLES {
-            self.queue_cycles %= SAMPLE_RATE_CYCLES;
-            // Need to verify that this is the right way to do left and right audio
-            queue.queue(&[left, right]);
+    fn push(&mut self, vals: [u8; 4]) {
+        for (samples, &val) in self.channels.iter_mut().zip(&vals) {
+            if samples.len() == SCOPE_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back(val);
         }
     }
 }
 
-#[derive(Clone, Copy)]
+/// Writes to the sound registers since the log was last cleared.
+#[derive(Clone, Debug, Default)]
+pub struct RegisterLog {
+    pub writes: Vec<RegisterWrite>,
+
+    /// How many cycles the APU has run for since the log was cleared.
+    pub cycles: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterWrite {
+    /// When the write happened, in cycles since the log was cleared.
+    pub cycle: usize,
+
+    /// The register's address, 0x10-0x3F.
+    pub addr: u8,
+    pub val: u8,
+}
+
+impl RegisterLog {
+    pub fn clear(&mut self) {
+        self.writes.clear();
+        self.cycles = 0;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum EnvelopeDirection {
     Decrease = 0,
     Increase = 1,
@@ -215,3 +475,198 @@ impl std::convert::From<u8> for EnvelopeDirection {
         }
     }
 }
+
+
+/// A channel's volume envelope, which steps its volume from the initial volume in NRx2 towards
+/// silence or full volume, once every `sweeps` ticks of the frame sequencer's 64 Hz clock.
+#[derive(Clone)]
+pub struct Envelope {
+    /// The volume the channel is playing at
+    pub volume: u8,
+    /// Ticks left until the next step
+    timer: u8,
+    /// Whether the volume can still change, which stops once it reaches 0 or 15
+    pub running: bool,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope { volume: 0, timer: 0, running: false }
+    }
+
+    pub fn restart(&mut self, volume: u8, sweeps: u8) {
+        self.volume = volume;
+        self.timer = sweeps;
+        self.running = true;
+    }
+
+    /// Writing NRx2 while the channel plays changes the volume right away ("zombie mode"), in a
+    /// way that depends on the old envelope settings. Games like Prehistorik Man use this to set
+    /// the volume without restarting the note.
+    pub fn write_while_playing(&mut self, old_direction: EnvelopeDirection, old_sweeps: u8, new_direction: EnvelopeDirection) {
+        // The volume is 4 bits, so all of this wraps around.
+        if old_sweeps == 0 && self.running {
+            self.volume = self.volume.wrapping_add(1) & 0xF;
+        } else if old_direction == EnvelopeDirection::Decrease {
+            self.volume = self.volume.wrapping_add(2) & 0xF;
+        }
+        if old_direction != new_direction {
+            self.volume = 16u8.wrapping_sub(self.volume) & 0xF;
+        }
+    }
+
+    pub fn clock(&mut self, direction: EnvelopeDirection, sweeps: u8) {
+        if sweeps == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = sweeps;
+        if !self.running {
+            return;
+        }
+        match direction {
+            EnvelopeDirection::Increase if self.volume < 15 => self.volume += 1,
+            EnvelopeDirection::Decrease if self.volume > 0 => self.volume -= 1,
+            _ => self.running = false,
+        }
+    }
+}
+
+/// Counts down while a channel plays and stops it at 0, if enabled by bit 6 of NRx4.
+#[derive(Clone)]
+pub struct LengthCounter {
+    /// The length of the longest note, 64, or 256 on channel 3
+    max: u16,
+    pub counter: u16,
+    pub enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter { max, counter: 0, enabled: false }
+    }
+
+    /// Load the length part of NRx1, which counts up to `max`.
+    pub fn load(&mut self, length: u8) {
+        self.counter = self.max - u16::from(length);
+    }
+
+    /// The note length the counter was loaded with, for reading back.
+    pub fn length(&self) -> u8 {
+        (self.max - self.counter) as u8
+    }
+
+    /// Returns whether the channel should stop.
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    /// Handle a write to NRx4. `extra_clock` is whether the frame sequencer won't clock lengths on
+    /// its next step, in which case enabling the counter clocks it once right away. Returns whether
+    /// the channel should stop, which a trigger overrides.
+    pub fn write_control(&mut self, val: u8, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = val & (1 << 6) != 0;
+        let trigger = val & (1 << 7) != 0;
+        let mut stop = false;
+        if extra_clock && !was_enabled && self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            stop = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && extra_clock {
+                self.counter -= 1;
+            }
+        }
+        stop
+    }
+}
+
+impl SaveState for Envelope {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.volume);
+        w.u8(self.timer);
+        w.bool(self.running);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.volume = r.u8()? & 0xF;
+        self.timer = r.u8()? & 0b111;
+        self.running = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpu::CYCLES_PER_FRAME;
+    use quickcheck::quickcheck;
+
+    quickcheck! {
+        fn envelope_zombie_mode(volume: u8, old: u8, new: u8, running: bool) -> bool {
+            let (old_direction, old_sweeps, new_direction) = (old & 1, old >> 1 & 0b111, new & 1);
+            let mut envelope = Envelope::new();
+            envelope.restart(volume & 0xF, old_sweeps);
+            envelope.running = running;
+            envelope.write_while_playing(old_direction.into(), old_sweeps, new_direction.into());
+            let mut expected = u16::from(volume & 0xF);
+            if old_sweeps == 0 && running {
+                expected += 1;
+            } else if EnvelopeDirection::from(old_direction) == EnvelopeDirection::Decrease {
+                expected += 2;
+            }
+            if old_direction != new_direction {
+                expected = 16 - expected % 16;
+            }
+            u16::from(envelope.volume) == expected % 16
+        }
+
+        fn length_counter(length: u8, enabled: bool) -> bool {
+            let length = length & 0x3F;
+            let mut counter = LengthCounter::new(64);
+            counter.load(length);
+            counter.enabled = enabled;
+            if counter.length() != length {
+                return false;
+            }
+            let stopped_after = (1..=256).find(|_| counter.clock());
+            stopped_after == if enabled { Some(64 - u32::from(length)) } else { None }
+        }
+
+        fn sweep_overflow_stops_channel(frequency: u16, shift: u8, negate: bool) -> bool {
+            // Triggering channel 1 works out the first sweep step right away, which stops the
+            // channel if it would go past the highest frequency. Lowering the frequency never does.
+            let (frequency, shift) = (frequency & 0x7FF, shift & 0b111);
+            let mut audio = Audio::new();
+            audio.write_reg(0x26, 0x80);
+            audio.write_reg(0x12, 0xF0);
+            audio.write_reg(0x10, 1 << 4 | (negate as u8) << 3 | shift);
+            audio.write_reg(0x13, frequency as u8);
+            audio.write_reg(0x14, 0x80 | (frequency >> 8) as u8);
+            let overflows = !negate && shift != 0 && frequency + (frequency >> shift) > 2047;
+            let playing = audio.read_reg(0x26) & 1 != 0;
+            playing == !overflows
+        }
+
+        fn capture_ignores_rate_control(scale: u8) -> bool {
+            let mut nominal = Audio::new();
+            nominal.capture = Some(Vec::new());
+            let mut scaled = nominal.clone();
+            scaled.set_sample_period_scale(0.25 + f64::from(scale) / 64.0);
+            for _ in 0..CYCLES_PER_FRAME / 4 {
+                nominal.step(4, None);
+                scaled.step(4, None);
+            }
+            nominal.capture == scaled.capture
+        }
+    }
+}
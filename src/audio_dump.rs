@@ -0,0 +1,83 @@
+//! Recording the mixed sound output to a WAV file, for capturing soundtracks and for comparing
+//! audio across emulator versions.
+//!
+//! The samples are exactly what would go to the speakers: unsigned 8-bit stereo at the emulator's
+//! output sample rate, 44100 Hz unless configured otherwise. The header's lengths are only known at
+//! the end, so they're filled in by `finish`.
+
+use crate::audio::DEFAULT_SAMPLE_RATE;
+use crate::cpu::Cpu;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 8;
+
+/// The size of the RIFF header, `fmt ` chunk, and `data` chunk header.
+const HEADER_LEN: u32 = 44;
+
+pub struct AudioDump {
+    out: BufWriter<File>,
+
+    /// Bytes of samples written so far.
+    data_len: u32,
+
+    /// The rate the samples were made at, which the frontend may change to match the sound card.
+    sample_rate: u32,
+}
+
+impl AudioDump {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut dump = AudioDump {
+            out: BufWriter::new(File::create(path)?),
+            data_len: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        };
+        dump.write_header()?;
+        Ok(dump)
+    }
+
+    /// Prepare `cpu` for recording, by capturing its audio output.
+    pub fn attach(&self, cpu: &mut Cpu) {
+        if cpu.audio.capture.is_none() {
+            cpu.audio.capture = Some(Vec::new());
+        }
+    }
+
+    /// Append the samples `cpu` captured since they were last cleared.
+    pub fn write_frame(&mut self, cpu: &Cpu) -> io::Result<()> {
+        self.sample_rate = cpu.audio.sample_rate();
+        if let Some(samples) = &cpu.audio.capture {
+            self.out.write_all(samples)?;
+            self.data_len = self.data_len.saturating_add(samples.len() as u32);
+        }
+        Ok(())
+    }
+
+    /// Fill in the lengths in the header, leaving a complete file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let out = &mut self.out;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_LEN - 8).saturating_add(self.data_len).to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // Uncompressed PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&CHANNELS.to_le_bytes())?;
+        out.write_all(&self.sample_rate.to_le_bytes())?;
+        out.write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&self.data_len.to_le_bytes())
+    }
+}
@@ -0,0 +1,141 @@
+//! The Barcode Boy, Namco's card swiping accessory for the link port, used by games like Battle
+//! Space and Monster Maker: Barcode Saga to turn barcodes into monsters and items.
+//!
+//! The game starts by clocking a handshake of 0x10, 0x07, 0x10, 0x07, to which the scanner
+//! answers 0xFF, 0xFF, 0x10, 0x07. It then waits on the external clock, and each swiped card is
+//! clocked in as 0x02, the barcode's 13 digits in ASCII, and 0x03.
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::serial::SerialDevice;
+use failure_derive::Fail;
+use std::any::Any;
+use std::collections::VecDeque;
+
+/// The scanner's answers to the handshake, in order. After that it only shifts out 1 bits.
+const HANDSHAKE: [u8; 4] = [0xFF, 0xFF, 0x10, 0x07];
+
+const START: u8 = 0x02;
+const END: u8 = 0x03;
+
+/// How long the scanner waits between the bytes of a barcode, giving the game time to store each
+/// one.
+const BYTE_INTERVAL_CYCLES: usize = 8192;
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum BarcodeError {
+    #[fail(display = "barcodes are 12 or 13 digits, not {:?}", _0)]
+    Invalid(String),
+
+    #[fail(display = "barcode {} has the wrong check digit, it should end in {}", barcode, check_digit)]
+    CheckDigit {
+        barcode: String,
+        check_digit: u8,
+    },
+}
+
+#[derive(Clone, Default)]
+pub struct BarcodeBoy {
+    /// How many handshake bytes the game has sent.
+    handshake: usize,
+
+    /// Bytes of scanned barcodes waiting to be clocked in.
+    queue: VecDeque<u8>,
+
+    /// Cycles until the next byte can be clocked in.
+    wait: usize,
+}
+
+impl BarcodeBoy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Swipe a card with the given EAN-13 barcode. With only 12 digits, the check digit is worked
+    /// out and added.
+    pub fn scan(&mut self, barcode: &str) -> Result<(), BarcodeError> {
+        let digits = parse_barcode(barcode)?;
+        self.queue.push_back(START);
+        self.queue.extend(digits.iter().map(|&digit| b'0' + digit));
+        self.queue.push_back(END);
+        Ok(())
+    }
+}
+
+impl SerialDevice for BarcodeBoy {
+    fn exchange(&mut self, _sent: u8) -> u8 {
+        let answer = HANDSHAKE.get(self.handshake).cloned().unwrap_or(0xFF);
+        self.handshake += 1;
+        answer
+    }
+
+    fn step(&mut self, cycles: usize) {
+        self.wait = self.wait.saturating_sub(cycles);
+    }
+
+    fn clock_in(&mut self, _sent: u8) -> Option<u8> {
+        if self.wait > 0 {
+            return None;
+        }
+        let byte = self.queue.pop_front()?;
+        self.wait = BYTE_INTERVAL_CYCLES;
+        Some(byte)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn box_clone(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "Barcode Boy"
+    }
+
+    fn save(&self, w: &mut StateWriter) {
+        w.usize(self.handshake);
+        let queue: Vec<u8> = self.queue.iter().cloned().collect();
+        w.bytes(&queue);
+        w.usize(self.wait);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.handshake = r.usize()?;
+        self.queue = r.bytes()?.iter().cloned().collect();
+        self.wait = r.usize()?;
+        if self.wait > BYTE_INTERVAL_CYCLES {
+            return Err(SaveStateError::InvalidValue("Barcode Boy wait"));
+        }
+        Ok(())
+    }
+}
+
+/// Parse the digits of an EAN-13 barcode, adding the check digit if it's left out.
+pub fn parse_barcode(barcode: &str) -> Result<Vec<u8>, BarcodeError> {
+    let barcode = barcode.trim();
+    let mut digits: Vec<u8> = barcode.bytes()
+        .map(|b| if b.is_ascii_digit() { Some(b - b'0') } else { None })
+        .collect::<Option<_>>()
+        .ok_or_else(|| BarcodeError::Invalid(barcode.to_string()))?;
+    let check_digit = match digits.len() {
+        12 | 13 => check_digit(&digits[..12]),
+        _ => return Err(BarcodeError::Invalid(barcode.to_string())),
+    };
+    match digits.get(12) {
+        None => digits.push(check_digit),
+        Some(&digit) if digit != check_digit =>
+            return Err(BarcodeError::CheckDigit { barcode: barcode.to_string(), check_digit }),
+        Some(_) => {}
+    }
+    Ok(digits)
+}
+
+/// The EAN-13 check digit: the digits are weighted 1, 3, 1, 3, ..., and the check digit brings
+/// the sum up to a multiple of 10.
+fn check_digit(digits: &[u8]) -> u8 {
+    let sum: u32 = digits.iter().enumerate()
+        .map(|(i, &digit)| u32::from(digit) * if i % 2 == 0 { 1 } else { 3 })
+        .sum();
+    ((10 - sum % 10) % 10) as u8
+}
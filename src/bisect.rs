@@ -0,0 +1,140 @@
+//! `rugby bisect`, which replays a reproduction bundle and binary-searches for the frame where a
+//! debugger expression becomes true, like `git bisect` over frames instead of commits. It's meant
+//! for desyncs and glitches: name the memory or register that ends up wrong, and it finds the
+//! frame that made it wrong and a savestate from just before.
+//!
+//! One pass plays the whole bundle, keeping a savestate every `CHECKPOINT_FRAMES` frames. Each
+//! step of the search then loads the nearest checkpoint and plays on from there, applying the
+//! recorded inputs at the same cycles playback does, so every frame is reached in exactly the
+//! state `repro play` reaches it in.
+
+use crate::cpu::Cpu;
+use crate::expr::Expr;
+use crate::gpu::CYCLES_PER_FRAME;
+use crate::repro::{InputEvent, Repro};
+use crate::savestate::SaveStateError;
+use std::collections::HashSet;
+
+/// How many frames apart checkpoints are. Each one is a full savestate, so this trades memory for
+/// how far each search step has to play.
+const CHECKPOINT_FRAMES: u64 = 64;
+
+pub enum Outcome {
+    /// The expression was already true in the bundle's starting state.
+    TrueAtStart,
+
+    /// The expression was still false at the end of the last frame.
+    NeverTrue,
+
+    /// The expression was false after frame `frame - 1` and true after `frame`. `state` is the
+    /// savestate in between, at the start of `frame`.
+    Found { frame: u64, cycle: u64, state: Vec<u8> },
+}
+
+struct Replay<'a> {
+    cpu: &'a mut Cpu,
+    inputs: &'a [InputEvent],
+    start_cycle: u64,
+
+    /// The savestates taken every `CHECKPOINT_FRAMES` frames, starting with frame 0.
+    checkpoints: Vec<Vec<u8>>,
+}
+
+/// Play `frames` frames of `repro` (or up to its last input, if not given) in `cpu`, which must
+/// hold the bundle's ROM, and search for the frame where `check` becomes true. The search assumes
+/// it stays true once it is, like a corrupted value that's never fixed. If it doesn't, the frame
+/// found is one where it becomes true, though maybe not the first.
+pub fn bisect(cpu: &mut Cpu, repro: &Repro, check: &Expr, frames: Option<u64>) -> Result<Outcome, SaveStateError> {
+    cpu.load_state(&repro.state)?;
+    let start_cycle = cpu.cycles() as u64;
+    let frames = frames.unwrap_or_else(|| frames_to_last_input(&repro.inputs, start_cycle));
+    let mut replay = Replay { cpu, inputs: &repro.inputs, start_cycle, checkpoints: Vec::new() };
+
+    if check.is_true(replay.cpu) {
+        return Ok(Outcome::TrueAtStart);
+    }
+    replay.record(frames);
+    if !check.is_true(replay.cpu) {
+        return Ok(Outcome::NeverTrue);
+    }
+
+    // False after `low` and true after `high`.
+    let (mut low, mut high) = (0, frames);
+    while high - low > 1 {
+        let middle = low + (high - low) / 2;
+        replay.seek(middle)?;
+        if check.is_true(replay.cpu) {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+    replay.seek(low)?;
+    Ok(Outcome::Found { frame: high, cycle: replay.cpu.cycles() as u64, state: replay.cpu.save_state() })
+}
+
+/// How many frames from `start_cycle` it takes to reach the last input. Zero if every input is
+/// before then, as in a bundle whose inputs don't match its state.
+fn frames_to_last_input(inputs: &[InputEvent], start_cycle: u64) -> u64 {
+    let last = inputs.last().map_or(start_cycle, |event| event.cycle);
+    (last.saturating_sub(start_cycle) + CYCLES_PER_FRAME as u64 - 1) / CYCLES_PER_FRAME as u64
+}
+
+impl<'a> Replay<'a> {
+    /// Play from the start to the end of `frames`, keeping checkpoints along the way.
+    fn record(&mut self, frames: u64) {
+        for frame in 0..frames {
+            if frame % CHECKPOINT_FRAMES == 0 {
+                self.checkpoints.push(self.cpu.save_state());
+            }
+            self.play_to(frame + 1);
+        }
+    }
+
+    /// Load the state at the end of `frame`.
+    fn seek(&mut self, frame: u64) -> Result<(), SaveStateError> {
+        let checkpoint = frame / CHECKPOINT_FRAMES;
+        self.cpu.load_state(&self.checkpoints[checkpoint as usize])?;
+        for frame in checkpoint * CHECKPOINT_FRAMES..frame {
+            self.play_to(frame + 1);
+        }
+        Ok(())
+    }
+
+    /// Run to the end of `frame`, applying each input recorded before then once its cycle comes.
+    fn play_to(&mut self, frame: u64) {
+        let no_watches = HashSet::new();
+        let end = self.start_cycle + frame * CYCLES_PER_FRAME as u64;
+        let cycle = self.cpu.cycles() as u64;
+        // Events were recorded on instruction boundaries, so stepping to each one lands exactly
+        // on it. One at the current cycle may have been applied already, which is harmless.
+        for event in self.inputs.iter().skip_while(|event| event.cycle < cycle) {
+            if event.cycle >= end {
+                break;
+            }
+            let cycles = event.cycle.saturating_sub(self.cpu.cycles() as u64);
+            self.cpu.step_cycles(cycles as usize, None, &no_watches);
+            self.cpu.joypad.set_pressed(event.pressed);
+        }
+        let cycle = self.cpu.cycles() as u64;
+        if cycle < end {
+            self.cpu.step_cycles((end - cycle) as usize, None, &no_watches);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frames_to_last_input() {
+        let frame = CYCLES_PER_FRAME as u64;
+        let inputs = [InputEvent { cycle: 1000, pressed: 0 }, InputEvent { cycle: 1000 + frame + 1, pressed: 8 }];
+        assert_eq!(frames_to_last_input(&inputs, 1000), 2);
+        assert_eq!(frames_to_last_input(&inputs[..1], 1000), 0);
+        assert_eq!(frames_to_last_input(&[], 1000), 0);
+        // Inputs from before the state's cycle count don't underflow.
+        assert_eq!(frames_to_last_input(&inputs, 1000 + 5 * frame), 0);
+    }
+}
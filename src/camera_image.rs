@@ -0,0 +1,83 @@
+//! `--camera-image`, the picture the Pocket Camera's sensor sees. The PNG is scaled to cover the
+//! sensor, cropping whatever sticks out, and turned to grayscale. It's reloaded whenever the file
+//! changes, so a tool that keeps overwriting it with webcam frames makes the camera live, e.g.
+//! `ffmpeg -f v4l2 -i /dev/video0 -vf fps=10 -update 1 -y frame.png`.
+
+use failure::ResultExt;
+use rugby::cart::{CAMERA_HEIGHT, CAMERA_WIDTH};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often to check whether the image changed. Often enough for a webcam tool writing a few
+/// frames a second.
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct CameraImage {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Option<Instant>,
+}
+
+impl CameraImage {
+    pub fn new(path: PathBuf) -> Self {
+        CameraImage { path, modified: None, last_check: None }
+    }
+
+    /// The picture, if the file changed since it was last loaded. A file that's missing or half
+    /// written is skipped until the next change, since a webcam tool may be replacing it.
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        if self.last_check.map_or(false, |last_check| last_check.elapsed() < WATCH_INTERVAL) {
+            return None;
+        }
+        self.last_check = Some(Instant::now());
+        let modified = std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+        match load(&self.path) {
+            Ok(image) => {
+                self.modified = modified;
+                Some(image)
+            }
+            Err(e) => {
+                log::debug!("Skipping camera image: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Load a PNG as sensor brightness bytes, for `Cart::set_camera_image`.
+pub fn load(path: &Path) -> Result<Vec<u8>, failure::Error> {
+    let file = File::open(path).with_context(|_| format!("Failed to open camera image: {}", path.display()))?;
+    let mut decoder = png::Decoder::new(file);
+    // Palettes and 16-bit channels become plain 8-bit ones.
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().context("Failed to read camera image")?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).context("Failed to decode camera image")?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channels = info.color_type.samples();
+    let gray = |x: usize, y: usize| -> u8 {
+        let pixel = &pixels[y * info.line_size + x * channels..][..channels];
+        match channels {
+            1 | 2 => pixel[0],
+            _ => ((u32::from(pixel[0]) * 299 + u32::from(pixel[1]) * 587 + u32::from(pixel[2]) * 114) / 1000) as u8,
+        }
+    };
+
+    // Scale by whichever side fits more tightly, and center the crop.
+    let scale = (width as f32 / CAMERA_WIDTH as f32).min(height as f32 / CAMERA_HEIGHT as f32);
+    let left = (width as f32 - CAMERA_WIDTH as f32 * scale) / 2.0;
+    let top = (height as f32 - CAMERA_HEIGHT as f32 * scale) / 2.0;
+    let mut image = Vec::with_capacity(CAMERA_WIDTH * CAMERA_HEIGHT);
+    for y in 0..CAMERA_HEIGHT {
+        for x in 0..CAMERA_WIDTH {
+            let source_x = ((left + (x as f32 + 0.5) * scale) as usize).min(width - 1);
+            let source_y = ((top + (y as f32 + 0.5) * scale) as usize).min(height - 1);
+            image.push(gray(source_x, source_y));
+        }
+    }
+    Ok(image)
+}
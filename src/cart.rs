@@ -1,10 +1,32 @@
-use crate::cart_header::{CartHeader, CartType, MemSize};
+use crate::cart_header::{CartHardware, CartHeader, CartType, HeaderParseError, MemSize};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 use failure_derive::Fail;
-use log::warn;
+use log::{info, warn};
 
 const ROM_BANK_SIZE: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
 
+/// Where the Nintendo logo is in every game's header, which the boot ROM checks.
+const LOGO_RANGE: std::ops::Range<usize> = 0x104..0x134;
+
+/// The MBC2's built-in RAM: 512 half-bytes.
+const MBC2_RAM_SIZE: usize = 512;
+
+/// The MBC6 splits the ROM and RAM areas in half, each with its own bank number.
+const MBC6_ROM_BANK_SIZE: usize = 0x2000;
+const MBC6_RAM_BANK_SIZE: usize = 0x1000;
+const MBC6_RAM_SIZE: usize = 0x8000;
+
+/// The MBC7's 93LC56 EEPROM: 128 16-bit words.
+const MBC7_EEPROM_SIZE: usize = 256;
+
+/// What the MBC7's accelerometer reads when level, and how far 1 g moves it.
+const MBC7_TILT_CENTER: f32 = 0x81D0 as f32;
+const MBC7_TILT_PER_G: f32 = 0x70 as f32;
+
+/// The Game Boy's clock speed, which the MBC3 clock counts seconds in.
+const CYCLES_PER_SECOND: u32 = 4_194_304;
+
 #[derive(Clone, Debug)]
 pub struct CartConfig {
     pub cart_type: CartType,
@@ -20,7 +42,11 @@ impl CartConfig {
                 MemSize::Bytes(b) => b,
                 MemSize::Unknown(_) => return Err(CartError::RomSizeUnknown),
             },
-            ram_size: match cart_header.rom_size {
+            ram_size: match cart_header.ram_size {
+                // The MBC2 has its RAM built in, and the header says there's none.
+                _ if cart_header.cart_type == CartType::Mbc2 => MBC2_RAM_SIZE,
+                _ if cart_header.cart_type == CartType::Mbc6 => MBC6_RAM_SIZE,
+                _ if cart_header.cart_type == CartType::Mbc7 => MBC7_EEPROM_SIZE,
                 MemSize::Bytes(b) => b,
                 MemSize::Unknown(_) => return Err(CartError::RamSizeUnknown),
             },
@@ -29,14 +55,24 @@ impl CartConfig {
 }
 
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub enum Cart {
     NoMbc(NoMbc),
     Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mmm01(Mmm01),
     Mbc3(Mbc3),
     Mbc5(Mbc5),
+    Mbc6(Mbc6),
+    Mbc7(Mbc7),
+    HuC1(HuC1),
+    HuC3(HuC3),
+    PocketCamera(PocketCamera),
+    WisdomTree(WisdomTree),
 }
 
 #[derive(Clone, Debug, Fail)]
+#[non_exhaustive]
 pub enum CartError {
     #[fail(display = "provided RAM is {} bytes but the cartridge requires {}", actual, expected)]
     ProvidedRamWrongSize {
@@ -49,9 +85,64 @@ pub enum CartError {
 
     #[fail(display = "RAM size unknown in cartridge header")]
     RamSizeUnknown,
+
+    #[fail(display = "the {} mapper (cartridge type 0x{:02X}) isn't supported yet", mapper, code)]
+    UnsupportedMapper {
+        mapper: CartType,
+        code: u8,
+    },
+
+    #[fail(display = "cartridge type 0x{:02X} in the header isn't a known mapper", _0)]
+    UnknownMapper(u8),
+
+    #[fail(display = "failed to parse cartridge header: {}", _0)]
+    Header(HeaderParseError),
+}
+
+/// A snapshot of the mapper's banking state, for debugging output.
+#[derive(Clone, Debug)]
+pub struct MbcState {
+    pub mapper: &'static str,
+    pub rom_bank: u16,
+    pub ram_bank: u16,
+    pub ram_enabled: bool,
+    pub mode: Option<MbcMode>,
+    /// The latched RTC registers (seconds, minutes, hours, day low, day high), for MBC3.
+    pub rtc: Option<[u8; 5]>,
+    /// Whether the rumble motor is on, for carts that have one.
+    pub rumble: Option<bool>,
+}
+
+impl std::fmt::Display for MbcState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Mapper:\t{}", self.mapper)?;
+        writeln!(f, "ROM bank:\t0x{:02X}", self.rom_bank)?;
+        writeln!(f, "RAM bank:\t0x{:02X}", self.ram_bank)?;
+        writeln!(f, "RAM enabled:\t{}", self.ram_enabled)?;
+        if let Some(mode) = self.mode {
+            writeln!(f, "Banking mode:\t{:?}", mode)?;
+        }
+        if let Some([s, m, h, dl, dh]) = self.rtc {
+            let day = u16::from(dh & 1) << 8 | u16::from(dl);
+            writeln!(f, "RTC latch:\tday {} {:02}:{:02}:{:02}", day, h, m, s)?;
+            writeln!(f, "RTC halted:\t{}", dh & (1 << 6) != 0)?;
+            writeln!(f, "RTC day carry:\t{}", dh & (1 << 7) != 0)?;
+        }
+        if let Some(rumble) = self.rumble {
+            writeln!(f, "Rumble:\t{}", if rumble { "on" } else { "off" })?;
+        }
+        Ok(())
+    }
 }
 
 impl Cart {
+    /// Set up the cartridge described by a ROM's header, with the given RAM contents if any.
+    pub fn from_rom(rom: Box<[u8]>, ram: Option<Box<[u8]>>) -> Result<Cart, CartError> {
+        let cart_header = CartHeader::from_rom(&rom).map_err(CartError::Header)?;
+        let cart_config = CartConfig::from_cart_header(&cart_header)?;
+        Cart::new(rom, ram, &cart_config)
+    }
+
     pub fn new(
         rom: Box<[u8]>,
         ram_opt: Option<Box<[u8]>>,
@@ -69,13 +160,31 @@ impl Cart {
             }
             None => vec![0; config.ram_size].into_boxed_slice(),
         };
+        if rom.len() < config.rom_size {
+            warn!(
+                "ROM is {} bytes but the header says {}, mirroring it to fill the missing banks",
+                rom.len(),
+                config.rom_size,
+            );
+        }
 
         Ok(match config.cart_type {
             CartType::NoMbc => Cart::NoMbc(NoMbc::new(rom, ram)),
             CartType::Mbc1 => Cart::Mbc1(Mbc1::new(rom, ram)),
+            CartType::Mbc2 => Cart::Mbc2(Mbc2::new(rom, ram)),
+            CartType::Mmm01 => Cart::Mmm01(Mmm01::new(rom, ram)),
             CartType::Mbc3 => Cart::Mbc3(Mbc3::new(rom, ram)),
             CartType::Mbc5 => Cart::Mbc5(Mbc5::new(rom, ram)),
-            _ => panic!("Unimplemented Mbc Type!"),
+            CartType::Mbc6 => Cart::Mbc6(Mbc6::new(rom, ram)),
+            CartType::Mbc7 => Cart::Mbc7(Mbc7::new(rom, ram)),
+            CartType::HuC1 => Cart::HuC1(HuC1::new(rom, ram)),
+            CartType::HuC3 => Cart::HuC3(HuC3::new(rom, ram)),
+            CartType::PocketCamera => Cart::PocketCamera(PocketCamera::new(rom, ram)),
+            CartType::WisdomTree => Cart::WisdomTree(WisdomTree::new(rom)),
+            CartType::BandaiTama5 => {
+                return Err(CartError::UnsupportedMapper { mapper: config.cart_type, code: 0xFD });
+            }
+            CartType::Unknown(code) => return Err(CartError::UnknownMapper(code)),
         })
     }
 
@@ -83,8 +192,16 @@ impl Cart {
         match self {
             Cart::NoMbc(nombc) => nombc.read(addr),
             Cart::Mbc1(mbc1) => mbc1.read(addr),
+            Cart::Mbc2(mbc2) => mbc2.read(addr),
+            Cart::Mmm01(mmm01) => mmm01.read(addr),
             Cart::Mbc3(mbc3) => mbc3.read(addr),
             Cart::Mbc5(mbc5) => mbc5.read(addr),
+            Cart::Mbc6(mbc6) => mbc6.read(addr),
+            Cart::Mbc7(mbc7) => mbc7.read(addr),
+            Cart::HuC1(huc1) => huc1.read(addr),
+            Cart::HuC3(huc3) => huc3.read(addr),
+            Cart::PocketCamera(camera) => camera.read(addr),
+            Cart::WisdomTree(wisdom_tree) => wisdom_tree.read(addr),
         }
     }
 
@@ -92,18 +209,372 @@ impl Cart {
         match self {
             Cart::NoMbc(nombc) => nombc.write(addr, val),
             Cart::Mbc1(mbc1) => mbc1.write(addr, val),
+            Cart::Mbc2(mbc2) => mbc2.write(addr, val),
+            Cart::Mmm01(mmm01) => mmm01.write(addr, val),
             Cart::Mbc3(mbc3) => mbc3.write(addr, val),
             Cart::Mbc5(mbc5) => mbc5.write(addr, val),
+            Cart::Mbc6(mbc6) => mbc6.write(addr, val),
+            Cart::Mbc7(mbc7) => mbc7.write(addr, val),
+            Cart::HuC1(huc1) => huc1.write(addr, val),
+            Cart::HuC3(huc3) => huc3.write(addr, val),
+            Cart::PocketCamera(camera) => camera.write(addr, val),
+            Cart::WisdomTree(wisdom_tree) => wisdom_tree.write(addr),
+        }
+    }
+
+    /// Advance the cartridge's clock, if it has one, and the Pocket Camera's captures.
+    pub fn tick(&mut self, cycles: usize) {
+        match self {
+            Cart::Mbc3(mbc3) => mbc3.tick(cycles),
+            Cart::HuC3(huc3) => huc3.tick(cycles),
+            Cart::PocketCamera(camera) => camera.tick(cycles),
+            _ => {}
+        }
+    }
+
+    /// Whether the cart has a clock that keeps running while the game is off.
+    pub fn has_clock(&self) -> bool {
+        match self {
+            Cart::Mbc3(_) | Cart::HuC3(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The running real-time clock, for MBC3 cartridges.
+    pub fn rtc(&self) -> Option<Rtc> {
+        match self {
+            Cart::Mbc3(mbc3) => Some(mbc3.clock),
+            _ => None,
+        }
+    }
+
+    /// Set the running real-time clock, e.g. from a battery save. Does nothing without a clock.
+    pub fn set_rtc(&mut self, rtc: Rtc) {
+        if let Cart::Mbc3(mbc3) = self {
+            mbc3.clock = rtc;
+            mbc3.rtc = rtc.registers();
+        }
+    }
+
+    /// Set the latched RTC registers the game reads, e.g. from a battery save. Does nothing
+    /// without a clock.
+    pub fn set_rtc_latched(&mut self, registers: [u8; 5]) {
+        if let Cart::Mbc3(mbc3) = self {
+            mbc3.rtc = registers;
+        }
+    }
+
+    /// The running clock, for HuC3 cartridges.
+    pub fn huc3_clock(&self) -> Option<HuC3Clock> {
+        match self {
+            Cart::HuC3(huc3) => Some(huc3.clock),
+            _ => None,
+        }
+    }
+
+    /// Set the HuC3's clock, e.g. from a battery save. Does nothing for other carts.
+    pub fn set_huc3_clock(&mut self, clock: HuC3Clock) {
+        if let Cart::HuC3(huc3) = self {
+            huc3.clock = clock;
+        }
+    }
+
+    /// Whether the cart's rumble motor is running. Always false for carts without one.
+    pub fn rumble(&self) -> bool {
+        match self {
+            Cart::Mbc5(mbc5) => mbc5.rumble(),
+            _ => false,
+        }
+    }
+
+    /// Tilt the cart's accelerometer, in g. Positive `x` tilts it to the right, and positive `y`
+    /// towards the player. Does nothing for carts without one.
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        if let Cart::Mbc7(mbc7) = self {
+            mbc7.tilt = (x, y);
+        }
+    }
+
+    /// Set what the Pocket Camera's sensor sees, as `CAMERA_WIDTH` by `CAMERA_HEIGHT` brightness
+    /// bytes from dark (0) to light (255), row by row. Does nothing for other carts.
+    pub fn set_camera_image(&mut self, image: &[u8]) {
+        if let Cart::PocketCamera(camera) = self {
+            if image.len() == camera.image.len() {
+                camera.image.copy_from_slice(image);
+            }
+        }
+    }
+
+    /// Whether a write to this ROM address changes a mapper register. Writes to other ROM
+    /// addresses do nothing.
+    pub fn is_register(&self, addr: u16) -> bool {
+        match self {
+            Cart::NoMbc(_) => false,
+            Cart::Mbc1(_) | Cart::Mmm01(_) | Cart::Mbc3(_) => addr <= 0x7FFF,
+            Cart::HuC1(_) | Cart::HuC3(_) | Cart::PocketCamera(_) => addr <= 0x5FFF,
+            Cart::Mbc5(_) | Cart::Mbc7(_) => addr <= 0x5FFF,
+            Cart::Mbc2(_) | Cart::Mbc6(_) | Cart::WisdomTree(_) => addr <= 0x3FFF,
+        }
+    }
+
+    /// Describe the current banking state of the mapper.
+    pub fn debug_state(&self) -> MbcState {
+        match self {
+            Cart::NoMbc(_) => MbcState {
+                mapper: "none",
+                rom_bank: 1,
+                ram_bank: 0,
+                ram_enabled: true,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::Mbc1(mbc1) => MbcState {
+                mapper: if mbc1.multicart { "MBC1M" } else { "MBC1" },
+                rom_bank: mbc1.rom_bank(),
+                ram_bank: mbc1.ram_bank(),
+                ram_enabled: mbc1.ram_enabled,
+                mode: Some(mbc1.mode),
+                rtc: None,
+                rumble: None,
+            },
+            Cart::Mbc2(mbc2) => MbcState {
+                mapper: "MBC2",
+                rom_bank: mbc2.rom_bank as u16,
+                ram_bank: 0,
+                ram_enabled: mbc2.ram_enabled,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::Mmm01(mmm01) => MbcState {
+                mapper: if mmm01.mapped { "MMM01" } else { "MMM01 (menu)" },
+                rom_bank: mmm01.rom_bank(),
+                ram_bank: mmm01.ram_bank(),
+                ram_enabled: mmm01.ram_enabled,
+                mode: Some(mmm01.mode),
+                rtc: None,
+                rumble: None,
+            },
+            Cart::Mbc3(mbc3) => MbcState {
+                mapper: "MBC3",
+                rom_bank: mbc3.rom_bank as u16,
+                ram_bank: mbc3.ram_rtc_bank as u16,
+                ram_enabled: mbc3.ram_rtc_enabled,
+                mode: None,
+                rtc: Some(mbc3.rtc),
+                rumble: None,
+            },
+            Cart::Mbc5(mbc5) => MbcState {
+                mapper: "MBC5",
+                rom_bank: u16::from_le_bytes([mbc5.rom_bank_reg1, mbc5.rom_bank_reg2]),
+                ram_bank: mbc5.ram_bank() as u16,
+                ram_enabled: mbc5.ram_enabled,
+                mode: None,
+                rtc: None,
+                rumble: if mbc5.has_rumble { Some(mbc5.rumble()) } else { None },
+            },
+            // The debugger's views are in 16 KiB ROM and 8 KiB RAM banks, so show the ones holding
+            // the lower halves' banks.
+            Cart::Mbc6(mbc6) => MbcState {
+                mapper: "MBC6",
+                rom_bank: u16::from(mbc6.rom_banks[0]) / 2,
+                ram_bank: u16::from(mbc6.ram_banks[0]) / 2,
+                ram_enabled: mbc6.ram_enabled,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::Mbc7(mbc7) => MbcState {
+                mapper: "MBC7",
+                rom_bank: mbc7.rom_bank as u16,
+                ram_bank: 0,
+                ram_enabled: mbc7.ram_enabled && mbc7.registers_enabled,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::HuC1(huc1) => MbcState {
+                mapper: "HuC1",
+                rom_bank: huc1.rom_bank as u16,
+                ram_bank: huc1.ram_bank as u16,
+                ram_enabled: !huc1.ir_mode,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::HuC3(huc3) => MbcState {
+                mapper: "HuC3",
+                rom_bank: huc3.rom_bank as u16,
+                ram_bank: huc3.ram_bank as u16,
+                ram_enabled: huc3.mode == 0xA,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::PocketCamera(camera) => MbcState {
+                mapper: if camera.registers_selected { "Pocket Camera (sensor)" } else { "Pocket Camera" },
+                rom_bank: camera.rom_bank as u16,
+                ram_bank: camera.ram_bank as u16,
+                ram_enabled: camera.ram_enabled,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+            Cart::WisdomTree(wisdom_tree) => MbcState {
+                mapper: "Wisdom Tree",
+                rom_bank: wisdom_tree.bank as u16,
+                ram_bank: 0,
+                ram_enabled: false,
+                mode: None,
+                rtc: None,
+                rumble: None,
+            },
+        }
+    }
+
+    /// Check internal consistency for `--paranoid` mode.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let ram = self.ram();
+        // `bank_index` masks with the memory size, which is only correct for powers of two. ROM
+        // reads handle other sizes themselves, see `get_rom`.
+        if ram.len() != 0 && !ram.len().is_power_of_two() {
+            return Err(format!("RAM size {} is not a power of two", ram.len()));
+        }
+
+        match self {
+            Cart::NoMbc(_) | Cart::Mbc6(_) | Cart::Mbc7(_) | Cart::WisdomTree(_) => Ok(()),
+            Cart::Mbc1(mbc1) => {
+                if mbc1.bank_reg1 == 0 || mbc1.bank_reg1 > 0x1F || mbc1.bank_reg2 > 0b11 {
+                    return Err(format!(
+                        "MBC1 bank registers out of range: 0x{:02X}, 0x{:02X}",
+                        mbc1.bank_reg1,
+                        mbc1.bank_reg2,
+                    ));
+                }
+                Ok(())
+            }
+            Cart::Mbc2(mbc2) => {
+                if mbc2.rom_bank == 0 || mbc2.rom_bank > 0xF {
+                    return Err(format!("MBC2 ROM bank {} out of range", mbc2.rom_bank));
+                }
+                Ok(())
+            }
+            Cart::Mmm01(mmm01) => {
+                if mmm01.rom_bank_low > 0x1F || mmm01.rom_bank_locked & !0x1E != 0 {
+                    return Err(format!(
+                        "MMM01 ROM bank registers out of range: 0x{:02X}, locked 0x{:02X}",
+                        mmm01.rom_bank_low,
+                        mmm01.rom_bank_locked,
+                    ));
+                }
+                Ok(())
+            }
+            Cart::Mbc3(mbc3) => {
+                if mbc3.rom_bank == 0 || mbc3.rom_bank > 0x7F {
+                    return Err(format!("MBC3 ROM bank {} out of range", mbc3.rom_bank));
+                }
+                Ok(())
+            }
+            Cart::Mbc5(mbc5) => {
+                if mbc5.rom_bank_reg2 > 1 || mbc5.ram_bank_reg > 0xF {
+                    return Err(format!(
+                        "MBC5 bank registers out of range: ROM high bit {}, RAM bank {}",
+                        mbc5.rom_bank_reg2,
+                        mbc5.ram_bank_reg,
+                    ));
+                }
+                Ok(())
+            }
+            Cart::HuC1(huc1) => {
+                if huc1.rom_bank > 0x3F || huc1.ram_bank > 0b11 {
+                    return Err(format!(
+                        "HuC1 bank registers out of range: ROM bank {}, RAM bank {}",
+                        huc1.rom_bank,
+                        huc1.ram_bank,
+                    ));
+                }
+                Ok(())
+            }
+            Cart::HuC3(huc3) => {
+                if huc3.clock.days > 0xFFF || huc3.clock.seconds >= 60 {
+                    return Err(format!(
+                        "HuC3 clock out of range: day {}, second {}",
+                        huc3.clock.days,
+                        huc3.clock.seconds,
+                    ));
+                }
+                Ok(())
+            }
+            Cart::PocketCamera(camera) => {
+                if camera.rom_bank > 0x3F || camera.ram_bank > 0xF {
+                    return Err(format!(
+                        "Pocket Camera bank registers out of range: ROM bank {}, RAM bank {}",
+                        camera.rom_bank,
+                        camera.ram_bank,
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Put the mapper registers back to their power-on values, as when the Game Boy is switched off
+    /// and on again. The RAM and the MBC3 and HuC3 clocks keep their contents, since they're
+    /// battery-backed.
+    pub fn reset(&mut self) {
+        use std::mem::take;
+        match self {
+            Cart::NoMbc(_) => {}
+            Cart::Mbc1(mbc1) => *mbc1 = Mbc1::new(take(&mut mbc1.rom), take(&mut mbc1.ram)),
+            Cart::Mbc2(mbc2) => *mbc2 = Mbc2::new(take(&mut mbc2.rom), take(&mut mbc2.ram)),
+            Cart::Mmm01(mmm01) => *mmm01 = Mmm01::new(take(&mut mmm01.rom), take(&mut mmm01.ram)),
+            Cart::Mbc3(mbc3) => {
+                let (rtc, clock, clock_cycles) = (mbc3.rtc, mbc3.clock, mbc3.clock_cycles);
+                *mbc3 = Mbc3::new(take(&mut mbc3.rom), take(&mut mbc3.ram));
+                mbc3.rtc = rtc;
+                mbc3.clock = clock;
+                mbc3.clock_cycles = clock_cycles;
+            }
+            Cart::Mbc5(mbc5) => *mbc5 = Mbc5::new(take(&mut mbc5.rom), take(&mut mbc5.ram)),
+            Cart::Mbc6(mbc6) => *mbc6 = Mbc6::new(take(&mut mbc6.rom), take(&mut mbc6.ram)),
+            Cart::Mbc7(mbc7) => *mbc7 = Mbc7::new(take(&mut mbc7.rom), take(&mut mbc7.ram)),
+            Cart::HuC1(huc1) => *huc1 = HuC1::new(take(&mut huc1.rom), take(&mut huc1.ram)),
+            Cart::HuC3(huc3) => {
+                let fresh = HuC3::new(take(&mut huc3.rom), take(&mut huc3.ram));
+                // The clock and its alarm are battery-backed too.
+                *huc3 = HuC3 {
+                    clock: huc3.clock,
+                    clock_cycles: huc3.clock_cycles,
+                    alarm_minutes: huc3.alarm_minutes,
+                    alarm_days: huc3.alarm_days,
+                    alarm_enabled: huc3.alarm_enabled,
+                    ..fresh
+                };
+            }
+            Cart::PocketCamera(camera) => {
+                // What the sensor sees comes from the frontend, not the Game Boy.
+                let image = take(&mut camera.image);
+                *camera = PocketCamera { image, ..PocketCamera::new(take(&mut camera.rom), take(&mut camera.ram)) };
+            }
+            Cart::WisdomTree(wisdom_tree) => *wisdom_tree = WisdomTree::new(take(&mut wisdom_tree.rom)),
         }
     }
 
-    #[cfg(test)] // Silence "never used" warning.
     pub fn rom(&self) -> &[u8] {
         match self {
             Cart::NoMbc(nombc) => &nombc.rom,
             Cart::Mbc1(mbc1) => &mbc1.rom,
+            Cart::Mbc2(mbc2) => &mbc2.rom,
+            Cart::Mmm01(mmm01) => &mmm01.rom,
             Cart::Mbc3(mbc3) => &mbc3.rom,
             Cart::Mbc5(mbc5) => &mbc5.rom,
+            Cart::Mbc6(mbc6) => &mbc6.rom,
+            Cart::Mbc7(mbc7) => &mbc7.rom,
+            Cart::HuC1(huc1) => &huc1.rom,
+            Cart::PocketCamera(camera) => &camera.rom,
+            Cart::HuC3(huc3) => &huc3.rom,
+            Cart::WisdomTree(wisdom_tree) => &wisdom_tree.rom,
         }
     }
 
@@ -111,9 +582,283 @@ impl Cart {
         match self {
             Cart::NoMbc(nombc) => &nombc.ram,
             Cart::Mbc1(mbc1) => &mbc1.ram,
+            Cart::Mbc2(mbc2) => &mbc2.ram,
+            Cart::Mmm01(mmm01) => &mmm01.ram,
             Cart::Mbc3(mbc3) => &mbc3.ram,
             Cart::Mbc5(mbc5) => &mbc5.ram,
+            Cart::Mbc6(mbc6) => &mbc6.ram,
+            Cart::Mbc7(mbc7) => &mbc7.ram,
+            Cart::HuC1(huc1) => &huc1.ram,
+            Cart::PocketCamera(camera) => &camera.ram,
+            Cart::HuC3(huc3) => &huc3.ram,
+            Cart::WisdomTree(_) => &[],
+        }
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        match self {
+            Cart::NoMbc(nombc) => &mut nombc.ram,
+            Cart::Mbc1(mbc1) => &mut mbc1.ram,
+            Cart::Mbc2(mbc2) => &mut mbc2.ram,
+            Cart::Mmm01(mmm01) => &mut mmm01.ram,
+            Cart::Mbc3(mbc3) => &mut mbc3.ram,
+            Cart::Mbc5(mbc5) => &mut mbc5.ram,
+            Cart::Mbc6(mbc6) => &mut mbc6.ram,
+            Cart::Mbc7(mbc7) => &mut mbc7.ram,
+            Cart::HuC1(huc1) => &mut huc1.ram,
+            Cart::PocketCamera(camera) => &mut camera.ram,
+            Cart::HuC3(huc3) => &mut huc3.ram,
+            Cart::WisdomTree(_) => &mut [],
+        }
+    }
+
+    /// The number of 16 KiB ROM banks, counting the ROM as if padded to a power of two like
+    /// `get_rom` does.
+    pub fn rom_banks(&self) -> u16 {
+        (self.rom().len().next_power_of_two() / ROM_BANK_SIZE).max(2) as u16
+    }
+
+    /// The number of 8 KiB RAM banks. Carts with less RAM than that have one small bank.
+    pub fn ram_banks(&self) -> u16 {
+        ((self.ram().len() + RAM_BANK_SIZE - 1) / RAM_BANK_SIZE) as u16
+    }
+
+    /// Read from any ROM bank, regardless of which one is mapped in. Only the low 14 bits of
+    /// `addr` are used.
+    pub fn read_rom_bank(&self, bank: u16, addr: u16) -> u8 {
+        get_rom(self.rom(), bank, addr)
+    }
+
+    /// Read from any RAM bank, regardless of which one is mapped in or whether RAM is enabled.
+    /// Only the low 13 bits of `addr` are used. Panics if there's no RAM.
+    pub fn read_ram_bank(&self, bank: u16, addr: u16) -> u8 {
+        get_ram(self.ram(), bank, addr)
+    }
+
+    /// Write to any RAM bank, like `read_ram_bank`.
+    pub fn write_ram_bank(&mut self, bank: u16, addr: u16, val: u8) {
+        set_ram(self.ram_mut(), bank, addr, val);
+    }
+}
+
+/// Savestates hold the cartridge RAM and the mapper registers, but not the ROM, which must be
+/// loaded separately.
+impl SaveState for Cart {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(self.ram());
+        match self {
+            Cart::NoMbc(_) => w.u8(0),
+            Cart::Mbc1(mbc1) => {
+                w.u8(1);
+                w.bool(mbc1.mode == MbcMode::Ram);
+                w.bool(mbc1.ram_enabled);
+                w.u8(mbc1.bank_reg1);
+                w.u8(mbc1.bank_reg2);
+            }
+            Cart::Mbc2(mbc2) => {
+                w.u8(2);
+                w.bool(mbc2.ram_enabled);
+                w.u8(mbc2.rom_bank);
+            }
+            Cart::Mmm01(mmm01) => {
+                w.u8(0x0B);
+                w.bool(mmm01.mapped);
+                w.bool(mmm01.ram_enabled);
+                w.bool(mmm01.mode == MbcMode::Ram);
+                w.bool(mmm01.mode_locked);
+                w.bytes(&[mmm01.rom_bank_high, mmm01.rom_bank_mid, mmm01.rom_bank_low, mmm01.rom_bank_locked]);
+                w.bytes(&[mmm01.ram_bank_high, mmm01.ram_bank_low, mmm01.ram_bank_locked]);
+            }
+            Cart::Mbc3(mbc3) => {
+                w.u8(3);
+                w.u8(mbc3.rom_bank);
+                w.bool(mbc3.ram_rtc_enabled);
+                w.u8(mbc3.ram_rtc_bank);
+                w.bytes(&mbc3.rtc);
+                w.bytes(&mbc3.clock.registers());
+                w.u32(mbc3.clock_cycles);
+                w.bool(mbc3.latch_armed);
+            }
+            Cart::Mbc5(mbc5) => {
+                w.u8(5);
+                w.bool(mbc5.ram_enabled);
+                w.u8(mbc5.rom_bank_reg1);
+                w.u8(mbc5.rom_bank_reg2);
+                w.u8(mbc5.ram_bank_reg);
+            }
+            Cart::Mbc6(mbc6) => {
+                w.u8(6);
+                w.bool(mbc6.ram_enabled);
+                w.bytes(&mbc6.ram_banks);
+                w.bytes(&mbc6.rom_banks);
+                w.bool(mbc6.flash_selected[0]);
+                w.bool(mbc6.flash_selected[1]);
+            }
+            Cart::Mbc7(mbc7) => {
+                w.u8(7);
+                w.u8(mbc7.rom_bank);
+                w.bool(mbc7.ram_enabled);
+                w.bool(mbc7.registers_enabled);
+                w.u16(mbc7.latched.0);
+                w.u16(mbc7.latched.1);
+                w.bool(mbc7.latch_erased);
+                mbc7.eeprom.save(w);
+            }
+            Cart::HuC1(huc1) => {
+                w.u8(0xC1);
+                w.bool(huc1.ir_mode);
+                w.bool(huc1.ir_led);
+                w.u8(huc1.rom_bank);
+                w.u8(huc1.ram_bank);
+            }
+            Cart::HuC3(huc3) => {
+                w.u8(0xC3);
+                w.u8(huc3.mode);
+                w.u8(huc3.rom_bank);
+                w.u8(huc3.ram_bank);
+                w.bool(huc3.ir_led);
+                w.u8(huc3.address);
+                w.u8(huc3.answer);
+                w.u8(huc3.extended);
+                w.u8(huc3.clock.seconds);
+                w.u16(huc3.clock.minutes);
+                w.u16(huc3.clock.days);
+                w.u32(huc3.clock_cycles);
+                w.u16(huc3.alarm_minutes);
+                w.u16(huc3.alarm_days);
+                w.bool(huc3.alarm_enabled);
+            }
+            Cart::PocketCamera(camera) => {
+                w.u8(0xFC);
+                w.bool(camera.ram_enabled);
+                w.u8(camera.rom_bank);
+                w.u8(camera.ram_bank);
+                w.bool(camera.registers_selected);
+                w.bytes(&camera.registers);
+                w.u32(camera.capture_cycles);
+            }
+            Cart::WisdomTree(wisdom_tree) => {
+                w.u8(0x80);
+                w.u8(wisdom_tree.bank);
+            }
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let ram = r.bytes()?;
+        let mapper = r.u8()?;
+        let cart_ram: &mut [u8] = match self {
+            Cart::NoMbc(nombc) if mapper == 0 => &mut nombc.ram,
+            Cart::Mbc1(mbc1) if mapper == 1 => {
+                mbc1.mode = if r.bool()? { MbcMode::Ram } else { MbcMode::Rom };
+                mbc1.ram_enabled = r.bool()?;
+                // As with writes, bank 0 selects bank 1.
+                mbc1.bank_reg1 = (r.u8()? & 0b1_1111).max(1);
+                mbc1.bank_reg2 = r.u8()? & 0b11;
+                &mut mbc1.ram
+            }
+            Cart::Mbc2(mbc2) if mapper == 2 => {
+                mbc2.ram_enabled = r.bool()?;
+                mbc2.rom_bank = r.u8()? & 0xF;
+                &mut mbc2.ram
+            }
+            Cart::Mmm01(mmm01) if mapper == 0x0B => {
+                mmm01.mapped = r.bool()?;
+                mmm01.ram_enabled = r.bool()?;
+                mmm01.mode = if r.bool()? { MbcMode::Ram } else { MbcMode::Rom };
+                mmm01.mode_locked = r.bool()?;
+                let mut rom_bank = [0; 4];
+                r.bytes_into(&mut rom_bank)?;
+                mmm01.rom_bank_high = rom_bank[0] & 0b11;
+                mmm01.rom_bank_mid = rom_bank[1] & 0b11;
+                mmm01.rom_bank_low = rom_bank[2] & 0b1_1111;
+                mmm01.rom_bank_locked = rom_bank[3] & 0b1_1110;
+                let mut ram_bank = [0; 3];
+                r.bytes_into(&mut ram_bank)?;
+                mmm01.ram_bank_high = ram_bank[0] & 0b11;
+                mmm01.ram_bank_low = ram_bank[1] & 0b11;
+                mmm01.ram_bank_locked = ram_bank[2] & 0b11;
+                &mut mmm01.ram
+            }
+            Cart::Mbc3(mbc3) if mapper == 3 => {
+                mbc3.rom_bank = (r.u8()? & 0b0111_1111).max(1);
+                mbc3.ram_rtc_enabled = r.bool()?;
+                mbc3.ram_rtc_bank = r.u8()?;
+                r.bytes_into(&mut mbc3.rtc)?;
+                let mut registers = [0; 5];
+                r.bytes_into(&mut registers)?;
+                mbc3.clock = Rtc::from_registers(registers);
+                mbc3.clock_cycles = r.u32()? % CYCLES_PER_SECOND;
+                mbc3.latch_armed = r.bool()?;
+                &mut mbc3.ram
+            }
+            Cart::Mbc5(mbc5) if mapper == 5 => {
+                mbc5.ram_enabled = r.bool()?;
+                mbc5.rom_bank_reg1 = r.u8()?;
+                mbc5.rom_bank_reg2 = r.u8()? & 0b0001;
+                mbc5.ram_bank_reg = r.u8()? & 0b1111;
+                &mut mbc5.ram
+            }
+            Cart::Mbc6(mbc6) if mapper == 6 => {
+                mbc6.ram_enabled = r.bool()?;
+                r.bytes_into(&mut mbc6.ram_banks)?;
+                r.bytes_into(&mut mbc6.rom_banks)?;
+                mbc6.flash_selected = [r.bool()?, r.bool()?];
+                &mut mbc6.ram
+            }
+            Cart::Mbc7(mbc7) if mapper == 7 => {
+                mbc7.rom_bank = r.u8()?;
+                mbc7.ram_enabled = r.bool()?;
+                mbc7.registers_enabled = r.bool()?;
+                mbc7.latched = (r.u16()?, r.u16()?);
+                mbc7.latch_erased = r.bool()?;
+                mbc7.eeprom.load(r)?;
+                &mut mbc7.ram
+            }
+            Cart::HuC1(huc1) if mapper == 0xC1 => {
+                huc1.ir_mode = r.bool()?;
+                huc1.ir_led = r.bool()?;
+                huc1.rom_bank = r.u8()? & 0x3F;
+                huc1.ram_bank = r.u8()? & 0b11;
+                &mut huc1.ram
+            }
+            Cart::HuC3(huc3) if mapper == 0xC3 => {
+                huc3.mode = r.u8()? & 0xF;
+                huc3.rom_bank = r.u8()? & 0x7F;
+                huc3.ram_bank = r.u8()? & 0b11;
+                huc3.ir_led = r.bool()?;
+                huc3.address = r.u8()?;
+                huc3.answer = r.u8()?;
+                huc3.extended = r.u8()?;
+                huc3.clock.seconds = r.u8()? % 60;
+                huc3.clock.minutes = r.u16()?;
+                huc3.clock.days = r.u16()? & 0xFFF;
+                huc3.clock_cycles = r.u32()? % CYCLES_PER_SECOND;
+                huc3.alarm_minutes = r.u16()?;
+                huc3.alarm_days = r.u16()?;
+                huc3.alarm_enabled = r.bool()?;
+                &mut huc3.ram
+            }
+            Cart::PocketCamera(camera) if mapper == 0xFC => {
+                camera.ram_enabled = r.bool()?;
+                camera.rom_bank = r.u8()? & 0x3F;
+                camera.ram_bank = r.u8()? & 0xF;
+                camera.registers_selected = r.bool()?;
+                r.bytes_into(&mut camera.registers)?;
+                camera.capture_cycles = r.u32()?;
+                &mut camera.ram
+            }
+            Cart::WisdomTree(wisdom_tree) if mapper == 0x80 => {
+                wisdom_tree.bank = r.u8()?;
+                &mut []
+            }
+            _ => return Err(SaveStateError::InvalidValue("savestate is for a different mapper")),
+        };
+        if cart_ram.len() != ram.len() {
+            return Err(SaveStateError::InvalidValue("cartridge RAM size mismatch"));
         }
+        cart_ram.copy_from_slice(ram);
+        Ok(())
     }
 }
 
@@ -131,7 +876,7 @@ impl NoMbc {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             // ROM
-            0x0000...0x7FFF => self.rom[addr as usize],
+            0x0000...0x7FFF => get_rom(&self.rom, addr >> 14, addr),
 
             // RAM
             0xA000...0xBFFF => {
@@ -163,6 +908,14 @@ impl NoMbc {
     }
 }
 
+/// The MBC1 splits the bank number over two registers: the low 5 bits of the ROM bank, which
+/// reads 0 as 1, and 2 bits that are either the high bits of the ROM bank or the RAM bank. In mode
+/// 0 the 2 bits only go to the switchable ROM bank. In mode 1 they also pick the RAM bank and the
+/// bank at 0x0000, which lets carts of 1 MiB and up map banks 0x20, 0x40 and 0x60 there.
+///
+/// MBC1M multicarts wire the 2 bits one line lower, so they pick one of four 256 KiB games, and
+/// the top bit of the 5 goes unused. They can only be told apart by the logo in the header of the
+/// second game.
 #[derive(Clone, Debug)]
 pub struct Mbc1 {
     rom: Box<[u8]>,
@@ -171,6 +924,7 @@ pub struct Mbc1 {
     ram_enabled: bool,
     bank_reg1: u8,
     bank_reg2: u8,
+    multicart: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -181,32 +935,47 @@ pub enum MbcMode {
 
 impl Mbc1 {
     fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
-        Self { rom, ram, mode: MbcMode::Rom, ram_enabled: false, bank_reg1: 1, bank_reg2: 0 }
+        let multicart = is_mbc1_multicart(&rom);
+        Self { rom, ram, mode: MbcMode::Rom, ram_enabled: false, bank_reg1: 1, bank_reg2: 0, multicart }
+    }
+
+    /// How far up the bank number the 2 bit register goes.
+    fn upper_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    /// The bank mapped at 0x0000-0x3FFF.
+    fn zero_bank(&self) -> u16 {
+        match self.mode {
+            MbcMode::Rom => 0,
+            MbcMode::Ram => u16::from(self.bank_reg2) << self.upper_shift(),
+        }
+    }
+
+    /// The bank mapped at 0x4000-0x7FFF. The low register is checked for 0 before the multicart
+    /// drops its top bit, so a multicart can map the first bank of each game here too.
+    fn rom_bank(&self) -> u16 {
+        let low = if self.multicart { self.bank_reg1 & 0b1111 } else { self.bank_reg1 };
+        u16::from(self.bank_reg2) << self.upper_shift() | u16::from(low)
+    }
+
+    fn ram_bank(&self) -> u16 {
+        match self.mode {
+            MbcMode::Rom => 0,
+            MbcMode::Ram => u16::from(self.bank_reg2),
+        }
     }
 
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x3FFF => {
-                let bank = match self.mode {
-                    MbcMode::Rom => 0,
-                    MbcMode::Ram => self.bank_reg2 << 5,
-                };
-                get_rom(&self.rom, bank as u16, addr)
-            }
+            0x0000...0x3FFF => get_rom(&self.rom, self.zero_bank(), addr),
 
-            0x4000...0x7FFF => {
-                let bank = self.bank_reg2 << 5 | self.bank_reg1;
-                get_rom(&self.rom, bank as u16, addr)
-            }
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank(), addr),
 
             0xA000...0xBFFF => {
                 // When RAM is disabled, the hardware returns all bits set.
                 if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
-                let bank = match self.mode {
-                    MbcMode::Rom => 0,
-                    MbcMode::Ram => self.bank_reg2,
-                };
-                get_ram(&self.ram, bank as u16, addr)
+                get_ram(&self.ram, self.ram_bank(), addr)
             }
 
             _ => panic!("Unimplemented MBC1 read at address: {}", addr),
@@ -242,11 +1011,8 @@ impl Mbc1 {
             0xA000...0xBFFF => {
                 // When RAM is disabled, the hardware ignores writes.
                 if !self.ram_enabled || self.ram.len() == 0 { return; }
-                let bank = match self.mode {
-                    MbcMode::Rom => 0,
-                    MbcMode::Ram => self.bank_reg2,
-                };
-                set_ram(&mut self.ram, bank as u16, addr, val);
+                let bank = self.ram_bank();
+                set_ram(&mut self.ram, bank, addr, val);
             }
 
             _ => panic!("Unimplemented MBC1 write address: {}, value: {}", addr, val),
@@ -254,142 +1020,862 @@ impl Mbc1 {
     }
 }
 
+/// The MBC2 has one register range, 0x0000-0x3FFF, with bit 8 of the address picking between RAM
+/// enable and the ROM bank. Its RAM is 512 half-bytes built into the chip, which repeat through
+/// 0xA000-0xBFFF and read back with the upper half of each byte set.
 #[derive(Clone, Debug)]
-pub struct Mbc3 {
+pub struct Mbc2 {
     rom: Box<[u8]>,
     ram: Box<[u8]>,
+    ram_enabled: bool,
     rom_bank: u8,
-    ram_rtc_enabled: bool,
-    ram_rtc_bank: u8,
-    rtc: [u8; 5],
 }
 
-impl Mbc3 {
+impl Mbc2 {
     fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
-        Self { rom, ram, rom_bank: 1, ram_rtc_enabled: false, ram_rtc_bank: 0, rtc: [0; 5] }
+        Self { rom, ram, ram_enabled: false, rom_bank: 1 }
     }
 
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            // ROM Bank 0
             0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
 
-            // Switchable ROM bank
             0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
 
-            // Switchable RAM bank
             0xA000...0xBFFF => {
                 // When RAM is disabled, the hardware returns all bits set.
-                if !self.ram_rtc_enabled { return 0xFF; }
-
-                let bank = self.ram_rtc_bank as u16;
-                match bank {
-                    // Read from RTC values
-                    0x8...0xC => {
-                        //TODO(wcarlson): Read from RTC values
-                        unimplemented!()
-                    }
-
-                    // Read from RAM bank
-                    _ => get_ram(&self.ram, bank, addr),
-                }
+                if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
+                0xF0 | self.ram[addr as usize % self.ram.len()]
             }
 
-            _ => panic!("Unimplemented MBC3 read at address: {}", addr),
+            _ => panic!("Unimplemented MBC2 read at address: {}", addr),
         }
     }
 
     fn write(&mut self, addr: u16, val: u8) {
         match addr {
-            // RAM Enable
-            0x0000...0x1FFF => {
-                self.ram_rtc_enabled = (val & 0b1111) == 0b1010;
-            }
-
-            // ROM bank
-            0x2000...0x3FFF => {
-                self.rom_bank = val & 0b0111_1111;
-                if self.rom_bank == 0 {
-                    self.rom_bank = 1;
+            // RAM enable when bit 8 of the address is clear, ROM bank when it's set
+            0x0000...0x3FFF => {
+                if addr & 0x100 == 0 {
+                    self.ram_enabled = (val & 0b1111) == 0b1010;
+                } else {
+                    self.rom_bank = (val & 0b1111).max(1);
                 }
             }
 
-            // RAM Bank / RTC register
-            0x4000...0x5FFF => {
-                self.ram_rtc_bank = val;
-            }
-
-            // Latch clock data
-            0x6000...0x7FFF => {
-                warn!("unimplemented: write to MBC3 RTC latch register");
-            }
+            // No registers here, writes are ignored.
+            0x4000...0x7FFF => {}
 
-            // Switchable RAM bank
             0xA000...0xBFFF => {
                 // When RAM is disabled, the hardware ignores writes.
-                if !self.ram_rtc_enabled { return; }
-
-                let bank = self.ram_rtc_bank as u16;
-                match bank {
-                    // Write to RTC values
-                    0x8...0xC => {
-                        warn!("unimplemented: write to MBC3 RTC data");
-                    }
-
-                    // Write to RAM bank
-                    _ => {
-                        // let ram_index = (addr - 0xA000) as usize;
-                        // self.ram[ram_index + bank * RAM_BANK_SIZE] = val;
-                        set_ram(&mut self.ram, bank, addr, val);
-                    }
-                }
+                if !self.ram_enabled || self.ram.len() == 0 { return; }
+                let len = self.ram.len();
+                self.ram[addr as usize % len] = val & 0xF;
             }
 
-            _ => panic!("Unimplemented MBC1 write address: {}, value: {}", addr, val),
+            _ => panic!("Unimplemented MBC2 write address: {}, value: {}", addr, val),
         }
     }
 }
 
+/// The MMM01, used by multicarts like Taito Variety Pack. It starts out unmapped, showing the menu
+/// in the last 32 KiB of the ROM, and the menu sets up an MBC1-like mapper for the chosen game by
+/// writing the game's outer bank bits and which bank bits the game may still change. Setting bit
+/// 6 of the RAM enable register maps the game in, after which only the MBC1-style registers work,
+/// until the Game Boy is reset back to the menu.
+///
+/// While unmapped, the extra register bits are:
+///
+/// - 0x0000-0x1FFF: bit 6 maps the game, and bits 4-5 lock bits of the RAM bank.
+/// - 0x2000-0x3FFF: bits 5-6 are bits 5-6 of the ROM bank.
+/// - 0x4000-0x5FFF: bits 2-3 are the upper RAM bank bits, bits 4-5 are bits 7-8 of the ROM bank,
+///   and bit 6 locks the banking mode.
+/// - 0x6000-0x7FFF: bits 2-5 lock bits 1-4 of the ROM bank.
+///
+/// A locked bit keeps the value it had when the game was mapped in. The multiplex bit, which swaps
+/// ROM and RAM bank bits, isn't emulated since no known multicart sets it.
 #[derive(Clone, Debug)]
-pub struct Mbc5 {
+pub struct Mmm01 {
     rom: Box<[u8]>,
     ram: Box<[u8]>,
+    mapped: bool,
     ram_enabled: bool,
-    rom_bank_reg1: u8,
-    rom_bank_reg2: u8,
-    ram_bank_reg: u8,
+    mode: MbcMode,
+    mode_locked: bool,
+
+    /// The ROM bank is these three registers' bits in order from highest to lowest: 2, 2, and 5.
+    rom_bank_high: u8,
+    rom_bank_mid: u8,
+    rom_bank_low: u8,
+
+    /// Which bits of `rom_bank_low` are locked, already shifted into place.
+    rom_bank_locked: u8,
+
+    ram_bank_high: u8,
+    ram_bank_low: u8,
+    ram_bank_locked: u8,
 }
 
-impl Mbc5 {
+impl Mmm01 {
     fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
         Self {
             rom,
             ram,
+            mapped: false,
             ram_enabled: false,
-            rom_bank_reg1: 0,
-            rom_bank_reg2: 0,
-            ram_bank_reg: 0,
+            mode: MbcMode::Rom,
+            mode_locked: false,
+            rom_bank_high: 0,
+            rom_bank_mid: 0,
+            rom_bank_low: 0,
+            rom_bank_locked: 0,
+            ram_bank_high: 0,
+            ram_bank_low: 0,
+            ram_bank_locked: 0,
+        }
+    }
+
+    /// The game's bank number without the low bits it can change.
+    fn outer_bank(&self) -> u16 {
+        u16::from(self.rom_bank_high) << 7 | u16::from(self.rom_bank_mid) << 5
+            | u16::from(self.rom_bank_low & self.rom_bank_locked)
+    }
+
+    /// The bank mapped at 0x0000-0x3FFF. Unmapped, it's the second to last, masked to the ROM size.
+    fn zero_bank(&self) -> u16 {
+        if self.mapped { self.outer_bank() } else { 0x1FE }
+    }
+
+    /// The bank mapped at 0x4000-0x7FFF. Like the MBC1, when the bits the game can change are all
+    /// 0, the lowest one reads as 1.
+    fn rom_bank(&self) -> u16 {
+        if !self.mapped {
+            return 0x1FF;
         }
+        let low = self.rom_bank_low & !self.rom_bank_locked;
+        self.outer_bank() | u16::from(if low == 0 { 1 } else { low })
+    }
+
+    fn ram_bank(&self) -> u16 {
+        let low = match self.mode {
+            MbcMode::Rom => self.ram_bank_low & self.ram_bank_locked,
+            MbcMode::Ram => self.ram_bank_low,
+        };
+        u16::from(self.ram_bank_high) << 2 | u16::from(low)
     }
 
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x3FFF => {
-                get_rom(&self.rom, 0, addr)
-            }
+            0x0000...0x3FFF => get_rom(&self.rom, self.zero_bank(), addr),
 
-            0x4000...0x7FFF => {
-                let bank = u16::from_le_bytes([self.rom_bank_reg1, self.rom_bank_reg2]);
-                get_rom(&self.rom, bank, addr)
-            }
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank(), addr),
 
             0xA000...0xBFFF => {
                 // When RAM is disabled, the hardware returns all bits set.
                 if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
-                get_ram(&self.ram, self.ram_bank_reg as u16, addr)
+                get_ram(&self.ram, self.ram_bank(), addr)
             }
 
-            _ => panic!("Unimplemented MBC5 read at address: {}", addr),
+            _ => panic!("Unimplemented MMM01 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => {
+                self.ram_enabled = (val & 0b1111) == 0b1010;
+                if !self.mapped {
+                    self.ram_bank_locked = val >> 4 & 0b11;
+                    self.mapped = val & 0x40 != 0;
+                }
+            }
+
+            0x2000...0x3FFF => {
+                let locked = if self.mapped { self.rom_bank_locked } else { 0 };
+                self.rom_bank_low = self.rom_bank_low & locked | val & 0b1_1111 & !locked;
+                if !self.mapped {
+                    self.rom_bank_mid = val >> 5 & 0b11;
+                }
+            }
+
+            0x4000...0x5FFF => {
+                let locked = if self.mapped { self.ram_bank_locked } else { 0 };
+                self.ram_bank_low = self.ram_bank_low & locked | val & 0b11 & !locked;
+                if !self.mapped {
+                    self.ram_bank_high = val >> 2 & 0b11;
+                    self.rom_bank_high = val >> 4 & 0b11;
+                    self.mode_locked = val & 0x40 != 0;
+                }
+            }
+
+            0x6000...0x7FFF => {
+                if !self.mode_locked {
+                    self.mode = if val & 1 == 0 { MbcMode::Rom } else { MbcMode::Ram };
+                }
+                if !self.mapped {
+                    self.rom_bank_locked = (val >> 2 & 0b1111) << 1;
+                }
+            }
+
+            0xA000...0xBFFF => {
+                // When RAM is disabled, the hardware ignores writes.
+                if !self.ram_enabled || self.ram.len() == 0 { return; }
+                let bank = self.ram_bank();
+                set_ram(&mut self.ram, bank, addr, val);
+            }
+
+            _ => panic!("Unimplemented MMM01 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+/// The MBC6, only used by Net de Get: Minigame @ 100. Its ROM and RAM areas are split in half, with
+/// 8 KiB ROM banks at 0x4000 and 0x6000 and 4 KiB RAM banks at 0xA000 and 0xB000, each picked by
+/// its own register. Either ROM half can map the cart's 1 MiB flash chip instead, which the game
+/// downloads minigames into. The flash reads as erased, since programming it isn't emulated.
+#[derive(Clone, Debug)]
+pub struct Mbc6 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    ram_enabled: bool,
+
+    /// The bank numbers of the lower and upper halves.
+    ram_banks: [u8; 2],
+    rom_banks: [u8; 2],
+
+    /// Whether each ROM half maps the flash instead of ROM.
+    flash_selected: [bool; 2],
+}
+
+impl Mbc6 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self { rom, ram, ram_enabled: false, ram_banks: [0; 2], rom_banks: [0; 2], flash_selected: [false; 2] }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
+
+            0x4000...0x7FFF => {
+                let half = (addr as usize - 0x4000) / MBC6_ROM_BANK_SIZE;
+                if self.flash_selected[half] {
+                    return 0xFF;
+                }
+                let bank = u16::from(self.rom_banks[half]);
+                let index = bank_index(bank, addr, MBC6_ROM_BANK_SIZE, self.rom.len().next_power_of_two());
+                self.rom.get(index).cloned().unwrap_or(0xFF)
+            }
+
+            0xA000...0xBFFF => {
+                // When RAM is disabled, the hardware returns all bits set.
+                if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
+                self.ram[self.ram_index(addr)]
+            }
+
+            _ => panic!("Unimplemented MBC6 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x03FF => self.ram_enabled = (val & 0b1111) == 0b1010,
+            0x0400...0x07FF => self.ram_banks[0] = val & 0b111,
+            0x0800...0x0BFF => self.ram_banks[1] = val & 0b111,
+
+            // Flash enable and write enable. Without flash programming, there's nothing to enable.
+            0x0C00...0x1FFF => {}
+
+            0x2000...0x27FF => self.rom_banks[0] = val & 0x7F,
+            0x2800...0x2FFF => self.flash_selected[0] = val == 0x08,
+            0x3000...0x37FF => self.rom_banks[1] = val & 0x7F,
+            0x3800...0x3FFF => self.flash_selected[1] = val == 0x08,
+
+            // Commands to the flash chip.
+            0x4000...0x7FFF => {}
+
+            0xA000...0xBFFF => {
+                // When RAM is disabled, the hardware ignores writes.
+                if !self.ram_enabled || self.ram.len() == 0 { return; }
+                let index = self.ram_index(addr);
+                self.ram[index] = val;
+            }
+
+            _ => panic!("Unimplemented MBC6 write address: {}, value: {}", addr, val),
+        }
+    }
+
+    fn ram_index(&self, addr: u16) -> usize {
+        let half = (addr as usize - 0xA000) / MBC6_RAM_BANK_SIZE;
+        bank_index(u16::from(self.ram_banks[half]), addr, MBC6_RAM_BANK_SIZE, self.ram.len())
+    }
+}
+
+/// The MBC7, used by Kirby Tilt 'n' Tumble and Command Master. It has a two-axis accelerometer,
+/// and a 93LC56 serial EEPROM instead of RAM. Both are reached through registers at
+/// 0xA000-0xAFFF, picked by bits 4-7 of the address, once they're enabled twice over: 0x0A
+/// written to 0x0000-0x1FFF, then 0x40 to 0x4000-0x5FFF.
+///
+/// The accelerometer is read by erasing the latched values with 0x55 to Ax0x, then latching the
+/// current tilt with 0xAA to Ax1x. The EEPROM is bit-banged through Ax8x, see `Eeprom`.
+#[derive(Clone, Debug)]
+pub struct Mbc7 {
+    rom: Box<[u8]>,
+
+    /// The EEPROM's contents, each word low byte first.
+    ram: Box<[u8]>,
+
+    rom_bank: u8,
+    ram_enabled: bool,
+    registers_enabled: bool,
+
+    /// The tilt set by the frontend, see `Cart::set_tilt`.
+    tilt: (f32, f32),
+
+    /// The last X and Y readings latched, and whether they've been erased since.
+    latched: (u16, u16),
+    latch_erased: bool,
+
+    eeprom: Eeprom,
+}
+
+impl Mbc7 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self {
+            rom,
+            ram,
+            rom_bank: 1,
+            ram_enabled: false,
+            registers_enabled: false,
+            tilt: (0.0, 0.0),
+            latched: (0x8000, 0x8000),
+            latch_erased: false,
+            eeprom: Eeprom::default(),
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
+
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            0xA000...0xAFFF if self.ram_enabled && self.registers_enabled => {
+                match (addr >> 4) & 0xF {
+                    0x2 => self.latched.0 as u8,
+                    0x3 => (self.latched.0 >> 8) as u8,
+                    0x4 => self.latched.1 as u8,
+                    0x5 => (self.latched.1 >> 8) as u8,
+                    0x6 => 0x00,
+                    0x8 => self.eeprom.read(),
+                    _ => 0xFF,
+                }
+            }
+
+            0xA000...0xBFFF => 0xFF,
+
+            _ => panic!("Unimplemented MBC7 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = (val & 0b1111) == 0b1010,
+
+            0x2000...0x3FFF => self.rom_bank = val & 0x7F,
+
+            0x4000...0x5FFF => self.registers_enabled = val == 0x40,
+
+            0x6000...0x7FFF => {}
+
+            0xA000...0xAFFF if self.ram_enabled && self.registers_enabled => {
+                match (addr >> 4) & 0xF {
+                    0x0 if val == 0x55 => {
+                        self.latched = (0x8000, 0x8000);
+                        self.latch_erased = true;
+                    }
+                    0x1 if val == 0xAA && self.latch_erased => {
+                        // Tilting right or towards the player lowers the readings.
+                        let reading = |tilt: f32| (MBC7_TILT_CENTER - tilt * MBC7_TILT_PER_G) as u16;
+                        self.latched = (reading(self.tilt.0), reading(self.tilt.1));
+                        self.latch_erased = false;
+                    }
+                    0x8 => self.eeprom.write(val, &mut self.ram),
+                    _ => {}
+                }
+            }
+
+            0xA000...0xBFFF => {}
+
+            _ => panic!("Unimplemented MBC7 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+/// The MBC7's 93LC56 EEPROM, driven one bit at a time through a register: bit 7 is chip select,
+/// bit 6 the clock, bit 1 data in, and bit 0 data out. Each command is clocked in on rising clock
+/// edges as a start bit, a 2-bit opcode, and an 8-bit address, of which the low 7 bits pick a word:
+///
+/// - `10` reads the word, shifting it out after a dummy 0 bit, then carries on with the next ones.
+/// - `01` writes the 16 bits clocked in next to the word.
+/// - `11` erases the word to 0xFFFF.
+/// - `00` picks by the top two address bits: `11` enables writing and erasing, `00` disables them,
+///   `10` erases every word, and `01` writes the 16 bits clocked in next to every word.
+///
+/// Writes and erases finish at once, so data out reads 1 (ready) right after them.
+#[derive(Clone, Debug, Default)]
+struct Eeprom {
+    select: bool,
+    clock: bool,
+    data_in: bool,
+    data_out: bool,
+    write_enabled: bool,
+    command: EepromCommand,
+
+    /// The bits clocked in for the current command, and how many.
+    shift: u16,
+    bits: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EepromCommand {
+    /// Waiting for a start bit.
+    Idle,
+    /// Clocking in the opcode and address.
+    Receiving,
+    /// Shifting out `word`, with this many bits of it left.
+    Reading { word: u8, bits_left: u8 },
+    /// Clocking in the data for a word, or every word for `None`.
+    Writing(Option<u8>),
+}
+
+impl Default for EepromCommand {
+    fn default() -> Self {
+        EepromCommand::Idle
+    }
+}
+
+impl Eeprom {
+    fn read(&self) -> u8 {
+        (self.select as u8) << 7 | (self.clock as u8) << 6 | (self.data_in as u8) << 1 | self.data_out as u8
+    }
+
+    fn write(&mut self, val: u8, data: &mut [u8]) {
+        let (select, clock, data_in) = (val & 0x80 != 0, val & 0x40 != 0, val & 0x02 != 0);
+        if !select {
+            self.command = EepromCommand::Idle;
+        } else if clock && !self.clock {
+            self.clock_in(data_in, data);
+        }
+        self.select = select;
+        self.clock = clock;
+        self.data_in = data_in;
+    }
+
+    fn clock_in(&mut self, bit: bool, data: &mut [u8]) {
+        match self.command {
+            EepromCommand::Idle => {
+                if bit {
+                    self.command = EepromCommand::Receiving;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            }
+            EepromCommand::Receiving => {
+                self.shift = self.shift << 1 | bit as u16;
+                self.bits += 1;
+                if self.bits == 10 {
+                    self.run_command((self.shift >> 8) as u8, self.shift as u8, data);
+                }
+            }
+            EepromCommand::Reading { word, bits_left } => {
+                let (word, bits_left) = if bits_left == 0 { ((word + 1) & 0x7F, 16) } else { (word, bits_left) };
+                self.data_out = read_word(data, word) & (1 << (bits_left - 1)) != 0;
+                self.command = EepromCommand::Reading { word, bits_left: bits_left - 1 };
+            }
+            EepromCommand::Writing(word) => {
+                self.shift = self.shift << 1 | bit as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    if self.write_enabled {
+                        match word {
+                            Some(word) => write_word(data, word, self.shift),
+                            None => (0..0x80).for_each(|word| write_word(data, word, self.shift)),
+                        }
+                    }
+                    self.data_out = true;
+                    self.command = EepromCommand::Idle;
+                }
+            }
+        }
+    }
+
+    fn run_command(&mut self, opcode: u8, addr: u8, data: &mut [u8]) {
+        let word = addr & 0x7F;
+        self.command = EepromCommand::Idle;
+        self.data_out = true;
+        match opcode {
+            0b10 => {
+                self.command = EepromCommand::Reading { word, bits_left: 16 };
+                self.data_out = false;
+            }
+            0b01 => {
+                self.command = EepromCommand::Writing(Some(word));
+                self.shift = 0;
+                self.bits = 0;
+            }
+            0b11 if self.write_enabled => write_word(data, word, 0xFFFF),
+            0b00 => match addr >> 6 {
+                0b11 => self.write_enabled = true,
+                0b00 => self.write_enabled = false,
+                0b10 if self.write_enabled => (0..0x80).for_each(|word| write_word(data, word, 0xFFFF)),
+                0b01 => {
+                    self.command = EepromCommand::Writing(None);
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.select);
+        w.bool(self.clock);
+        w.bool(self.data_in);
+        w.bool(self.data_out);
+        w.bool(self.write_enabled);
+        let (kind, word, bits_left) = match self.command {
+            EepromCommand::Idle => (0, 0, 0),
+            EepromCommand::Receiving => (1, 0, 0),
+            EepromCommand::Reading { word, bits_left } => (2, word, bits_left),
+            EepromCommand::Writing(Some(word)) => (3, word, 0),
+            EepromCommand::Writing(None) => (4, 0, 0),
+        };
+        w.u8(kind);
+        w.u8(word);
+        w.u8(bits_left);
+        w.u16(self.shift);
+        w.u8(self.bits);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.select = r.bool()?;
+        self.clock = r.bool()?;
+        self.data_in = r.bool()?;
+        self.data_out = r.bool()?;
+        self.write_enabled = r.bool()?;
+        let (kind, word, bits_left) = (r.u8()?, r.u8()?, r.u8()?);
+        self.command = match kind {
+            0 => EepromCommand::Idle,
+            1 => EepromCommand::Receiving,
+            2 if bits_left <= 16 => EepromCommand::Reading { word: word & 0x7F, bits_left },
+            3 => EepromCommand::Writing(Some(word & 0x7F)),
+            4 => EepromCommand::Writing(None),
+            _ => return Err(SaveStateError::InvalidValue("invalid MBC7 EEPROM command")),
+        };
+        self.shift = r.u16()?;
+        self.bits = r.u8()?;
+        // A command runs as soon as its last bit is in, so there can't be that many waiting.
+        let full = match self.command {
+            EepromCommand::Receiving => 10,
+            EepromCommand::Writing(_) => 16,
+            EepromCommand::Idle | EepromCommand::Reading { .. } => return Ok(()),
+        };
+        if self.bits >= full {
+            return Err(SaveStateError::InvalidValue("MBC7 EEPROM bit count out of range"));
+        }
+        Ok(())
+    }
+}
+
+fn read_word(data: &[u8], word: u8) -> u16 {
+    let i = word as usize * 2 % data.len().max(2);
+    u16::from_le_bytes([data.get(i).cloned().unwrap_or(0xFF), data.get(i + 1).cloned().unwrap_or(0xFF)])
+}
+
+fn write_word(data: &mut [u8], word: u8, val: u16) {
+    let i = word as usize * 2 % data.len().max(2);
+    if let Some(bytes) = data.get_mut(i..i + 2) {
+        bytes.copy_from_slice(&val.to_le_bytes());
+    }
+}
+
+/// MBC1M multicarts are 1 MiB, made of four 256 KiB games that each start with a header. Plain
+/// MBC1 games of that size have code in bank 0x10 instead, so a second copy of the logo there
+/// gives the multicart away.
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    const GAME_SIZE: usize = 0x40000;
+    rom.len() == 4 * GAME_SIZE
+        && rom[LOGO_RANGE] == rom[GAME_SIZE + LOGO_RANGE.start..GAME_SIZE + LOGO_RANGE.end]
+}
+
+#[derive(Clone, Debug)]
+pub struct Mbc3 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    rom_bank: u8,
+    ram_rtc_enabled: bool,
+    ram_rtc_bank: u8,
+
+    /// The RTC registers as last latched, which are what the game reads.
+    rtc: [u8; 5],
+
+    /// The running clock.
+    clock: Rtc,
+
+    /// Cycles counted towards the clock's next second.
+    clock_cycles: u32,
+
+    /// Whether the last write to the latch register was 0. Writing 1 next latches the clock.
+    latch_armed: bool,
+}
+
+impl Mbc3 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self {
+            rom,
+            ram,
+            rom_bank: 1,
+            ram_rtc_enabled: false,
+            ram_rtc_bank: 0,
+            rtc: [0; 5],
+            clock: Rtc::default(),
+            clock_cycles: 0,
+            latch_armed: false,
+        }
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        if self.clock.halted {
+            return;
+        }
+        self.clock_cycles += cycles as u32;
+        while self.clock_cycles >= CYCLES_PER_SECOND {
+            self.clock_cycles -= CYCLES_PER_SECOND;
+            self.clock.advance(1);
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // ROM Bank 0
+            0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
+
+            // Switchable ROM bank
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            // Switchable RAM bank
+            0xA000...0xBFFF => {
+                // When RAM is disabled, the hardware returns all bits set.
+                if !self.ram_rtc_enabled { return 0xFF; }
+
+                let bank = self.ram_rtc_bank as u16;
+                match bank {
+                    // Read from the latched RTC values
+                    0x8...0xC => self.rtc[bank as usize - 0x8],
+
+                    // Read from RAM bank
+                    _ => {
+                        if self.ram.len() == 0 { return 0xFF; }
+                        get_ram(&self.ram, bank, addr)
+                    }
+                }
+            }
+
+            _ => panic!("Unimplemented MBC3 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM Enable
+            0x0000...0x1FFF => {
+                self.ram_rtc_enabled = (val & 0b1111) == 0b1010;
+            }
+
+            // ROM bank
+            0x2000...0x3FFF => {
+                self.rom_bank = val & 0b0111_1111;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+
+            // RAM Bank / RTC register
+            0x4000...0x5FFF => {
+                self.ram_rtc_bank = val;
+            }
+
+            // Latch clock data
+            0x6000...0x7FFF => {
+                if self.latch_armed && val == 1 {
+                    self.rtc = self.clock.registers();
+                }
+                self.latch_armed = val == 0;
+            }
+
+            // Switchable RAM bank
+            0xA000...0xBFFF => {
+                // When RAM is disabled, the hardware ignores writes.
+                if !self.ram_rtc_enabled { return; }
+
+                let bank = self.ram_rtc_bank as u16;
+                match bank {
+                    // Write to the running RTC. Writing the seconds also restarts the current
+                    // second.
+                    0x8...0xC => {
+                        if bank == 0x8 {
+                            self.clock_cycles = 0;
+                        }
+                        self.clock.set_register(bank as u8 - 0x8, val);
+                        self.rtc[bank as usize - 0x8] = self.clock.registers()[bank as usize - 0x8];
+                    }
+
+                    // Write to RAM bank
+                    _ => {
+                        if self.ram.len() == 0 { return; }
+                        set_ram(&mut self.ram, bank, addr, val);
+                    }
+                }
+            }
+
+            _ => panic!("Unimplemented MBC3 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+/// The MBC3's real-time clock. It counts seconds while the game runs, and battery saves record it
+/// so it can catch up on the time the game was switched off.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rtc {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+
+    /// The 9-bit day counter.
+    pub days: u16,
+
+    /// A halted clock doesn't count.
+    pub halted: bool,
+
+    /// Set when the day counter overflows, until the game clears it.
+    pub day_carry: bool,
+}
+
+impl Rtc {
+    pub fn from_registers(registers: [u8; 5]) -> Self {
+        let mut rtc = Rtc::default();
+        for (i, &val) in registers.iter().enumerate() {
+            rtc.set_register(i as u8, val);
+        }
+        rtc
+    }
+
+    /// The register values: seconds, minutes, hours, day low, and day high.
+    pub fn registers(&self) -> [u8; 5] {
+        let day_high = (self.days >> 8) as u8 & 1
+            | (self.halted as u8) << 6
+            | (self.day_carry as u8) << 7;
+        [self.seconds, self.minutes, self.hours, self.days as u8, day_high]
+    }
+
+    /// Write one register, numbered in the same order as `registers`. Unused bits are dropped.
+    pub fn set_register(&mut self, index: u8, val: u8) {
+        match index {
+            0 => self.seconds = val & 0b0011_1111,
+            1 => self.minutes = val & 0b0011_1111,
+            2 => self.hours = val & 0b0001_1111,
+            3 => self.days = self.days & 0x100 | u16::from(val),
+            4 => {
+                self.days = u16::from(val & 1) << 8 | self.days & 0xFF;
+                self.halted = val & (1 << 6) != 0;
+                self.day_carry = val & (1 << 7) != 0;
+            }
+            _ => panic!("invalid RTC register {}", index),
+        }
+    }
+
+    /// Count `seconds` more seconds, unless the clock is halted.
+    pub fn advance(&mut self, seconds: u64) {
+        if self.halted {
+            return;
+        }
+        // Games can set out-of-range values like 61 seconds. Real hardware counts up to the
+        // register's limit before wrapping, but normalizing is close enough and never goes back.
+        let total = u64::from(self.seconds)
+            + u64::from(self.minutes) * 60
+            + u64::from(self.hours) * 60 * 60
+            + u64::from(self.days) * 24 * 60 * 60
+            + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / (60 * 60) % 24) as u8;
+        let days = total / (24 * 60 * 60);
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+}
+
+/// The MBC5, with a 9-bit ROM bank number for up to 8 MiB of ROM and 16 banks of RAM. Carts with
+/// a rumble motor wire the top bit of the RAM bank number to the motor instead, leaving 8 banks.
+#[derive(Clone, Debug)]
+pub struct Mbc5 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    ram_enabled: bool,
+    rom_bank_reg1: u8,
+    rom_bank_reg2: u8,
+    ram_bank_reg: u8,
+    has_rumble: bool,
+}
+
+/// The bit of the MBC5's RAM bank register that runs the motor on rumble carts.
+const MBC5_RUMBLE_BIT: u8 = 1 << 3;
+
+impl Mbc5 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        let has_rumble = CartHeader::from_rom(&rom)
+            .map_or(false, |header| header.hardware.contains(CartHardware::Rumble));
+        Self {
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank_reg1: 0,
+            rom_bank_reg2: 0,
+            ram_bank_reg: 0,
+            has_rumble,
+        }
+    }
+
+    fn ram_bank(&self) -> u8 {
+        if self.has_rumble { self.ram_bank_reg & !MBC5_RUMBLE_BIT } else { self.ram_bank_reg }
+    }
+
+    fn rumble(&self) -> bool {
+        self.has_rumble && self.ram_bank_reg & MBC5_RUMBLE_BIT != 0
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3FFF => {
+                get_rom(&self.rom, 0, addr)
+            }
+
+            0x4000...0x7FFF => {
+                let bank = u16::from_le_bytes([self.rom_bank_reg1, self.rom_bank_reg2]);
+                get_rom(&self.rom, bank, addr)
+            }
+
+            0xA000...0xBFFF => {
+                // When RAM is disabled, the hardware returns all bits set.
+                if !self.ram_enabled || self.ram.len() == 0 { return 0xFF; }
+                get_ram(&self.ram, self.ram_bank() as u16, addr)
+            }
+
+            _ => panic!("Unimplemented MBC5 read at address: {}", addr),
         }
     }
 
@@ -410,7 +1896,7 @@ impl Mbc5 {
                 self.rom_bank_reg2 = val & 0b0001;
             }
 
-            // RAM bank write
+            // RAM bank write, and the motor on rumble carts
             0x4000...0x5FFF => {
                 self.ram_bank_reg = val & 0b1111;
             }
@@ -419,7 +1905,8 @@ impl Mbc5 {
             0xA000...0xBFFF => {
                 // When RAM is disabled, the hardware ignores writes.
                 if !self.ram_enabled || self.ram.len() == 0 { return; }
-                set_ram(&mut self.ram, self.ram_bank_reg as u16, addr, val);
+                let bank = self.ram_bank();
+                set_ram(&mut self.ram, bank as u16, addr, val);
             }
 
             _ => panic!("Unimplemented MBC5 write address: {}, value: {}", addr, val),
@@ -427,14 +1914,499 @@ impl Mbc5 {
     }
 }
 
+/// Hudson's HuC1, used by games like Pokémon Card GB and Super B-Daman. Its registers are laid out
+/// like the MBC1's, but with a 6-bit ROM bank and no banking modes. It has no RAM enable: instead
+/// 0x0E written to 0x0000-0x1FFF swaps the RAM area for the infrared port, and anything else swaps
+/// it back. In IR mode bit 0 of a write turns the LED on or off, and reads have bit 0 set while
+/// light is seen. There's never anyone on the other end, so reads are always dark.
+#[derive(Clone, Debug)]
+pub struct HuC1 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    ir_mode: bool,
+    ir_led: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+/// What the HuC1's IR register reads with no light seen. The upper bits always read as set.
+const HUC1_IR_DARK: u8 = 0xC0;
+
+impl HuC1 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self { rom, ram, ir_mode: false, ir_led: false, rom_bank: 1, ram_bank: 0 }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
+
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            0xA000...0xBFFF if self.ir_mode => HUC1_IR_DARK,
+
+            0xA000...0xBFFF => {
+                if self.ram.len() == 0 { return 0xFF; }
+                get_ram(&self.ram, self.ram_bank as u16, addr)
+            }
+
+            _ => panic!("Unimplemented HuC1 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // RAM or IR select
+            0x0000...0x1FFF => self.ir_mode = val == 0x0E,
+
+            0x2000...0x3FFF => self.rom_bank = val & 0x3F,
+
+            0x4000...0x5FFF => self.ram_bank = val & 0b11,
+
+            // No registers here, writes are ignored.
+            0x6000...0x7FFF => {}
+
+            0xA000...0xBFFF if self.ir_mode => self.ir_led = val & 1 != 0,
+
+            0xA000...0xBFFF => {
+                if self.ram.len() == 0 { return; }
+                set_ram(&mut self.ram, self.ram_bank as u16, addr, val);
+            }
+
+            _ => panic!("Unimplemented HuC1 write address: {}, value: {}", addr, val),
+        }
+    }
+}
+
+/// Hudson's HuC3, used by Robopon and a few other Japanese games. It adds a clock and the infrared
+/// port to a HuC1-like layout. The value written to 0x0000-0x1FFF picks what 0xA000-0xBFFF is:
+///
+/// - `0xA`: RAM. `0x0` maps it read-only.
+/// - `0xB`: clock commands. Each write is a command in the upper nibble and an argument in the
+///   lower one, see `HuC3::command`.
+/// - `0xC`: the clock's answer to the last read command, in the low nibble.
+/// - `0xD`: the clock's ready flag, which is always set since commands finish at once.
+/// - `0xE`: the infrared port, like the HuC1's.
+///
+/// The clock is reached through nibble-wide registers: 0x00-0x02 are the minutes since midnight
+/// and 0x03-0x05 the day counter, both low nibble first, and 0x58-0x5F are the alarm. Extended
+/// command `0xE` sounds the cart's piezo speaker, which isn't emulated beyond noting it.
+#[derive(Clone, Debug)]
+pub struct HuC3 {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    mode: u8,
+    rom_bank: u8,
+    ram_bank: u8,
+    ir_led: bool,
+
+    /// The clock register the next command reads or writes.
+    address: u8,
+
+    /// The answer to the last read command, and the last extended command.
+    answer: u8,
+    extended: u8,
+
+    clock: HuC3Clock,
+    clock_cycles: u32,
+
+    /// When the alarm goes off, in the same units as the clock, and whether it will.
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+}
+
+/// The HuC3's clock. Unlike the MBC3's, it only counts minutes since midnight and days, with no
+/// seconds a game can see, no way to halt it, and a 12-bit day counter that wraps with no flag.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HuC3Clock {
+    /// Seconds into the current minute, kept so a saved clock doesn't lose them.
+    pub seconds: u8,
+
+    /// 0-1439.
+    pub minutes: u16,
+
+    /// The 12-bit day counter.
+    pub days: u16,
+}
+
+const HUC3_MINUTES_PER_DAY: u64 = 24 * 60;
+
+impl HuC3Clock {
+    /// Count `seconds` more seconds.
+    pub fn advance(&mut self, seconds: u64) {
+        let total = u64::from(self.seconds) + seconds;
+        self.seconds = (total % 60) as u8;
+        let minutes = u64::from(self.minutes) + total / 60;
+        self.minutes = (minutes % HUC3_MINUTES_PER_DAY) as u16;
+        self.days = ((u64::from(self.days) + minutes / HUC3_MINUTES_PER_DAY) & 0xFFF) as u16;
+    }
+}
+
+impl HuC3 {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        Self {
+            rom,
+            ram,
+            mode: 0,
+            rom_bank: 1,
+            ram_bank: 0,
+            ir_led: false,
+            address: 0,
+            answer: 0,
+            extended: 0,
+            clock: HuC3Clock::default(),
+            clock_cycles: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+        }
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        self.clock_cycles += cycles as u32;
+        while self.clock_cycles >= CYCLES_PER_SECOND {
+            self.clock_cycles -= CYCLES_PER_SECOND;
+            self.clock.advance(1);
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
+
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            0xA000...0xBFFF => match self.mode {
+                0x0 | 0xA => {
+                    if self.ram.len() == 0 { return 0xFF; }
+                    get_ram(&self.ram, self.ram_bank as u16, addr)
+                }
+                // The status query answers 1 for a working clock.
+                0xC if self.extended == 0x2 => 1,
+                0xC => self.answer,
+                0xD => 1,
+                0xE => HUC1_IR_DARK,
+                _ => 0xFF,
+            },
+
+            _ => panic!("Unimplemented HuC3 read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.mode = val & 0xF,
+
+            0x2000...0x3FFF => self.rom_bank = val & 0x7F,
+
+            0x4000...0x5FFF => self.ram_bank = val & 0b11,
+
+            // No registers here, writes are ignored.
+            0x6000...0x7FFF => {}
+
+            0xA000...0xBFFF => match self.mode {
+                0xA => {
+                    if self.ram.len() == 0 { return; }
+                    set_ram(&mut self.ram, self.ram_bank as u16, addr, val);
+                }
+                0xB => self.command(val >> 4 & 0x7, val & 0xF),
+                0xE => self.ir_led = val & 1 != 0,
+                // Writes to the ready flag start a command on hardware, which is already done.
+                _ => {}
+            },
+
+            _ => panic!("Unimplemented HuC3 write address: {}, value: {}", addr, val),
+        }
+    }
+
+    /// Run a clock command:
+    ///
+    /// - `1` reads the register at the address into the answer, then moves to the next one.
+    /// - `2` writes `arg` to the register, and `3` does the same and moves to the next one.
+    /// - `4` and `5` set the low and high nibble of the address.
+    /// - `6` is an extended command: `2` asks whether the clock works, and `E` sounds the speaker.
+    fn command(&mut self, command: u8, arg: u8) {
+        match command {
+            0x1 => {
+                self.answer = self.register(self.address);
+                self.address = self.address.wrapping_add(1);
+            }
+            0x2 | 0x3 => {
+                self.set_register(self.address, arg);
+                if command == 0x3 {
+                    self.address = self.address.wrapping_add(1);
+                }
+            }
+            0x4 => self.address = self.address & 0xF0 | arg,
+            0x5 => self.address = self.address & 0x0F | arg << 4,
+            0x6 => {
+                self.extended = arg;
+                if arg == 0xE {
+                    info!("HuC3 speaker tone requested, which isn't emulated");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The 12-bit value a nibble-wide register is part of, and which nibble of it.
+    fn register_field(&self, address: u8) -> Option<(u16, u8)> {
+        match address {
+            0x00...0x02 => Some((self.clock.minutes, address)),
+            0x03...0x05 => Some((self.clock.days, address - 0x03)),
+            0x58...0x5A => Some((self.alarm_minutes, address - 0x58)),
+            0x5B...0x5D => Some((self.alarm_days, address - 0x5B)),
+            _ => None,
+        }
+    }
+
+    fn register(&self, address: u8) -> u8 {
+        if address == 0x5F {
+            return self.alarm_enabled as u8;
+        }
+        self.register_field(address).map_or(0, |(value, nibble)| (value >> (nibble * 4)) as u8 & 0xF)
+    }
+
+    /// Write one nibble. An out of range time, like minute 1500, is brought back in range by the
+    /// next tick.
+    fn set_register(&mut self, address: u8, arg: u8) {
+        let (value, nibble) = match self.register_field(address) {
+            Some(field) => field,
+            None => {
+                if address == 0x5F {
+                    self.alarm_enabled = arg & 1 != 0;
+                }
+                return;
+            }
+        };
+        let shift = nibble * 4;
+        let value = value & !(0xF << shift) | u16::from(arg) << shift;
+        match address {
+            0x00...0x02 => self.clock.minutes = value,
+            0x03...0x05 => self.clock.days = value,
+            0x58...0x5A => self.alarm_minutes = value,
+            _ => self.alarm_days = value,
+        }
+    }
+}
+
+/// The Pocket Camera's mapper and M64282FP image sensor. The mapper is MBC5-like, with a 6-bit ROM
+/// bank and 128 KiB of RAM. Bit 4 of the RAM bank register swaps the RAM area for the sensor's
+/// registers, mirrored every 0x80 bytes:
+///
+/// - 0xA000: bit 0 starts a capture and reads as set until it's done. Bits 1-2 are kept.
+/// - 0xA001: the gain and edge enhancement.
+/// - 0xA002-0xA003: the exposure time, high byte first, in 16 µs steps.
+/// - 0xA004-0xA005: the edge ratio, invert bit (0xA004 bit 3), and voltage offsets.
+/// - 0xA006-0xA035: a 4x4 matrix of three thresholds per pixel, which dithers the sensor's
+///   brightness down to four shades.
+///
+/// Only 0xA000 can be read back; the rest read as 0. A finished capture is written as 16x14 tiles
+/// to RAM bank 0 at 0xA100, where the game copies it from. The picture comes from
+/// `Cart::set_camera_image`. Exposure scales its brightness, with 0x1000 keeping it as it is, and
+/// the invert bit flips it. Gain, edge enhancement, and the voltage offsets aren't modeled.
+#[derive(Clone, Debug)]
+pub struct PocketCamera {
+    rom: Box<[u8]>,
+    ram: Box<[u8]>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    registers_selected: bool,
+    registers: [u8; CAMERA_REGISTERS],
+
+    /// Cycles left in the capture that's running, if any.
+    capture_cycles: u32,
+
+    /// What the sensor sees, one brightness byte per pixel, from dark to light.
+    image: Box<[u8]>,
+}
+
+/// The picture size of the Pocket Camera's sensor, as the game sees it.
+pub const CAMERA_WIDTH: usize = 128;
+pub const CAMERA_HEIGHT: usize = 112;
+
+/// The Pocket Camera's sensor registers: the control registers and the dither matrix.
+const CAMERA_REGISTERS: usize = 0x36;
+const CAMERA_MATRIX: usize = 0x06;
+
+/// The exposure time that keeps the picture's brightness as it is.
+const CAMERA_UNIT_EXPOSURE: u32 = 0x1000;
+
+/// Where in RAM bank 0 a finished capture goes.
+const CAMERA_IMAGE_OFFSET: usize = 0x100;
+
+impl PocketCamera {
+    fn new(rom: Box<[u8]>, ram: Box<[u8]>) -> Self {
+        // Until the frontend sets a picture, the sensor sees a gradient from dark at the top left
+        // to light at the bottom right.
+        let image = (0..CAMERA_WIDTH * CAMERA_HEIGHT)
+            .map(|i| ((i % CAMERA_WIDTH + i / CAMERA_WIDTH) * 255 / (CAMERA_WIDTH + CAMERA_HEIGHT - 2)) as u8)
+            .collect();
+        Self {
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            registers_selected: false,
+            registers: [0; CAMERA_REGISTERS],
+            capture_cycles: 0,
+            image,
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3FFF => get_rom(&self.rom, 0, addr),
+
+            0x4000...0x7FFF => get_rom(&self.rom, self.rom_bank as u16, addr),
+
+            0xA000...0xBFFF if self.registers_selected => {
+                if addr & 0x7F != 0 { return 0x00; }
+                self.registers[0] & 0b110 | (self.capture_cycles > 0) as u8
+            }
+
+            // Unlike most mappers, RAM can be read while it's disabled.
+            0xA000...0xBFFF => {
+                if self.ram.len() == 0 { return 0xFF; }
+                get_ram(&self.ram, self.ram_bank as u16, addr)
+            }
+
+            _ => panic!("Unimplemented Pocket Camera read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = (val & 0b1111) == 0b1010,
+
+            0x2000...0x3FFF => self.rom_bank = val & 0x3F,
+
+            0x4000...0x5FFF => {
+                self.ram_bank = val & 0xF;
+                self.registers_selected = val & 0x10 != 0;
+            }
+
+            // No registers here, writes are ignored.
+            0x6000...0x7FFF => {}
+
+            0xA000...0xBFFF if self.registers_selected => {
+                let register = (addr & 0x7F) as usize;
+                if register >= CAMERA_REGISTERS { return; }
+                self.registers[register] = val;
+                if register == 0 && val & 1 != 0 && self.capture_cycles == 0 {
+                    self.capture_cycles = self.capture_time();
+                }
+            }
+
+            0xA000...0xBFFF => {
+                if !self.ram_enabled || self.ram.len() == 0 { return; }
+                set_ram(&mut self.ram, self.ram_bank as u16, addr, val);
+            }
+
+            _ => panic!("Unimplemented Pocket Camera write address: {}, value: {}", addr, val),
+        }
+    }
+
+    fn exposure(&self) -> u32 {
+        u32::from(self.registers[2]) << 8 | u32::from(self.registers[3])
+    }
+
+    /// How long a capture takes, in cycles: about 32,000 machine cycles to read the sensor out,
+    /// 512 more without the N bit (0xA001 bit 7), and 16 per step of exposure.
+    fn capture_time(&self) -> u32 {
+        let n = self.registers[1] & 0x80 != 0;
+        4 * (32_446 + if n { 0 } else { 512 } + 16 * self.exposure())
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        if self.capture_cycles == 0 {
+            return;
+        }
+        self.capture_cycles = self.capture_cycles.saturating_sub(cycles as u32);
+        if self.capture_cycles == 0 {
+            self.capture();
+        }
+    }
+
+    /// Expose and dither the picture, and write it to RAM as tiles.
+    fn capture(&mut self) {
+        let exposure = self.exposure();
+        let invert = self.registers[4] & 0x08 != 0;
+        let matrix = &self.registers[CAMERA_MATRIX..];
+        let mut tiles = vec![0; CAMERA_WIDTH * CAMERA_HEIGHT / 4];
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let seen = u32::from(self.image[y * CAMERA_WIDTH + x]);
+                let mut brightness = (seen * exposure / CAMERA_UNIT_EXPOSURE).min(255) as u8;
+                if invert {
+                    brightness = !brightness;
+                }
+                let thresholds = &matrix[((y % 4) * 4 + x % 4) * 3..][..3];
+                let shade = 3 - thresholds.iter().filter(|&&threshold| brightness >= threshold).count() as u8;
+
+                let tile = (y / 8) * (CAMERA_WIDTH / 8) + x / 8;
+                let row = tile * 16 + (y % 8) * 2;
+                let bit = 7 - x % 8;
+                tiles[row] |= (shade & 1) << bit;
+                tiles[row + 1] |= (shade >> 1) << bit;
+            }
+        }
+        if self.ram.len() >= CAMERA_IMAGE_OFFSET + tiles.len() {
+            self.ram[CAMERA_IMAGE_OFFSET..][..tiles.len()].copy_from_slice(&tiles);
+        }
+    }
+}
+
+/// The mapper used by Wisdom Tree's unlicensed games. It has no RAM, and switches the whole
+/// 0x0000-0x7FFF range between 32 KiB banks, selected by the low byte of the address written to
+/// rather than the value.
+#[derive(Clone, Debug)]
+pub struct WisdomTree {
+    rom: Box<[u8]>,
+    bank: u8,
+}
+
+impl WisdomTree {
+    fn new(rom: Box<[u8]>) -> Self {
+        Self { rom, bank: 0 }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x7FFF => get_rom(&self.rom, self.bank as u16 * 2 + (addr >> 14), addr),
+
+            // There is no RAM.
+            0xA000...0xBFFF => 0xFF,
+
+            _ => panic!("Unimplemented Wisdom Tree read at address: {}", addr),
+        }
+    }
+
+    fn write(&mut self, addr: u16) {
+        match addr {
+            0x0000...0x3FFF => self.bank = addr as u8,
+            0x4000...0x7FFF | 0xA000...0xBFFF => {}
+            _ => panic!("Unimplemented Wisdom Tree write address: {}", addr),
+        }
+    }
+}
+
 fn bank_index(bank: u16, addr: u16, bank_size: usize, total_size: usize) -> usize {
     let bank_base = bank as usize * bank_size;
     let addr_in_bank = addr as usize & (bank_size - 1);
     (bank_base | addr_in_bank) & (total_size - 1)
 }
 
+/// Read from a ROM bank. Some homebrew and bootleg ROM files are smaller than their header
+/// claims, so this acts as if the file were on a ROM chip of the next power-of-two size with the
+/// missing address lines unconnected: bank numbers past the end wrap around, mirroring the file,
+/// and whatever is still past the end of a file with an uneven size reads as 0xFF.
 fn get_rom(rom: &[u8], bank: u16, addr: u16) -> u8 {
-    rom[bank_index(bank, addr, ROM_BANK_SIZE, rom.len())]
+    let index = bank_index(bank, addr, ROM_BANK_SIZE, rom.len().next_power_of_two());
+    rom.get(index).cloned().unwrap_or(0xFF)
 }
 
 fn get_ram(ram: &[u8], bank: u16, addr: u16) -> u8 {
@@ -444,3 +2416,138 @@ fn get_ram(ram: &[u8], bank: u16, addr: u16) -> u8 {
 fn set_ram(ram: &mut [u8], bank: u16, addr: u16, val: u8) {
     ram[bank_index(bank, addr, RAM_BANK_SIZE, ram.len())] = val;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart_header::NINTENDO_LOGO;
+    use crate::checksum::header_checksum;
+    use quickcheck::{quickcheck, TestResult};
+
+    quickcheck! {
+        fn undersized_roms_are_mirrored(banks: u8, bank: u8) -> TestResult {
+            // Each bank is filled with its own number, and the header claims the largest MBC5 ROM.
+            let banks = banks as usize % 12 + 1;
+            let rom: Vec<u8> = (0..banks).flat_map(|b| vec![b as u8; 0x4000]).collect();
+            let config = CartConfig { cart_type: CartType::Mbc5, rom_size: 0x80_0000, ram_size: 0 };
+            let mut cart = Cart::new(rom.into_boxed_slice(), None, &config).unwrap();
+            cart.write(0x2000, bank);
+
+            let mirrored = bank as usize & (banks.next_power_of_two() - 1);
+            let expected = if mirrored < banks { mirrored as u8 } else { 0xFF };
+            let actual = cart.read(0x4000 | bank as u16 % 0x4000);
+            if actual != expected {
+                return TestResult::error(format!("bank {} of {}: expected 0x{:02X}, got 0x{:02X}", bank, banks, expected, actual));
+            }
+            TestResult::from_bool(cart.read(0x0000) == 0 && cart.check_invariants().is_ok())
+        }
+
+        fn mmm01_menu_needs_a_valid_header(fill: u8, logo: bool, checksum: bool) -> bool {
+            let mut rom = vec![fill; 0x10000];
+            rom[0x147] = 0x01;
+            let menu = &mut rom[0x8000..];
+            menu[0x147] = 0x0B;
+            if logo {
+                menu[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+            }
+            menu[0x14D] = header_checksum(menu).wrapping_add(!checksum as u8);
+            let header = CartHeader::from_rom(&rom).unwrap();
+            if header.cart_type != if logo && checksum { CartType::Mmm01 } else { CartType::Mbc1 } {
+                return false;
+            }
+            // The menu's global checksum covers the games before it too.
+            rom[0x200] ^= 1;
+            let changed = CartHeader::from_rom(&rom).unwrap();
+            changed.checks.global_checksum.actual != header.checks.global_checksum.actual
+        }
+
+        fn eeprom_bit_count_is_checked_on_load(receiving: bool, bits: u8) -> bool {
+            let (command, full) = if receiving {
+                (EepromCommand::Receiving, 10)
+            } else {
+                (EepromCommand::Writing(None), 16)
+            };
+            let eeprom = Eeprom { command, bits, ..Eeprom::default() };
+            let mut w = StateWriter::with_header(b"TESTSAVE", 1);
+            eeprom.save(&mut w);
+            let data = w.finish();
+            let mut r = StateReader::with_header(&data, b"TESTSAVE", 1).unwrap();
+            Eeprom::default().load(&mut r).is_ok() == (bits < full)
+        }
+
+        fn mbc1_bank_registers_are_masked_on_load(reg1: u8, reg2: u8) -> bool {
+            let config = CartConfig { cart_type: CartType::Mbc1, rom_size: 0x80000, ram_size: 0x8000 };
+            let mut cart = Cart::new(vec![0; 0x80000].into_boxed_slice(), None, &config).unwrap();
+            if let Cart::Mbc1(mbc1) = &mut cart {
+                mbc1.bank_reg1 = reg1;
+                mbc1.bank_reg2 = reg2;
+            }
+            reloaded(&cart).unwrap().check_invariants().is_ok()
+        }
+
+        fn mbc5_bank_registers_are_masked_on_load(rom_bank_high: u8, ram_bank: u8) -> bool {
+            let config = CartConfig { cart_type: CartType::Mbc5, rom_size: 0x8000, ram_size: 0x2_0000 };
+            let mut cart = Cart::new(vec![0; 0x8000].into_boxed_slice(), None, &config).unwrap();
+            if let Cart::Mbc5(mbc5) = &mut cart {
+                mbc5.rom_bank_reg2 = rom_bank_high;
+                mbc5.ram_bank_reg = ram_bank;
+            }
+            reloaded(&cart).unwrap().check_invariants().is_ok()
+        }
+
+        fn rtc_advance_adds_up(a: u32, b: u32) -> bool {
+            let (a, b) = (u64::from(a), u64::from(b));
+            let mut split = Rtc::default();
+            split.advance(a);
+            split.advance(b);
+            let mut whole = Rtc::default();
+            whole.advance(a + b);
+            split == whole
+        }
+    }
+
+    #[test]
+    fn invariants_catch_bad_bank_registers() {
+        let config = CartConfig { cart_type: CartType::Mbc1, rom_size: 0x80000, ram_size: 0x8000 };
+        let mut cart = Cart::new(vec![0; 0x80000].into_boxed_slice(), None, &config).unwrap();
+        assert_eq!(cart.check_invariants(), Ok(()));
+
+        if let Cart::Mbc1(mbc1) = &mut cart {
+            mbc1.bank_reg1 = 0;
+        }
+        assert!(cart.check_invariants().is_err());
+    }
+
+    #[test]
+    fn mbc3_bank_registers_survive_a_reload() {
+        // Each RAM bank is filled with its own number plus one, and the clock registers with 0xC0
+        // plus their index.
+        let ram: Box<[u8]> = (0..0x8000).map(|i| (i / 0x2000) as u8 + 1).collect();
+        let config = CartConfig { cart_type: CartType::Mbc3, rom_size: 0x200000, ram_size: 0x8000 };
+        let mut cart = Cart::new(vec![0; 0x200000].into_boxed_slice(), Some(ram), &config).unwrap();
+        // Any byte can be written to the bank registers, and reads mask it.
+        for bank in 0..0x100 {
+            let bank = bank as u8;
+            if let Cart::Mbc3(mbc3) = &mut cart {
+                mbc3.rom_bank = bank;
+                mbc3.ram_rtc_enabled = true;
+                mbc3.ram_rtc_bank = bank;
+                mbc3.rtc = [0xC0, 0xC1, 0xC2, 0xC3, 0xC4];
+            }
+            let loaded = reloaded(&cart).unwrap();
+            assert_eq!(loaded.read(0xA000), cart.read(0xA000), "bank 0x{:02X}", bank);
+            assert!(loaded.check_invariants().is_ok(), "bank 0x{:02X}", bank);
+        }
+    }
+
+    /// Save `cart` and load the state into a fresh copy of it.
+    fn reloaded(cart: &Cart) -> Result<Cart, SaveStateError> {
+        let mut w = StateWriter::with_header(b"TESTSAVE", 1);
+        cart.save(&mut w);
+        let data = w.finish();
+        let mut loaded = cart.clone();
+        loaded.reset();
+        loaded.load(&mut StateReader::with_header(&data, b"TESTSAVE", 1)?)?;
+        Ok(loaded)
+    }
+}
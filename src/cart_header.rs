@@ -1,5 +1,6 @@
 use enumflags2::BitFlags;
 use enumflags2_derive::EnumFlags;
+use crate::checksum::{global_checksum_with_header_at, header_checksum};
 use failure_derive::Fail;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -37,9 +38,43 @@ pub struct CartHeader {
 
     /// Some games have more than one version, and this byte indicates that. Usually zero.
     pub rom_version: u8,
+
+    /// Whether the logo and checksums are intact.
+    pub checks: HeaderChecks,
+}
+
+/// The Nintendo logo at 0x104-0x133. The boot ROM compares it against its own copy and locks up if
+/// they differ, which was Nintendo's way of keeping unlicensed publishers off the system.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+/// The results of checking the parts of a ROM meant to catch bad dumps and bad connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeaderChecks {
+    /// Whether the logo matches `NINTENDO_LOGO`. Real hardware won't boot the cartridge if not.
+    pub logo: bool,
+
+    /// The checksum of the header bytes 0x134-0x14C. Real hardware won't boot the cartridge if it's
+    /// wrong.
+    pub header_checksum: Checksum<u8>,
+
+    /// The sum of every byte in the ROM. Nothing on real hardware checks it, so homebrew often
+    /// leaves it zero, but in a commercial game a wrong one usually means a bad dump.
+    pub global_checksum: Checksum<u16>,
 }
 
+/// A checksum as the header gives it and as computed from the ROM.
 #[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checksum<T> {
+    pub expected: T,
+    pub actual: T,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum CartType {
     NoMbc,
     Mbc1,
@@ -53,9 +88,36 @@ pub enum CartType {
     HuC3,
     PocketCamera,
     BandaiTama5,
+
+    /// Wisdom Tree's unlicensed mapper. Its carts claim to have no MBC in the header.
+    WisdomTree,
+
     Unknown(u8),
 }
 
+/// The mapper's name, like "MBC1" or "Pocket Camera".
+impl std::fmt::Display for CartType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            CartType::NoMbc => "none",
+            CartType::Mbc1 => "MBC1",
+            CartType::Mbc2 => "MBC2",
+            CartType::Mbc3 => "MBC3",
+            CartType::Mbc5 => "MBC5",
+            CartType::Mbc6 => "MBC6",
+            CartType::Mbc7 => "MBC7",
+            CartType::Mmm01 => "MMM01",
+            CartType::HuC1 => "HuC1",
+            CartType::HuC3 => "HuC3",
+            CartType::PocketCamera => "Pocket Camera",
+            CartType::BandaiTama5 => "Bandai TAMA5",
+            CartType::WisdomTree => "Wisdom Tree",
+            CartType::Unknown(n) => return write!(f, "unknown (0x{:02X})", n),
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Copy, Clone, Debug, EnumFlags)]
 #[repr(u8)]
 pub enum CartHardware {
@@ -150,6 +212,28 @@ pub enum LicenseeCode {
     New([u8; 2]),
 }
 
+impl LicenseeCode {
+    /// The publisher's name, if the code is a known one.
+    pub fn publisher(&self) -> Option<&'static str> {
+        match self {
+            LicenseeCode::Old(code) =>
+                OLD_LICENSEES.iter().find(|&&(c, _)| c == *code).map(|&(_, name)| name),
+            LicenseeCode::New(code) =>
+                NEW_LICENSEES.iter().find(|&&(c, _)| c.as_bytes() == code).map(|&(_, name)| name),
+        }
+    }
+}
+
+/// The code as it appears in the header: two hex digits for old codes, two characters for new ones.
+impl std::fmt::Display for LicenseeCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LicenseeCode::Old(code) => write!(f, "{:02X}", code),
+            LicenseeCode::New(code) => write!(f, "{}", String::from_utf8_lossy(code)),
+        }
+    }
+}
+
 /// Indicates whether the cartridge is supposed to be sold in Japan or outside Japan.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum DestinationCode {
@@ -158,7 +242,77 @@ pub enum DestinationCode {
     Invalid(u8),
 }
 
+impl std::fmt::Display for DestinationCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DestinationCode::Japan => write!(f, "Japan"),
+            DestinationCode::International => write!(f, "international"),
+            DestinationCode::Invalid(n) => write!(f, "invalid (0x{:02X})", n),
+        }
+    }
+}
+
+/// Publishers by old licensee code, from the Pan Docs.
+const OLD_LICENSEES: &[(u8, &str)] = &[
+    (0x00, "none"), (0x01, "Nintendo"), (0x08, "Capcom"), (0x09, "Hot-B"), (0x0A, "Jaleco"),
+    (0x0B, "Coconuts Japan"), (0x0C, "Elite Systems"), (0x13, "Electronic Arts"),
+    (0x18, "Hudson Soft"), (0x19, "ITC Entertainment"), (0x1A, "Yanoman"), (0x1D, "Japan Clary"),
+    (0x1F, "Virgin Interactive"), (0x24, "PCM Complete"), (0x25, "San-X"),
+    (0x28, "Kotobuki Systems"), (0x29, "Seta"), (0x30, "Infogrames"), (0x31, "Nintendo"),
+    (0x32, "Bandai"), (0x34, "Konami"), (0x35, "HectorSoft"), (0x38, "Capcom"),
+    (0x39, "Banpresto"), (0x3C, "Entertainment International"), (0x3E, "Gremlin"),
+    (0x41, "Ubisoft"), (0x42, "Atlus"), (0x44, "Malibu"), (0x46, "Angel"),
+    (0x47, "Spectrum HoloByte"), (0x49, "Irem"), (0x4A, "Virgin Interactive"), (0x4D, "Malibu"),
+    (0x4F, "U.S. Gold"), (0x50, "Absolute"), (0x51, "Acclaim"), (0x52, "Activision"),
+    (0x53, "American Sammy"), (0x54, "GameTek"), (0x55, "Park Place"), (0x56, "LJN"),
+    (0x57, "Matchbox"), (0x59, "Milton Bradley"), (0x5A, "Mindscape"), (0x5B, "Romstar"),
+    (0x5C, "Naxat Soft"), (0x5D, "Tradewest"), (0x60, "Titus"), (0x61, "Virgin Interactive"),
+    (0x67, "Ocean"), (0x69, "Electronic Arts"), (0x6E, "Elite Systems"), (0x6F, "Electro Brain"),
+    (0x70, "Infogrames"), (0x71, "Interplay"), (0x72, "Broderbund"), (0x73, "Sculptured Software"),
+    (0x75, "The Sales Curve"), (0x78, "THQ"), (0x79, "Accolade"), (0x7A, "Triffix Entertainment"),
+    (0x7C, "MicroProse"), (0x7F, "Kemco"), (0x80, "Misawa Entertainment"), (0x83, "LOZC"),
+    (0x86, "Tokuma Shoten"), (0x8B, "Bullet-Proof Software"), (0x8C, "Vic Tokai"), (0x8E, "Ape"),
+    (0x8F, "I'Max"), (0x91, "Chunsoft"), (0x92, "Video System"), (0x93, "Tsuburaya Productions"),
+    (0x95, "Varie"), (0x96, "Yonezawa/S'Pal"), (0x97, "Kaneko"), (0x99, "Arc"),
+    (0x9A, "Nihon Bussan"), (0x9B, "Tecmo"), (0x9C, "Imagineer"), (0x9D, "Banpresto"),
+    (0x9F, "Nova"), (0xA1, "Hori Electric"), (0xA2, "Bandai"), (0xA4, "Konami"), (0xA6, "Kawada"),
+    (0xA7, "Takara"), (0xA9, "Technos Japan"), (0xAA, "Broderbund"), (0xAC, "Toei Animation"),
+    (0xAD, "Toho"), (0xAF, "Namco"), (0xB0, "Acclaim"), (0xB1, "ASCII or Nexsoft"),
+    (0xB2, "Bandai"), (0xB4, "Square Enix"), (0xB6, "HAL Laboratory"), (0xB7, "SNK"),
+    (0xB9, "Pony Canyon"), (0xBA, "Culture Brain"), (0xBB, "Sunsoft"), (0xBD, "Sony Imagesoft"),
+    (0xBF, "Sammy"), (0xC0, "Taito"), (0xC2, "Kemco"), (0xC3, "Square"), (0xC4, "Tokuma Shoten"),
+    (0xC5, "Data East"), (0xC6, "Tonkinhouse"), (0xC8, "Koei"), (0xC9, "UFL"), (0xCA, "Ultra"),
+    (0xCB, "Vap"), (0xCC, "Use Corporation"), (0xCD, "Meldac"), (0xCE, "Pony Canyon"),
+    (0xCF, "Angel"), (0xD0, "Taito"), (0xD1, "Sofel"), (0xD2, "Quest"),
+    (0xD3, "Sigma Enterprises"), (0xD4, "ASK Kodansha"), (0xD6, "Naxat Soft"),
+    (0xD7, "Copya System"), (0xD9, "Banpresto"), (0xDA, "Tomy"), (0xDB, "LJN"), (0xDD, "NCS"),
+    (0xDE, "Human"), (0xDF, "Altron"), (0xE0, "Jaleco"), (0xE1, "Towa Chiki"), (0xE2, "Yutaka"),
+    (0xE3, "Varie"), (0xE5, "Epoch"), (0xE7, "Athena"), (0xE8, "Asmik Ace Entertainment"),
+    (0xE9, "Natsume"), (0xEA, "King Records"), (0xEB, "Atlus"), (0xEC, "Epic/Sony Records"),
+    (0xEE, "IGS"), (0xF0, "A Wave"), (0xF3, "Extreme Entertainment"), (0xFF, "LJN"),
+];
+
+/// Publishers by new licensee code, from the Pan Docs.
+const NEW_LICENSEES: &[(&str, &str)] = &[
+    ("00", "none"), ("01", "Nintendo"), ("08", "Capcom"), ("13", "Electronic Arts"),
+    ("18", "Hudson Soft"), ("19", "B-AI"), ("20", "KSS"), ("22", "POW"), ("24", "PCM Complete"),
+    ("25", "San-X"), ("28", "Kemco Japan"), ("29", "Seta"), ("30", "Viacom"), ("31", "Nintendo"),
+    ("32", "Bandai"), ("33", "Ocean/Acclaim"), ("34", "Konami"), ("35", "HectorSoft"),
+    ("37", "Taito"), ("38", "Hudson Soft"), ("39", "Banpresto"), ("41", "Ubisoft"),
+    ("42", "Atlus"), ("44", "Malibu"), ("46", "Angel"), ("47", "Bullet-Proof Software"),
+    ("49", "Irem"), ("50", "Absolute"), ("51", "Acclaim"), ("52", "Activision"),
+    ("53", "American Sammy"), ("54", "Konami"), ("55", "Hi Tech Entertainment"), ("56", "LJN"),
+    ("57", "Matchbox"), ("58", "Mattel"), ("59", "Milton Bradley"), ("60", "Titus"),
+    ("61", "Virgin Interactive"), ("64", "LucasArts"), ("67", "Ocean"), ("69", "Electronic Arts"),
+    ("70", "Infogrames"), ("71", "Interplay"), ("72", "Broderbund"), ("73", "Sculptured Software"),
+    ("75", "The Sales Curve"), ("78", "THQ"), ("79", "Accolade"), ("80", "Misawa Entertainment"),
+    ("83", "LOZC"), ("86", "Tokuma Shoten"), ("87", "Tsukuda Original"), ("91", "Chunsoft"),
+    ("92", "Video System"), ("93", "Ocean/Acclaim"), ("95", "Varie"), ("96", "Yonezawa/S'Pal"),
+    ("97", "Kaneko"), ("99", "Pack-In-Video"), ("A4", "Konami"),
+];
+
 #[derive(Clone, Debug, Fail, PartialEq)]
+#[non_exhaustive]
 pub enum HeaderParseError {
     #[fail(display = "manufacturer code was not valid UTF-8: {:?}", _0)]
     InvalidManufacturerCodeUtf8(Vec<u8>),
@@ -169,9 +323,11 @@ pub enum HeaderParseError {
 
 impl CartHeader {
     /// Parse the cartridge header from the given ROM. The header is in the range 0x100..0x150, so
-    /// the input slice must be at least large enough to contain that.
+    /// the input slice must be at least large enough to contain that. MMM01 multicarts boot into a
+    /// menu in their last 32 KiB, and the header there describes the whole cart.
     pub fn from_rom(rom: &[u8]) -> Result<Self, HeaderParseError> {
-        let bytes = rom.get(0x100..0x150).ok_or(HeaderParseError::RomTooShort(rom.len()))?;
+        let start = mmm01_menu(rom).unwrap_or(0);
+        let bytes = rom.get(start + 0x100..start + 0x150).ok_or(HeaderParseError::RomTooShort(rom.len()))?;
 
         // The last byte of the title is used to determine if this is a Game Boy Color game. This
         // GBC flag uses byts which aren't valid ASCII, so we know this isn't actually part of the
@@ -205,10 +361,9 @@ impl CartHeader {
             _ => SgbFlag::Unsupported,
         };
 
-        let licensee_code = if sgb_flag == SgbFlag::Unsupported {
-            LicenseeCode::Old(bytes[0x4B])
-        } else {
-            LicenseeCode::New([bytes[0x44], bytes[0x45]])
+        let licensee_code = match bytes[0x4B] {
+            0x33 => LicenseeCode::New([bytes[0x44], bytes[0x45]]),
+            code => LicenseeCode::Old(code),
         };
 
         let cart_type = match bytes[0x47] {
@@ -226,6 +381,11 @@ impl CartHeader {
             0xFF => CartType::HuC1,
             n => CartType::Unknown(n),
         };
+        let cart_type = if cart_type == CartType::NoMbc && is_wisdom_tree(rom) {
+            CartType::WisdomTree
+        } else {
+            cart_type
+        };
 
         use self::CartHardware::*;
         let mut hardware = BitFlags::empty();
@@ -238,6 +398,8 @@ impl CartHeader {
             0x1D => hardware |= Ram | Rumble,
             0x1E => hardware |= Ram | Battery | Rumble,
             0x22 => hardware |= Ram | Battery | Accelerometer,
+            0xFC => hardware |= Ram | Battery,
+            0xFE => hardware |= Ram | Timer | Battery,
             _ => {}
         };
 
@@ -264,6 +426,15 @@ impl CartHeader {
 
         let rom_version = bytes[0x4C];
 
+        let checks = HeaderChecks {
+            logo: bytes[0x04..0x34] == NINTENDO_LOGO[..],
+            header_checksum: Checksum { expected: bytes[0x4D], actual: header_checksum(&rom[start..]) },
+            global_checksum: Checksum {
+                expected: u16::from(bytes[0x4E]) << 8 | u16::from(bytes[0x4F]),
+                actual: global_checksum_with_header_at(rom, start),
+            },
+        };
+
         Ok(CartHeader {
             title,
             cart_type,
@@ -276,6 +447,86 @@ impl CartHeader {
             licensee_code,
             destination_code,
             rom_version,
+            checks,
         })
     }
-}
\ No newline at end of file
+}
+
+impl HeaderChecks {
+    /// Whether every check passed.
+    pub fn all_passed(&self) -> bool {
+        self.logo && self.header_checksum.is_valid() && self.global_checksum.is_valid()
+    }
+
+    /// A description of each check that failed, like "header checksum is 0x12, expected 0x34".
+    pub fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if !self.logo {
+            failures.push(String::from("Nintendo logo doesn't match"));
+        }
+        if !self.header_checksum.is_valid() {
+            let Checksum { expected, actual } = self.header_checksum;
+            failures.push(format!("header checksum is {:#04X}, expected {:#04X}", actual, expected));
+        }
+        if !self.global_checksum.is_valid() {
+            let Checksum { expected, actual } = self.global_checksum;
+            failures.push(format!("global checksum is {:#06X}, expected {:#06X}", actual, expected));
+        }
+        failures
+    }
+}
+
+impl<T: PartialEq> Checksum<T> {
+    pub fn is_valid(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+/// Wisdom Tree carts have a plain ROM-only header, but are bigger than 32 KiB and carry the
+/// publisher's name somewhere in the ROM.
+fn is_wisdom_tree(rom: &[u8]) -> bool {
+    rom.len() > 0x8000
+        && rom.windows(11).any(|w| w == b"WISDOM TREE" || w == b"WISDOM\0TREE")
+}
+
+/// Where the menu in the last 32 KiB of an MMM01 multicart starts, if `rom` is one. The first
+/// game's header is at the start of the ROM as usual, and names its own mapper. Plenty of other
+/// ROMs happen to have an MMM01 cartridge type byte there, so the menu's header has to be one the
+/// boot ROM would accept, too.
+fn mmm01_menu(rom: &[u8]) -> Option<usize> {
+    let start = rom.len().checked_sub(0x8000).filter(|&start| start > 0)?;
+    let menu = &rom[start..];
+    let is_mmm01 = match menu[0x147] {
+        0x0B...0x0D => true,
+        _ => false,
+    };
+    if is_mmm01 && menu[0x104..0x134] == NINTENDO_LOGO[..] && menu[0x14D] == header_checksum(menu) {
+        Some(start)
+    } else {
+        None
+    }
+}
+
+impl std::str::FromStr for CartType {
+    type Err = String;
+
+    /// Parse a mapper name like `mbc1` or `wisdom-tree`, for overriding the header.
+    fn from_str(s: &str) -> Result<CartType, String> {
+        Ok(match &*s.to_lowercase() {
+            "none" => CartType::NoMbc,
+            "mbc1" => CartType::Mbc1,
+            "mbc2" => CartType::Mbc2,
+            "mbc3" => CartType::Mbc3,
+            "mbc5" => CartType::Mbc5,
+            "mbc6" => CartType::Mbc6,
+            "mbc7" => CartType::Mbc7,
+            "mmm01" => CartType::Mmm01,
+            "huc1" => CartType::HuC1,
+            "huc3" => CartType::HuC3,
+            "camera" => CartType::PocketCamera,
+            "tama5" => CartType::BandaiTama5,
+            "wisdom-tree" => CartType::WisdomTree,
+            _ => return Err(format!("unknown mapper: {}", s)),
+        })
+    }
+}
@@ -0,0 +1,140 @@
+//! Game Genie and GameShark cheat codes.
+//!
+//! A Game Genie sits between the cartridge and the Game Boy and replaces bytes read from ROM, so
+//! its codes name a ROM address, the new byte, and optionally the byte that must be there for the
+//! replacement to happen (since the same address holds different code in different banks). A
+//! GameShark instead writes a byte to RAM once per frame, at the start of vertical blank.
+
+use failure_derive::Fail;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum CheatError {
+    #[fail(display = "{:?} is not a Game Genie (ABC-DEF or ABC-DEF-GHI) or GameShark (01VVAAAA) code", _0)]
+    UnknownFormat(String),
+
+    #[fail(display = "Game Genie code {:?} patches 0x{:04X}, which is outside ROM", _0, _1)]
+    NotRom(String, u16),
+
+    #[fail(display = "GameShark code {:?} writes to 0x{:04X}, which is outside RAM", _0, _1)]
+    NotRam(String, u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatCode {
+    /// Read `value` instead of the ROM byte at `addr`, if the ROM byte is `compare` (or always
+    /// without one).
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+
+    /// Write `value` to `addr` at every vertical blank. Writes go wherever the current banking
+    /// sends them, which is what the usual `01` bank byte means, so other bank bytes are kept but
+    /// not acted on.
+    GameShark { bank: u8, addr: u16, value: u8 },
+}
+
+impl CheatCode {
+    /// Parse every code in `codes`, which may join several with `+`, like cheat databases do for
+    /// cheats that need more than one code.
+    pub fn parse_all(codes: &str) -> Result<Vec<CheatCode>, CheatError> {
+        codes.split('+').map(|code| code.trim().parse()).collect()
+    }
+}
+
+impl FromStr for CheatCode {
+    type Err = CheatError;
+
+    fn from_str(s: &str) -> Result<CheatCode, CheatError> {
+        let unknown = || CheatError::UnknownFormat(s.to_string());
+        let digits = s.chars().filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(unknown)?;
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+        let dashes: Vec<usize> = s.match_indices('-').map(|(i, _)| i).collect();
+
+        match (digits.len(), &dashes[..]) {
+            // The digits of ABC-DEF-GHI are the new byte (AB), the address with its top digit
+            // inverted and moved to the end (CDEF), and the compare byte rotated and scrambled
+            // (G and I; H only checks the code was typed correctly).
+            (6, [3]) | (9, [3, 7]) => {
+                let addr = (u16::from(digits[5]) << 12 | u16::from(digits[2]) << 8
+                    | u16::from(digits[3]) << 4 | u16::from(digits[4])) ^ 0xF000;
+                if addr > 0x7FFF {
+                    return Err(CheatError::NotRom(s.to_string(), addr));
+                }
+                let compare = if digits.len() == 9 {
+                    Some((digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA)
+                } else {
+                    None
+                };
+                Ok(CheatCode::GameGenie { addr, value: byte(0), compare })
+            }
+
+            // TTVVLLHH: bank, value, and the address in little-endian order.
+            (8, []) => {
+                let addr = u16::from(byte(6)) << 8 | u16::from(byte(4));
+                if addr < 0xA000 {
+                    return Err(CheatError::NotRam(s.to_string(), addr));
+                }
+                Ok(CheatCode::GameShark { bank: byte(0), addr, value: byte(2) })
+            }
+
+            _ => Err(unknown()),
+        }
+    }
+}
+
+/// Apply the Game Genie codes in `cheats` to `val`, the byte the cartridge returned for a read of
+/// `addr`.
+pub fn patch_rom(cheats: &[CheatCode], addr: u16, val: u8) -> u8 {
+    for cheat in cheats {
+        if let CheatCode::GameGenie { addr: patched, value, compare } = *cheat {
+            if patched == addr && compare.map_or(true, |compare| compare == val) {
+                return value;
+            }
+        }
+    }
+    val
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    #[test]
+    fn parses_game_genie_codes() {
+        assert_eq!("01A-B2C".parse(), Ok(CheatCode::GameGenie { addr: 0x3AB2, value: 0x01, compare: None }));
+        assert_eq!("01a-b2c-e6f".parse(), Ok(CheatCode::GameGenie { addr: 0x3AB2, value: 0x01, compare: Some(0x41) }));
+        assert_eq!("01A-B20".parse::<CheatCode>(), Err(CheatError::NotRom(String::from("01A-B20"), 0xFAB2)));
+    }
+
+    #[test]
+    fn parses_gameshark_codes() {
+        assert_eq!("01FF12C1".parse(), Ok(CheatCode::GameShark { bank: 0x01, addr: 0xC112, value: 0xFF }));
+        assert_eq!("01FF1234".parse::<CheatCode>(), Err(CheatError::NotRam(String::from("01FF1234"), 0x3412)));
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        for code in &["", "xyz", "01FF12C", "01FF12C1A", "01A-B2C-E6", "01AB-2C", "01A-B2C-"] {
+            assert_eq!(code.parse::<CheatCode>(), Err(CheatError::UnknownFormat(code.to_string())), "{}", code);
+        }
+    }
+
+    #[test]
+    fn parses_joined_codes() {
+        assert_eq!(CheatCode::parse_all("01FF12C1 + 01A-B2C").map(|codes| codes.len()), Ok(2));
+        assert!(CheatCode::parse_all("01FF12C1+").is_err());
+    }
+
+    quickcheck! {
+        fn patches_game_genie_reads(value: u8, matches: bool) -> bool {
+            // The address digits decode to 0x004A and the compare digits to 0x01.
+            let cheats = CheatCode::parse_all(&format!("{:02X}0-4AF-E6E", value)).unwrap();
+            let old = if matches { 0x01 } else { 0x02 };
+            let expected = if matches { value } else { old };
+            patch_rom(&cheats, 0x4A, old) == expected && patch_rom(&cheats, 0x4B, 0x00) == 0x00
+        }
+    }
+}
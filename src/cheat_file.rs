@@ -0,0 +1,229 @@
+//! Each game's cheats, kept in a TOML file named after the ROM in a `cheats` directory next to the
+//! config file, and importing them from other emulators' cheat lists.
+//!
+//! ```toml
+//! [[cheat]]
+//! name = "Infinite health"
+//! code = "01FF12C1"
+//! enabled = true
+//! ```
+//!
+//! A cheat's code can join several Game Genie or GameShark codes with `+`. Two formats can be
+//! imported: RetroArch's `.cht` files, and the plain lists VBA-M's cheat dialog and most cheat
+//! sites use, with one code per line followed by its name.
+
+use crate::config::Config;
+use failure::ResultExt;
+use failure_derive::Fail;
+use rugby::cheat::{CheatCode, CheatError};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum CheatFileError {
+    #[fail(display = "line {}: {}", _0, _1)]
+    BadLine(usize, String),
+
+    #[fail(display = "cheat {:?}: {}", _0, _1)]
+    BadCode(String, CheatError),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheatFile {
+    #[serde(default, rename = "cheat")]
+    pub cheats: Vec<Cheat>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cheat {
+    pub name: String,
+    pub code: String,
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+impl CheatFile {
+    /// Where the cheats for the ROM at `rom_path` are kept.
+    pub fn path_for(rom_path: &Path) -> Option<PathBuf> {
+        let mut name = rom_path.file_stem()?.to_os_string();
+        name.push(".toml");
+        Some(Config::path()?.parent()?.join("cheats").join(name))
+    }
+
+    /// Load the cheats at `path`, or none if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<CheatFile, failure::Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(CheatFile::default()),
+            Err(e) => return Err(e).with_context(|_| format!("Failed to read cheat file: {}", path.display()))?,
+        };
+        let file: CheatFile = toml::from_str(&contents)
+            .with_context(|_| format!("Failed to parse cheat file: {}", path.display()))?;
+        for cheat in &file.cheats {
+            cheat.codes()?;
+        }
+        Ok(file)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), failure::Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create the cheats directory")?;
+        }
+        let contents = toml::to_string(self).context("Failed to serialize cheats")?;
+        std::fs::write(path, contents)
+            .with_context(|_| format!("Failed to write cheat file: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// The codes of every enabled cheat, for `Cpu::cheats`.
+    pub fn enabled_codes(&self) -> Result<Vec<CheatCode>, CheatFileError> {
+        let mut codes = Vec::new();
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            codes.extend(cheat.codes()?);
+        }
+        Ok(codes)
+    }
+
+    /// Add the cheats in `imported` that aren't already here, by code. Returns how many were new.
+    pub fn merge(&mut self, imported: Vec<Cheat>) -> usize {
+        let before = self.cheats.len();
+        for cheat in imported {
+            if !self.cheats.iter().any(|existing| existing.code.eq_ignore_ascii_case(&cheat.code)) {
+                self.cheats.push(cheat);
+            }
+        }
+        self.cheats.len() - before
+    }
+}
+
+impl Cheat {
+    pub fn codes(&self) -> Result<Vec<CheatCode>, CheatFileError> {
+        CheatCode::parse_all(&self.code).map_err(|e| CheatFileError::BadCode(self.name.clone(), e))
+    }
+}
+
+/// Parse a cheat list in either format, telling them apart by RetroArch's `cheats = N` line.
+/// Every code is checked, so nothing unusable gets imported.
+pub fn import(contents: &str) -> Result<Vec<Cheat>, CheatFileError> {
+    let is_retroarch = contents.lines().any(|line| {
+        let mut parts = line.splitn(2, '=');
+        parts.next().map(str::trim) == Some("cheats") && parts.next().is_some()
+    });
+    let cheats = if is_retroarch { import_retroarch(contents)? } else { import_plain(contents)? };
+    for cheat in &cheats {
+        cheat.codes()?;
+    }
+    Ok(cheats)
+}
+
+/// RetroArch's `.cht` format: `cheatN_desc`, `cheatN_code`, and `cheatN_enable` keys for each
+/// cheat N, with quoted or bare values. Other keys, like `cheats` itself, are skipped.
+fn import_retroarch(contents: &str) -> Result<Vec<Cheat>, CheatFileError> {
+    let mut cheats: BTreeMap<usize, (Option<String>, Option<String>, bool)> = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim().trim_matches('"')),
+            _ => return Err(CheatFileError::BadLine(i + 1, String::from("expected `key = value`"))),
+        };
+        let (index, field) = match parse_retroarch_key(key) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let entry = cheats.entry(index).or_default();
+        match field {
+            "desc" => entry.0 = Some(value.to_string()),
+            "code" => entry.1 = Some(value.to_string()),
+            "enable" => entry.2 = value == "true",
+            _ => {}
+        }
+    }
+    Ok(cheats.into_iter().filter_map(|(index, (name, code, enabled))| {
+        let code = code.filter(|code| !code.is_empty())?;
+        Some(Cheat { name: name.unwrap_or_else(|| format!("Cheat {}", index)), code, enabled })
+    }).collect())
+}
+
+/// Split a key like `cheat12_code` into 12 and `code`.
+fn parse_retroarch_key(key: &str) -> Option<(usize, &str)> {
+    if !key.starts_with("cheat") {
+        return None;
+    }
+    let rest = &key["cheat".len()..];
+    let split = rest.find('_')?;
+    Some((rest[..split].parse().ok()?, &rest[split + 1..]))
+}
+
+/// One code per line, then the cheat's name. Blank lines and lines starting with `#` or `;` are
+/// skipped. The cheats start out disabled, since lists like these don't say which to use.
+fn import_plain(contents: &str) -> Result<Vec<Cheat>, CheatFileError> {
+    let mut cheats = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let code = parts.next().unwrap_or_default();
+        if CheatCode::parse_all(code).is_err() {
+            return Err(CheatFileError::BadLine(i + 1, format!("{:?} doesn't start with a cheat code", line)));
+        }
+        let name = parts.next().map(str::trim).filter(|name| !name.is_empty()).unwrap_or(code);
+        cheats.push(Cheat { name: name.to_string(), code: code.to_string(), enabled: false });
+    }
+    Ok(cheats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn imports_retroarch_lists() {
+        let contents = "\
+cheats = 3
+
+cheat0_desc = \"Infinite health\"
+cheat0_code = \"01FF12C1\"
+cheat0_enable = true
+
+cheat1_code = \"01A-B2C+01FF13C1\"
+cheat1_enable = false
+
+cheat2_desc = \"Empty\"
+cheat2_code = \"\"
+";
+        assert_eq!(import(contents), Ok(vec![
+            Cheat { name: String::from("Infinite health"), code: String::from("01FF12C1"), enabled: true },
+            Cheat { name: String::from("Cheat 1"), code: String::from("01A-B2C+01FF13C1"), enabled: false },
+        ]));
+    }
+
+    #[test]
+    fn imports_plain_lists_disabled() {
+        let contents = "# Some game\n01FF12C1 Infinite health\n\n; More\n01A-B2C\n";
+        assert_eq!(import(contents), Ok(vec![
+            Cheat { name: String::from("Infinite health"), code: String::from("01FF12C1"), enabled: false },
+            Cheat { name: String::from("01A-B2C"), code: String::from("01A-B2C"), enabled: false },
+        ]));
+    }
+
+    #[test]
+    fn rejects_bad_lists() {
+        match import("01FF12C1 Fine\nnot a code\n") {
+            Err(CheatFileError::BadLine(2, _)) => {}
+            result => panic!("{:?}", result),
+        }
+        match import("cheats = 1\ncheat0_code = \"01FF1234\"\n") {
+            Err(CheatFileError::BadCode(_, CheatError::NotRam(..))) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+}
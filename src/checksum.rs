@@ -0,0 +1,53 @@
+//! Checksums for identifying ROMs and summarizing memory contents.
+
+/// Compute the standard CRC-32 (as used by zip, PNG, and the WLA DX `[rom checksum]` section) of
+/// the given bytes.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// The header checksum at 0x014D, over the header bytes 0x0134-0x014C. The boot ROM refuses to
+/// start a cartridge where this doesn't match.
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..0x14D].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+/// The global checksum at 0x014E-0x014F (big endian), the sum of every byte in the ROM except the
+/// checksum itself. Nothing on real hardware checks it.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    global_checksum_with_header_at(rom, 0)
+}
+
+/// The global checksum of a ROM whose header is in the bank at `start` rather than the first one,
+/// like an MMM01 multicart's menu.
+pub fn global_checksum_with_header_at(rom: &[u8], start: usize) -> u16 {
+    rom.iter().enumerate()
+        .filter(|&(i, _)| i != start + 0x14E && i != start + 0x14F)
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
+/// A CRC-32 computed incrementally, for data that isn't in one contiguous slice.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
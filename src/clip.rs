@@ -0,0 +1,155 @@
+//! GIF clips: the frontend keeps the last few seconds of frames in a rolling buffer, and a hotkey
+//! writes them out as an animated GIF, for sharing bug reports and gameplay moments.
+//!
+//! The Game Boy only has 4 shades, so the GIF palette is exact. Runs of identical frames become a
+//! single longer frame, and each frame only stores the rectangle that changed since the last one.
+
+use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::photo::Palette;
+use serde_derive::Deserialize;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+type Screen = [[u8; SCREEN_WIDTH]; SCREEN_HEIGHT];
+
+const FRAMES_PER_SECOND: usize = 60;
+
+/// GIF delays are in hundredths of a second, and most viewers slow down frames shorter than this.
+const MIN_DELAY: usize = 2;
+
+/// Settings for clips, from the `[clip]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClipOptions {
+    /// How many seconds of frames to keep. 0 turns off recording.
+    pub seconds: u32,
+
+    /// The size of each Game Boy pixel in the GIF.
+    pub scale: u32,
+
+    pub palette: Palette,
+}
+
+impl Default for ClipOptions {
+    fn default() -> Self {
+        ClipOptions { seconds: 10, scale: 2, palette: Palette::Dmg }
+    }
+}
+
+pub struct ClipRecorder {
+    frames: VecDeque<Box<Screen>>,
+    capacity: usize,
+}
+
+impl ClipRecorder {
+    pub fn new(options: &ClipOptions) -> Self {
+        let capacity = options.seconds as usize * FRAMES_PER_SECOND;
+        ClipRecorder { frames: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Add a frame, forgetting the oldest one if the buffer is full.
+    pub fn push(&mut self, screen: &Screen) {
+        if self.capacity == 0 {
+            return;
+        }
+        let frame = if self.frames.len() == self.capacity {
+            let mut oldest = self.frames.pop_front().unwrap();
+            *oldest = *screen;
+            oldest
+        } else {
+            Box::new(*screen)
+        };
+        self.frames.push_back(frame);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Write every buffered frame to a looping GIF at `path`.
+    pub fn write_gif(&self, path: &Path, options: &ClipOptions) -> Result<(), failure::Error> {
+        let scale = options.scale.max(1) as usize;
+        let palette: Vec<u8> = options.palette.colors().iter().flatten().cloned().collect();
+        let file = BufWriter::new(File::create(path)?);
+        let width = (SCREEN_WIDTH * scale) as u16;
+        let height = (SCREEN_HEIGHT * scale) as u16;
+        let mut encoder = gif::Encoder::new(file, width, height, &palette)?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+
+        // Each distinct frame waits to be written until the next one starts, which determines its
+        // delay. A frame too short to show is replaced by the next one, keeping start times exact.
+        let mut pending: Option<(&Screen, usize)> = None;
+        let mut last_written: Option<&Screen> = None;
+        for (i, screen) in self.frames.iter().enumerate() {
+            let screen = &**screen;
+            if let Some((pending_screen, start)) = pending {
+                if pending_screen == screen {
+                    continue;
+                }
+                let delay = centiseconds(i) - centiseconds(start);
+                if delay < MIN_DELAY {
+                    pending = Some((screen, start));
+                    continue;
+                }
+                encoder.write_frame(&gif_frame(pending_screen, last_written, delay, scale))?;
+                last_written = Some(pending_screen);
+            }
+            pending = Some((screen, i));
+        }
+        if let Some((screen, start)) = pending {
+            let delay = (centiseconds(self.frames.len()) - centiseconds(start)).max(MIN_DELAY);
+            encoder.write_frame(&gif_frame(screen, last_written, delay, scale))?;
+        }
+        Ok(())
+    }
+}
+
+/// The time from the start of the clip to the start of `frame`, rounded to the nearest hundredth
+/// of a second.
+fn centiseconds(frame: usize) -> usize {
+    (frame * 100 + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND
+}
+
+/// A GIF frame covering only the pixels of `screen` which differ from `previous`.
+fn gif_frame(screen: &Screen, previous: Option<&Screen>, delay: usize, scale: usize) -> gif::Frame<'static> {
+    let (mut left, mut top, mut right, mut bottom) = (0, 0, SCREEN_WIDTH, SCREEN_HEIGHT);
+    if let Some(previous) = previous {
+        let changed = |x: usize, y: usize| screen[y][x] != previous[y][x];
+        let changed_rows: Vec<usize> = (0..SCREEN_HEIGHT).filter(|&y| screen[y] != previous[y]).collect();
+        match (changed_rows.first(), changed_rows.last()) {
+            (Some(&first), Some(&last)) => {
+                top = first;
+                bottom = last + 1;
+                left = (0..SCREEN_WIDTH).find(|&x| (top..bottom).any(|y| changed(x, y))).unwrap();
+                right = (0..SCREEN_WIDTH).rev().find(|&x| (top..bottom).any(|y| changed(x, y))).unwrap() + 1;
+            }
+            // Nothing changed, but the frame still has to be shown for its delay.
+            _ => {
+                right = 1;
+                bottom = 1;
+            }
+        }
+    }
+
+    let mut buffer = Vec::with_capacity((right - left) * (bottom - top) * scale * scale);
+    for row in &screen[top..bottom] {
+        for _ in 0..scale {
+            for &shade in &row[left..right] {
+                buffer.extend(std::iter::repeat(shade).take(scale));
+            }
+        }
+    }
+
+    let mut frame = gif::Frame::default();
+    frame.delay = delay as u16;
+    frame.dispose = gif::DisposalMethod::Keep;
+    frame.left = (left * scale) as u16;
+    frame.top = (top * scale) as u16;
+    frame.width = ((right - left) * scale) as u16;
+    frame.height = ((bottom - top) * scale) as u16;
+    frame.buffer = Cow::Owned(buffer);
+    frame
+}
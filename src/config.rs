@@ -0,0 +1,474 @@
+//! User configuration, read from `config.toml` in the platform's config directory (e.g.
+//! `~/.config/rugby/config.toml` on Linux) or from the file named by `RUGBY_CONFIG`. Every setting
+//! is optional. `ConfigWatcher` notices when the file changes, so the frontend can apply most
+//! settings without restarting.
+
+use crate::audio::{DEFAULT_SAMPLE_RATE, SAMPLE_BUFFER_SIZE};
+use crate::clip::ClipOptions;
+use crate::photo::{Palette, PhotoOptions};
+use crate::votes::VoteOptions;
+use failure::ResultExt;
+use log::info;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use sdl2::audio::AudioSpecDesired;
+use sdl2::controller::Button;
+use sdl2::keyboard::Keycode;
+use serde::de::{Deserialize, Deserializer, Error};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+/// How long `ConfigWatcher` waits for the file to stop changing before reloading it, so an editor
+/// can finish saving.
+const WATCH_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub window: WindowConfig,
+    pub video: VideoConfig,
+    pub keys: KeysConfig,
+    pub controller: ControllerConfig,
+    pub photo: PhotoOptions,
+    pub clip: ClipOptions,
+    pub session: SessionConfig,
+    pub save: SaveConfig,
+    pub votes: VoteOptions,
+    pub ui: UiConfig,
+    pub audio: AudioConfig,
+    pub power: PowerConfig,
+    pub info: InfoConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Template for the window title. See `frontend::Status` for the available placeholders.
+    pub title: String,
+
+    /// Pause while the window doesn't have focus.
+    pub pause_when_unfocused: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// Only draw 10 sprites per scan line like real hardware. Turning this off removes flicker.
+    pub sprite_limit: bool,
+
+    /// How to show that the game is paused or has had the LCD off for a while, so it doesn't look
+    /// like it hung.
+    pub idle_screen: IdleScreen,
+
+    /// The colors to draw the window's screen in.
+    pub palette: Palette,
+
+    /// When the debugger stops mid-frame, mark the line the PPU is on and dim the lines below it,
+    /// which still hold the previous frame.
+    pub scanline_marker: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleScreen {
+    /// Darken the last frame.
+    Dim,
+    /// Label the last frame with why it isn't changing.
+    Overlay,
+    /// Leave the last frame as it is.
+    None,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    pub player1: KeyBindings,
+
+    /// Only used with `--dual`.
+    pub player2: KeyBindings,
+
+    pub tilt: TiltKeys,
+}
+
+/// Keyboard controls for one emulator instance, by SDL key name, like `"W"` or `"Return"`. Unlike
+/// the other sections, every key has to be given, since the players' defaults differ.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeyBindings {
+    #[serde(deserialize_with = "deserialize_key")]
+    pub up: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub down: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub left: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub right: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub a: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub b: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub start: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub select: Keycode,
+}
+
+/// Keys that tilt the first player's MBC7 cart, like Kirby Tilt 'n' Tumble, by SDL key name.
+/// Controllers tilt it with the left stick.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TiltKeys {
+    #[serde(deserialize_with = "deserialize_key")]
+    pub up: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub down: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub left: Keycode,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub right: Keycode,
+}
+
+/// Which game controller buttons press the Game Boy's, by SDL button name, like `"a"` or
+/// `"back"`. The d-pad is always the d-pad, and unmapped shoulder buttons change the speed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControllerConfig {
+    #[serde(deserialize_with = "deserialize_button")]
+    pub a: Button,
+    #[serde(deserialize_with = "deserialize_button")]
+    pub b: Button,
+    #[serde(deserialize_with = "deserialize_button")]
+    pub start: Button,
+    #[serde(deserialize_with = "deserialize_button")]
+    pub select: Button,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Save the emulator state on quit, to be resumed with `run --continue`. See `session`.
+    pub sleep: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// The language for messages, like `de`, instead of the one from the environment. See `i18n`.
+    pub language: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// The sample rate to ask the sound card for, in Hz. The emulator makes samples at whatever
+    /// rate the card ends up with.
+    #[serde(deserialize_with = "deserialize_sample_rate")]
+    pub sample_rate: u32,
+
+    /// The sound card's buffer size, in samples. Bigger buffers add latency but are less likely
+    /// to crackle on slow machines. See `rate_control`.
+    pub buffer_samples: u16,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerConfig {
+    /// Sleep until each frame is due instead of keeping the CPU awake watching the audio queue,
+    /// and show the CPU usage. See `frame_limiter`.
+    pub low_power: bool,
+
+    /// In low-power mode, draw every other frame, while still emulating all 60.
+    pub half_rate: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfoConfig {
+    /// A DAT file to look ROMs up in, for `info --dat`. See `rom_db`.
+    pub dat: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SaveConfig {
+    /// How often to write changed cartridge RAM to the save file, in seconds. 0 only writes it
+    /// on pause and exit. See `save_file`.
+    pub flush_seconds: u64,
+
+    /// Where to keep the save files of battery-backed games run without `-s`, named after the
+    /// ROM. None keeps them next to the ROM.
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            title: String::from("{game} — Rugby ({fps} fps)"),
+            pause_when_unfocused: false,
+        }
+    }
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig { sprite_limit: true, idle_screen: IdleScreen::Dim, palette: Palette::Dmg, scanline_marker: false }
+    }
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        KeysConfig {
+            player1: KeyBindings {
+                up: Keycode::W,
+                down: Keycode::S,
+                left: Keycode::A,
+                right: Keycode::D,
+                a: Keycode::K,
+                b: Keycode::J,
+                start: Keycode::Return,
+                select: Keycode::Tab,
+            },
+            player2: KeyBindings {
+                up: Keycode::Up,
+                down: Keycode::Down,
+                left: Keycode::Left,
+                right: Keycode::Right,
+                a: Keycode::Period,
+                b: Keycode::Comma,
+                start: Keycode::Slash,
+                select: Keycode::Semicolon,
+            },
+            tilt: TiltKeys::default(),
+        }
+    }
+}
+
+impl Default for TiltKeys {
+    fn default() -> Self {
+        // The number pad's arrows, since the arrow keys are the second player's d-pad.
+        TiltKeys { up: Keycode::Kp8, down: Keycode::Kp2, left: Keycode::Kp4, right: Keycode::Kp6 }
+    }
+}
+
+impl KeysConfig {
+    /// Each instance's controls, in order.
+    pub fn players(&self) -> [&KeyBindings; 2] {
+        [&self.player1, &self.player2]
+    }
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        ControllerConfig { a: Button::A, b: Button::X, start: Button::Start, select: Button::Back }
+    }
+}
+
+/// The lowest and highest sample rates to ask for, in Hz. Sound cards don't go outside these, and
+/// audio dumps and the sample period are only worked out right within them.
+const MIN_SAMPLE_RATE: u32 = 8000;
+const MAX_SAMPLE_RATE: u32 = 192_000;
+
+/// Parse a sample rate for `--sample-rate`, checking it's one a sound card could play.
+pub fn parse_sample_rate(s: &str) -> Result<u32, String> {
+    let rate = s.parse().map_err(|_| format!("invalid sample rate: {}", s))?;
+    check_sample_rate(rate)
+}
+
+fn check_sample_rate(rate: u32) -> Result<u32, String> {
+    if rate < MIN_SAMPLE_RATE || rate > MAX_SAMPLE_RATE {
+        return Err(format!("sample rate {} Hz is outside {}-{} Hz", rate, MIN_SAMPLE_RATE, MAX_SAMPLE_RATE));
+    }
+    Ok(rate)
+}
+
+fn deserialize_sample_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    check_sample_rate(u32::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Keycode, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Keycode::from_name(&name).ok_or_else(|| {
+        D::Error::custom(format!("unknown key {:?}, expected an SDL key name like \"W\" or \"Return\"", name))
+    })
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig { sample_rate: DEFAULT_SAMPLE_RATE, buffer_samples: SAMPLE_BUFFER_SIZE as u16 }
+    }
+}
+
+impl AudioConfig {
+    /// What to ask SDL for when opening the sound card.
+    pub fn desired_spec(&self) -> AudioSpecDesired {
+        AudioSpecDesired {
+            freq: Some(self.sample_rate as i32),
+            channels: Some(2), // Stereo
+            samples: Some(self.buffer_samples),
+        }
+    }
+}
+
+impl Default for SaveConfig {
+    fn default() -> Self {
+        SaveConfig { flush_seconds: 10, directory: None }
+    }
+}
+
+impl SaveConfig {
+    pub fn flush_interval(&self) -> Option<Duration> {
+        if self.flush_seconds == 0 { None } else { Some(Duration::from_secs(self.flush_seconds)) }
+    }
+
+    /// The save file for the ROM at `rom_path`, in the save directory if there is one and next to
+    /// the ROM otherwise.
+    pub fn path_for(&self, rom_path: &Path) -> Option<PathBuf> {
+        match &self.directory {
+            Some(directory) => {
+                let mut name = rom_path.file_stem()?.to_os_string();
+                name.push(".sav");
+                Some(directory.join(name))
+            }
+            None => Some(rom_path.with_extension("sav")),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file, falling back to the defaults if there isn't one.
+    pub fn load() -> Result<Config, failure::Error> {
+        match Config::path() {
+            Some(path) => Config::load_from(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// Load the config file at `path`, or the defaults if there's no file there.
+    fn load_from(path: &Path) -> Result<Config, failure::Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|_| format!("Failed to read config file: {}", path.display()))?
+            }
+        };
+        let config = toml::from_str(&contents)
+            .with_context(|_| format!("Failed to parse config file: {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Where the config file is, or would be.
+    pub fn path() -> Option<PathBuf> {
+        match std::env::var_os("RUGBY_CONFIG") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => dirs::config_dir().map(|dir| dir.join("rugby").join("config.toml")),
+        }
+    }
+}
+
+fn deserialize_button<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Button, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Button::from_string(&name).ok_or_else(|| {
+        D::Error::custom(format!("unknown button {:?}, expected an SDL button name like \"a\" or \"back\"", name))
+    })
+}
+
+/// Notices changes to the config file through the OS's file change notifications. It watches the
+/// file's directory rather than the file, since many editors save by replacing the file, and so
+/// that creating the file is noticed too. The directory has to exist when the watcher starts.
+pub struct ConfigWatcher {
+    path: Option<PathBuf>,
+    events: Receiver<DebouncedEvent>,
+
+    /// Sends to `events` until it's dropped.
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl ConfigWatcher {
+    pub fn new() -> Self {
+        Self::watching(Config::path())
+    }
+
+    fn watching(path: Option<PathBuf>) -> Self {
+        let (sender, events) = mpsc::channel();
+        let watcher = path.as_ref().and_then(|path| match watch_directory(path, sender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                info!("Not watching {} for changes: {}", path.display(), e);
+                None
+            }
+        });
+        ConfigWatcher { path, events, _watcher: watcher }
+    }
+
+    /// Reload the config if the file changed since it was last loaded. Deleting the file goes back
+    /// to the defaults.
+    pub fn poll(&mut self) -> Option<Result<Config, failure::Error>> {
+        let name = self.path.as_ref()?.file_name()?;
+        let is_config = |path: &Path| path.file_name() == Some(name);
+        let mut changed = false;
+        for event in self.events.try_iter() {
+            changed |= match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Remove(path) =>
+                    is_config(&path),
+                DebouncedEvent::Rename(from, to) => is_config(&from) || is_config(&to),
+                DebouncedEvent::Rescan => true,
+                _ => false,
+            };
+        }
+        if changed { Some(Config::load_from(self.path.as_ref()?)) } else { None }
+    }
+}
+
+fn watch_directory(path: &Path, sender: mpsc::Sender<DebouncedEvent>) -> notify::Result<RecommendedWatcher> {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let mut watcher = notify::watcher(sender, WATCH_DELAY)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watcher_notices_the_file_being_written() {
+        let dir = std::env::temp_dir().join(format!("rugby-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let mut watcher = ConfigWatcher::watching(Some(path.clone()));
+        std::fs::write(dir.join("other.toml"), "").unwrap();
+        std::fs::write(&path, "[power]\nlow_power = true\n").unwrap();
+
+        let mut reloaded = None;
+        for _ in 0..50 {
+            std::thread::sleep(WATCH_DELAY / 2);
+            reloaded = watcher.poll();
+            if reloaded.is_some() {
+                break;
+            }
+        }
+        assert!(watcher.poll().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(reloaded.unwrap().unwrap().power.low_power);
+    }
+
+    #[test]
+    fn sample_rates_are_checked() {
+        assert_eq!(parse_sample_rate("48000"), Ok(48000));
+        assert!(parse_sample_rate("0").is_err());
+        assert!(parse_sample_rate("7999").is_err());
+        assert!(parse_sample_rate("4294967295").is_err());
+        assert!(parse_sample_rate("fast").is_err());
+
+        let config: Result<Config, _> = toml::from_str("[audio]\nsample_rate = 96000\n");
+        assert_eq!(config.unwrap().audio.sample_rate, 96000);
+        let config: Result<Config, _> = toml::from_str("[audio]\nsample_rate = 0\n");
+        assert!(config.is_err());
+    }
+}
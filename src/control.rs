@@ -0,0 +1,434 @@
+//! The control socket for `rugby serve`, which lets other programs drive and observe a windowless
+//! emulator, e.g. a Twitch-plays bot.
+//!
+//! The socket is a Unix domain socket at the given path, or a TCP socket if the address looks like
+//! `HOST:PORT`. TCP has no authentication, so only loopback addresses like `127.0.0.1:PORT` are
+//! allowed; anything that can reach the port can drive the emulator. Any number of clients can
+//! connect. Each sends one command per line and gets one
+//! reply line back: `ok`, `ok <value>`, or `error <message>`. Commands:
+//!
+//! - `press KEY...` and `release KEY...` hold or let go of keys, named `a`, `b`, `select`,
+//!   `start`, `right`, `left`, `up`, and `down`.
+//! - `tap KEY [FRAMES]` holds a key for a number of frames (6 by default), then releases it.
+//! - `pressed` replies with the held keys as a bitmask, in the layout of `Joypad::pressed`.
+//! - `eval EXPR` replies with the value of a debugger expression, like `[0xC000]` or `pc`.
+//! - `frame` replies with the number of frames emulated so far.
+//! - `regions` replies with the memory regions, see `memory_map`, like `ROMX:4000-7FFF:64:r`
+//!   for the name, range, number of banks, and whether it's readable and writable.
+//! - `peek ADDR [LEN]` replies with `LEN` bytes (1 by default) in hex, and `poke ADDR BYTE...`
+//!   writes bytes given in hex. Addresses are parsed by `memory_map::parse_address`, so they can
+//!   be hex like `C000`, decimal like `#49152`, symbol names with `-S` or `-M`, sums like
+//!   `Main+4`, and have a bank like `03:4000`.
+//! - `pause` and `resume` stop and restart emulation, and `advance` runs one frame and pauses.
+//!   `state` replies with `running`, or `paused` and the reasons, like `paused (user)`.
+//! - `speed up|down|MULTIPLIER` changes the emulation speed, between 0.25 and 4 times normal.
+//! - `reset` switches the Game Boy off and on again, keeping cartridge RAM.
+//! - `screenshot PATH` exports the screen as a PNG, with the config's photo mode settings.
+//! - `indices [PATH]` exports the screen's palette indices to a file, see `palette_indices`.
+//!   Without a path, it replies with them as one digit per pixel, row by row.
+//! - `save-state PATH` and `load-state PATH` write and read savestates.
+//! - `vote KEY [USER]` votes for a key to press, see `votes`. `tally` replies with the votes so
+//!   far, like `start=5 a=3`, and `policy anarchy|democracy` changes how votes are counted.
+//! - `scan BARCODE` swipes a card with a 12 or 13 digit barcode through the Barcode Boy, which
+//!   the server has to be started with `--barcode-boy` to plug in.
+//! - `quit` stops the server.
+//!
+//! File paths must be relative, without `..`, and are taken from the directory the server was
+//! started in, so clients can't read or overwrite files elsewhere.
+
+use crate::barcode_boy::BarcodeBoy;
+use crate::config::Config;
+use crate::cpu::Cpu;
+use crate::emulator_session::{Command, EmulatorSession};
+use crate::expr::Expr;
+use crate::memory_map;
+use crate::palette_indices;
+use crate::photo;
+use crate::save_file::SaveFile;
+use crate::votes::VoteBox;
+use log::{info, warn};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+/// How long `tap` holds a key if no number of frames is given.
+const DEFAULT_TAP_FRAMES: u64 = 6;
+
+/// Key names, with their bits in the layout of `Joypad::pressed`.
+const KEYS: [(&str, u8); 8] = [
+    ("a", 1 << 0),
+    ("b", 1 << 1),
+    ("select", 1 << 2),
+    ("start", 1 << 3),
+    ("right", 1 << 4),
+    ("left", 1 << 5),
+    ("up", 1 << 6),
+    ("down", 1 << 7),
+];
+
+/// A command line from a client, along with where to send the reply.
+struct Request {
+    line: String,
+    reply: Sender<String>,
+}
+
+pub struct ControlServer {
+    requests: Receiver<Request>,
+
+    /// The Unix socket file, removed when the server shuts down.
+    socket_path: Option<PathBuf>,
+
+    quit: bool,
+
+    /// Keys held by `tap`, and the frame to release them on.
+    taps: Vec<(u8, u64)>,
+
+    pub votes: VoteBox,
+
+    /// Pausing, speed, resets, and savestates, shared with the SDL frontend's hotkeys.
+    pub session: EmulatorSession,
+}
+
+impl ControlServer {
+    /// Start listening at `addr`, which is either `HOST:PORT` or a Unix socket path.
+    pub fn bind(addr: &str, votes: VoteBox) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let socket_path = match addr.parse::<SocketAddr>() {
+            Ok(addr) => {
+                if !addr.ip().is_loopback() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the control socket has no authentication, so TCP only listens on loopback addresses like 127.0.0.1",
+                    ));
+                }
+                let listener = TcpListener::bind(addr)?;
+                thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        match stream.and_then(|s| Ok((BufReader::new(s.try_clone()?), s))) {
+                            Ok((reader, writer)) => spawn_connection(reader, writer, tx.clone()),
+                            Err(e) => warn!("Failed to accept control connection: {}", e),
+                        }
+                    }
+                });
+                None
+            }
+            Err(_) => {
+                let path = PathBuf::from(addr);
+                listen_unix(&path, tx)?;
+                Some(path)
+            }
+        };
+        info!("Listening for control commands on {}", addr);
+        Ok(ControlServer {
+            requests: rx,
+            socket_path,
+            quit: false,
+            taps: Vec::new(),
+            votes,
+            session: EmulatorSession::new(),
+        })
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Run every command received since the last call, count votes, and release finished taps.
+    /// `frame` is the number of frames emulated so far.
+    pub fn handle_requests(
+        &mut self, cpu: &mut Cpu, frame: u64, save_file: &mut Option<SaveFile>, config: &Config,
+    ) {
+        let mut pressed = cpu.joypad.pressed();
+        for &(keys, _) in self.taps.iter().filter(|&&(_, until)| until <= frame) {
+            pressed &= !keys;
+        }
+        self.taps.retain(|&(_, until)| until > frame);
+        cpu.joypad.set_pressed(pressed);
+
+        if let Some(keys) = self.votes.tick(frame) {
+            let hold_frames = self.votes.hold_frames();
+            self.tap(cpu, keys, frame, hold_frames);
+        }
+
+        loop {
+            let request = match self.requests.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return,
+            };
+            let reply = match self.execute(request.line.trim(), cpu, frame, save_file, config) {
+                Ok(None) => String::from("ok"),
+                Ok(Some(value)) => format!("ok {}", value),
+                Err(e) => format!("error {}", e),
+            };
+            // The client may have hung up already, which is fine.
+            let _ = request.reply.send(reply);
+        }
+    }
+
+    /// Hold `keys` for the given number of frames.
+    fn tap(&mut self, cpu: &mut Cpu, keys: u8, frame: u64, frames: u64) {
+        cpu.joypad.set_pressed(cpu.joypad.pressed() | keys);
+        self.taps.push((keys, frame.saturating_add(frames)));
+    }
+
+    fn execute(
+        &mut self, line: &str, cpu: &mut Cpu, frame: u64, save_file: &mut Option<SaveFile>, config: &Config,
+    ) -> Result<Option<String>, String> {
+        let (cmd, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        match cmd {
+            "press" => {
+                let keys = parse_keys(args.split_whitespace())?;
+                cpu.joypad.set_pressed(cpu.joypad.pressed() | keys);
+            }
+            "release" => {
+                let keys = parse_keys(args.split_whitespace())?;
+                cpu.joypad.set_pressed(cpu.joypad.pressed() & !keys);
+            }
+            "tap" => {
+                let mut words = args.split_whitespace();
+                let keys = parse_keys(words.next())?;
+                let frames = match words.next() {
+                    Some(n) => n.parse().map_err(|_| format!("invalid number of frames: {}", n))?,
+                    None => DEFAULT_TAP_FRAMES,
+                };
+                self.tap(cpu, keys, frame, frames);
+            }
+            "pressed" => return Ok(Some(format!("0x{:02X}", cpu.joypad.pressed()))),
+            "eval" => {
+                let expr = Expr::parse(args).and_then(|expr| expr.check_names(cpu).map(|()| expr))
+                    .map_err(|e| e.to_string())?;
+                return Ok(Some(expr.eval(cpu).to_string()));
+            }
+            "frame" => return Ok(Some(frame.to_string())),
+            "regions" => {
+                let regions: Vec<_> = memory_map::regions(cpu)
+                    .map(|region| format!(
+                        "{}:{:04X}-{:04X}:{}:{}{}",
+                        region.name,
+                        region.start,
+                        region.end,
+                        region.banks,
+                        if region.readable { "r" } else { "" },
+                        if region.writable { "w" } else { "" },
+                    ))
+                    .collect();
+                return Ok(Some(regions.join(" ")));
+            }
+            "peek" => {
+                let mut words = args.split_whitespace();
+                let (bank, addr) = parse_banked_address(cpu, words.next())?;
+                let len = match words.next() {
+                    Some(n) => n.parse().map_err(|_| format!("invalid length: {}", n))?,
+                    None => 1,
+                };
+                let mut bytes = Vec::new();
+                for offset in 0..len {
+                    let val = memory_map::read(cpu, bank, addr.wrapping_add(offset))
+                        .map_err(|e| e.to_string())?;
+                    bytes.push(format!("{:02X}", val));
+                }
+                return Ok(Some(bytes.join(" ")));
+            }
+            "poke" => {
+                let mut words = args.split_whitespace();
+                let (bank, addr) = parse_banked_address(cpu, words.next())?;
+                let bytes = words
+                    .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte: {}", byte)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (offset, &val) in bytes.iter().enumerate() {
+                    memory_map::write(cpu, bank, addr.wrapping_add(offset as u16), val)
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+            "pause" => self.run_command(cpu, Command::Pause)?,
+            "resume" => self.run_command(cpu, Command::Resume)?,
+            "advance" => self.run_command(cpu, Command::FrameAdvance)?,
+            "state" => return Ok(Some(cpu.pause.to_string())),
+            "speed" => {
+                let command = match required(args)? {
+                    "up" => Command::SpeedUp,
+                    "down" => Command::SlowDown,
+                    n => Command::SetSpeed(n.parse().map_err(|_| format!("invalid speed: {}", n))?),
+                };
+                self.run_command(cpu, command)?;
+                return Ok(Some(self.session.speed_multiplier().to_string()));
+            }
+            "reset" => self.run_command(cpu, Command::Reset)?,
+            "screenshot" => {
+                photo::export(&cpu.gpu, &config.photo, &confined_path(args)?).map_err(|e| e.to_string())?;
+            }
+            "indices" => {
+                if args.is_empty() {
+                    return Ok(Some(palette_indices::digits(&cpu.gpu)));
+                }
+                palette_indices::export(&cpu.gpu, &confined_path(args)?).map_err(|e| e.to_string())?;
+            }
+            "save-state" => {
+                self.run_command(cpu, Command::SaveState(confined_path(args)?))?;
+                // Keep the save file at least as current as the savestate.
+                if let Some(save_file) = save_file {
+                    save_file.flush(&cpu.cart).map_err(|e| e.to_string())?;
+                }
+            }
+            "load-state" => self.run_command(cpu, Command::LoadState(confined_path(args)?))?,
+            "vote" => {
+                let mut words = args.split_whitespace();
+                let keys = parse_keys(words.next())?;
+                if let Some(keys) = self.votes.vote(keys, words.next())? {
+                    let hold_frames = self.votes.hold_frames();
+                    self.tap(cpu, keys, frame, hold_frames);
+                }
+            }
+            "tally" => {
+                let tally: Vec<_> = self.votes.tally().into_iter()
+                    .map(|(keys, votes)| format!("{}={}", key_name(keys), votes))
+                    .collect();
+                return Ok(Some(tally.join(" ")));
+            }
+            "policy" => self.votes.set_policy(args.parse()?, frame),
+            "scan" => {
+                let barcode_boy = cpu.serial.device_mut::<BarcodeBoy>()
+                    .ok_or_else(|| String::from("no Barcode Boy plugged in, start with --barcode-boy"))?;
+                barcode_boy.scan(required(args)?).map_err(|e| e.to_string())?;
+            }
+            "quit" => self.quit = true,
+            _ => return Err(format!("unknown command: {:?}", cmd)),
+        }
+        Ok(None)
+    }
+
+    fn run_command(&mut self, cpu: &mut Cpu, command: Command) -> Result<(), String> {
+        self.session.execute(&mut [cpu], command).map_err(|e| {
+            let causes: Vec<_> = e.iter_chain().map(|cause| cause.to_string()).collect();
+            causes.join(": ")
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Parse an address for `peek` and `poke`, using the mapped bank if none is given.
+fn parse_banked_address(cpu: &Cpu, arg: Option<&str>) -> Result<(u16, u16), String> {
+    memory_map::parse_banked_address(cpu, required(arg.unwrap_or(""))?)
+}
+
+fn required(arg: &str) -> Result<&str, String> {
+    if arg.is_empty() { Err(String::from("missing argument")) } else { Ok(arg) }
+}
+
+/// A file path from a client, which has to stay inside the server's working directory.
+fn confined_path(arg: &str) -> Result<PathBuf, String> {
+    let path = Path::new(required(arg)?);
+    let inside = path.components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if !inside {
+        return Err(format!("path must be relative and without `..`: {}", arg));
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Parse key names into a bitmask in the layout of `Joypad::pressed`.
+fn parse_keys<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<u8, String> {
+    let mut keys = 0;
+    for name in names {
+        let name = name.to_lowercase();
+        match KEYS.iter().find(|&&(key, _)| key == name) {
+            Some(&(_, bit)) => keys |= bit,
+            None => return Err(format!("unknown key: {:?}", name)),
+        }
+    }
+    if keys == 0 {
+        return Err(String::from("no keys given"));
+    }
+    Ok(keys)
+}
+
+/// The names of the keys in a bitmask, joined with `+`.
+pub fn key_name(keys: u8) -> String {
+    let names: Vec<_> = KEYS.iter().filter(|&&(_, bit)| keys & bit != 0).map(|&(name, _)| name).collect();
+    names.join("+")
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &Path, tx: Sender<Request>) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    // A socket file left over from a previous run would make binding fail. Anything else at the
+    // path, or a socket another server is still listening on, is left alone.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "something other than a socket is at that path"));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on that socket"));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream.and_then(|s| Ok((BufReader::new(s.try_clone()?), s))) {
+                Ok((reader, writer)) => spawn_connection(reader, writer, tx.clone()),
+                Err(e) => warn!("Failed to accept control connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_path: &Path, _tx: Sender<Request>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets aren't supported here, use HOST:PORT"))
+}
+
+/// Forward a client's commands to the emulator and write back its replies, on a separate thread.
+fn spawn_connection(reader: impl BufRead + Send + 'static, mut writer: impl Write + Send + 'static, tx: Sender<Request>) {
+    thread::spawn(move || {
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if tx.send(Request { line, reply: reply_tx }).is_err() {
+                return;
+            }
+            let reply = match reply_rx.recv() {
+                Ok(reply) => reply,
+                Err(_) => return,
+            };
+            if writeln!(writer, "{}", reply).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_stay_in_the_working_directory() {
+        assert_eq!(confined_path("shots/a.png"), Ok(PathBuf::from("shots/a.png")));
+        assert_eq!(confined_path("./a.state"), Ok(PathBuf::from("./a.state")));
+        assert!(confined_path("/etc/passwd").is_err());
+        assert!(confined_path("../a.state").is_err());
+        assert!(confined_path("shots/../../a.state").is_err());
+        assert!(confined_path("").is_err());
+    }
+}
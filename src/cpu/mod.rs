@@ -1,19 +1,29 @@
-use crate::audio::Audio;
+use crate::audio::{Audio, AudioSink};
 use crate::cart::Cart;
+use crate::cart_header::{CartHeader, GbcFlag};
+use crate::cheat::{self, CheatCode};
 use crate::debug::Watch;
-use crate::gpu::Gpu;
-use crate::interrupts::Interrupt;
+use crate::expr::Expr;
+use crate::gpu::{Gpu, SpriteLimit, CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::interrupts::{self, Interrupt, InterruptController};
+use crate::io_register::IoDevice;
 use crate::joypad::Joypad;
-use crate::timer::Timer;
+use crate::pause::PauseState;
+use crate::savestate::{self, SaveState, SaveStateError, StateChunks, StateReader, StateWriter};
+use crate::serial::{self, Serial};
+use crate::timer::{self, Timer};
+use crate::version;
+use std::cell::RefCell;
+#[cfg(feature = "opcode-hooks")]
+use std::collections::HashMap;
 use std::collections::HashSet;
-use sdl2::audio::AudioQueue;
-use enumflags2::BitFlags;
 use log::{debug, info, log_enabled, trace, warn};
 use self::inst::{Cond, Inst, Operand16, Operand8};
-use self::registers::{Flag, Reg16, Reg8, Registers};
+use self::registers::{Reg16, Reg8, Registers};
 
 mod inst;
 pub mod registers;
+mod report;
 
 #[cfg(test)]
 mod test;
@@ -21,6 +31,94 @@ mod test;
 // TODO: Refactor later to extract memory-related stuff out of the cpu module.
 const WORK_RAM_SIZE: usize = 8 * 1024; // 8 KB
 const HIGH_RAM_SIZE: usize = 127; // For the address range 0xFF80-0xFFFE (inclusive).
+const SPEED_SWITCH_CYCLES: usize = 8200; // How long the CPU pauses to switch speed, per Pan Docs.
+const FRAME_SEQUENCER_BIT: u32 = 12; // DIV's bit 4 in the system counter, which clocks the APU.
+
+/// Whether debugger watchpoints and trace logging are compiled into the CPU loop. Controlled by the
+/// `instrumentation` Cargo feature. Every check of this is constant-folded, so builds without the
+/// feature pay nothing for the hooks.
+pub const INSTRUMENTATION: bool = cfg!(feature = "instrumentation");
+
+/// A handler for one of the opcodes the Game Boy doesn't define, like 0xED, registered with
+/// `Cpu::set_opcode_hook`. It's called with the opcode, once PC has moved past it, so it can read
+/// operands from PC on and move it further. It returns how many cycles the instruction took.
+#[cfg(feature = "opcode-hooks")]
+pub type OpcodeHook = fn(&mut Cpu, u8) -> usize;
+
+/// Which Game Boy to emulate. The only Game Boy Color hardware so far is the double speed mode, so
+/// this mostly changes the state the boot ROM leaves behind, which is what games check to pick
+/// their color or monochrome code paths.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    /// The model a game expects: a Game Boy Color if the header says it requires one, otherwise
+    /// the original Game Boy.
+    pub fn from_header(header: &CartHeader) -> Model {
+        match header.gbc_flag {
+            GbcFlag::Required => Model::Cgb,
+            GbcFlag::Supported | GbcFlag::Unsupported => Model::Dmg,
+        }
+    }
+}
+
+/// Sets up a `Cpu` for frontends outside this crate, so new options don't break their code. Start
+/// one with `Cpu::builder`.
+#[derive(Clone, Debug)]
+pub struct CpuBuilder {
+    cart: Cart,
+    model: Option<Model>,
+    sprite_limit: SpriteLimit,
+    sample_rate: Option<u32>,
+    debug_output: bool,
+}
+
+impl CpuBuilder {
+    /// Emulate `model` instead of the one the cartridge header asks for.
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    pub fn sprite_limit(mut self, sprite_limit: SpriteLimit) -> Self {
+        self.sprite_limit = sprite_limit;
+        self
+    }
+
+    /// Make sound at `rate` Hz instead of the default 44100.
+    pub fn sample_rate(mut self, rate: u32) -> Self {
+        self.sample_rate = Some(rate);
+        self
+    }
+
+    /// Echo serial port bytes and writes to the debug register at 0xFF7F to stdout, like
+    /// `--debug-output`.
+    pub fn debug_output(mut self, debug_output: bool) -> Self {
+        self.debug_output = debug_output;
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        let model = self.model.unwrap_or_else(|| {
+            CartHeader::from_rom(self.cart.rom()).map_or(Model::Dmg, |header| Model::from_header(&header))
+        });
+        let mut cpu = Cpu::new(self.cart);
+        if model != cpu.model() {
+            cpu.set_model(model);
+        }
+        cpu.gpu.sprite_limit = self.sprite_limit;
+        if let Some(rate) = self.sample_rate {
+            cpu.audio.set_sample_rate(rate);
+        }
+        cpu.serial.echo = self.debug_output;
+        cpu.debug_register = self.debug_output;
+        cpu
+    }
+}
 
 enum Dest {
     Mem8(u16),
@@ -50,6 +148,9 @@ pub struct Cpu {
     /// The player controller hardware.
     pub joypad: Joypad,
 
+    /// The serial link port.
+    pub serial: Serial,
+
     /// Game cartridge.
     pub cart: Cart,
 
@@ -73,16 +174,8 @@ pub struct Cpu {
     /// instruction.
     pending_enable_interrupts: bool,
 
-    /// The `IF` Interrupt Flags register accessed via I/O port 0xFF0F.
-    interrupt_flags_register: BitFlags<Interrupt>,
-
-    /// The `IE` Interrupt Enable register accessed via I/O port 0xFFFF.
-    interrupt_enable_register: BitFlags<Interrupt>,
-
-    /// Contains the 3 unused bits of the IE register, which nonetheless are read/write-able on the
-    /// Game Boy.
-    // TODO(solson): Refactor to combine this with the `interrupt_enable_register` field.
-    interrupt_enable_unused_bits: u8,
+    /// The `IF` and `IE` registers, shared with every component that can request an interrupt.
+    interrupts: InterruptController,
 
     /// If the cpu is halted
     halted: bool,
@@ -90,9 +183,76 @@ pub struct Cpu {
     /// If the cpu is stopped
     stopped: bool,
 
+    /// Whether a Game Boy Color is running in double speed mode, where the CPU and timer run
+    /// twice as fast relative to everything else. Bit 7 of `KEY1`.
+    double_speed: bool,
+
+    /// Whether the next STOP switches speed instead of stopping. Bit 0 of `KEY1`.
+    speed_switch_armed: bool,
+
+    /// How many more cycles the CPU stays paused for after a speed switch.
+    speed_switch_cycles: usize,
+
+    /// If true, check the internal invariants of every component on each memory access and abort
+    /// with a state dump on the first violation. Set by `--paranoid`.
+    pub paranoid: bool,
+
+    /// If true, stop as if a watch was hit when the ROM does something suspicious, like reading
+    /// RAM it never wrote. Set by `--strict`.
+    pub strict: bool,
+
+    /// If true, echo bytes written to the emulator-specific debug register at 0xFF7F to stdout.
+    /// Set by `--debug-output`, along with `serial.echo`.
+    pub debug_register: bool,
+
+    /// The first suspicious access made by the current instruction, in strict mode.
+    strict_violation: RefCell<Option<String>>,
+
+    /// How many more cycles an OAM DMA transfer would take on hardware, during which the CPU can
+    /// only reach high RAM and I/O. The copy itself happens at once, so this is only for strict
+    /// mode to catch code that runs from ROM or touches RAM during the transfer.
+    dma_cycles_left: usize,
+
+    /// The address of the currently-executing instruction.
+    current_pc: u16,
+
+    /// Which bytes of work RAM and high RAM have been written, for strict mode.
+    work_ram_written: Box<[bool]>,
+    high_ram_written: Box<[bool]>,
+
     /// Symbolic information for more detailed debug output.
     // TODO(solson): Should we find another place to store this?
     pub debug_symbols: Option<crate::wla_symbols::WlaSymbols>,
+
+    /// Section and function extents from an RGBDS map file, used to aggregate profiles.
+    pub debug_map: Option<crate::rgbds_map::RgbdsMap>,
+
+    /// Cycles spent per instruction address, while profiling is on.
+    pub profile: Option<crate::profile::Profile>,
+
+    /// Interrupt latencies, while they're being collected.
+    pub irq_stats: Option<crate::irq_stats::IrqStats>,
+
+    /// Recent memory accesses, while the heatmap window is open.
+    pub heatmap: Option<crate::heatmap::Heatmap>,
+
+    /// Stop as if a watch was hit as soon as this expression is true after an instruction. Set by
+    /// `--exit-when` or the debugger's `bw` command.
+    pub break_when: Option<Expr>,
+
+    /// Game Genie and GameShark codes in effect. See `cheat`.
+    pub cheats: Vec<CheatCode>,
+
+    /// Why the frontend isn't running this CPU, if it isn't. Frontends check this before stepping,
+    /// and the CPU itself ignores it.
+    pub pause: PauseState,
+
+    /// Set with `set_model`.
+    model: Model,
+
+    /// Handlers for illegal opcodes, which otherwise crash the emulator. See `OpcodeHook`.
+    #[cfg(feature = "opcode-hooks")]
+    opcode_hooks: HashMap<u8, OpcodeHook>,
 }
 
 impl Cpu {
@@ -104,6 +264,7 @@ impl Cpu {
             timer: Timer::new(),
             gpu: Gpu::new(),
             joypad: Joypad::new(),
+            serial: Serial::new(),
             audio: Audio::new(),
             cart,
             current_opcode: 0,
@@ -111,30 +272,126 @@ impl Cpu {
             interrupts_enabled: false,
             pending_disable_interrupts: false,
             pending_enable_interrupts: false,
-            interrupt_flags_register: BitFlags::from(Interrupt::VBlank),
-            interrupt_enable_register: BitFlags::empty(),
-            interrupt_enable_unused_bits: 0,
+            interrupts: InterruptController::new(),
             halted: false,
             stopped: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            speed_switch_cycles: 0,
+            paranoid: false,
+            strict: false,
+            debug_register: false,
+            strict_violation: RefCell::new(None),
+            dma_cycles_left: 0,
+            current_pc: 0,
+            work_ram_written: vec![false; WORK_RAM_SIZE].into_boxed_slice(),
+            high_ram_written: vec![false; HIGH_RAM_SIZE].into_boxed_slice(),
             debug_symbols: None,
+            debug_map: None,
+            profile: None,
+            irq_stats: None,
+            heatmap: None,
+            break_when: None,
+            cheats: Vec::new(),
+            pause: PauseState::default(),
+            model: Model::Dmg,
+            #[cfg(feature = "opcode-hooks")]
+            opcode_hooks: HashMap::new(),
         }
     }
 
-    /// Keep executing instructions until more than the given number of cycles have passed.
-    /// Returns true if we have hit a watch.
-    pub fn step_cycles(&mut self, cycles: usize, audio_queue: &mut AudioQueue<u8>, watches: &HashSet<Watch>) -> bool {
+    /// Set up a CPU step by step, starting with the cartridge. Unlike `new`, the model defaults to
+    /// the one the cartridge header asks for.
+    pub fn builder(cart: Cart) -> CpuBuilder {
+        CpuBuilder { cart, model: None, sprite_limit: SpriteLimit::Hardware, sample_rate: None, debug_output: false }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    /// Emulate a different model, resetting the registers to its boot state. Call this before
+    /// running anything.
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.regs = Registers::after_boot(model);
+        self.serial.cgb = model == Model::Cgb;
+        self.audio.cgb = model == Model::Cgb;
+    }
+
+    /// Switch the Game Boy off and on again. Cartridge RAM survives, as it would with a battery, and
+    /// so do the model and the frontend's settings, like `break_when`, `pause`, and the debug
+    /// options. The cycle count keeps going, so it still orders everything that happened.
+    pub fn reset(&mut self) {
+        self.cart.reset();
+        self.regs = Registers::after_boot(self.model);
+        self.work_ram.iter_mut().for_each(|b| *b = 0);
+        self.high_ram.iter_mut().for_each(|b| *b = 0);
+        self.timer = Timer::new();
+
+        let sprite_limit = self.gpu.sprite_limit;
+        self.gpu = Gpu::new();
+        self.gpu.sprite_limit = sprite_limit;
+
+        self.joypad = Joypad::new();
+
+        let (echo, capture) = (self.serial.echo, self.serial.capture.take());
+        let (link, device) = (self.serial.link.take(), self.serial.device.take());
+        self.serial = Serial::new();
+        self.serial.echo = echo;
+        self.serial.capture = capture;
+        self.serial.link = link;
+        self.serial.device = device;
+        self.serial.cgb = self.model == Model::Cgb;
+
+        let (capture, muted, register_log, scope, sample_rate) = (
+            self.audio.capture.take(), self.audio.muted, self.audio.register_log.take(), self.audio.scope.take(),
+            self.audio.sample_rate(),
+        );
+        self.audio = Audio::new();
+        self.audio.set_sample_rate(sample_rate);
+        self.audio.capture = capture;
+        self.audio.muted = muted;
+        self.audio.register_log = register_log;
+        self.audio.scope = scope;
+        self.audio.cgb = self.model == Model::Cgb;
+
+        self.current_opcode = 0;
+        self.interrupts_enabled = false;
+        self.pending_disable_interrupts = false;
+        self.pending_enable_interrupts = false;
+        self.interrupts = InterruptController::new();
+        self.halted = false;
+        self.stopped = false;
+        self.double_speed = false;
+        self.speed_switch_armed = false;
+        self.speed_switch_cycles = 0;
+        self.strict_violation.borrow_mut().take();
+        self.dma_cycles_left = 0;
+        self.current_pc = 0;
+        self.work_ram_written.iter_mut().for_each(|b| *b = false);
+        self.high_ram_written.iter_mut().for_each(|b| *b = false);
+    }
+
+    /// Keep executing instructions until more than the given number of cycles have passed. Sound is
+    /// only output if there is an `audio` sink. Returns true if we have hit a watch.
+    pub fn step_cycles(&mut self, cycles: usize, mut audio: Option<&mut dyn AudioSink>, watches: &HashSet<Watch>) -> bool {
         let mut curr_cycles: usize = 0;
-        let check_watches = watches.len() > 0;
+        let check_watches = INSTRUMENTATION && watches.len() > 0;
         while curr_cycles < cycles {
-            let mut interrupts = BitFlags::empty();
             match self.step(false, check_watches, watches) {
                 Some(step_cycles) => {
-                    self.audio.step(step_cycles, audio_queue);
-                    interrupts |= self.gpu.step(step_cycles);
-                    interrupts |= self.timer.step(step_cycles);
-                    interrupts |= self.joypad.step();
-                    self.request_interrupts(interrupts);
-                    curr_cycles += step_cycles;
+                    self.audio.end_cpu_step();
+                    // The APU's clock stops along with the CPU's.
+                    if !self.stopped {
+                        let audio_cycles = self.real_time_cycles(step_cycles);
+                        self.audio.step(audio_cycles, audio.as_mut().map(|sink| &mut **sink as &mut dyn AudioSink));
+                    }
+                    self.step_components(step_cycles);
+                    curr_cycles += self.real_time_cycles(step_cycles);
+                    if self.report_strict_violation() || self.break_condition_met() {
+                        return true;
+                    }
                 },
                 None => return true,
             }
@@ -142,37 +399,291 @@ impl Cpu {
         false
     }
 
+    /// Run for `machine_cycles` of the CPU's machine cycles, 4 clock cycles each, which take half
+    /// as long in real time in double speed mode. Every component advances along, but there's no
+    /// sound or watches. Returns how many machine cycles the run overshot by, which is 0 when it
+    /// stopped exactly on the target. Instructions aren't split yet, so one that would run past
+    /// the target still finishes, and timing tests can check the overshoot to be sure the boundary
+    /// they want wasn't inside one. Halted and stopped CPUs always stop exactly.
+    pub fn run_cycles_exact(&mut self, machine_cycles: usize) -> usize {
+        let target = self.cycles + machine_cycles * 4;
+        while self.cycles < target {
+            match self.step(false, false, &HashSet::new()) {
+                Some(step_cycles) => {
+                    self.audio.end_cpu_step();
+                    if !self.stopped {
+                        let audio_cycles = self.real_time_cycles(step_cycles);
+                        self.audio.step(audio_cycles, None);
+                    }
+                    self.step_components(step_cycles);
+                }
+                None => break,
+            }
+        }
+        self.cycles.saturating_sub(target) / 4
+    }
+
+    /// Run for one frame's worth of cycles, without sound or watches.
+    pub fn run_frame(&mut self) {
+        self.step_cycles(CYCLES_PER_FRAME, None, &HashSet::new());
+    }
+
+    /// Run for one frame's worth of cycles, sending the sound to `sink` at the sample rate.
+    pub fn run_frame_with_audio(&mut self, sink: &mut dyn AudioSink) {
+        self.step_cycles(CYCLES_PER_FRAME, Some(sink), &HashSet::new());
+    }
+
+    /// The last frame drawn, one shade from 0 (lightest) to 3 per pixel.
+    pub fn screen(&self) -> &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT] {
+        &self.gpu.screen_buffer
+    }
+
+    /// The buttons, for frontends to press and release.
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    /// Whether `break_when` is set and currently true.
+    pub fn break_condition_met(&self) -> bool {
+        let met = self.break_when.as_ref().map_or(false, |expr| expr.is_true(self));
+        // Reading memory for the expression mustn't count as the ROM's own access in strict mode.
+        self.strict_violation.borrow_mut().take();
+        met
+    }
+
+    /// The core CPU registers.
+    pub fn regs(&self) -> &Registers {
+        &self.regs
+    }
+
+    /// The registers, for opcode hooks to change.
+    #[cfg(feature = "opcode-hooks")]
+    pub fn regs_mut(&mut self) -> &mut Registers {
+        &mut self.regs
+    }
+
+    /// Run `hook` for `opcode` instead of crashing, to prototype a new instruction, or `None` to
+    /// remove it. Hooks survive `reset`, but aren't saved in savestates.
+    ///
+    /// Panics if `opcode` is one the Game Boy already defines.
+    #[cfg(feature = "opcode-hooks")]
+    pub fn set_opcode_hook(&mut self, opcode: u8, hook: Option<OpcodeHook>) {
+        let bytes = [opcode, 0, 0];
+        let len = inst::INSTRUCTION_LENGTH[opcode as usize];
+        if let Inst::Invalid(_) = Inst::from_bytes(&bytes[..len]) {
+            match hook {
+                Some(hook) => self.opcode_hooks.insert(opcode, hook),
+                None => self.opcode_hooks.remove(&opcode),
+            };
+        } else {
+            panic!("opcode {:#04X} is already an instruction", opcode);
+        }
+    }
+
+    /// The total number of cycles executed so far.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Snapshot the complete emulator state, excluding the cartridge ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.chunk(b"CPU ", self);
+        w.chunk(b"TIMR", &self.timer);
+        w.chunk(b"GPU ", &self.gpu);
+        w.chunk(b"JOYP", &self.joypad);
+        w.chunk(b"SERL", &self.serial);
+        w.chunk(b"CART", &self.cart);
+        w.chunk(b"APU ", &self.audio);
+        if let Some(device) = &self.serial.device {
+            w.chunk(b"SDEV", device);
+        }
+        let title = CartHeader::from_rom(self.cart.rom()).map(|header| header.title).unwrap_or_default();
+        w.chunk(b"TITL", &RomTitle(title));
+        w.chunk(b"BILD", &Build(version::BUILD.as_bytes().to_vec()));
+        w.finish_savestate(self.cart.rom())
+    }
+
+    /// Describe a savestate without loading it, which needs no ROM.
+    pub fn state_info(data: &[u8]) -> Result<StateInfo, SaveStateError> {
+        let (header, data) = savestate::unpack_any_rom(data)?;
+        let chunks = StateChunks::parse(&data)?;
+
+        // Load the CPU chunk into a CPU with a blank cartridge just to read its cycle count.
+        let blank = Cart::from_rom(vec![0; 0x8000].into_boxed_slice(), None)
+            .expect("a blank ROM is a valid cartridge");
+        let mut cpu = Cpu::new(blank);
+        chunks.load(b"CPU ", &mut cpu)?;
+        let mut gpu = Gpu::new();
+        chunks.load(b"GPU ", &mut gpu)?;
+        let title = if chunks.contains(b"TITL") {
+            let mut title = RomTitle(Vec::new());
+            chunks.load(b"TITL", &mut title)?;
+            Some(String::from_utf8_lossy(&title.0).into_owned())
+        } else {
+            None
+        };
+        let build = Build::read(&chunks)?;
+        Ok(StateInfo { header, title, build, cycles: cpu.cycles, gpu })
+    }
+
+    /// Restore a snapshot taken by `save_state`. The same cartridge must already be loaded. If the
+    /// state is corrupted or for another ROM, nothing is restored, but on other errors the
+    /// emulator may be left partially restored.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let data = savestate::unpack(data, self.cart.rom())?;
+        let chunks = StateChunks::parse(&data)?;
+        chunks.load(b"CPU ", self)?;
+        chunks.load(b"TIMR", &mut self.timer)?;
+        chunks.load(b"GPU ", &mut self.gpu)?;
+        chunks.load(b"JOYP", &mut self.joypad)?;
+        chunks.load(b"SERL", &mut self.serial)?;
+        chunks.load(b"CART", &mut self.cart)?;
+        chunks.load(b"APU ", &mut self.audio)?;
+        match &mut self.serial.device {
+            Some(device) if chunks.contains(b"SDEV") => chunks.load(b"SDEV", device)?,
+            None if chunks.contains(b"SDEV") => warn!("Not loading the savestate's serial device state, since none is plugged in"),
+            _ => {}
+        }
+        if let Some(irq_stats) = &mut self.irq_stats {
+            irq_stats.forget_requests();
+        }
+        chunks.warn_unknown(&[b"CPU ", b"TIMR", b"GPU ", b"JOYP", b"SERL", b"CART", b"APU ", b"SDEV", b"TITL", b"BILD"]);
+        match Build::read(&chunks)? {
+            Some(ref build) if build != version::BUILD => warn!(
+                "Savestate was made by Rugby {}, but this is {}, so emulation may not go the same way from here",
+                build, version::BUILD,
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
     /// step n instructions forward.
     pub fn step_n(&mut self, n: usize, watches: &HashSet<Watch>) {
-        let check_watches = n > 1;
+        let check_watches = INSTRUMENTATION && n > 1;
         for _ in 0..n {
-            let mut interrupts = BitFlags::empty();
             match self.step(true, check_watches, watches) {
                 Some(step_cycles) => {
-                    interrupts |= self.gpu.step(step_cycles);
-                    interrupts |= self.timer.step(step_cycles);
-                    interrupts |= self.joypad.step();
-                    self.request_interrupts(interrupts);
+                    self.audio.end_cpu_step();
+                    self.step_components(step_cycles);
+                    if self.report_strict_violation() || (n > 1 && self.break_condition_met()) {
+                        break;
+                    }
                 },
                 None => break,
             }
         }
     }
 
+    /// Make the writes of the GameShark codes in `cheats`, once per frame at vertical blank. They
+    /// aren't the game's writes, so they skip tracing, the heatmap, and the paranoid and strict
+    /// checks.
+    fn apply_game_shark_codes(&mut self) {
+        for i in 0..self.cheats.len() {
+            if let CheatCode::GameShark { addr, value, .. } = self.cheats[i] {
+                self.store(addr, value);
+            }
+        }
+    }
+
+    /// Advance every component that can request interrupts, and the cartridge's clock, by the
+    /// cycles of the last instruction.
+    ///
+    /// In double speed mode, the timer and serial port keep pace with the CPU while the rest see
+    /// half as many cycles. While stopped, only the joypad and the cartridge's own clock run.
+    fn step_components(&mut self, cycles: usize) {
+        let real_time_cycles = self.real_time_cycles(cycles);
+        self.dma_cycles_left = self.dma_cycles_left.saturating_sub(cycles);
+        self.cart.tick(real_time_cycles);
+        if !self.stopped {
+            let was_drawing = self.gpu.beam().is_some();
+            self.gpu.step(real_time_cycles, &mut self.interrupts);
+            if was_drawing && self.gpu.beam().is_none() && !self.cheats.is_empty() {
+                self.apply_game_shark_codes();
+            }
+            let counter = self.timer.system_counter();
+            // DIV is held in reset while the speed switches.
+            if self.speed_switch_cycles == 0 {
+                self.timer.step(cycles, &mut self.interrupts);
+                for _ in 0..timer::falling_edges(counter, cycles, self.frame_sequencer_bit()) {
+                    self.audio.clock_frame_sequencer();
+                }
+            }
+            self.serial.step(cycles, counter, &mut self.interrupts);
+        }
+        self.joypad.step(&mut self.interrupts);
+    }
+
+    /// The system counter bit that clocks the APU's frame sequencer. It's one higher in double
+    /// speed mode, so the sequencer keeps its 512 Hz rate.
+    fn frame_sequencer_bit(&self) -> u32 {
+        if self.double_speed { FRAME_SEQUENCER_BIT + 1 } else { FRAME_SEQUENCER_BIT }
+    }
+
+    /// Reset DIV. The frame sequencer is clocked off a bit of it, so if that bit was set this is
+    /// a falling edge that clocks the sequencer early, like on hardware.
+    fn reset_divider(&mut self) {
+        if self.timer.system_counter() & (1 << self.frame_sequencer_bit()) != 0 {
+            self.audio.clock_frame_sequencer();
+        }
+        self.timer.reset_divider();
+    }
+
+    /// How many cycles at normal speed pass while the CPU runs for `cycles`.
+    fn real_time_cycles(&self, cycles: usize) -> usize {
+        if self.double_speed { cycles / 2 } else { cycles }
+    }
+
+    /// Execute STOP: switch speed if a Game Boy Color game asked for it through `KEY1`, or stop
+    /// the CPU, timer, LCD, and APU until a selected joypad key is pressed. Either way, DIV is
+    /// reset.
+    fn stop(&mut self) {
+        self.reset_divider();
+        if self.model == Model::Cgb && self.speed_switch_armed {
+            self.double_speed = !self.double_speed;
+            self.speed_switch_armed = false;
+            self.speed_switch_cycles = SPEED_SWITCH_CYCLES;
+        } else {
+            self.stopped = true;
+        }
+    }
+
     /// Execute a single instruction. Returns how many cycles it took and None if a watch is hit.
     fn step(&mut self, print_instr: bool, check_watches: bool, watches: &HashSet<Watch>) -> Option<usize> {
+        let start_cycles = self.cycles;
+        // Forget accesses made from outside the CPU loop, e.g. by the debugger.
+        self.strict_violation.get_mut().take();
+        if self.speed_switch_cycles > 0 {
+            self.speed_switch_cycles = self.speed_switch_cycles.saturating_sub(4);
+            return Some(self.idle(4));
+        }
+        // Only a key press wakes the CPU from STOP, even with the joypad interrupt disabled.
+        if self.stopped {
+            if !self.joypad.key_line_low() {
+                return Some(self.idle(4));
+            }
+            self.stopped = false;
+        }
+
         let pending_enable_interrupts = self.pending_enable_interrupts;
         let pending_disable_interrupts = self.pending_disable_interrupts;
         self.pending_enable_interrupts = false;
         self.pending_disable_interrupts = false;
+        if INSTRUMENTATION {
+            if let Some(irq_stats) = &mut self.irq_stats {
+                irq_stats.observe(self.interrupts.requested(), self.cycles);
+            }
+        }
         self.handle_interrupts();
 
         if self.halted {
-            return Some(4);
+            return Some(self.idle(4));
         }
 
         // Get the opcode for the current instruction and find the total instruction length.
         let base_pc = self.regs.pc.get();
+        self.current_pc = base_pc;
         self.current_opcode = self.read_mem(base_pc);
         let instruction_len = inst::INSTRUCTION_LENGTH[self.current_opcode as usize];
 
@@ -196,7 +707,7 @@ impl Cpu {
         if print_instr {
             println!("PC=0x{:04X}: {:?}", base_pc, inst);
         }
-        else {
+        else if INSTRUMENTATION {
             trace!("PC=0x{:04X}: {:?}", base_pc, inst);
         }
         if check_watches && self.is_watch_hit(inst, watches) {
@@ -204,6 +715,7 @@ impl Cpu {
             return None;
         }
         self.regs.pc += instruction_len as u16;
+        self.cycles += cycles;
 
         self.execute(inst);
 
@@ -215,45 +727,123 @@ impl Cpu {
             self.interrupts_enabled = false;
         }
 
-        Some(cycles)
+        if INSTRUMENTATION && self.profile.is_some() {
+            let bank = match base_pc {
+                0x4000...0x7FFF => self.cart.debug_state().rom_bank,
+                _ => 0,
+            };
+            if let Some(profile) = &mut self.profile {
+                profile.record(bank, base_pc, self.cycles - start_cycles);
+            }
+        }
+
+        // Includes any extra cycles taken by conditional jumps, calls, and returns.
+        Some(self.cycles - start_cycles)
+    }
+
+    /// Spend `cycles` running no instruction, e.g. while halted, and return them.
+    fn idle(&mut self, cycles: usize) -> usize {
+        self.cycles += cycles;
+        if INSTRUMENTATION {
+            if let Some(profile) = &mut self.profile {
+                profile.record_idle(cycles);
+            }
+        }
+        cycles
+    }
+
+    /// Print and clear the strict mode violation from the last instruction, if any. Returns
+    /// whether there was one.
+    fn report_strict_violation(&self) -> bool {
+        match self.strict_violation.borrow_mut().take() {
+            Some(violation) => {
+                println!("STRICT: PC=0x{:04X}: {}", self.current_pc, violation);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record a strict mode violation, keeping only the first one per instruction.
+    fn strict_violation(&self, violation: impl FnOnce() -> String) {
+        let mut current = self.strict_violation.borrow_mut();
+        if current.is_none() {
+            *current = Some(violation());
+        }
+    }
+
+    fn check_strict_read(&self, addr: u16) {
+        match addr {
+            0x0000...0xFEFF if self.dma_cycles_left > 0 =>
+                self.strict_violation(|| format!(
+                    "read from 0x{:04X} during OAM DMA, which leaves only high RAM reachable. Run the \
+                     code that waits for the transfer from high RAM",
+                    addr,
+                )),
+            0x8000...0x9FFF if self.gpu.vram_blocked() =>
+                self.strict_violation(|| format!("read from VRAM at 0x{:04X} during mode 3", addr)),
+            0xC000...0xFDFF if !self.work_ram_written[(addr as usize - 0xC000) % WORK_RAM_SIZE] =>
+                self.strict_violation(|| format!("read from uninitialized work RAM at 0x{:04X}", addr)),
+            0xFE00...0xFE9F if self.gpu.oam_blocked() =>
+                self.strict_violation(|| format!("read from OAM at 0x{:04X} during mode 2 or 3", addr)),
+            0xFF80...0xFFFE if !self.high_ram_written[addr as usize - 0xFF80] =>
+                self.strict_violation(|| format!("read from uninitialized high RAM at 0x{:04X}", addr)),
+            _ => {}
+        }
+    }
+
+    fn check_strict_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0xFEFF if self.dma_cycles_left > 0 =>
+                self.strict_violation(|| format!(
+                    "write of 0x{:02X} to 0x{:04X} during OAM DMA, which leaves only high RAM reachable",
+                    val,
+                    addr,
+                )),
+            0x0000...0x7FFF if !self.cart.is_register(addr) =>
+                self.strict_violation(|| format!(
+                    "write of 0x{:02X} to ROM at 0x{:04X}, which is not a mapper register",
+                    val,
+                    addr,
+                )),
+            0x8000...0x9FFF if self.gpu.vram_blocked() =>
+                self.strict_violation(|| format!("write to VRAM at 0x{:04X} during mode 3", addr)),
+            0xC000...0xFDFF => self.work_ram_written[(addr as usize - 0xC000) % WORK_RAM_SIZE] = true,
+            0xFE00...0xFE9F if self.gpu.oam_blocked() =>
+                self.strict_violation(|| format!("write to OAM at 0x{:04X} during mode 2 or 3", addr)),
+            0xFF80...0xFFFE => self.high_ram_written[addr as usize - 0xFF80] = true,
+            _ => {}
+        }
     }
 
     fn handle_interrupts(&mut self) {
-        use Interrupt::*;
-        for &i in &[VBlank, Lcd, Timer, Serial, Joypad] {
+        for &i in &interrupts::PRIORITY {
             self.check_interrupt(i);
         }
-        if self.interrupt_flags_register.contains(Joypad) {
-            self.stopped = false;
-        }
     }
 
     fn check_interrupt(&mut self, i: Interrupt) {
-        let flagged = self.interrupt_flags_register.contains(i);
-        let enabled = self.interrupt_enable_register.contains(i);
+        let flagged = self.interrupts.is_requested(i);
+        let enabled = self.interrupts.is_enabled(i);
         if flagged { self.halted = false; }
         if self.interrupts_enabled && enabled && flagged {
             debug!("Handling interrupt {:?}", i);
             // TODO(solson): Use `call` or `call_restart`?
             self.push_stack(self.regs.pc.get());
             self.regs.pc.set(i.handler_addr());
-            self.interrupt_flags_register.remove(i);
-        }
-    }
-
-    pub fn request_interrupts(&mut self, interrupts: BitFlags<Interrupt>) {
-        if log_enabled!(log::Level::Debug) {
-            for i in interrupts.iter() {
-                debug!("Requesting interrupt {:?}", i);
+            self.interrupts.acknowledge(i);
+            if INSTRUMENTATION {
+                if let Some(irq_stats) = &mut self.irq_stats {
+                    irq_stats.serviced(i, self.cycles);
+                }
             }
         }
-        self.interrupt_flags_register |= interrupts;
     }
 
     fn execute(&mut self, inst: Inst) {
         match inst {
             Inst::Nop => {}
-            Inst::Stop => self.stopped = true,
+            Inst::Stop => self.stop(),
             Inst::Halt => self.halted = true,
             Inst::Di => self.pending_disable_interrupts = true,
             Inst::Ei => self.pending_enable_interrupts = true,
@@ -304,8 +894,21 @@ impl Cpu {
             Inst::Cpl => self.complement_accum(),
             Inst::Ccf => self.complement_carry_flag(),
             Inst::Scf => self.set_carry_flag(),
-            Inst::Invalid(opcode) => panic!("tried to execute invalid opcode {:#X}", opcode),
+            Inst::Invalid(opcode) => self.invalid_opcode(opcode),
+        }
+    }
+
+    /// An opcode the Game Boy doesn't define, which locks up the CPU on hardware. Research builds
+    /// can hook it instead, see `set_opcode_hook`.
+    fn invalid_opcode(&mut self, opcode: u8) {
+        #[cfg(feature = "opcode-hooks")]
+        {
+            if let Some(&hook) = self.opcode_hooks.get(&opcode) {
+                self.cycles += hook(self, opcode);
+                return;
+            }
         }
+        panic!("tried to execute invalid opcode {:#X}", opcode);
     }
 
     /// The `Inst::Jp` instruction.
@@ -315,7 +918,7 @@ impl Cpu {
         let addr_val = self.get_operand_16(addr);
         if self.check_cond_and_update_cycles(cond) {
             // TODO(solson): Deduplicate this code with the block in `call`.
-            if log_enabled!(log::Level::Trace) {
+            if INSTRUMENTATION && log_enabled!(log::Level::Trace) {
                 if let Some(symbols) = &self.debug_symbols {
                     let rom_addr = crate::wla_symbols::RomAddr { bank: 1, addr: addr_val };
                     match symbols.labels.get(&rom_addr) {
@@ -343,7 +946,7 @@ impl Cpu {
     /// Call a subroutine if the condition is met.
     fn call(&mut self, fn_addr: u16, cond: Cond) {
         if self.check_cond_and_update_cycles(cond) {
-            if log_enabled!(log::Level::Trace) {
+            if INSTRUMENTATION && log_enabled!(log::Level::Trace) {
                 if let Some(symbols) = &self.debug_symbols {
                     // TODO(solson): Fix this harded bank number. (It's not exactly clear to me how
                     // to interpret the WLA DX symbol file bank numbers yet.)
@@ -411,17 +1014,17 @@ impl Cpu {
         let sp = self.regs.sp.get();
         let val = (sp as i32 + offset as i32) as u16;
         self.regs.set_16(dest, val);
-        self.set_flag(Flag::Zero, false);
-        self.set_flag(Flag::Sub, false);
+        self.regs.f.set_zero(false);
+        self.regs.f.set_sub(false);
         // TODO(wcarlson): Potential bugs in this section. Unsure if these implementations are
         // correct
         warn!("executing LdHlSp or AddSp which may be buggy");
         if offset >= 0 {
-            self.set_flag(Flag::HalfCarry, get_add_half_carry((sp & 0xFF) as u8, offset as u8));
-            self.set_flag(Flag::Carry, (sp & 0xFF) as u16 + offset as u16 > 0xFF);
+            self.regs.f.set_half_carry(get_add_half_carry((sp & 0xFF) as u8, offset as u8));
+            self.regs.f.set_carry((sp & 0xFF) as u16 + offset as u16 > 0xFF);
         } else {
-            self.set_flag(Flag::HalfCarry, get_sub_half_carry((val & 0xFF) as u8, (sp & 0xFF) as u8));
-            self.set_flag(Flag::Carry, (val & 0xFF) <= (sp & 0xFF)); // Uncertain about this
+            self.regs.f.set_half_carry(get_sub_half_carry((val & 0xFF) as u8, (sp & 0xFF) as u8));
+            self.regs.f.set_carry((val & 0xFF) <= (sp & 0xFF)); // Uncertain about this
         }
     }
 
@@ -430,9 +1033,9 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val.wrapping_add(1);
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, get_add_half_carry(old_val, 1));
+        self.regs.f.set_zero(new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(get_add_half_carry(old_val, 1));
     }
 
     /// The 8-bit `Inst::Dec` instruction.
@@ -440,9 +1043,9 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val.wrapping_sub(1);
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, new_val == 0);
-        self.set_flag(Flag::Sub, true);
-        self.set_flag(Flag::HalfCarry, get_sub_half_carry(old_val, 1));
+        self.regs.f.set_zero(new_val == 0);
+        self.regs.f.set_sub(true);
+        self.regs.f.set_half_carry(get_sub_half_carry(old_val, 1));
     }
 
     /// The 16-bit `Inst::Inc` instruction.
@@ -463,10 +1066,10 @@ impl Cpu {
         let n_val = self.get_operand_8(n);
         let (new_accum, carry) = accum.overflowing_add(n_val);
         self.regs.a = new_accum;
-        self.set_flag(Flag::Zero, new_accum == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, get_add_half_carry(accum, n_val));
-        self.set_flag(Flag::Carry, carry);
+        self.regs.f.set_zero(new_accum == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(get_add_half_carry(accum, n_val));
+        self.regs.f.set_carry(carry);
     }
 
     /// The `Inst::AddHl` instruction
@@ -475,25 +1078,25 @@ impl Cpu {
         let n_val = self.get_operand_16(n);
         let (new_hl, carry) = old_hl.overflowing_add(n_val);
         self.regs.hl.set(new_hl);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, get_add_half_carry_high(old_hl, n_val));
-        self.set_flag(Flag::Carry, carry);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(get_add_half_carry_high(old_hl, n_val));
+        self.regs.f.set_carry(carry);
     }
 
     /// The `Inst::AdcA` instruction
     fn add_accum_with_carry(&mut self, n: Operand8) {
         let accum = self.regs.a;
         let n_val = self.get_operand_8(n);
-        let carry_val = self.get_flag(Flag::Carry) as u8;
+        let carry_val = self.regs.f.carry() as u8;
         let (midway_accum, midway_carry) = accum.overflowing_add(n_val);
         let (final_accum, final_carry) = midway_accum.overflowing_add(carry_val);
         self.regs.a = final_accum;
-        self.set_flag(Flag::Zero, final_accum == 0);
-        self.set_flag(Flag::Sub, false);
+        self.regs.f.set_zero(final_accum == 0);
+        self.regs.f.set_sub(false);
         let half_carry = get_add_half_carry(accum, n_val) ||
             get_add_half_carry(midway_accum, carry_val);
-        self.set_flag(Flag::HalfCarry, half_carry);
-        self.set_flag(Flag::Carry, midway_carry || final_carry);
+        self.regs.f.set_half_carry(half_carry);
+        self.regs.f.set_carry(midway_carry || final_carry);
     }
 
     /// The `Inst::Sub` instruction
@@ -502,63 +1105,63 @@ impl Cpu {
         let n_val = self.get_operand_8(n);
         let (new_accum, carry) = accum.overflowing_sub(n_val);
         self.regs.a = new_accum;
-        self.set_flag(Flag::Zero, new_accum == 0);
-        self.set_flag(Flag::Sub, true);
-        self.set_flag(Flag::HalfCarry, get_sub_half_carry(accum, n_val));
-        self.set_flag(Flag::Carry, carry);
+        self.regs.f.set_zero(new_accum == 0);
+        self.regs.f.set_sub(true);
+        self.regs.f.set_half_carry(get_sub_half_carry(accum, n_val));
+        self.regs.f.set_carry(carry);
     }
 
     /// The `Inst::SbcA` instruction
     fn sub_accum_with_carry(&mut self, n: Operand8) {
         let accum = self.regs.a;
         let n_val = self.get_operand_8(n);
-        let carry_val = self.get_flag(Flag::Carry) as u8;
+        let carry_val = self.regs.f.carry() as u8;
         let (midway_accum, midway_carry) = accum.overflowing_sub(n_val);
         let (final_accum, final_carry) = midway_accum.overflowing_sub(carry_val);
         self.regs.a = final_accum;
-        self.set_flag(Flag::Zero, final_accum == 0);
-        self.set_flag(Flag::Sub, true);
+        self.regs.f.set_zero(final_accum == 0);
+        self.regs.f.set_sub(true);
         let half_carry = get_sub_half_carry(accum, n_val) ||
             get_sub_half_carry(midway_accum, carry_val);
-        self.set_flag(Flag::HalfCarry, half_carry);
-        self.set_flag(Flag::Carry, midway_carry || final_carry);
+        self.regs.f.set_half_carry(half_carry);
+        self.regs.f.set_carry(midway_carry || final_carry);
     }
 
     /// The `Inst::And` instruction.
     fn and_accum(&mut self, n: Operand8) {
         self.regs.a &= self.get_operand_8(n);
-        self.set_flag(Flag::Zero, self.regs.a == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, true);
-        self.set_flag(Flag::Carry, false);
+        self.regs.f.set_zero(self.regs.a == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(true);
+        self.regs.f.set_carry(false);
     }
 
     /// The `Inst::Xor` instruction.
     fn xor_accum(&mut self, n: Operand8) {
         self.regs.a ^= self.get_operand_8(n);
-        self.set_flag(Flag::Zero, self.regs.a == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, false);
+        self.regs.f.set_zero(self.regs.a == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(false);
     }
 
     /// The `Inst::Or` instruction.
     fn or_accum(&mut self, n: Operand8) {
         self.regs.a |= self.get_operand_8(n);
-        self.set_flag(Flag::Zero, self.regs.a == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, false);
+        self.regs.f.set_zero(self.regs.a == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(false);
     }
 
     /// The `Inst::Cp` instruction.
     fn compare_accum(&mut self, n: Operand8) {
         let left = self.regs.a;
         let right = self.get_operand_8(n);
-        self.set_flag(Flag::Zero, left == right);
-        self.set_flag(Flag::Sub, true);
-        self.set_flag(Flag::HalfCarry, get_sub_half_carry(left, right));
-        self.set_flag(Flag::Carry, left < right);
+        self.regs.f.set_zero(left == right);
+        self.regs.f.set_sub(true);
+        self.regs.f.set_half_carry(get_sub_half_carry(left, right));
+        self.regs.f.set_carry(left < right);
     }
 
     /// The `Inst::Rlc` instruction.
@@ -566,22 +1169,22 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val.rotate_left(1);
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, set_zero_flag && new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x80 != 0);
+        self.regs.f.set_zero(set_zero_flag && new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x80 != 0);
     }
 
     /// The `Inst::Rl` instruction.
     fn rotate_left(&mut self, n: Operand8, set_zero_flag: bool) {
         let old_val = self.get_operand_8(n);
-        let old_carry = self.get_flag(Flag::Carry) as u8;
+        let old_carry = self.regs.f.carry() as u8;
         let new_val = (old_val << 1) | old_carry;
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, set_zero_flag && new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x80 != 0);
+        self.regs.f.set_zero(set_zero_flag && new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x80 != 0);
     }
 
     /// The `Inst::Rrc` instruction.
@@ -589,22 +1192,22 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val.rotate_right(1);
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, set_zero_flag && new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x01 != 0);
+        self.regs.f.set_zero(set_zero_flag && new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x01 != 0);
     }
 
     /// The `Inst::Rr` instruction.
     fn rotate_right(&mut self, n: Operand8, set_zero_flag: bool) {
         let old_val = self.get_operand_8(n);
-        let old_carry = self.get_flag(Flag::Carry) as u8;
+        let old_carry = self.regs.f.carry() as u8;
         let new_val = (old_carry << 7) | (old_val >> 1);
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, set_zero_flag && new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x01 != 0);
+        self.regs.f.set_zero(set_zero_flag && new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x01 != 0);
     }
 
     /// The `Inst::Sla` instruction.
@@ -612,10 +1215,10 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val << 1;
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x80 != 0);
+        self.regs.f.set_zero(new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x80 != 0);
     }
 
     /// The `Inst::Sra` instruction.
@@ -624,10 +1227,10 @@ impl Cpu {
         let high_bit = old_val & 0x80;
         let new_val = (old_val >> 1) | high_bit;
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x01 != 0);
+        self.regs.f.set_zero(new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x01 != 0);
     }
 
     /// The `Inst::Srl` instruction.
@@ -635,10 +1238,10 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val >> 1;
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, old_val & 0x01 != 0);
+        self.regs.f.set_zero(new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(old_val & 0x01 != 0);
     }
 
     /// The `Inst::Swap` instruction.
@@ -646,18 +1249,18 @@ impl Cpu {
         let old_val = self.get_operand_8(n);
         let new_val = old_val.rotate_right(4);
         self.set_operand_8(n, new_val);
-        self.set_flag(Flag::Zero, new_val == 0);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, false);
+        self.regs.f.set_zero(new_val == 0);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(false);
     }
 
     /// The `Inst::Bit` instruction.
     fn test_bit(&mut self, bit: u8, n: Operand8) {
         let is_zero = self.get_operand_8(n) & (1 << bit) == 0;
-        self.set_flag(Flag::Zero, is_zero);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, true);
+        self.regs.f.set_zero(is_zero);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(true);
     }
 
     /// The `Inst::Res` instruction.
@@ -674,9 +1277,9 @@ impl Cpu {
 
     /// The `Inst::Daa` instruction.
     fn decimal_adjust_accum(&mut self) {
-        let half_carry = self.get_flag(Flag::HalfCarry);
-        let carry = self.get_flag(Flag::Carry);
-        let sub = self.get_flag(Flag::Sub);
+        let half_carry = self.regs.f.half_carry();
+        let carry = self.regs.f.carry();
+        let sub = self.regs.f.sub();
 
         let mut accum = self.regs.a;
         let mut set_carry = false;
@@ -698,31 +1301,31 @@ impl Cpu {
         };
 
         self.regs.a = accum;
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, set_carry);
-        self.set_flag(Flag::Zero, accum == 0);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(set_carry);
+        self.regs.f.set_zero(accum == 0);
     }
 
     /// The `Inst::Cpl` instruction.
     fn complement_accum(&mut self) {
         self.regs.a = !self.regs.a;
-        self.set_flag(Flag::Sub, true);
-        self.set_flag(Flag::HalfCarry, true);
+        self.regs.f.set_sub(true);
+        self.regs.f.set_half_carry(true);
     }
 
     /// The `Inst::Ccf` instruction.
     fn complement_carry_flag(&mut self) {
-        let carry = !self.get_flag(Flag::Carry);
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, carry);
+        let carry = !self.regs.f.carry();
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(carry);
     }
 
     /// The `Inst::Scf` instruction.
     fn set_carry_flag(&mut self) {
-        self.set_flag(Flag::Sub, false);
-        self.set_flag(Flag::HalfCarry, false);
-        self.set_flag(Flag::Carry, true);
+        self.regs.f.set_sub(false);
+        self.regs.f.set_half_carry(false);
+        self.regs.f.set_carry(true);
     }
 
     /// If the given condition is met, increment the cycle count accordingly and return true.
@@ -738,10 +1341,10 @@ impl Cpu {
     fn is_cond_met(&self, cond: Cond) -> bool {
         match cond {
             Cond::None => true,
-            Cond::Zero => self.get_flag(Flag::Zero),
-            Cond::NotZero => !self.get_flag(Flag::Zero),
-            Cond::Carry => self.get_flag(Flag::Carry),
-            Cond::NotCarry => !self.get_flag(Flag::Carry),
+            Cond::Zero => self.regs.f.zero(),
+            Cond::NotZero => !self.regs.f.zero(),
+            Cond::Carry => self.regs.f.carry(),
+            Cond::NotCarry => !self.regs.f.carry(),
         }
     }
 
@@ -756,18 +1359,6 @@ impl Cpu {
         self.read_mem_16(addr)
     }
 
-    fn set_flag(&mut self, flag: Flag, val: bool) {
-        if val {
-            self.regs.f.insert(flag);
-        } else {
-            self.regs.f.remove(flag);
-        }
-    }
-
-    fn get_flag(&self, flag: Flag) -> bool {
-        self.regs.f.contains(flag)
-    }
-
     /// Get the value of the given 8-bit operand.
     ///
     /// NOTE: Accessing some operands has side effects, so you should not call this twice on a
@@ -832,15 +1423,47 @@ impl Cpu {
         }
     }
 
+    /// Read memory for a tool rather than the game: the value the CPU would see, without strict
+    /// mode, paranoid checks, or the heatmap noticing.
     pub fn read_mem_debug(&self, addr: u16) -> u8 {
-        self.read_mem(addr)
+        self.peek(addr)
     }
 
+    /// Write memory for a tool rather than the game, with the same effects on the hardware as a
+    /// write from the CPU, but without strict mode, paranoid checks, or the heatmap noticing.
+    pub fn write_mem_debug(&mut self, addr: u16, val: u8) {
+        self.store(addr, val)
+    }
+
+    /// A read by the CPU, in the course of running the game.
     fn read_mem(&self, addr: u16) -> u8 {
-        let val = match addr {
+        if self.paranoid {
+            self.check_invariants(addr);
+        }
+        if self.strict {
+            self.check_strict_read(addr);
+        }
+        if INSTRUMENTATION {
+            if let Some(heatmap) = &self.heatmap {
+                heatmap.record_read(addr);
+            }
+        }
+
+        let val = self.peek(addr);
+
+        if INSTRUMENTATION {
+            trace!("read(0x{:04X}) => 0x{:02X}", addr, val);
+        }
+
+        val
+    }
+
+    /// The value at `addr`, with no side effects at all.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
             // First 16KB is ROM Bank 00 (in cartridge, fixed at bank 00)
             // Second 16KB are ROM Banks 01..NN (in cartridge, switchable bank number)
-            0x0000...0x7FFF => self.cart.read(addr),
+            0x0000...0x7FFF => cheat::patch_rom(&self.cheats, addr, self.cart.read(addr)),
 
             // 8KB Video RAM (VRAM) (switchable bank 0-1 in CGB Mode)
             0x8000...0x9FFF => {
@@ -862,7 +1485,7 @@ impl Cpu {
             }
 
             // Same as C000-DDFF (ECHO) (typically not used)
-            0xE000...0xFDFF => self.read_mem(addr - 0xE000 + 0xC000),
+            0xE000...0xFDFF => self.peek(addr - 0xE000 + 0xC000),
 
             // Sprite Attribute Table (OAM)
             0xFE00...0xFE9F => {
@@ -886,21 +1509,31 @@ impl Cpu {
             }
 
             // Interrupt Enable Register
-            // The top 3 bits are unused and always 1.
-            0xFFFF => {
-                (self.interrupt_enable_unused_bits & 0b1110_0000) |
-                    self.interrupt_enable_register.bits()
-            }
-        };
-
-        trace!("read(0x{:04X}) => 0x{:02X}", addr, val);
-
-        val
+            0xFFFF => self.interrupts.read_enable(),
+        }
     }
 
+    /// A write by the CPU, in the course of running the game.
     fn write_mem(&mut self, addr: u16, val: u8) {
-        trace!("write(0x{:04X}, 0x{:02X})", addr, val);
+        if INSTRUMENTATION {
+            trace!("write(0x{:04X}, 0x{:02X})", addr, val);
+        }
+        if self.paranoid {
+            self.check_invariants(addr);
+        }
+        if self.strict {
+            self.check_strict_write(addr, val);
+        }
+        if INSTRUMENTATION {
+            if let Some(heatmap) = &mut self.heatmap {
+                heatmap.record_write(addr);
+            }
+        }
+        self.store(addr, val);
+    }
 
+    /// Write `val` to `addr`, affecting the hardware the way any write does.
+    fn store(&mut self, addr: u16, val: u8) {
         match addr {
             // 32KB cartridge write
             0x0000...0x7FFF => self.cart.write(addr, val),
@@ -925,7 +1558,7 @@ impl Cpu {
             }
 
             // Same as C000-DDFF (ECHO) (typically not used)
-            0xE000...0xFDFF => self.write_mem(addr - 0xE000 + 0xC000, val),
+            0xE000...0xFDFF => self.store(addr - 0xE000 + 0xC000, val),
 
             // Sprite Attribute Table (OAM). TODO: Can only write during H-Blank or V-Blank phase
             0xFE00...0xFE9F => {
@@ -949,10 +1582,23 @@ impl Cpu {
             }
 
             // Interrupt Enable Register
-            0xFFFF => {
-                self.interrupt_enable_register = BitFlags::from_bits_truncate(val);
-                self.interrupt_enable_unused_bits = val & 0b1110_0000;
-            }
+            0xFFFF => self.interrupts.write_enable(val),
+        }
+    }
+
+    /// Check the invariants the emulator relies on internally, for use in `--paranoid` mode.
+    /// Panics with a dump of the emulator state if any are violated.
+    fn check_invariants(&self, addr: u16) {
+        let result = self.cart.check_invariants()
+            .and_then(|()| self.gpu.check_invariants())
+            .and_then(|()| self.timer.check_invariants());
+
+        if let Err(violation) = result {
+            eprintln!("Paranoid check failed while accessing 0x{:04X}: {}", addr, violation);
+            eprintln!("Cycles executed: {}", self.cycles);
+            eprintln!("Current opcode: 0x{:02X}", self.current_opcode);
+            self.print_regs();
+            panic!("paranoid check failed: {}", violation);
         }
     }
 
@@ -970,17 +1616,18 @@ impl Cpu {
 
     fn read_io_port(&self, port: u8) -> u8 {
         match port {
-            0x00 => self.joypad.read_reg(),
-            0x01...0x02 => {
-                warn!("unimplemented read from serial I/O FF{:02X}", port);
-                0xFF
-            }
+            0x00 => self.joypad.read_reg(port),
+            0x01...0x02 => self.serial.read_reg(port),
             0x04...0x07 => self.timer.read_reg(port),
-            // The top 3 bits are unused and always 1.
-            0x0F => 0b1110_0000 | self.interrupt_flags_register.bits(),
+            0x0F => self.interrupts.read_flags(),
             0x10...0x14 | 0x16...0x19 | 0x1A...0x1E | 0x20...0x26 | 0x30...0x3F =>
                 self.audio.read_reg(port),
             0x40...0x45 | 0x47...0x4B => self.gpu.read_reg(port),
+            // KEY1, the Game Boy Color's speed switch. Unused bits read as 1.
+            0x4D if self.model == Model::Cgb =>
+                (self.double_speed as u8) << 7 | 0b0111_1110 | self.speed_switch_armed as u8,
+            // PCM12 and PCM34, read-only.
+            0x76...0x77 if self.model == Model::Cgb => self.audio.read_pcm(port),
 
             // Cannot read from DMA transfer register.
             // TODO(solson): Should it return 0xFF like most other inaccessible registers?
@@ -995,26 +1642,37 @@ impl Cpu {
 
     fn write_io_port(&mut self, port: u8, val: u8) {
         match port {
-            0x00 => self.joypad.write_reg(val),
-            0x01...0x02 => warn!("unimplemented write to serial I/O port FF{:02X}", port),
-            0x04...0x07 => self.timer.write_reg(port, val),
-            0x0F => self.interrupt_flags_register = BitFlags::from_bits_truncate(val),
+            0x00 => self.joypad.write_reg(port, val),
+            0x01...0x02 => self.serial.write_reg(port, val),
+            // Writing any value resets DIV.
+            0x04 => self.reset_divider(),
+            0x05...0x07 => self.timer.write_reg(port, val),
+            0x0F => self.interrupts.write_flags(val),
             0x10...0x14 | 0x16...0x19 | 0x1A...0x1E | 0x20...0x26 | 0x30...0x3F =>
                 self.audio.write_reg(port, val),
             0x40...0x45 | 0x47...0x4B => self.gpu.write_reg(port, val),
+            0x4D if self.model == Model::Cgb => self.speed_switch_armed = val & 1 != 0,
 
             // DMA Transfer - Takes 160 microseconds to complete. During this time, only HRAM can
-            // be accessed.
-            // TODO(solson): Actually implement the HRAM memory restriction, and make OAM happen
-            // over time rather than in a single instant.
+            // be accessed. The copy happens at once, and strict mode flags the accesses the
+            // transfer would have blocked.
             0x46 => {
                 info!("DMA TRANSFER START");
                 let start_addr: u16 = val as u16 * 0x100; // Addresses are from 0xXX00 - 0xXX9F
+                self.dma_cycles_left = 0;
+                // DMA reads and writes memory itself, not through the CPU, so the LCD mode doesn't
+                // block it and the reads aren't the game's for strict mode or the heatmap.
                 for i in 0..0xA0 {
-                    self.write_mem(0xFE00 + i, self.read_mem(start_addr + i))
+                    let val = self.peek(start_addr + i);
+                    self.gpu.write_sprite_ram(i as usize, val);
                 }
+                // A setup cycle, and one per byte.
+                self.dma_cycles_left = (1 + 0xA0) * 4;
             }
 
+            // Not a real Game Boy register, but homebrew can print through it in debug mode.
+            0x7F if self.debug_register => serial::print_debug_byte(val),
+
             // Unmapped I/O ports always ignore writes.
             0x03 | 0x08...0x0E | 0x15 | 0x1F | 0x27...0x2F | 0x4C...0x7F => {}
 
@@ -1142,3 +1800,113 @@ fn get_sub_half_carry(left: u8, right: u8) -> bool {
 fn get_add_half_carry_high(left: u16, right: u16) -> bool {
     (left & 0xFFF) + (right & 0xFFF) > 0xFFF
 }
+
+/// What `Cpu::state_info` finds out about a savestate.
+pub struct StateInfo {
+    pub header: savestate::StateHeader,
+
+    /// The title from the game's header. States saved before it was recorded don't have it.
+    pub title: Option<String>,
+
+    /// The build of Rugby that made the state, see `version::BUILD`. Older states don't have it.
+    pub build: Option<String>,
+
+    /// How many cycles the game had run for.
+    pub cycles: usize,
+
+    /// The GPU as saved, with the last frame drawn in its screen buffer.
+    pub gpu: Gpu,
+}
+
+/// The game's title, saved so tools can tell what a state is for without the ROM.
+struct RomTitle(Vec<u8>);
+
+impl SaveState for RomTitle {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.0);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.0 = r.bytes()?.to_vec();
+        Ok(())
+    }
+}
+
+/// The build of Rugby that made a state, so loading it in another build can warn that emulation may
+/// differ.
+struct Build(Vec<u8>);
+
+impl Build {
+    fn read(chunks: &StateChunks) -> Result<Option<String>, SaveStateError> {
+        if !chunks.contains(b"BILD") {
+            return Ok(None);
+        }
+        let mut build = Build(Vec::new());
+        chunks.load(b"BILD", &mut build)?;
+        Ok(Some(String::from_utf8_lossy(&build.0).into_owned()))
+    }
+}
+
+impl SaveState for Build {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.0);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.0 = r.bytes()?.to_vec();
+        Ok(())
+    }
+}
+
+/// The "CPU " savestate chunk holds the CPU core only. The components it owns are saved in their
+/// own chunks by `Cpu::save_state`.
+impl SaveState for Cpu {
+    fn save(&self, w: &mut StateWriter) {
+        for &reg in &[Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP, Reg16::PC] {
+            w.u16(self.regs.get_16(reg));
+        }
+        w.bytes(&self.work_ram);
+        w.bytes(&self.high_ram);
+        w.u8(self.current_opcode);
+        w.usize(self.cycles);
+        w.bool(self.interrupts_enabled);
+        w.bool(self.pending_disable_interrupts);
+        w.bool(self.pending_enable_interrupts);
+        w.u8(self.interrupts.requested().bits());
+        w.u8(self.interrupts.read_enable());
+        w.bool(self.halted);
+        w.bool(self.stopped);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch_armed);
+        w.usize(self.speed_switch_cycles);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        for &reg in &[Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP, Reg16::PC] {
+            let val = r.u16()?;
+            self.regs.set_16(reg, val);
+        }
+        r.bytes_into(&mut self.work_ram)?;
+        r.bytes_into(&mut self.high_ram)?;
+        // Savestates don't record which RAM was written, so treat all of it as initialized.
+        for written in self.work_ram_written.iter_mut().chain(self.high_ram_written.iter_mut()) {
+            *written = true;
+        }
+        self.current_opcode = r.u8()?;
+        self.cycles = r.usize()?;
+        self.interrupts_enabled = r.bool()?;
+        self.pending_disable_interrupts = r.bool()?;
+        self.pending_enable_interrupts = r.bool()?;
+        self.interrupts.write_flags(r.u8()?);
+        self.interrupts.write_enable(r.u8()?);
+        self.halted = r.bool()?;
+        self.stopped = r.bool()?;
+        self.double_speed = r.bool()?;
+        self.speed_switch_armed = r.bool()?;
+        self.speed_switch_cycles = r.usize()?;
+        if self.speed_switch_cycles > SPEED_SWITCH_CYCLES {
+            return Err(SaveStateError::InvalidValue("speed switch cycles"));
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use enumflags2::BitFlags;
 use enumflags2_derive::EnumFlags;
+use super::Model;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum Reg8 { A, B, C, D, E, H, L }
@@ -9,7 +10,7 @@ pub enum Reg8 { A, B, C, D, E, H, L }
 pub enum Reg16 { AF, BC, DE, HL, SP, PC }
 
 /// Game Boy CPU flags, as stored in `F`, the flags register.
-#[derive(Clone, Copy, Debug, EnumFlags)]
+#[derive(Clone, Copy, Debug, EnumFlags, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Flag {
     Carry     = 1 << 4,
@@ -18,6 +19,56 @@ pub enum Flag {
     Zero      = 1 << 7,
 }
 
+/// A typed view of `F`, the flags register. Only the top 4 bits exist; the bottom 4 always read
+/// as 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Flags(BitFlags<Flag>);
+
+impl Flags {
+    /// Build flags from the raw contents of `F`, dropping the nonexistent low bits.
+    pub fn from_bits(bits: u8) -> Self {
+        Flags(BitFlags::from_bits_truncate(bits))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0.bits()
+    }
+
+    pub fn get(self, flag: Flag) -> bool {
+        self.0.contains(flag)
+    }
+
+    pub fn set(&mut self, flag: Flag, val: bool) {
+        if val {
+            self.0.insert(flag);
+        } else {
+            self.0.remove(flag);
+        }
+    }
+
+    /// Iterate over the flags which are set.
+    pub fn iter(self) -> impl Iterator<Item = Flag> {
+        self.0.iter()
+    }
+
+    /// The `Z` flag, set when a result is zero.
+    pub fn zero(self) -> bool { self.get(Flag::Zero) }
+
+    /// The `N` flag, set when the last arithmetic operation was a subtraction.
+    pub fn sub(self) -> bool { self.get(Flag::Sub) }
+
+    /// The `H` flag, set on a carry out of (or borrow into) bit 3.
+    pub fn half_carry(self) -> bool { self.get(Flag::HalfCarry) }
+
+    /// The `C` flag, set on a carry out of (or borrow into) the top bit.
+    pub fn carry(self) -> bool { self.get(Flag::Carry) }
+
+    pub fn set_zero(&mut self, val: bool) { self.set(Flag::Zero, val) }
+    pub fn set_sub(&mut self, val: bool) { self.set(Flag::Sub, val) }
+    pub fn set_half_carry(&mut self, val: bool) { self.set(Flag::HalfCarry, val) }
+    pub fn set_carry(&mut self, val: bool) { self.set(Flag::Carry, val) }
+}
+
 /// Represents a 16-bit register in the Game Boy CPU.
 #[derive(Clone, Copy, Debug)]
 pub struct Register(u16);
@@ -73,7 +124,7 @@ pub struct Registers {
     pub a: u8,
 
     /// Register `F`, the low half of `AF`, also known as the flags register.
-    pub f: BitFlags<Flag>,
+    pub f: Flags,
 
     /// Register `BC`. Also accessible in 8-bit halves, `B` (high) and `C` (low).
     pub bc: Register,
@@ -93,10 +144,28 @@ pub struct Registers {
 }
 
 impl Registers {
+    /// The registers as the DMG boot ROM leaves them.
     pub fn new() -> Self {
+        Self::after_boot(Model::Dmg)
+    }
+
+    /// The registers as the given model's boot ROM leaves them. Games tell a Game Boy Color apart
+    /// by `A` being 0x11.
+    pub fn after_boot(model: Model) -> Self {
+        if model == Model::Cgb {
+            return Self {
+                a: 0x11,
+                f: Flags::from_bits(0x80),
+                bc: Register(0x0000),
+                de: Register(0xFF56),
+                hl: Register(0x000D),
+                sp: Register(0xFFFE),
+                pc: Register(0x0100),
+            };
+        }
         Self {
             a: 0x01,
-            f: Flag::Carry | Flag::HalfCarry | Flag::Zero,
+            f: Flags(Flag::Carry | Flag::HalfCarry | Flag::Zero),
             bc: Register(0x0013),
             de: Register(0x00D8),
             hl: Register(0x014D),
@@ -134,7 +203,7 @@ impl Registers {
             Reg16::AF => {
                 let [low, high] = val.to_le_bytes();
                 self.a = high;
-                self.f = BitFlags::from_bits_truncate(low);
+                self.f = Flags::from_bits(low);
             }
             Reg16::BC => self.bc.set(val),
             Reg16::DE => self.de.set(val),
@@ -155,3 +224,20 @@ impl Registers {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    quickcheck! {
+        fn flags_view(bits: u8) -> bool {
+            let flags = Flags::from_bits(bits);
+            flags.bits() == bits & 0xF0
+                && flags.zero() == (bits & 0x80 != 0)
+                && flags.sub() == (bits & 0x40 != 0)
+                && flags.half_carry() == (bits & 0x20 != 0)
+                && flags.carry() == (bits & 0x10 != 0)
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Human-readable dumps of the complete emulator state, meant to be attached to bug reports.
+
+use crate::checksum::crc32;
+use crate::gpu::CYCLES_PER_FRAME;
+use crate::interrupts::Interrupt;
+use std::io::{self, Write};
+use super::{Cpu, Reg16};
+
+/// The named I/O registers in the 0xFF00-0xFF7F range, plus IE at 0xFFFF.
+const IO_REGISTERS: &[(u16, &str)] = &[
+    (0xFF00, "P1/JOYP"),
+    (0xFF01, "SB"),
+    (0xFF02, "SC"),
+    (0xFF04, "DIV"),
+    (0xFF05, "TIMA"),
+    (0xFF06, "TMA"),
+    (0xFF07, "TAC"),
+    (0xFF0F, "IF"),
+    (0xFF10, "NR10"),
+    (0xFF11, "NR11"),
+    (0xFF12, "NR12"),
+    (0xFF13, "NR13"),
+    (0xFF14, "NR14"),
+    (0xFF16, "NR21"),
+    (0xFF17, "NR22"),
+    (0xFF18, "NR23"),
+    (0xFF19, "NR24"),
+    (0xFF1A, "NR30"),
+    (0xFF1B, "NR31"),
+    (0xFF1C, "NR32"),
+    (0xFF1D, "NR33"),
+    (0xFF1E, "NR34"),
+    (0xFF20, "NR41"),
+    (0xFF21, "NR42"),
+    (0xFF22, "NR43"),
+    (0xFF23, "NR44"),
+    (0xFF24, "NR50"),
+    (0xFF25, "NR51"),
+    (0xFF26, "NR52"),
+    (0xFF40, "LCDC"),
+    (0xFF41, "STAT"),
+    (0xFF42, "SCY"),
+    (0xFF43, "SCX"),
+    (0xFF44, "LY"),
+    (0xFF45, "LYC"),
+    (0xFF46, "DMA"),
+    (0xFF47, "BGP"),
+    (0xFF48, "OBP0"),
+    (0xFF49, "OBP1"),
+    (0xFF4A, "WY"),
+    (0xFF4B, "WX"),
+    (0xFFFF, "IE"),
+];
+
+impl Cpu {
+    /// Write a report of the complete emulator state: registers, decoded I/O registers, mapper
+    /// state, timers, interrupt state, and checksums of each memory region.
+    pub fn write_state_report(&self, out: impl Write) -> io::Result<()> {
+        let mut out = tabwriter::TabWriter::new(out);
+
+        writeln!(out, "== CPU ==")?;
+        writeln!(out, "Cycles:\t{}", self.cycles)?;
+        for &reg in &[Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP, Reg16::PC] {
+            writeln!(out, "{:?}:\t0x{:04X}", reg, self.regs.get_16(reg))?;
+        }
+        writeln!(out, "Flags:\t{:?}", self.regs.f.iter().collect::<Vec<_>>())?;
+        writeln!(out, "Opcode at PC:\t0x{:02X}", self.peek(self.regs.pc.get()))?;
+        writeln!(out, "Halted:\t{}", self.halted)?;
+        writeln!(out, "Stopped:\t{}", self.stopped)?;
+        writeln!(out, "Double speed:\t{}", self.double_speed)?;
+        writeln!(out)?;
+
+        writeln!(out, "== Interrupts ==")?;
+        writeln!(out, "IME:\t{}", self.interrupts_enabled)?;
+        writeln!(out, "Pending EI:\t{}", self.pending_enable_interrupts)?;
+        writeln!(out, "Pending DI:\t{}", self.pending_disable_interrupts)?;
+        writeln!(out, "Requested (IF):\t{}", interrupt_list(self.interrupts.requested()))?;
+        writeln!(out, "Enabled (IE):\t{}", interrupt_list(self.interrupts.enabled()))?;
+        writeln!(out)?;
+
+        writeln!(out, "== Timer ==")?;
+        let tac = self.peek(0xFF07);
+        writeln!(out, "DIV:\t0x{:02X}", self.peek(0xFF04))?;
+        writeln!(out, "TIMA:\t0x{:02X}", self.peek(0xFF05))?;
+        writeln!(out, "TMA:\t0x{:02X}", self.peek(0xFF06))?;
+        writeln!(out, "Running:\t{}", tac & 0b100 != 0)?;
+        writeln!(out, "Frequency:\t{} Hz", [4096, 262_144, 65_536, 16_384][(tac & 0b11) as usize])?;
+        writeln!(out)?;
+
+        writeln!(out, "== LCD ==")?;
+        let lcdc = self.peek(0xFF40);
+        let stat = self.peek(0xFF41);
+        writeln!(out, "Display enabled:\t{}", lcdc & (1 << 7) != 0)?;
+        writeln!(out, "Window tile map:\t{}", if lcdc & (1 << 6) != 0 { "9C00" } else { "9800" })?;
+        writeln!(out, "Window enabled:\t{}", lcdc & (1 << 5) != 0)?;
+        writeln!(out, "BG/window tile data:\t{}", if lcdc & (1 << 4) != 0 { "8000" } else { "8800" })?;
+        writeln!(out, "BG tile map:\t{}", if lcdc & (1 << 3) != 0 { "9C00" } else { "9800" })?;
+        writeln!(out, "Sprite size:\t{}", if lcdc & (1 << 2) != 0 { "8x16" } else { "8x8" })?;
+        writeln!(out, "Sprites enabled:\t{}", lcdc & (1 << 1) != 0)?;
+        writeln!(out, "BG enabled:\t{}", lcdc & 1 != 0)?;
+        writeln!(out, "Mode:\t{}", stat & 0b11)?;
+        writeln!(out, "LY:\t{}", self.peek(0xFF44))?;
+        writeln!(out, "LYC:\t{}", self.peek(0xFF45))?;
+        writeln!(out)?;
+
+        writeln!(out, "== Cartridge ==")?;
+        write!(out, "{}", self.cart.debug_state())?;
+        writeln!(out)?;
+
+        writeln!(out, "== I/O registers ==")?;
+        for &(addr, name) in IO_REGISTERS {
+            let val = self.peek(addr);
+            writeln!(out, "{:04X} {}:\t0x{:02X}\t{:08b}", addr, name, val, val)?;
+        }
+        writeln!(out)?;
+
+        writeln!(out, "== Memory checksums (CRC-32) ==")?;
+        writeln!(out, "ROM:\t{:08X}\t{} bytes", crc32(self.cart.rom()), self.cart.rom().len())?;
+        writeln!(out, "Cartridge RAM:\t{:08X}\t{} bytes", crc32(self.cart.ram()), self.cart.ram().len())?;
+        writeln!(out, "VRAM:\t{:08X}", crc32(self.gpu.vram()))?;
+        writeln!(out, "Work RAM:\t{:08X}", crc32(&self.work_ram))?;
+        writeln!(out, "OAM:\t{:08X}", crc32(self.gpu.oam()))?;
+        writeln!(out, "High RAM:\t{:08X}", crc32(&self.high_ram))?;
+
+        out.flush()
+    }
+}
+
+impl Cpu {
+    /// Write a one-screen summary of where the emulator is: the frame, PC and its bank, the LCD
+    /// mode, pending interrupts, the timer, and the mapper's banks.
+    pub fn write_status(&self, out: impl Write) -> io::Result<()> {
+        let mut out = tabwriter::TabWriter::new(out);
+        let mbc = self.cart.debug_state();
+
+        let frame = self.cycles / CYCLES_PER_FRAME;
+        writeln!(out, "Frame:	{} (+{} cycles)	Cycles:	{}", frame, self.cycles % CYCLES_PER_FRAME, self.cycles)?;
+
+        let pc = self.regs.pc.get();
+        let location = match pc {
+            0x0000...0x3FFF => String::from("ROM bank 0x00"),
+            0x4000...0x7FFF => format!("ROM bank 0x{:02X}", mbc.rom_bank),
+            0x8000...0x9FFF => String::from("VRAM"),
+            0xA000...0xBFFF => format!("cartridge RAM bank 0x{:02X}", mbc.ram_bank),
+            0xC000...0xFDFF => String::from("work RAM"),
+            0xFF80...0xFFFE => String::from("high RAM"),
+            _ => String::from("I/O"),
+        };
+        let state = if self.stopped { ", stopped" } else if self.halted { ", halted" } else { "" };
+        writeln!(out, "PC:	0x{:04X} in {}{}	SP:	0x{:04X}", pc, location, state, self.regs.sp.get())?;
+
+        let stat = self.peek(0xFF41);
+        let mode = ["HBlank", "VBlank", "OAM search", "drawing"][(stat & 0b11) as usize];
+        let lcd_on = self.peek(0xFF40) & (1 << 7) != 0;
+        let lcd = if lcd_on { mode } else { "off" };
+        writeln!(out, "LCD:	{}	LY:	{} (LYC {})", lcd, self.peek(0xFF44), self.peek(0xFF45))?;
+
+        let pending = self.interrupts.requested() & self.interrupts.enabled();
+        let ime = if self.interrupts_enabled { "on" } else { "off" };
+        writeln!(out, "Interrupts:	{} pending	IME:	{}", interrupt_list(pending), ime)?;
+
+        let tac = self.peek(0xFF07);
+        let timer = if tac & 0b100 != 0 {
+            format!("{} Hz", [4096, 262_144, 65_536, 16_384][(tac & 0b11) as usize])
+        } else {
+            String::from("stopped")
+        };
+        writeln!(out, "Timer:	{}	TIMA:	0x{:02X} (TMA 0x{:02X})", timer, self.peek(0xFF05), self.peek(0xFF06))?;
+
+        let ram = if mbc.ram_enabled { "enabled" } else { "disabled" };
+        writeln!(out, "Mapper:	{}	Banks:	ROM 0x{:02X}, RAM 0x{:02X} ({})", mbc.mapper, mbc.rom_bank, mbc.ram_bank, ram)?;
+
+        out.flush()
+    }
+}
+
+fn interrupt_list(flags: enumflags2::BitFlags<Interrupt>) -> String {
+    let names: Vec<String> = flags.iter().map(|i| format!("{:?}", i)).collect();
+    if names.is_empty() { String::from("none") } else { names.join(", ") }
+}
@@ -226,3 +226,235 @@ cpu_tests! {
         ],
     }
 }
+
+quickcheck! {
+    fn test_savestate_round_trip(bc: u16, de: u16, hl: u16, sp: u16, addr: u16, val: u8) -> TestResult {
+        let (mut original, _) = setup(vec![0x00]);
+        original.regs.set_16(Reg16::BC, bc);
+        original.regs.set_16(Reg16::DE, de);
+        original.regs.set_16(Reg16::HL, hl);
+        original.regs.set_16(Reg16::SP, sp);
+        original.work_ram[addr as usize % WORK_RAM_SIZE] = val;
+        original.gpu.write_vram(addr as usize % 0x2000, val);
+        let state = original.save_state();
+
+        let (mut loaded, _) = setup(vec![0x00]);
+        loaded.load_state(&state).unwrap();
+        if loaded.save_state() != state {
+            return TestResult::error("savestate changed after loading it");
+        }
+        check_diff(&loaded, &original)
+    }
+    fn test_savestate_rejects_corruption(index: usize, mask: u8) -> TestResult {
+        if mask == 0 {
+            return TestResult::discard();
+        }
+        let (original, _) = setup(vec![0x00]);
+        let mut state = original.save_state();
+        let index = index % state.len();
+        state[index] ^= mask;
+
+        let (mut loaded, _) = setup(vec![0x00]);
+        TestResult::from_bool(loaded.load_state(&state).is_err())
+    }
+
+    fn test_run_cycles_exact(nops: u8, split: u8) -> bool {
+        // A ROM of NOPs, which take one machine cycle each, then `ld bc, 0`, which takes three.
+        let nops = nops as usize % 64;
+        let mut rom = vec![0x00; nops];
+        rom.extend_from_slice(&[0x01, 0x00, 0x00]);
+        let (mut cpu, _) = setup(rom);
+        let split = split as usize % (nops + 1);
+        let exact = cpu.run_cycles_exact(split) == 0 && cpu.run_cycles_exact(nops - split) == 0;
+        exact && cpu.cycles() == nops * 4 && cpu.run_cycles_exact(1) == 2
+    }
+}
+
+#[test]
+fn test_savestate_rejects_states_that_unpack_too_large() {
+    use crate::checksum::crc32;
+    let (mut cpu, _) = setup(vec![0x00]);
+    for &(size, fits) in &[(savestate::MAX_UNPACKED_SIZE, true), (savestate::MAX_UNPACKED_SIZE + 1, false)] {
+        let compressed = zstd::encode_all(&vec![0; size][..], 3).unwrap();
+        let mut state = b"RUGBYSAV\x02".to_vec();
+        state.extend_from_slice(&crc32(cpu.cart.rom()).to_le_bytes());
+        state.extend_from_slice(&crc32(&compressed).to_le_bytes());
+        state.extend_from_slice(&compressed);
+        let too_large = cpu.load_state(&state) == Err(SaveStateError::TooLarge(savestate::MAX_UNPACKED_SIZE));
+        assert_eq!(too_large, !fits, "{} bytes", size);
+    }
+}
+
+#[test]
+fn test_savestate_rejects_version_1() {
+    // Version 1 had the chunks uncompressed right after the version, and never shipped.
+    let (mut cpu, _) = setup(vec![0x00]);
+    let mut state = b"RUGBYSAV\x01".to_vec();
+    state.extend_from_slice(&savestate::unpack(&cpu.save_state(), cpu.cart.rom()).unwrap());
+    assert_eq!(cpu.load_state(&state), Err(SaveStateError::UnsupportedVersion(1)));
+}
+
+#[test]
+#[cfg(feature = "instrumentation")]
+fn test_game_shark_writes_skip_the_heatmap() {
+    use crate::cheat::CheatCode;
+    use crate::heatmap::Heatmap;
+    // jr -2, forever.
+    let (mut cpu, _) = setup(vec![0x18, 0xFE]);
+    cpu.cheats.push("014200C0".parse::<CheatCode>().unwrap());
+    cpu.heatmap = Some(Heatmap::new());
+    cpu.run_frame();
+    cpu.run_frame();
+    assert_eq!(cpu.peek(0xC000), 0x42);
+    assert_eq!(cpu.heatmap.as_ref().unwrap().intensity(0xC000).1, 0);
+}
+
+#[test]
+#[cfg(feature = "instrumentation")]
+fn test_irq_stats_forget_requests_from_before_a_load() {
+    use crate::interrupts::Interrupt;
+    let (mut cpu, _) = setup(vec![0x00; 0x100]);
+    let state = cpu.save_state();
+    cpu.irq_stats = Some(crate::irq_stats::IrqStats::new());
+    cpu.cycles = 1_000_000;
+    cpu.interrupts.request(Interrupt::VBlank);
+    cpu.step(false, false, &HashSet::new());
+
+    // Back to cycle 0, where the interrupt is requested again and serviced right away.
+    cpu.load_state(&state).unwrap();
+    cpu.interrupts.write_enable(1);
+    cpu.interrupts.request(Interrupt::VBlank);
+    cpu.interrupts_enabled = true;
+    cpu.step(false, false, &HashSet::new());
+
+    let mut report = Vec::new();
+    cpu.irq_stats.unwrap().write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    let vblank: Vec<_> = report.lines().nth(1).unwrap().split_whitespace().collect();
+    assert_eq!(vblank[..3], ["VBlank", "1", "0"], "{}", report);
+}
+
+#[cfg(feature = "opcode-hooks")]
+quickcheck! {
+    fn test_opcode_hook(a: u8, operand: u8) -> TestResult {
+        // A made-up `add a, d8` on 0xED.
+        fn add_immediate(cpu: &mut Cpu, _opcode: u8) -> usize {
+            let pc = cpu.regs().pc.get();
+            let operand = cpu.read_mem_debug(pc);
+            let a = cpu.regs().get_8(Reg8::A);
+            cpu.regs_mut().set_8(Reg8::A, a.wrapping_add(operand));
+            cpu.regs_mut().pc.set(pc + 1);
+            8
+        }
+        let (mut actual, mut expected) = setup(vec![0xED, operand]);
+        actual.set_opcode_hook(0xED, Some(add_immediate));
+        actual.regs.set_8(Reg8::A, a);
+        expected.regs.set_8(Reg8::A, a.wrapping_add(operand));
+        expected.cycles = 8;
+        actual.step(false, false, &HashSet::new());
+        check_diff(&actual, &expected)
+    }
+}
+
+#[test]
+fn test_wave_ram_access_only_right_after_a_fetch() {
+    let (mut cpu, _) = setup(vec![0x00; 0x8000]);
+    cpu.write_mem(0xFF26, 0x80);
+    for addr in 0xFF30..=0xFF3F {
+        cpu.write_mem(addr, 0x12);
+    }
+    // Play channel 3 at its highest frequency, which fetches a byte every 2 cycles.
+    cpu.write_mem(0xFF1A, 0x80);
+    cpu.write_mem(0xFF1C, 0x20);
+    cpu.write_mem(0xFF1D, 0xFF);
+    cpu.write_mem(0xFF1E, 0x87);
+    cpu.step_cycles(100, None, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF30), 0x12);
+    // Debugger steps don't run the APU, so there's no fetch to open wave RAM.
+    cpu.step_n(1, &HashSet::new());
+    assert_eq!(cpu.read_mem(0xFF30), 0xFF);
+}
+
+#[test]
+fn test_savestate_keeps_serial_device() {
+    use crate::barcode_boy::BarcodeBoy;
+    use crate::serial::SerialDevice;
+    use crate::workboy::WorkBoy;
+
+    let (mut cpu, _) = setup(vec![0x00; 0x8000]);
+    let mut scanner = BarcodeBoy::new();
+    scanner.scan("490000000000").unwrap();
+    assert_eq!(scanner.clock_in(0), Some(0x02));
+    cpu.serial.device = Some(Box::new(scanner));
+    let state = cpu.save_state();
+
+    // The scanner picks up where it was, one byte into the barcode.
+    cpu.serial.device = Some(Box::new(BarcodeBoy::new()));
+    cpu.load_state(&state).unwrap();
+    let scanner = cpu.serial.device_mut::<BarcodeBoy>().unwrap();
+    scanner.step(8192);
+    assert_eq!(scanner.clock_in(0), Some(b'4'));
+
+    // Another kind of device, or none, is left as it is.
+    cpu.serial.device = Some(Box::new(WorkBoy::new(0)));
+    cpu.load_state(&state).unwrap();
+    assert!(cpu.serial.device_mut::<WorkBoy>().is_some());
+    cpu.serial.device = None;
+    cpu.load_state(&state).unwrap();
+    assert!(cpu.serial.device.is_none());
+}
+
+#[test]
+fn test_channel_registers_recreate_the_apu() {
+    let (mut cpu, _) = setup(vec![0x00; 0x8000]);
+    let writes: [(u8, u8); 18] = [
+        (0x10, 0x35), (0x11, 0x9C), (0x12, 0xF3), (0x13, 0x42), (0x14, 0xC5),
+        (0x16, 0x50), (0x17, 0x88), (0x18, 0x21), (0x19, 0x03),
+        (0x1A, 0x80), (0x1B, 0x40), (0x1C, 0x40), (0x1D, 0xAB), (0x1E, 0x46),
+        (0x20, 0x10), (0x21, 0x71), (0x22, 0x5D), (0x23, 0x40),
+    ];
+    for &(addr, val) in &writes {
+        cpu.write_mem(0xFF00 | u16::from(addr), val);
+    }
+    let regs = cpu.audio.channel_registers();
+    let expected: Vec<_> = writes.iter().map(|&(addr, val)| (addr, if addr == 0x14 { val & 0x7F } else { val })).collect();
+    assert_eq!(regs, expected);
+
+    let mut copy = Audio::new();
+    for &(addr, val) in &regs {
+        copy.write_reg(addr, val);
+    }
+    assert_eq!(copy.channel_registers(), regs);
+}
+
+#[test]
+fn test_savestate_keeps_pcm_outputs() {
+    let (mut cpu, _) = setup(vec![0x00; 0x8000]);
+    // Channel 2 at full volume, and channel 4's noise.
+    cpu.write_mem(0xFF17, 0xF0);
+    cpu.write_mem(0xFF19, 0x87);
+    cpu.write_mem(0xFF21, 0xF0);
+    cpu.write_mem(0xFF23, 0x80);
+    cpu.step_cycles(1000, None, &HashSet::new());
+    let pcm = (cpu.audio.read_pcm(0x76), cpu.audio.read_pcm(0x77));
+    assert_ne!(pcm, (0, 0));
+    let state = cpu.save_state();
+
+    let (mut loaded, _) = setup(vec![0x00; 0x8000]);
+    loaded.load_state(&state).unwrap();
+    assert_eq!((loaded.audio.read_pcm(0x76), loaded.audio.read_pcm(0x77)), pcm);
+}
+
+#[test]
+fn test_oam_dma_is_not_a_strict_violation_during_mode_3() {
+    let (mut cpu, _) = setup(vec![0x00; 0x8000]);
+    while !cpu.gpu.vram_blocked() {
+        cpu.step_n(1, &HashSet::new());
+    }
+    cpu.strict = true;
+    cpu.write_mem(0xFF46, 0x00);
+    assert_eq!(cpu.strict_violation.get_mut().take(), None);
+    // Copying from work RAM the game never wrote isn't the game reading it.
+    cpu.write_mem(0xFF46, 0xC0);
+    assert_eq!(cpu.strict_violation.get_mut().take(), None);
+}
@@ -0,0 +1,192 @@
+//! `rugby doctor`, which checks everything Rugby needs from the system before a game can start
+//! and explains how to fix what's missing. Most startup crashes come down to one of these, and the
+//! panic messages from deep inside SDL rarely say what to do about them.
+
+use crate::config::Config;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+struct Check {
+    status: Status,
+    name: String,
+    detail: String,
+
+    /// What to do about a warning or error.
+    hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Check { status: Status::Ok, name: name.into(), detail: detail.into(), hint: None }
+    }
+
+    fn warning(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check { status: Status::Warning, name: name.into(), detail: detail.into(), hint: Some(hint.into()) }
+    }
+
+    fn error(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check { status: Status::Error, name: name.into(), detail: detail.into(), hint: Some(hint.into()) }
+    }
+}
+
+/// Run every check and print the results. Returns whether anything would stop Rugby from running.
+pub fn run(out: impl Write) -> io::Result<bool> {
+    let mut checks = vec![check_config(), check_data_dir()];
+    check_sdl(&mut checks);
+
+    let mut out = tabwriter::TabWriter::new(out);
+    for check in &checks {
+        let status = match check.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        };
+        writeln!(out, "{}\t{}\t{}", status, check.name, check.detail)?;
+        if let Some(hint) = &check.hint {
+            writeln!(out, "\t\t-> {}", hint)?;
+        }
+    }
+    out.flush()?;
+    Ok(checks.iter().any(|check| check.status == Status::Error))
+}
+
+fn check_config() -> Check {
+    let path = match Config::path() {
+        Some(path) => path,
+        None => return Check::ok("Config file", "no config directory, using the defaults"),
+    };
+    if !path.exists() {
+        return Check::ok("Config file", format!("{} doesn't exist, using the defaults", path.display()));
+    }
+    match Config::load() {
+        Ok(_) => Check::ok("Config file", path.display().to_string()),
+        Err(e) => {
+            let causes: Vec<_> = e.iter_chain().map(|cause| cause.to_string()).collect();
+            Check::error(
+                "Config file",
+                causes.join(": "),
+                "Fix the setting named above, or move the file away to go back to the defaults",
+            )
+        }
+    }
+}
+
+/// The data directory holds the sessions for `run --continue`. Everything else Rugby writes goes
+/// somewhere the user chose: saves and their `.rtc` sidecars next to the ROM or in the configured
+/// save directory, and repro bundles and other exports at the path given on the command line.
+fn check_data_dir() -> Check {
+    let dir = match crate::session::Session::path(Path::new("doctor")) {
+        Some(path) => path.parent().unwrap().to_path_buf(),
+        None => return Check::warning(
+            "Data directory",
+            "couldn't find a data directory",
+            "Set HOME (or XDG_DATA_HOME) so `run --continue` has somewhere to save sessions",
+        ),
+    };
+    let probe = dir.join(".rugby-doctor");
+    let result = std::fs::create_dir_all(&dir)
+        .and_then(|()| std::fs::write(&probe, b"ok"))
+        .and_then(|()| std::fs::remove_file(&probe));
+    match result {
+        Ok(()) => Check::ok("Data directory", dir.display().to_string()),
+        Err(e) => Check::warning(
+            "Data directory",
+            format!("{} isn't writable: {}", dir.display(), e),
+            "Fix the directory's permissions, or `run --continue` won't be able to save sessions",
+        ),
+    }
+}
+
+fn check_sdl(checks: &mut Vec<Check>) {
+    let sdl = match sdl2::init() {
+        Ok(sdl) => sdl,
+        Err(e) => {
+            checks.push(Check::error("SDL", e, "Install the SDL2 runtime library (e.g. libsdl2-2.0-0)"));
+            return;
+        }
+    };
+    let version = sdl2::version::version();
+    checks.push(Check::ok("SDL", format!("version {}", version)));
+
+    checks.push(match sdl.video() {
+        Ok(video) => match video.num_video_displays() {
+            Ok(n) if n > 0 => Check::ok("Video", format!("{} driver, {} display(s)", video.current_video_driver(), n)),
+            Ok(_) | Err(_) => Check::error(
+                "Video",
+                format!("{} driver, but no displays", video.current_video_driver()),
+                "Run Rugby inside a desktop session, or use `run --headless` or `serve`",
+            ),
+        },
+        Err(e) => Check::error(
+            "Video",
+            e,
+            "Run Rugby inside a desktop session, or pick a driver with SDL_VIDEODRIVER (e.g. x11 or wayland)",
+        ),
+    });
+
+    checks.push(match sdl.audio() {
+        Ok(audio) => {
+            // A broken config file is reported by `check_config`.
+            let desired_spec = Config::load().unwrap_or_default().audio.desired_spec();
+            match audio.open_queue::<u8, _>(None, &desired_spec) {
+                Ok(queue) => {
+                    let spec = queue.spec();
+                    Check::ok(
+                        "Audio device",
+                        format!(
+                            "{} driver, {} Hz, {} channel(s), {} sample buffer",
+                            audio.current_audio_driver(), spec.freq, spec.channels, spec.samples,
+                        ),
+                    )
+                }
+                Err(e) => Check::error(
+                    "Audio device",
+                    format!("{} driver: {}", audio.current_audio_driver(), e),
+                    "Check that a sound device is connected and not in exclusive use, or pick a driver with \
+                     SDL_AUDIODRIVER (e.g. pulseaudio or alsa)",
+                ),
+            }
+        }
+        Err(e) => Check::error("Audio device", e, "Pick a driver with SDL_AUDIODRIVER (e.g. pulseaudio or alsa)"),
+    });
+
+    check_controllers(&sdl, checks);
+}
+
+/// Joysticks SDL has no mapping for connect fine but do nothing in game, which looks like a bug.
+fn check_controllers(sdl: &sdl2::Sdl, checks: &mut Vec<Check>) {
+    let (controllers, joysticks) = match (sdl.game_controller(), sdl.joystick()) {
+        (Ok(controllers), Ok(joysticks)) => (controllers, joysticks),
+        (Err(e), _) | (_, Err(e)) => {
+            checks.push(Check::warning("Controllers", e, "Game controllers won't work, but the keyboard will"));
+            return;
+        }
+    };
+    let count = joysticks.num_joysticks().unwrap_or(0);
+    if count == 0 {
+        checks.push(Check::ok("Controllers", "none connected"));
+    }
+    for i in 0..count {
+        let name = joysticks.name_for_index(i).unwrap_or_else(|_| format!("joystick {}", i));
+        checks.push(if controllers.is_game_controller(i) {
+            Check::ok("Controller", name)
+        } else {
+            let guid = joysticks.device_guid(i).map(|guid| guid.string()).unwrap_or_default();
+            Check::warning(
+                "Controller",
+                format!("{} has no button mapping", name),
+                format!(
+                    "Add a mapping for GUID {} to SDL_GAMECONTROLLERCONFIG, e.g. made with SDL2 Gamepad Tool",
+                    guid,
+                ),
+            )
+        });
+    }
+}
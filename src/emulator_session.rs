@@ -0,0 +1,110 @@
+//! Actions a user can take on a running emulator, independent of how they asked for them. The SDL
+//! frontend's hotkeys and the `rugby serve` control socket both turn their input into `Command`s
+//! and run them through an `EmulatorSession`, so pausing, changing speed, resetting, and
+//! savestates behave the same everywhere. Other frontends (a TUI, a libretro core) should do the
+//! same.
+//!
+//! Frontends that run several instances side by side pass all of them, and pausing, speed, and
+//! resets apply to every one. Savestates apply to the first instance only.
+
+use crate::cpu::Cpu;
+use crate::gpu::CYCLES_PER_FRAME;
+use crate::pause::PauseReason;
+use failure::ResultExt;
+use std::path::PathBuf;
+
+const MIN_SPEED: f32 = 0.25;
+const MAX_SPEED: f32 = 4.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Pause if running, or resume if the user paused.
+    TogglePause,
+    Pause,
+    Resume,
+
+    /// Run one more frame and then pause.
+    FrameAdvance,
+
+    /// Double or halve the speed, up to 4x and down to 0.25x.
+    SpeedUp,
+    SlowDown,
+
+    /// Set the speed multiplier, clamped to the same range as `SpeedUp` and `SlowDown`.
+    SetSpeed(f32),
+
+    /// Switch the Game Boy off and on again, see `Cpu::reset`.
+    Reset,
+
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+}
+
+pub struct EmulatorSession {
+    speed_multiplier: f32,
+
+    /// Set by `FrameAdvance`, to pause again once the frame is done.
+    pause_after_frame: bool,
+}
+
+impl EmulatorSession {
+    pub fn new() -> Self {
+        EmulatorSession { speed_multiplier: 1.0, pause_after_frame: false }
+    }
+
+    pub fn speed_multiplier(&self) -> f32 {
+        self.speed_multiplier
+    }
+
+    /// How many cycles to emulate per frame of real time, at the current speed.
+    pub fn cycles_per_frame(&self) -> usize {
+        (CYCLES_PER_FRAME as f32 * self.speed_multiplier) as usize
+    }
+
+    pub fn execute(&mut self, cpus: &mut [&mut Cpu], command: Command) -> Result<(), failure::Error> {
+        match command {
+            Command::TogglePause => {
+                let paused = cpus.first().map_or(false, |cpu| cpu.pause.is_paused_for(PauseReason::User));
+                for cpu in cpus.iter_mut() {
+                    if paused { cpu.pause.resume(PauseReason::User) } else { cpu.pause.pause(PauseReason::User) }
+                }
+            }
+            Command::Pause => cpus.iter_mut().for_each(|cpu| cpu.pause.pause(PauseReason::User)),
+            Command::Resume => cpus.iter_mut().for_each(|cpu| cpu.pause.resume(PauseReason::User)),
+            Command::FrameAdvance => {
+                cpus.iter_mut().for_each(|cpu| cpu.pause.resume(PauseReason::User));
+                self.pause_after_frame = true;
+            }
+            Command::SpeedUp => self.set_speed(self.speed_multiplier * 2.0),
+            Command::SlowDown => self.set_speed(self.speed_multiplier / 2.0),
+            Command::SetSpeed(speed) => self.set_speed(speed),
+            Command::Reset => cpus.iter_mut().for_each(|cpu| cpu.reset()),
+            Command::SaveState(path) => {
+                if let Some(cpu) = cpus.first() {
+                    std::fs::write(&path, cpu.save_state())
+                        .with_context(|_| format!("Failed to write savestate: {}", path.display()))?;
+                }
+            }
+            Command::LoadState(path) => {
+                if let Some(cpu) = cpus.first_mut() {
+                    let data = std::fs::read(&path)
+                        .with_context(|_| format!("Failed to read savestate: {}", path.display()))?;
+                    cpu.load_state(&data).context("Failed to load savestate")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Call after emulating each frame, to finish a `FrameAdvance`.
+    pub fn end_frame(&mut self, cpus: &mut [&mut Cpu]) {
+        if self.pause_after_frame {
+            self.pause_after_frame = false;
+            cpus.iter_mut().for_each(|cpu| cpu.pause.pause(PauseReason::User));
+        }
+    }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.speed_multiplier = speed.max(MIN_SPEED).min(MAX_SPEED);
+    }
+}
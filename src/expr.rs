@@ -0,0 +1,422 @@
+//! A small expression language for inspecting emulator state, used by the debugger and by
+//! `run --exit-when`. For example: `pc == 0x1234`, `[0xA000] == 5`, or `a != 0 && [hl] > 3`.
+//!
+//! Operands are registers (`a`-`l`, `f`, `af`, `bc`, `de`, `hl`, `sp`, and `pc`, in any case),
+//! numbers and symbols in the syntax `memory_map::parse_address` takes (hex like `C000`, `0xC000`,
+//! or `$C000`, decimal like `#10`, and symbols from the loaded debug info), and `[addr]` or
+//! `[bank:addr]` for the byte at a memory address. Register names win over hex numbers and symbols,
+//! so `a` is the register and `$A` the number. Binary operators and their precedence are the same
+//! as Rust's: `+ - << >> & ^ |`, the comparisons, `&&`, and `||`. The unary operators are `-` and
+//! `!`, which is a logical not. Comparisons and logical operators give 1 or 0, and any nonzero
+//! value counts as true.
+
+use crate::cpu::Cpu;
+use crate::cpu::registers::{Reg8, Reg16};
+use crate::memory_map;
+use failure_derive::Fail;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    /// The original text, for display.
+    source: String,
+
+    root: Node,
+}
+
+#[derive(Debug, Fail)]
+pub enum ExprError {
+    #[fail(display = "unexpected character '{}'", _0)]
+    UnexpectedChar(char),
+
+    #[fail(display = "invalid number: '{}'", _0)]
+    InvalidNumber(String),
+
+    #[fail(display = "unknown register or symbol: '{}'", _0)]
+    UnknownName(String),
+
+    #[fail(display = "expected {}, found {}", expected, found)]
+    Unexpected { expected: &'static str, found: String },
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(i64),
+    Reg8(Reg8),
+    Flags,
+    Reg16(Reg16),
+    /// A name that isn't a register: a symbol from the debug info loaded into the CPU, or failing
+    /// that a hex number, like in addresses everywhere else. Symbols are looked up when evaluating,
+    /// since they're loaded after expressions given on the command line are parsed.
+    Name(String),
+    /// The byte at the address given by the second expression, in the bank given by the first if
+    /// any, or else the bank of the symbol in the address if it has one.
+    Mem(Option<Box<Node>>, Box<Node>),
+    Not(Box<Node>),
+    Neg(Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BinOp { Or, And, Eq, Ne, Lt, Le, Gt, Ge, BitOr, BitXor, BitAnd, Shl, Shr, Add, Sub }
+
+#[derive(Debug)]
+enum Token {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+/// Every operator and bracket, with longer ones first so `<=` isn't read as `<` then `=`.
+const OPERATORS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "|", "^", "&", "+", "-", "!", "(", ")", "[", "]", ":",
+];
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, ExprError> {
+        let mut tokens = tokenize(source)?.into_iter().peekable();
+        let root = parse_binary(&mut tokens, 0)?;
+        if let Some(token) = tokens.next() {
+            return Err(ExprError::Unexpected { expected: "an operator", found: describe(Some(token)) });
+        }
+        Ok(Expr { source: source.trim().to_string(), root })
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> i64 {
+        self.root.eval(cpu)
+    }
+
+    pub fn is_true(&self, cpu: &Cpu) -> bool {
+        self.eval(cpu) != 0
+    }
+
+    /// Check that every name is a symbol loaded into `cpu` or a hex number. Call this once the
+    /// debug info is loaded, since names that are neither evaluate to 0.
+    pub fn check_names(&self, cpu: &Cpu) -> Result<(), ExprError> {
+        self.root.check_names(cpu)
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ExprError;
+
+    fn from_str(s: &str) -> Result<Expr, ExprError> {
+        Expr::parse(s)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Node {
+    fn eval(&self, cpu: &Cpu) -> i64 {
+        match self {
+            Node::Num(n) => *n,
+            Node::Reg8(reg) => cpu.regs().get_8(*reg) as i64,
+            Node::Flags => cpu.regs().f.bits() as i64,
+            Node::Reg16(reg) => cpu.regs().get_16(*reg) as i64,
+            Node::Name(name) => resolve(cpu, name).map_or(0, |(_, value)| i64::from(value)),
+            Node::Mem(bank, addr) => {
+                let bank = bank.as_ref().map(|bank| bank.eval(cpu) as u16).or_else(|| addr.symbol_bank(cpu));
+                let addr = addr.eval(cpu) as u16;
+                match bank {
+                    // Banks that don't exist read like unmapped memory.
+                    Some(bank) => memory_map::read(cpu, bank, addr).unwrap_or(0xFF) as i64,
+                    None => cpu.peek(addr) as i64,
+                }
+            }
+            Node::Not(node) => (node.eval(cpu) == 0) as i64,
+            Node::Neg(node) => node.eval(cpu).wrapping_neg(),
+            Node::Binary(op, lhs, rhs) => op.apply(lhs.eval(cpu), rhs.eval(cpu)),
+        }
+    }
+
+    /// The bank of the symbol an address is based on, if it's in a switchable bank, like
+    /// `memory_map::parse_address` picks for `Symbol+4`.
+    fn symbol_bank(&self, cpu: &Cpu) -> Option<u16> {
+        match self {
+            Node::Name(name) => resolve(cpu, name).and_then(|(bank, _)| bank),
+            Node::Binary(BinOp::Add, lhs, rhs) | Node::Binary(BinOp::Sub, lhs, rhs) =>
+                lhs.symbol_bank(cpu).or_else(|| rhs.symbol_bank(cpu)),
+            _ => None,
+        }
+    }
+
+    fn check_names(&self, cpu: &Cpu) -> Result<(), ExprError> {
+        match self {
+            Node::Num(_) | Node::Reg8(_) | Node::Flags | Node::Reg16(_) => Ok(()),
+            Node::Name(name) => resolve(cpu, name).map(|_| ()).ok_or_else(|| ExprError::UnknownName(name.clone())),
+            Node::Mem(bank, addr) => {
+                if let Some(bank) = bank {
+                    bank.check_names(cpu)?;
+                }
+                addr.check_names(cpu)
+            }
+            Node::Not(node) | Node::Neg(node) => node.check_names(cpu),
+            Node::Binary(_, lhs, rhs) => {
+                lhs.check_names(cpu)?;
+                rhs.check_names(cpu)
+            }
+        }
+    }
+}
+
+/// What a name means: the symbol loaded into `cpu` with that name, with its bank if it has one, or
+/// else the hex number it spells.
+fn resolve(cpu: &Cpu, name: &str) -> Option<(Option<u16>, u16)> {
+    memory_map::symbol_address(cpu, name).or_else(|| memory_map::parse_number(name).map(|value| (None, value)))
+}
+
+impl BinOp {
+    fn from_token(token: &str) -> Option<BinOp> {
+        Some(match token {
+            "||" => BinOp::Or,
+            "&&" => BinOp::And,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<" => BinOp::Lt,
+            "<=" => BinOp::Le,
+            ">" => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            "|" => BinOp::BitOr,
+            "^" => BinOp::BitXor,
+            "&" => BinOp::BitAnd,
+            "<<" => BinOp::Shl,
+            ">>" => BinOp::Shr,
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            _ => return None,
+        })
+    }
+
+    /// Operators with higher precedence bind tighter.
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 3,
+            BinOp::BitOr => 4,
+            BinOp::BitXor => 5,
+            BinOp::BitAnd => 6,
+            BinOp::Shl | BinOp::Shr => 7,
+            BinOp::Add | BinOp::Sub => 8,
+        }
+    }
+
+    fn apply(self, lhs: i64, rhs: i64) -> i64 {
+        match self {
+            BinOp::Or => (lhs != 0 || rhs != 0) as i64,
+            BinOp::And => (lhs != 0 && rhs != 0) as i64,
+            BinOp::Eq => (lhs == rhs) as i64,
+            BinOp::Ne => (lhs != rhs) as i64,
+            BinOp::Lt => (lhs < rhs) as i64,
+            BinOp::Le => (lhs <= rhs) as i64,
+            BinOp::Gt => (lhs > rhs) as i64,
+            BinOp::Ge => (lhs >= rhs) as i64,
+            BinOp::BitOr => lhs | rhs,
+            BinOp::BitXor => lhs ^ rhs,
+            BinOp::BitAnd => lhs & rhs,
+            BinOp::Shl => lhs.wrapping_shl(rhs as u32),
+            BinOp::Shr => lhs.wrapping_shr(rhs as u32),
+            BinOp::Add => lhs.wrapping_add(rhs),
+            BinOp::Sub => lhs.wrapping_sub(rhs),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Name(name) => f.write_str(name),
+            Token::Op(op) => f.write_str(op),
+        }
+    }
+}
+
+fn describe(token: Option<Token>) -> String {
+    match token {
+        Some(token) => format!("'{}'", token),
+        None => String::from("end of expression"),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c.is_ascii_alphanumeric() || c == '$' || c == '#' || c == '_' || c == '.' {
+            let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+            let len = rest[1..].find(|c: char| !is_word(c)).map_or(rest.len(), |i| i + 1);
+            let word = &rest[..len];
+            if c.is_ascii_digit() || c == '$' || c == '#' {
+                let n = memory_map::parse_number(word).ok_or_else(|| ExprError::InvalidNumber(word.to_string()))?;
+                tokens.push(Token::Num(i64::from(n)));
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+            rest = &rest[len..];
+        } else {
+            return Err(ExprError::UnexpectedChar(c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+type Tokens = Peekable<std::vec::IntoIter<Token>>;
+
+/// Parse a chain of binary operators by precedence climbing, only consuming operators which bind
+/// at least as tightly as `min_precedence`.
+fn parse_binary(tokens: &mut Tokens, min_precedence: u8) -> Result<Node, ExprError> {
+    let mut lhs = parse_unary(tokens)?;
+    loop {
+        let op = match tokens.peek() {
+            Some(Token::Op(token)) => match BinOp::from_token(token) {
+                Some(op) if op.precedence() >= min_precedence => op,
+                _ => break,
+            },
+            _ => break,
+        };
+        tokens.next();
+        let rhs = parse_binary(tokens, op.precedence() + 1)?;
+        lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+fn parse_unary(tokens: &mut Tokens) -> Result<Node, ExprError> {
+    match tokens.next() {
+        Some(Token::Num(n)) => Ok(Node::Num(n)),
+        Some(Token::Name(name)) => Ok(parse_register(&name).unwrap_or(Node::Name(name))),
+        Some(Token::Op("!")) => Ok(Node::Not(Box::new(parse_unary(tokens)?))),
+        Some(Token::Op("-")) => Ok(Node::Neg(Box::new(parse_unary(tokens)?))),
+        Some(Token::Op("(")) => {
+            let node = parse_binary(tokens, 0)?;
+            expect(tokens, ")", "')'")?;
+            Ok(node)
+        }
+        Some(Token::Op("[")) => {
+            let mut bank = None;
+            let mut addr = parse_binary(tokens, 0)?;
+            if let Some(Token::Op(":")) = tokens.peek() {
+                tokens.next();
+                bank = Some(Box::new(addr));
+                addr = parse_binary(tokens, 0)?;
+            }
+            expect(tokens, "]", "']'")?;
+            Ok(Node::Mem(bank, Box::new(addr)))
+        }
+        token => Err(ExprError::Unexpected { expected: "a value", found: describe(token) }),
+    }
+}
+
+fn expect(tokens: &mut Tokens, op: &str, expected: &'static str) -> Result<(), ExprError> {
+    match tokens.next() {
+        Some(Token::Op(token)) if token == op => Ok(()),
+        token => Err(ExprError::Unexpected { expected, found: describe(token) }),
+    }
+}
+
+fn parse_register(name: &str) -> Option<Node> {
+    Some(match name.to_lowercase().as_ref() {
+        "a" => Node::Reg8(Reg8::A),
+        "b" => Node::Reg8(Reg8::B),
+        "c" => Node::Reg8(Reg8::C),
+        "d" => Node::Reg8(Reg8::D),
+        "e" => Node::Reg8(Reg8::E),
+        "h" => Node::Reg8(Reg8::H),
+        "l" => Node::Reg8(Reg8::L),
+        "f" => Node::Flags,
+        "af" => Node::Reg16(Reg16::AF),
+        "bc" => Node::Reg16(Reg16::BC),
+        "de" => Node::Reg16(Reg16::DE),
+        "hl" => Node::Reg16(Reg16::HL),
+        "sp" => Node::Reg16(Reg16::SP),
+        "pc" => Node::Reg16(Reg16::PC),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart::Cart;
+    use crate::memory_map::test::cpu_with_symbols;
+    use quickcheck::quickcheck;
+
+    fn eval(cpu: &Cpu, source: &str) -> i64 {
+        let expr = Expr::parse(source).unwrap();
+        expr.check_names(cpu).unwrap();
+        expr.eval(cpu)
+    }
+
+    #[test]
+    fn numbers_are_written_like_addresses() {
+        let cpu = cpu_with_symbols();
+        assert_eq!(eval(&cpu, "10"), 0x10);
+        assert_eq!(eval(&cpu, "0x10 + $10 + #10"), 0x2A);
+        assert_eq!(eval(&cpu, "FF"), 0xFF);
+        assert!(Expr::parse("10000").is_err());
+        assert!(Expr::parse("#ff").is_err());
+    }
+
+    #[test]
+    fn registers_come_before_numbers() {
+        let mut cpu = cpu_with_symbols();
+        cpu.step_n(2, &Default::default());
+        assert_eq!(eval(&cpu, "a"), 5);
+        assert_eq!(eval(&cpu, "A + $A"), 15);
+        assert_eq!(eval(&cpu, "bc == 1234"), 1);
+    }
+
+    #[test]
+    fn symbols_and_banks() {
+        let mut cpu = cpu_with_symbols();
+        cpu.write_mem_debug(0xC001, 7);
+        assert_eq!(eval(&cpu, "Main.loop - Main"), 4);
+        assert_eq!(eval(&cpu, "[wBuffer + 1]"), 7);
+        // Bank 1 is mapped, but the symbol is in bank 3.
+        assert_eq!(eval(&cpu, "[4010]"), 0);
+        assert_eq!(eval(&cpu, "[Face]"), 0x42);
+        assert_eq!(eval(&cpu, "[3:4010]"), 0x42);
+        assert_eq!(eval(&cpu, "[1:Face]"), 0);
+        assert_eq!(eval(&cpu, "[9:4010]"), 0xFF);
+    }
+
+    #[test]
+    fn unknown_names_are_caught_once_symbols_are_loaded() {
+        let cpu = cpu_with_symbols();
+        let expr = Expr::parse("[Nope] == 1").unwrap();
+        match expr.check_names(&cpu) {
+            Err(ExprError::UnknownName(name)) => assert_eq!(name, "Nope"),
+            result => panic!("{:?}", result),
+        }
+        assert!(Expr::parse("[1:]").is_err());
+        assert!(Expr::parse("a:b").is_err());
+    }
+
+    quickcheck! {
+        fn operators_follow_registers(bc: u16, hl: u16) -> bool {
+            // ld bc, BC; ld hl, HL
+            let mut rom = vec![0; 0x8000];
+            rom[0x100..0x106].copy_from_slice(&[0x01, bc as u8, (bc >> 8) as u8, 0x21, hl as u8, (hl >> 8) as u8]);
+            let mut cpu = Cpu::new(Cart::from_rom(rom.into_boxed_slice(), None).unwrap());
+            cpu.step_n(2, &Default::default());
+            let eval = |source: &str| Expr::parse(source).unwrap().eval(&cpu);
+            eval("b << 8 | c") == bc as i64
+                && eval("bc + hl - 1") == bc as i64 + hl as i64 - 1
+                && eval("bc == $0 || hl >= 0x0 && !(bc != BC)") == 1
+                && eval("[pc]") == 0x00
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Per-frame hashes of emulator output, for external tools that compare runs across emulator
+//! versions or against other emulators.
+//!
+//! Each emulated frame produces one line: the frame number and the CRC-32 of the screen buffer
+//! (one byte per pixel, shades 0-3, row by row), followed by the CRC-32 of the audio samples
+//! output during the frame if audio hashing is enabled. Hashes are 8 hex digits.
+
+use crate::checksum::Crc32;
+use crate::cpu::Cpu;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub struct FrameHashes {
+    out: BufWriter<File>,
+    audio: bool,
+}
+
+impl FrameHashes {
+    pub fn create(path: &Path, audio: bool) -> io::Result<Self> {
+        Ok(FrameHashes { out: BufWriter::new(File::create(path)?), audio })
+    }
+
+    /// Prepare `cpu` for hashing, by capturing its audio output if needed.
+    pub fn attach(&self, cpu: &mut Cpu) {
+        if self.audio {
+            cpu.audio.capture = Some(Vec::new());
+        }
+    }
+
+    /// Write the hashes of the frame `cpu` just finished. The caller clears the captured audio
+    /// afterwards.
+    pub fn write_frame(&mut self, frame: u64, cpu: &Cpu) -> io::Result<()> {
+        let mut video = Crc32::new();
+        for row in cpu.gpu.screen_buffer.iter() {
+            video.update(row);
+        }
+        write!(self.out, "{} {:08x}", frame, video.finish())?;
+        if self.audio {
+            let mut audio = Crc32::new();
+            audio.update(cpu.audio.capture.as_ref().map_or(&[][..], |samples| &samples[..]));
+            write!(self.out, " {:08x}", audio.finish())?;
+        }
+        writeln!(self.out)
+    }
+}
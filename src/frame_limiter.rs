@@ -0,0 +1,72 @@
+//! Pacing frames by sleeping until each one is due. The window's frontend normally paces itself by
+//! the audio queue (see `rate_control`), checking it every millisecond, which keeps the CPU from
+//! ever settling into a low-power state. Low-power mode and service mode sleep here instead, with
+//! one timer wakeup per frame.
+//!
+//! The time spent awake is measured too, to show how much of a CPU core the emulator needs.
+
+use crate::gpu::CYCLES_PER_FRAME;
+use std::time::{Duration, Instant};
+
+const CLOCK_RATE: u64 = 4_194_304;
+
+/// How often the CPU usage is updated.
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct FrameLimiter {
+    frame_time: Duration,
+    next_frame: Instant,
+
+    /// When the current measurement started, and how long the thread has slept since.
+    measure_start: Instant,
+    slept: Duration,
+
+    /// The share of the last second the thread spent awake, from 0 to 1.
+    cpu_usage: f32,
+}
+
+impl FrameLimiter {
+    pub fn new(frame_time: Duration) -> Self {
+        let now = Instant::now();
+        FrameLimiter {
+            frame_time,
+            next_frame: now,
+            measure_start: now,
+            slept: Duration::from_secs(0),
+            cpu_usage: 0.0,
+        }
+    }
+
+    /// Pace frames of `CYCLES_PER_FRAME` at the Game Boy's clock speed, so the sound is made as
+    /// fast as it's played.
+    pub fn game_boy() -> Self {
+        Self::new(Duration::from_nanos(CYCLES_PER_FRAME as u64 * 1_000_000_000 / CLOCK_RATE))
+    }
+
+    /// Sleep until the next frame is due.
+    pub fn wait(&mut self) {
+        self.next_frame += self.frame_time;
+        let now = Instant::now();
+        if self.next_frame > now {
+            std::thread::sleep(self.next_frame - now);
+            self.slept += now.elapsed();
+        } else {
+            // Don't try to catch up after falling behind, e.g. while the machine was suspended.
+            self.next_frame = now;
+        }
+
+        let elapsed = self.measure_start.elapsed();
+        if elapsed >= MEASURE_INTERVAL {
+            let awake = elapsed.checked_sub(self.slept).unwrap_or_default();
+            self.cpu_usage = awake.as_secs_f32() / elapsed.as_secs_f32();
+            self.measure_start = Instant::now();
+            self.slept = Duration::from_secs(0);
+        }
+    }
+
+    /// The share of a CPU core the thread spent awake over the last second, as a percentage. Time
+    /// spent in SDL's audio thread isn't counted.
+    pub fn cpu_percent(&self) -> u32 {
+        (self.cpu_usage * 100.0).round() as u32
+    }
+}
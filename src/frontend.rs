@@ -1,49 +1,729 @@
-use crate::audio::SAMPLE_BUFFER_SIZE;
+use crate::audio::{AudioSink, Scope, SCOPE_SAMPLES};
+use crate::audio_dump::AudioDump;
+use crate::barcode_boy::BarcodeBoy;
+use crate::camera_image::CameraImage;
+use crate::cart_header::CartHeader;
+use crate::clip::{ClipOptions, ClipRecorder};
+use crate::config::{Config, ConfigWatcher, ControllerConfig, IdleScreen, KeyBindings, TiltKeys, VideoConfig};
+use crate::control::ControlServer;
 use crate::cpu::Cpu;
 use crate::cpu::registers::{Reg8, Reg16};
 use crate::debug::Watch;
-use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
-use crate::joypad::{ButtonKey, DirKey};
+use crate::emulator_session::{Command, EmulatorSession};
+use crate::expr::Expr;
+use crate::frame_hash::FrameHashes;
+use crate::frame_limiter::FrameLimiter;
+use crate::i18n::{self, tr, tr_with};
+use crate::gpu::{CYCLES_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::heatmap::{self, Heatmap};
+use crate::input_stream::InputStream;
+use crate::irq_stats::IrqStats;
+use crate::memory_map;
+use crate::joypad::{ButtonKey, DirKey, Joypad};
+use crate::link::Link;
+use crate::palette_indices;
+use crate::pause::{PauseReason, PauseState};
+use crate::photo::{self, Palette, PhotoOptions};
+use crate::profile::Profile;
+use crate::rate_control;
+use crate::repro::{InputEvent, Repro};
+use crate::save_file::SaveFile;
+use crate::state_browser::{self, Action, Contents, StateBrowser};
+use crate::vgm::VgmRecorder;
+use crate::workboy::{self, WorkBoy};
 use log::info;
-use sdl2::audio::{AudioQueue, AudioSpecDesired};
-use sdl2::controller::Button;
-use sdl2::event::Event;
+use sdl2::audio::AudioQueue;
+use sdl2::controller::{Axis, Button};
+use sdl2::event::{Event, WindowEvent};
 use sdl2::EventPump;
 use sdl2::keyboard::{Keycode, Mod};
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::rect::{Point, Rect};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
 use sdl2::gfx::framerate::FPSManager;
 use sdl2::GameControllerSubsystem;
 use sdl2::controller::GameController;
 use linefeed::{Interface, ReadResult};
-use hex;
-use hex::FromHex;
 use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
-const CYCLES_PER_FRAME: usize = 69905;
 const WINDOW_SCALE: usize = 5;
 
-/// The four colors of the original Game Boy screen, from lightest to darkest, in RGB.
-const GAME_BOY_COLORS: [sdl2::pixels::Color; 4] = [
-    sdl2::pixels::Color { r: 155, g: 188, b: 15, a: 0xFF },
-    sdl2::pixels::Color { r: 139, g: 172, b: 15, a: 0xFF },
-    sdl2::pixels::Color { r: 48,  g: 98,  b: 48, a: 0xFF },
-    sdl2::pixels::Color { r: 15,  g: 56,  b: 15, a: 0xFF },
-];
+/// How long to wait for an event while paused before updating the window anyway.
+const IDLE_WAIT_MS: u32 = 250;
 
-pub fn start_frontend(cpu: &mut Cpu) {
-    let sdl = sdl2::init().expect("Failed to initialize SDL");
+/// How long messages about reloading the config file stay on screen.
+const CONFIG_MESSAGE_DURATION: Duration = Duration::from_secs(4);
+
+/// The program icon, with magenta marking transparent pixels.
+const ICON_BMP: &[u8] = include_bytes!("../assets/icon.bmp");
+
+/// Whether the emulator is recording or replaying a reproduction bundle.
+pub enum ReproMode {
+    Off,
+
+    /// Record every input change into the bundle.
+    Record(Repro),
+
+    /// Drive the joypad from the bundle's inputs. `next` is the index of the next input to apply.
+    Play { repro: Repro, next: usize },
+}
+
+/// Press or release the joypad key mapped to `keycode` in `keys`, if any.
+fn set_key(keys: &KeyBindings, joypad: &mut Joypad, keycode: Keycode, down: bool) {
+    let dirs = [
+        (keys.up, DirKey::Up),
+        (keys.down, DirKey::Down),
+        (keys.left, DirKey::Left),
+        (keys.right, DirKey::Right),
+    ];
+    let buttons = [
+        (keys.a, ButtonKey::A),
+        (keys.b, ButtonKey::B),
+        (keys.start, ButtonKey::Start),
+        (keys.select, ButtonKey::Select),
+    ];
+    for &(_, dir) in dirs.iter().filter(|&&(k, _)| k == keycode) {
+        if down { joypad.dir_key_down(dir) } else { joypad.dir_key_up(dir) }
+    }
+    for &(_, button) in buttons.iter().filter(|&&(k, _)| k == keycode) {
+        if down { joypad.button_key_down(button) } else { joypad.button_key_up(button) }
+    }
+}
+
+/// Press or release the joypad key mapped to a controller button. Returns whether the button is
+/// mapped to one.
+fn set_controller_button(controls: &ControllerConfig, joypad: &mut Joypad, button: Button, down: bool) -> bool {
+    let dirs = [
+        (Button::DPadUp, DirKey::Up),
+        (Button::DPadDown, DirKey::Down),
+        (Button::DPadLeft, DirKey::Left),
+        (Button::DPadRight, DirKey::Right),
+    ];
+    let buttons = [
+        (controls.a, ButtonKey::A),
+        (controls.b, ButtonKey::B),
+        (controls.start, ButtonKey::Start),
+        (controls.select, ButtonKey::Select),
+    ];
+    let mut mapped = false;
+    for &(_, dir) in dirs.iter().filter(|&&(b, _)| b == button) {
+        if down { joypad.dir_key_down(dir) } else { joypad.dir_key_up(dir) }
+        mapped = true;
+    }
+    for &(_, key) in buttons.iter().filter(|&&(b, _)| b == button) {
+        if down { joypad.button_key_down(key) } else { joypad.button_key_up(key) }
+        mapped = true;
+    }
+    mapped
+}
+
+/// How far a controller's stick has to move before it tilts the cart, out of 1.
+const STICK_DEAD_ZONE: f32 = 0.1;
+
+/// How a player is tilting an MBC7 cart, from the tilt keys and their controller's left stick.
+#[derive(Clone, Default)]
+struct Tilt {
+    up: bool,
+    down: bool,
+    left: bool,
+    right: bool,
+    stick: (f32, f32),
+}
+
+impl Tilt {
+    fn set_key(&mut self, keys: &TiltKeys, keycode: Keycode, down: bool) {
+        if keycode == keys.up { self.up = down }
+        if keycode == keys.down { self.down = down }
+        if keycode == keys.left { self.left = down }
+        if keycode == keys.right { self.right = down }
+    }
+
+    fn set_stick(&mut self, axis: Axis, value: i16) {
+        let value = f32::from(value) / f32::from(i16::max_value());
+        let value = if value.abs() < STICK_DEAD_ZONE { 0.0 } else { value };
+        match axis {
+            Axis::LeftX => self.stick.0 = value,
+            Axis::LeftY => self.stick.1 = value,
+            _ => {}
+        }
+    }
+
+    /// The tilt in g, as `Cart::set_tilt` takes it.
+    fn value(&self) -> (f32, f32) {
+        let keys = |negative: bool, positive: bool| positive as i8 as f32 - negative as i8 as f32;
+        let x = keys(self.left, self.right) + self.stick.0;
+        let y = keys(self.up, self.down) + self.stick.1;
+        (x.max(-1.0).min(1.0), y.max(-1.0).min(1.0))
+    }
+}
+
+/// What the emulator is doing, for display in the window title.
+struct Status {
+    /// The game title from the cartridge header, or several joined together with `--dual`.
+    game: String,
+
+    /// Frames rendered in the last second.
+    fps: u32,
 
+    speed_multiplier: f32,
+    pause: PauseState,
+}
+
+impl Status {
+    fn new(cpus: &[&mut Cpu]) -> Self {
+        let games: Vec<String> = cpus.iter().map(|cpu| match CartHeader::from_rom(cpu.cart.rom()) {
+            Ok(header) => String::from_utf8_lossy(&header.title).trim_end_matches('\0').trim().to_string(),
+            Err(_) => String::new(),
+        }).collect();
+        Status { game: games.join(" | "), fps: 0, speed_multiplier: 1.0, pause: PauseState::default() }
+    }
+
+    /// Fill in a window title template. The placeholders are `{game}`, `{fps}`, `{speed}`, and
+    /// `{state}` (`running`, or `paused` with the reasons).
+    fn format(&self, template: &str) -> String {
+        let game = if self.game.is_empty() { tr("title.untitled") } else { &self.game };
+        template
+            .replace("{game}", game)
+            .replace("{fps}", &self.fps.to_string())
+            .replace("{speed}", &format!("x{}", self.speed_multiplier))
+            .replace("{state}", &i18n::pause_state(self.pause))
+    }
+}
+
+/// Create a window with room for `screens` Game Boy screens side by side.
+fn create_window(sdl: &sdl2::Sdl, screens: usize) -> Canvas<Window> {
     let sdl_video = sdl.video().expect("Failed to access SDL video subsystem");
-    let window = sdl_video
+    let mut window = sdl_video
         .window(
             "Rugby",
-            (SCREEN_WIDTH * WINDOW_SCALE) as u32,
+            (SCREEN_WIDTH * WINDOW_SCALE * screens) as u32,
             (SCREEN_HEIGHT * WINDOW_SCALE) as u32,
         )
         .build()
         .expect("Failed to create SDL window");
-    let mut canvas = window.into_canvas().build().expect("Failed to get SDL window canvas");
+
+    let icon = sdl2::rwops::RWops::from_bytes(ICON_BMP)
+        .and_then(|mut rw| sdl2::surface::Surface::load_bmp_rw(&mut rw));
+    match icon {
+        Ok(mut icon) => {
+            let _ = icon.set_color_key(true, sdl2::pixels::Color::RGB(0xFF, 0x00, 0xFF));
+            window.set_icon(icon);
+        }
+        Err(e) => info!("Failed to load window icon: {}", e),
+    }
+
+    window.into_canvas().build().expect("Failed to get SDL window canvas")
+}
+
+/// How many frames the LCD has to stay off before the screen is shown as idle. Games turn it off
+/// for a frame or two all the time to load graphics, which shouldn't flash anything.
+const LCD_OFF_IDLE_FRAMES: u32 = 30;
+
+/// Why the screen of `cpu` isn't changing, if it's been still long enough to show it. See
+/// `IdleScreen`.
+fn idle_reason(cpu: &Cpu, lcd_off_frames: u32) -> Option<&'static str> {
+    if cpu.pause.is_paused() {
+        Some(tr("osd.paused"))
+    } else if lcd_off_frames >= LCD_OFF_IDLE_FRAMES {
+        Some(tr("osd.lcd_off"))
+    } else {
+        None
+    }
+}
+
+/// Count the frames the LCD of `cpu` has been off for, for `idle_reason`.
+fn count_lcd_off_frames(cpu: &Cpu, lcd_off_frames: &mut u32) {
+    *lcd_off_frames = if cpu.gpu.lcd_enabled() { 0 } else { lcd_off_frames.saturating_add(1) };
+}
+
+/// Draw the Game Boy screen of `cpu` into the `screen` area of the canvas. If the screen is idle
+/// for the given reason, it's drawn the way `video.idle_screen` says. With `beam`, as from
+/// `Gpu::beam`, the line the PPU is on is marked and the lines it hasn't drawn yet are dimmed.
+fn draw_screen(
+    canvas: &mut Canvas<Window>, texture: &mut ScreenTexture, cpu: &Cpu, screen: Rect, idle: Option<&str>,
+    beam: Option<(usize, usize)>, video: &VideoConfig,
+) {
+    let dim_from = if idle.is_some() && video.idle_screen == IdleScreen::Dim {
+        0
+    } else {
+        beam.map_or(SCREEN_HEIGHT, |(_, drawn)| drawn)
+    };
+    texture.update(&cpu.gpu.screen_buffer, video.palette, dim_from);
+    canvas.copy(&texture.texture, None, screen).unwrap();
+
+    if let Some((line, _)) = beam {
+        use sdl2::gfx::primitives::DrawRenderer;
+        let scale = (screen.height() as usize / SCREEN_HEIGHT).max(1) as i16;
+        let y = screen.y() as i16 + (line * screen.height() as usize / SCREEN_HEIGHT) as i16;
+        let (left, right) = (screen.left() as i16, screen.right() as i16 - 1);
+        let _ = canvas.box_(left, y, right, y + scale - 1, sdl2::pixels::Color::RGBA(0xFF, 0x20, 0x20, 0xC0));
+    }
+    if let (Some(label), IdleScreen::Overlay) = (idle, video.idle_screen) {
+        draw_label(canvas, label, screen);
+    }
+}
+
+/// The pixels one instance's screen is drawn with. Each frame is written straight into the
+/// texture's memory, four pixels at a time, looking up each group of four shades in a table of the
+/// 16 bytes they become. There's a second table for dimmed lines.
+struct ScreenTexture<'a> {
+    texture: Texture<'a>,
+    luts: Box<[[[u8; 16]; 256]; 2]>,
+
+    /// The palette the tables were made for.
+    lut_for: Option<Palette>,
+}
+
+impl<'a> ScreenTexture<'a> {
+    fn new(texture_creator: &'a TextureCreator<WindowContext>) -> Self {
+        let texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB888, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+            .expect("Failed to create screen texture");
+        ScreenTexture { texture, luts: Box::new([[[0; 16]; 256]; 2]), lut_for: None }
+    }
+
+    /// Copy a screen into the texture, dimming the lines from `dim_from` down.
+    fn update(&mut self, screen_buffer: &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT], palette: Palette, dim_from: usize) {
+        if self.lut_for != Some(palette) {
+            self.fill_luts(palette);
+        }
+        let luts = &self.luts;
+        self.texture.with_lock(None, |pixels, pitch| {
+            for (y, (row, line)) in pixels.chunks_mut(pitch).zip(screen_buffer.iter()).enumerate() {
+                let lut = &luts[(y >= dim_from) as usize];
+                for (out, shades) in row.chunks_exact_mut(16).zip(line.chunks_exact(4)) {
+                    let i = shades.iter().rev().fold(0, |i, &shade| i << 2 | (shade & 3) as usize);
+                    out.copy_from_slice(&lut[i]);
+                }
+            }
+        }).unwrap();
+    }
+
+    /// Work out the pixels for every group of four shades, with the first shade in the lowest two
+    /// bits of the index, at full and half brightness.
+    fn fill_luts(&mut self, palette: Palette) {
+        let colors = palette.colors();
+        for (dim, lut) in self.luts.iter_mut().enumerate() {
+            let mut pixels = [[0; 4]; 4];
+            for (pixel, color) in pixels.iter_mut().zip(&colors) {
+                let [r, g, b] = if dim == 1 { [color[0] / 2, color[1] / 2, color[2] / 2] } else { *color };
+                *pixel = (u32::from(r) << 16 | u32::from(g) << 8 | u32::from(b)).to_ne_bytes();
+            }
+            for (i, entry) in lut.iter_mut().enumerate() {
+                for (shift, out) in entry.chunks_exact_mut(4).enumerate() {
+                    out.copy_from_slice(&pixels[i >> (shift * 2) & 3]);
+                }
+            }
+        }
+        self.lut_for = Some(palette);
+    }
+}
+
+/// Draw one line of text in the middle of `area`, on a dark background.
+fn draw_label(canvas: &mut Canvas<Window>, label: &str, area: Rect) {
+    use sdl2::gfx::primitives::DrawRenderer;
+
+    const CHAR_SIZE: i16 = 8;
+    let width = label.chars().count() as i16 * CHAR_SIZE;
+    let (x, y) = (area.center().x() as i16 - width / 2, area.center().y() as i16 - CHAR_SIZE / 2);
+    let _ = canvas.box_(x - 4, y - 4, x + width + 3, y + CHAR_SIZE + 3, sdl2::pixels::Color::RGBA(0, 0, 0, 0xB0));
+    let _ = canvas.string(x, y, label, sdl2::pixels::Color::RGB(0xFF, 0xFF, 0xFF));
+}
+
+/// Optional ways of feeding input into and getting output out of the emulator. These all apply to
+/// the first instance only.
+pub struct FrontendOptions {
+    pub repro: ReproMode,
+    pub input: Option<InputStream>,
+    pub frame_hashes: Option<FrameHashes>,
+    pub audio_dump: Option<AudioDump>,
+
+    /// Where to record the sound register writes.
+    pub vgm: Option<VgmRecorder>,
+
+    /// Quit after emulating this many frames.
+    pub frame_limit: Option<u64>,
+
+    /// Where to write cartridge RAM as it changes.
+    pub save_file: Option<SaveFile>,
+
+    /// Where the game's save file is or would be. Savestate slots are kept next to it and named
+    /// after it.
+    pub save_path: PathBuf,
+
+    /// The link cable to another player, for the first instance.
+    pub link: Option<Link>,
+
+    /// Barcodes for F7 to scan with the first instance's Barcode Boy, each optionally followed by
+    /// a description.
+    pub barcodes: Vec<String>,
+
+    /// Run in low-power mode whatever the config says.
+    pub low_power: bool,
+
+    /// What the first instance's Pocket Camera sees, reloaded as the file changes.
+    pub camera: Option<CameraImage>,
+}
+
+impl FrontendOptions {
+    pub fn new() -> Self {
+        FrontendOptions {
+            repro: ReproMode::Off,
+            input: None,
+            frame_hashes: None,
+            audio_dump: None,
+            vgm: None,
+            frame_limit: None,
+            save_file: None,
+            save_path: PathBuf::from("rugby.sav"),
+            link: None,
+            barcodes: Vec::new(),
+            low_power: false,
+            camera: None,
+        }
+    }
+}
+
+/// Run a single emulator instance as fast as possible, without a window or sound, until the frame
+/// limit if there is one. Recording and replaying bundles isn't supported.
+pub fn run_headless(cpu: &mut Cpu, options: &mut FrontendOptions) {
+    let FrontendOptions { input, frame_hashes, audio_dump, vgm, frame_limit, link, camera, .. } = options;
+    attach_frame_outputs(frame_hashes, audio_dump, vgm, cpu);
+    // Cycles emulated so far, which the input stream, the frame outputs, and the frame limit count
+    // frames of.
+    let mut emulated: u64 = 0;
+    loop {
+        let (frame, offset) = emulated_frame(emulated);
+        if frame_limit.map_or(false, |limit| frame >= limit) {
+            break;
+        }
+        if let Some(pressed) = input.as_mut().and_then(|i| i.poll(frame, offset)) {
+            cpu.joypad.set_pressed(pressed);
+        }
+        if let Some(image) = camera.as_mut().and_then(CameraImage::poll) {
+            cpu.cart.set_camera_image(&image);
+        }
+        // Run to the end of the frame, which the last one may have run a few cycles into.
+        let cycles = CYCLES_PER_FRAME - offset as usize;
+        if let Some(link) = link {
+            link.start_frame(cpu, cycles);
+        }
+        let start = cpu.cycles();
+        let stop = step_cycles_with_input(cpu, cycles, input, emulated, None, &HashSet::new(), None);
+        emulated += (cpu.cycles() - start) as u64;
+        if let Some(link) = link {
+            link.end_frame(cpu);
+        }
+        if stop {
+            break;
+        }
+        write_frame_outputs(frame_hashes, audio_dump, vgm, frame, cpu);
+    }
+}
+
+/// Run a single emulator instance in real time without a window or sound, doing whatever the
+/// control socket's clients say until one of them sends `quit`.
+pub fn run_service(
+    cpu: &mut Cpu, control: &mut ControlServer, save_file: &mut Option<SaveFile>, config: &Config, window: bool,
+) {
+    // The window only shows the game and the vote. Input still comes from the control socket.
+    let sdl = if window { Some(sdl2::init().expect("Failed to initialize SDL")) } else { None };
+    let mut view = sdl.as_ref().map(|sdl| {
+        let canvas = create_window(sdl, 1);
+        let events = sdl.event_pump().expect("Failed to get SDL event pump");
+        (canvas, events)
+    });
+    let texture_creator = view.as_ref().map(|(canvas, _)| canvas.texture_creator());
+    let mut texture = texture_creator.as_ref().map(ScreenTexture::new);
+
+    let mut limiter = FrameLimiter::new(Duration::from_secs(1) / 60);
+    let mut frame: u64 = 0;
+    let mut lcd_off_frames = 0;
+    while !control.quit() {
+        control.handle_requests(cpu, frame, save_file, config);
+        if !cpu.pause.is_paused() {
+            cpu.step_cycles(control.session.cycles_per_frame(), None, &HashSet::new());
+            frame += 1;
+            control.session.end_frame(&mut [&mut *cpu]);
+            count_lcd_off_frames(cpu, &mut lcd_off_frames);
+        }
+        update_save_file(save_file, cpu);
+
+        if let (Some((canvas, events)), Some(texture)) = (&mut view, &mut texture) {
+            if events.poll_iter().any(|event| if let Event::Quit { .. } = event { true } else { false }) {
+                break;
+            }
+            let screen = canvas.viewport();
+            draw_screen(canvas, texture, cpu, screen, idle_reason(cpu, lcd_off_frames), None, &config.video);
+            draw_osd(canvas, &control.votes.osd_lines(frame));
+            canvas.present();
+        }
+
+        limiter.wait();
+    }
+}
+
+/// Draw lines of text in the top left corner of the canvas, on a dark background.
+fn draw_osd(canvas: &mut Canvas<Window>, lines: &[String]) {
+    use sdl2::gfx::primitives::DrawRenderer;
+
+    // The built-in SDL_gfx font is 8x8 pixels.
+    const CHAR_SIZE: i16 = 8;
+    const LINE_HEIGHT: i16 = CHAR_SIZE + 2;
+    let width = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) as i16 * CHAR_SIZE;
+    if width == 0 {
+        return;
+    }
+    let height = lines.len() as i16 * LINE_HEIGHT;
+    let _ = canvas.box_(0, 0, width + 7, height + 5, sdl2::pixels::Color::RGBA(0, 0, 0, 0xB0));
+    for (i, line) in lines.iter().enumerate() {
+        let y = 4 + i as i16 * LINE_HEIGHT;
+        let _ = canvas.string(4, y, line, sdl2::pixels::Color::RGB(0xFF, 0xFF, 0xFF));
+    }
+}
+
+/// Draw the savestate browser over `area`: a grid of slots, each with the screen it was saved at
+/// (or a blank one), its name, and how long ago it was saved. The selected slot is outlined.
+fn draw_state_browser(
+    canvas: &mut Canvas<Window>, browser: &StateBrowser, thumbnails: &mut [ScreenTexture], palette: Palette,
+    area: Rect,
+) {
+    use sdl2::gfx::primitives::DrawRenderer;
+    use sdl2::pixels::Color;
+
+    const CHAR_SIZE: i16 = 8;
+    const LINE_HEIGHT: i16 = CHAR_SIZE + 2;
+    let white = Color::RGB(0xFF, 0xFF, 0xFF);
+    let (left, top) = (area.left() as i16, area.top() as i16);
+    let (right, bottom) = (area.right() as i16 - 1, area.bottom() as i16 - 1);
+    let _ = canvas.box_(left, top, right, bottom, Color::RGBA(0, 0, 0, 0xE0));
+    let _ = canvas.string(left + 4, top + 4, tr("osd.savestates"), white);
+    let _ = canvas.string(left + 4, bottom - 3 - CHAR_SIZE, tr("osd.browser_help"), white);
+
+    // Text centered on `x`.
+    let text = |canvas: &mut Canvas<Window>, x: i32, y: i32, text: &str| {
+        let width = text.chars().count() as i16 * CHAR_SIZE;
+        let _ = canvas.string(x as i16 - width / 2, y as i16, text, white);
+    };
+    let grid_top = area.top() + i32::from(LINE_HEIGHT) + 4;
+    let grid_height = area.height().saturating_sub(2 * (LINE_HEIGHT as u32 + 4));
+    let (cell_width, cell_height) = (area.width() / state_browser::COLUMNS as u32, grid_height / state_browser::ROWS as u32);
+    // Each thumbnail is as big as fits above two lines of text, keeping the screen's shape.
+    let scale = (cell_width.saturating_sub(8) as f32 / SCREEN_WIDTH as f32)
+        .min(cell_height.saturating_sub(8 + 2 * LINE_HEIGHT as u32) as f32 / SCREEN_HEIGHT as f32);
+    let (width, height) = ((SCREEN_WIDTH as f32 * scale) as u32, (SCREEN_HEIGHT as f32 * scale) as u32);
+    let now = SystemTime::now();
+    for (i, (slot, texture)) in browser.slots.iter().zip(thumbnails).enumerate() {
+        let x = area.left() + (i % state_browser::COLUMNS) as i32 * cell_width as i32 + cell_width as i32 / 2;
+        let y = grid_top + (i / state_browser::COLUMNS) as i32 * cell_height as i32 + 4;
+        let thumbnail = Rect::new(x - width as i32 / 2, y, width.max(1), height.max(1));
+        match &slot.contents {
+            Contents::Saved { screen, .. } => {
+                texture.update(screen, palette, SCREEN_HEIGHT);
+                canvas.copy(&texture.texture, None, thumbnail).unwrap();
+            }
+            _ => {
+                let (l, t) = (thumbnail.left() as i16, thumbnail.top() as i16);
+                let (r, b) = (thumbnail.right() as i16 - 1, thumbnail.bottom() as i16 - 1);
+                let _ = canvas.box_(l, t, r, b, Color::RGB(0x30, 0x30, 0x30));
+            }
+        }
+        if i == browser.selected {
+            let (l, t) = (thumbnail.left() as i16, thumbnail.top() as i16);
+            let (r, b) = (thumbnail.right() as i16 - 1, thumbnail.bottom() as i16 - 1);
+            for inset in 1..4 {
+                let _ = canvas.rectangle(l - inset, t - inset, r + inset, b + inset, Color::RGB(0xFF, 0xD0, 0x20));
+            }
+        }
+        let y = thumbnail.bottom() + 4;
+        text(canvas, x, y, &state_browser::Slot::name(i));
+        text(canvas, x, y + i32::from(LINE_HEIGHT), &slot.status(now));
+    }
+}
+
+/// Draw each sound channel's recent output over the bottom half of `area`, one trace per channel
+/// from top to bottom. Muted channels are drawn dimmed.
+fn draw_scope(canvas: &mut Canvas<Window>, scope: &Scope, muted: &[bool; 4], area: Rect) {
+    use sdl2::gfx::primitives::DrawRenderer;
+
+    const COLORS: [(u8, u8, u8); 4] = [(0xFF, 0x60, 0x60), (0xFF, 0xD0, 0x40), (0x60, 0xA0, 0xFF), (0x80, 0xFF, 0x80)];
+    let width = area.width() as usize;
+    let strip_height = area.height() as i32 / 8;
+    if width < 2 || strip_height < 4 {
+        return;
+    }
+    let top = area.y() + area.height() as i32 / 2;
+    let _ = canvas.box_(
+        area.left() as i16, top as i16, area.right() as i16 - 1, area.bottom() as i16 - 1,
+        sdl2::pixels::Color::RGBA(0, 0, 0, 0xB0),
+    );
+    for (i, samples) in scope.channels.iter().enumerate() {
+        // Show half the samples, starting at the first rising edge in the older half, so a steady
+        // tone stands still instead of scrolling.
+        let shown = SCOPE_SAMPLES / 2;
+        if samples.len() < SCOPE_SAMPLES {
+            continue;
+        }
+        let start = (1..shown).find(|&j| samples[j - 1] < samples[j]).unwrap_or(0);
+        let bottom = top + (i as i32 + 1) * strip_height - 2;
+        let points: Vec<Point> = (0..width).map(|x| {
+            let val = samples[start + x * shown / width];
+            let y = bottom - i32::from(val) * (strip_height - 4) / 15;
+            Point::new(area.x() + x as i32, y)
+        }).collect();
+        let (r, g, b) = COLORS[i];
+        canvas.set_draw_color(if muted[i] {
+            sdl2::pixels::Color::RGB(r / 3, g / 3, b / 3)
+        } else {
+            sdl2::pixels::Color::RGB(r, g, b)
+        });
+        let _ = canvas.draw_lines(&points[..]);
+    }
+}
+
+/// How many pixels wide and high each address is in the heatmap window.
+const HEATMAP_SCALE: u32 = 2;
+
+/// The heatmap window (F4), a second window showing where the first instance's CPU reads and
+/// writes: one square per address, 256 to a row from 0x0000 at the top left, green for reads and
+/// red for writes. See `heatmap`. It's made hidden along with the main window and lives as long,
+/// so its texture is made once, and F4 shows and hides it.
+struct HeatmapWindow<'a> {
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+}
+
+/// Create the heatmap window, hidden until F4 shows it.
+fn create_heatmap_window(sdl: &sdl2::Sdl) -> Canvas<Window> {
+    let sdl_video = sdl.video().expect("Failed to access SDL video subsystem");
+    let size = 256 * HEATMAP_SCALE;
+    let window = sdl_video.window(tr("title.heatmap"), size, size)
+        .hidden()
+        .build()
+        .expect("Failed to create heatmap window");
+    window.into_canvas().build().expect("Failed to get heatmap window canvas")
+}
+
+impl<'a> HeatmapWindow<'a> {
+    fn new(canvas: Canvas<Window>, texture_creator: &'a TextureCreator<WindowContext>) -> Self {
+        let texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB888, 256, 256)
+            .expect("Failed to create heatmap texture");
+        HeatmapWindow { canvas, texture }
+    }
+
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    /// Draw the heatmap of `cpu`, labeling the regions big enough to fit their names.
+    fn draw(&mut self, cpu: &Cpu) {
+        use sdl2::gfx::primitives::DrawRenderer;
+
+        let heatmap = match &cpu.heatmap {
+            Some(heatmap) => heatmap,
+            None => return,
+        };
+        // Untouched addresses are dark blue rather than black, to tell the map from the background.
+        self.texture.with_lock(None, |pixels, pitch| {
+            for (row, addrs) in pixels.chunks_mut(pitch).zip((0..heatmap::SIZE).step_by(256)) {
+                for (x, out) in row.chunks_exact_mut(4).take(256).enumerate() {
+                    let (read, written) = heatmap.intensity((addrs + x) as u16);
+                    out.copy_from_slice(&(u32::from(written) << 16 | u32::from(read) << 8 | 0x18).to_ne_bytes());
+                }
+            }
+        }).unwrap();
+        self.canvas.copy(&self.texture, None, None).unwrap();
+
+        // The built-in SDL_gfx font is 8x8 pixels, so a label needs four rows of addresses.
+        for region in memory_map::regions(cpu).filter(|region| region.end - region.start >= 0x3FF) {
+            let y = (region.start >> 8) as i16 * HEATMAP_SCALE as i16;
+            let width = region.name.len() as i16 * 8;
+            let _ = self.canvas.box_(0, y, width + 3, y + 9, sdl2::pixels::Color::RGBA(0, 0, 0, 0xB0));
+            let _ = self.canvas.string(2, y + 1, region.name, sdl2::pixels::Color::RGB(0xFF, 0xFF, 0xFF));
+        }
+        self.canvas.present();
+    }
+}
+
+/// Show the heatmap window and start recording accesses for it, or hide it and stop.
+fn toggle_heatmap(window: &mut HeatmapWindow, cpu: &mut Cpu) {
+    if cpu.heatmap.take().is_some() {
+        window.canvas.window_mut().hide();
+    } else {
+        cpu.heatmap = Some(Heatmap::new());
+        window.canvas.window_mut().show();
+    }
+}
+
+/// Write cartridge RAM to the save file if it's time to, reporting rather than stopping on errors.
+fn update_save_file(save_file: &mut Option<SaveFile>, cpu: &Cpu) {
+    if let Some(save_file) = save_file {
+        if let Err(e) = save_file.update(&cpu.cart, cpu.pause.is_paused()) {
+            println!("Failed to write save file: {}", e);
+        }
+    }
+}
+
+/// Start capturing whatever `cpu` outputs that the frame hashes, audio dump, and VGM recording
+/// need.
+fn attach_frame_outputs(
+    frame_hashes: &Option<FrameHashes>, audio_dump: &Option<AudioDump>, vgm: &mut Option<VgmRecorder>, cpu: &mut Cpu,
+) {
+    if let Some(frame_hashes) = frame_hashes {
+        frame_hashes.attach(cpu);
+    }
+    if let Some(audio_dump) = audio_dump {
+        audio_dump.attach(cpu);
+    }
+    if let Some(Err(e)) = vgm.as_mut().map(|v| v.attach(cpu)) {
+        println!("Failed to write VGM file, giving up on it: {}", e);
+        *vgm = None;
+    }
+}
+
+/// Write the hash, audio, and sound register writes of the frame just emulated, giving up on any
+/// whose file can't be written.
+fn write_frame_outputs(
+    frame_hashes: &mut Option<FrameHashes>, audio_dump: &mut Option<AudioDump>, vgm: &mut Option<VgmRecorder>,
+    frame: u64, cpu: &mut Cpu,
+) {
+    let hash_result = frame_hashes.as_mut().map(|h| h.write_frame(frame, cpu));
+    if let Some(Err(e)) = hash_result {
+        println!("Failed to write frame hashes, giving up on them: {}", e);
+        *frame_hashes = None;
+    }
+    let dump_result = audio_dump.as_mut().map(|d| d.write_frame(cpu));
+    if let Some(Err(e)) = dump_result {
+        println!("Failed to write audio dump, giving up on it: {}", e);
+        *audio_dump = None;
+    }
+    let vgm_result = vgm.as_mut().map(|v| v.write_frame(&cpu.audio));
+    if let Some(Err(e)) = vgm_result {
+        println!("Failed to write VGM file, giving up on it: {}", e);
+        *vgm = None;
+    }
+    if let Some(samples) = &mut cpu.audio.capture {
+        samples.clear();
+    }
+    if let Some(log) = &mut cpu.audio.register_log {
+        log.clear();
+    }
+}
+
+/// Run one or more emulator instances side by side in a single window. Only the first instance
+/// is audible.
+pub fn start_frontend(cpus: &mut [&mut Cpu], options: &mut FrontendOptions, config: &Config) {
+    let sdl = sdl2::init().expect("Failed to initialize SDL");
+
+    let mut canvas = create_window(&sdl, cpus.len());
+    let heatmap_canvas = create_heatmap_window(&sdl);
+    let heatmap_textures = heatmap_canvas.texture_creator();
+    let mut heatmap = HeatmapWindow::new(heatmap_canvas, &heatmap_textures);
     let mut sdl_events = sdl.event_pump().expect("Failed to get SDL event pump");
 
     let mut sdl_fps = sdl2::gfx::framerate::FPSManager::new();
@@ -53,87 +733,328 @@ pub fn start_frontend(cpu: &mut Cpu) {
     let mut controllers = vec![];
 
     let sdl_audio = sdl.audio().expect("Failed to access SDL audio subsystem");
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(2), // Stereo
-        samples: Some(SAMPLE_BUFFER_SIZE as u16),
-    };
-    let mut audio_queue = sdl_audio.open_queue(None, &desired_spec).expect("Failed to open audio queue");
+    let mut audio_queue = sdl_audio.open_queue(None, &config.audio.desired_spec()).expect("Failed to open audio queue");
     audio_queue.resume();
+    for cpu in cpus.iter_mut() {
+        cpu.audio.set_sample_rate(audio_queue.spec().freq as u32);
+    }
 
-    run_emulator(cpu, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, false, None, &HashSet::new())
+    run_emulator(cpus, &mut canvas, &mut heatmap, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, false, None, &HashSet::new(), options, config)
 }
 
 fn run_emulator(
-    cpu: &mut Cpu, canvas: &mut Canvas<Window>, sdl_events: &mut EventPump, sdl_fps: &mut FPSManager,
+    cpus: &mut [&mut Cpu], canvas: &mut Canvas<Window>, heatmap: &mut HeatmapWindow, sdl_events: &mut EventPump,
+    sdl_fps: &mut FPSManager,
     sdl_controllers: &GameControllerSubsystem, controllers: &mut Vec<GameController>, audio_queue: &mut AudioQueue<u8>,
-    debug: bool, num_instrs: Option<usize>, watches: &HashSet<Watch>
+    debug: bool, num_instrs: Option<usize>, watches: &HashSet<Watch>, options: &mut FrontendOptions,
+    config: &Config,
 ) {
-    let mut speed_multiplier: f32 = 1.0;
-    let mut paused = false;
-    let mut pause_next_frame = false;
+    let FrontendOptions {
+        repro, input, frame_hashes, audio_dump, vgm, frame_limit, save_file, save_path, link, barcodes, low_power,
+        camera,
+    } = options;
+    attach_frame_outputs(frame_hashes, audio_dump, vgm, cpus[0]);
+    let mut session = EmulatorSession::new();
+    let mut status = Status::new(cpus);
+    // Edits to the config file are applied as the game runs. Settings only read at startup, like
+    // the sound card's, wait for a restart.
+    let mut config = config.clone();
+    let mut config_watcher = ConfigWatcher::new();
+    // A message about the config shown over the game, and when it goes away.
+    let mut config_message: Option<(String, Instant)> = None;
+    let mut limiter = FrameLimiter::game_boy();
+    let mut tilts = vec![Tilt::default(); cpus.len()];
+    let texture_creator = canvas.texture_creator();
+    let mut textures: Vec<_> = cpus.iter().map(|_| ScreenTexture::new(&texture_creator)).collect();
+    // The savestate browser while it's open, and the textures for its thumbnails, made the first
+    // time it opens.
+    let mut browser: Option<StateBrowser> = None;
+    let mut thumbnails = Vec::new();
+    let mut clip = ClipRecorder::new(&config.clip);
+    // The number of frames of real time run so far, not counting frames spent paused.
+    let mut frame: u64 = 0;
+    // Cycles emulated so far by the first instance, which the input stream, the frame outputs, and
+    // the frame limit count frames of.
+    let mut emulated: u64 = 0;
+    let mut frames = 0;
+    let mut fps_start = Instant::now();
+    // Whether the window is minimized, so there's no point drawing.
+    let mut minimized = false;
+    // Whether the window needs drawing even though the screen hasn't changed, e.g. because it was
+    // uncovered.
+    let mut redraw = true;
+    // Whether to return once the window is drawn, after stepping in the debugger or hitting a
+    // breakpoint, so it shows the frame as far as it got rather than the last complete one.
+    let mut stop_after_draw = false;
+    let mut was_paused = false;
+    let mut lcd_off_frames = vec![0; cpus.len()];
+    let mut next_barcode = 0;
+    // With a WorkBoy plugged in, the keyboard is its keyboard, except for the function keys.
+    let workboy = cpus[0].serial.device_mut::<WorkBoy>().is_some();
     'main: loop {
-        const BYTES_PER_PIXEL: usize = 4;
-        let mut image = [0u8; SCREEN_WIDTH * SCREEN_HEIGHT * BYTES_PER_PIXEL];
-
-        for tile_row in 0..SCREEN_HEIGHT {
-            for tile_col in 0..SCREEN_WIDTH {
-                let pixel_i = (tile_row * SCREEN_WIDTH + tile_col) * 4;
-                let color_i = cpu.gpu.screen_buffer[tile_row][tile_col] as usize;
-                let color = GAME_BOY_COLORS[color_i].rgb();
-                image[pixel_i + 2] = color.0;
-                image[pixel_i + 1] = color.1;
-                image[pixel_i + 0] = color.2;
+        // Redraw on pausing and resuming, to show whether the game is paused.
+        if cpus[0].pause.is_paused() != was_paused {
+            was_paused = !was_paused;
+            redraw = true;
+        }
+        if let Some(result) = config_watcher.poll() {
+            let message = match result {
+                Ok(new_config) => {
+                    // Keys held down might not be released under the new bindings.
+                    if new_config.keys.players() != config.keys.players() || new_config.controller != config.controller {
+                        for cpu in cpus.iter_mut() {
+                            cpu.joypad.set_pressed(0);
+                        }
+                    }
+                    if new_config.clip.seconds != config.clip.seconds {
+                        clip = ClipRecorder::new(&new_config.clip);
+                    }
+                    config = new_config;
+                    tr("osd.config_reloaded").to_string()
+                }
+                Err(e) => {
+                    let causes: Vec<_> = e.iter_chain().map(|cause| cause.to_string()).collect();
+                    tr_with("osd.config_error", &[("error", &causes.join(": "))])
+                }
+            };
+            println!("{}", message);
+            config_message = Some((message, Instant::now() + CONFIG_MESSAGE_DURATION));
+            redraw = true;
+        }
+        if config_message.as_ref().map_or(false, |&(_, until)| Instant::now() >= until) {
+            config_message = None;
+            redraw = true;
+        }
+        let low_power = *low_power || config.power.low_power;
+        // At half rate, only even frames are drawn. Odd ones are still emulated.
+        let skip_frame = low_power && config.power.half_rate && frame % 2 == 1;
+        if !minimized && (!cpus[0].pause.is_paused() && !skip_frame || redraw) {
+            let screen_width = canvas.viewport().width() / cpus.len() as u32;
+            let screen_height = canvas.viewport().height();
+            for (i, (cpu, texture)) in cpus.iter().zip(&mut textures).enumerate() {
+                let screen = Rect::new(i as i32 * screen_width as i32, 0, screen_width, screen_height);
+                let idle = idle_reason(cpu, lcd_off_frames[i]);
+                let beam = if stop_after_draw && config.video.scanline_marker { cpu.gpu.beam() } else { None };
+                draw_screen(canvas, texture, cpu, screen, idle, beam, &config.video);
+                if let Some(scope) = &cpu.audio.scope {
+                    draw_scope(canvas, scope, &cpu.audio.muted, screen);
+                }
+            }
+            if let Some(browser) = &browser {
+                if thumbnails.is_empty() {
+                    thumbnails = (0..state_browser::SLOTS).map(|_| ScreenTexture::new(&texture_creator)).collect();
+                }
+                let screen = Rect::new(0, 0, screen_width, screen_height);
+                draw_state_browser(canvas, browser, &mut thumbnails, config.video.palette, screen);
+            }
+            let mut osd_lines = Vec::new();
+            if let Some((message, _)) = &config_message {
+                osd_lines.push(message.clone());
+            }
+            if low_power {
+                osd_lines.push(tr_with("osd.cpu_usage", &[("percent", &limiter.cpu_percent())]));
             }
+            draw_osd(canvas, &osd_lines);
+            canvas.present();
+            heatmap.draw(cpus[0]);
+            frames += 1;
+            redraw = false;
         }
 
-        let surface = sdl2::surface::Surface::from_data(
-            &mut image[..],
-            SCREEN_WIDTH as u32,
-            SCREEN_HEIGHT as u32,
-            (SCREEN_WIDTH * BYTES_PER_PIXEL) as u32,
-            sdl2::pixels::PixelFormatEnum::RGB888,
-        ).unwrap();
-        let texture_creator = canvas.texture_creator();
-        let texture = texture_creator.create_texture_from_surface(&surface).unwrap();
+        if fps_start.elapsed() >= Duration::from_secs(1) {
+            status.fps = frames;
+            frames = 0;
+            fps_start = Instant::now();
+        }
+        status.speed_multiplier = session.speed_multiplier();
+        status.pause = cpus[0].pause;
+        let title = status.format(&config.window.title);
+        if canvas.window().title() != title {
+            let _ = canvas.window_mut().set_title(&title);
+        }
+        if stop_after_draw {
+            break 'main;
+        }
 
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+        session.end_frame(cpus);
 
-        if pause_next_frame {
-            pause_next_frame = false;
-            paused = true;
-        }
+        // While replaying, the joypad is driven by the recorded inputs only.
+        let replaying = match repro {
+            ReproMode::Play { repro, next } => *next < repro.inputs.len(),
+            _ => false,
+        };
+        let joypad_before_events = cpus[0].joypad.clone();
 
-        for event in sdl_events.poll_iter() {
+        // While paused, nothing happens until an event arrives, so sleep until one does instead of
+        // spinning at 60 Hz. The timeout keeps the window title's FPS up to date.
+        let idle = cpus[0].pause.is_paused() && num_instrs.is_none();
+        let first_event = if idle { sdl_events.wait_event_timeout(IDLE_WAIT_MS) } else { None };
+        for event in first_event.into_iter().chain(sdl_events.poll_iter()) {
             match event {
                 Event::Quit { .. } => break 'main,
 
+                Event::Window { window_id, win_event, .. } if window_id == heatmap.id() => {
+                    if let WindowEvent::Close = win_event {
+                        toggle_heatmap(heatmap, cpus[0]);
+                    }
+                }
+                Event::Window { win_event, .. } => match win_event {
+                    // With the heatmap window around, even hidden, closing this one doesn't quit
+                    // by itself.
+                    WindowEvent::Close => break 'main,
+                    WindowEvent::FocusLost if config.window.pause_when_unfocused =>
+                        set_paused(cpus, PauseReason::FocusLost, true),
+                    WindowEvent::FocusGained if config.window.pause_when_unfocused =>
+                        set_paused(cpus, PauseReason::FocusLost, false),
+                    WindowEvent::Minimized | WindowEvent::Hidden => minimized = true,
+                    WindowEvent::Restored | WindowEvent::Maximized | WindowEvent::Shown => {
+                        minimized = false;
+                        redraw = true;
+                    }
+                    WindowEvent::Exposed | WindowEvent::SizeChanged(..) => redraw = true,
+                    _ => {}
+                },
+
+                // While the savestate browser is open, it takes the keys and buttons that would
+                // press joypad keys. Releasing them still goes to the game, so none stay held.
+                Event::KeyDown { keycode: Some(keycode), .. } if browser.is_some() => {
+                    let action = match keycode {
+                        Keycode::F3 | Keycode::Escape => Some(Action::Close),
+                        Keycode::Return | Keycode::KpEnter => Some(Action::Load),
+                        Keycode::Left => Some(Action::Move(-1, 0)),
+                        Keycode::Right => Some(Action::Move(1, 0)),
+                        Keycode::Up => Some(Action::Move(0, -1)),
+                        Keycode::Down => Some(Action::Move(0, 1)),
+                        _ => {
+                            let mut joypad = Joypad::new();
+                            set_key(config.keys.players()[0], &mut joypad, keycode, true);
+                            state_browser::action(joypad.pressed())
+                        }
+                    };
+                    if let Some(action) = action {
+                        browser_action(&mut browser, action, &mut session, cpus);
+                        redraw = true;
+                    }
+                }
+                Event::ControllerButtonDown { button, .. } if browser.is_some() => {
+                    let mut joypad = Joypad::new();
+                    let action = if set_controller_button(&config.controller, &mut joypad, button, true) {
+                        state_browser::action(joypad.pressed())
+                    } else if button == Button::Guide {
+                        Some(Action::Close)
+                    } else {
+                        None
+                    };
+                    if let Some(action) = action {
+                        browser_action(&mut browser, action, &mut session, cpus);
+                        redraw = true;
+                    }
+                }
+
+                Event::TextInput { text, .. } if workboy && browser.is_none() => {
+                    if let Some(workboy) = cpus[0].serial.device_mut::<WorkBoy>() {
+                        for c in text.chars() {
+                            workboy.press(workboy::Key::Char(c));
+                        }
+                    }
+                }
+                Event::KeyDown { keycode: Some(keycode), keymod, .. } if workboy && !is_function_key(keycode) => {
+                    let key = workboy_key(keycode, keymod);
+                    if let (Some(key), Some(workboy)) = (key, cpus[0].serial.device_mut::<WorkBoy>()) {
+                        workboy.press(key);
+                    }
+                }
+
                 Event::KeyDown { keycode: Some(keycode), keymod, repeat, .. } => {
                     let modifiers = Mod::LSHIFTMOD | Mod::RSHIFTMOD | Mod::LCTRLMOD |
                         Mod::RCTRLMOD | Mod::LALTMOD | Mod::RALTMOD | Mod::LGUIMOD |
                         Mod::RGUIMOD;
-                    if !keymod.intersects(modifiers) {
+                    let shift = Mod::LSHIFTMOD | Mod::RSHIFTMOD;
+                    if let Some(channel) = audio_channel_key(keycode) {
+                        // 1-4 mute a channel, and with shift solo it.
+                        if !repeat && !keymod.intersects(modifiers & !shift) {
+                            for cpu in cpus.iter_mut() {
+                                if keymod.intersects(shift) {
+                                    cpu.audio.toggle_solo(channel);
+                                } else {
+                                    cpu.audio.muted[channel] = !cpu.audio.muted[channel];
+                                }
+                            }
+                            let playing: Vec<String> = cpus[0].audio.muted.iter().enumerate()
+                                .map(|(i, &muted)| if muted { "-".to_string() } else { (i + 1).to_string() })
+                                .collect();
+                            println!("{}", tr_with("messages.audio_channels", &[("channels", &playing.join(" "))]));
+                        }
+                    } else if !keymod.intersects(modifiers) {
+                        if !repeat {
+                            for (cpu, keys) in cpus.iter_mut().zip(&config.keys.players()) {
+                                set_key(keys, &mut cpu.joypad, keycode, true);
+                            }
+                            tilts[0].set_key(&config.keys.tilt, keycode, true);
+                        }
                         match keycode {
-                            Keycode::W if !repeat => cpu.joypad.dir_key_down(DirKey::Up),
-                            Keycode::A if !repeat => cpu.joypad.dir_key_down(DirKey::Left),
-                            Keycode::S if !repeat => cpu.joypad.dir_key_down(DirKey::Down),
-                            Keycode::D if !repeat => cpu.joypad.dir_key_down(DirKey::Right),
-                            Keycode::Return if !repeat =>
-                                cpu.joypad.button_key_down(ButtonKey::Start),
-                            Keycode::Tab if !repeat =>
-                                cpu.joypad.button_key_down(ButtonKey::Select),
-                            Keycode::K if !repeat => cpu.joypad.button_key_down(ButtonKey::A),
-                            Keycode::J if !repeat => cpu.joypad.button_key_down(ButtonKey::B),
                             Keycode::P if !repeat => {
-                                paused = !paused;
                                 if debug {
                                     break 'main;
                                 }
+                                run_command(&mut session, cpus, Command::TogglePause);
                             },
-                            Keycode::Space => {
-                                paused = false;
-                                pause_next_frame = true;
+                            Keycode::Space => run_command(&mut session, cpus, Command::FrameAdvance),
+                            Keycode::F2 if !repeat => run_command(&mut session, cpus, Command::Reset),
+                            Keycode::F5 if !repeat =>
+                                run_command(&mut session, cpus, Command::SaveState(state_browser::slot_path(save_path, 0))),
+                            Keycode::F8 if !repeat =>
+                                run_command(&mut session, cpus, Command::LoadState(state_browser::slot_path(save_path, 0))),
+                            Keycode::F3 if !repeat => {
+                                browser = Some(open_browser(cpus, save_path));
+                                redraw = true;
+                            }
+                            Keycode::F12 if !repeat => {
+                                for (i, cpu) in cpus.iter().enumerate() {
+                                    let path = if cpus.len() == 1 {
+                                        format!("rugby-state-{}.txt", cpu.cycles())
+                                    } else {
+                                        format!("rugby-state-{}-{}.txt", i + 1, cpu.cycles())
+                                    };
+                                    dump_state(cpu, &path);
+                                }
+                            }
+                            Keycode::F10 if !repeat => {
+                                let path = format!("rugby-clip-{}.gif", cpus[0].cycles());
+                                save_clip(&clip, &config.clip, &path);
+                            }
+                            Keycode::F11 if !repeat => {
+                                for (i, cpu) in cpus.iter().enumerate() {
+                                    let path = if cpus.len() == 1 {
+                                        format!("rugby-art-{}.png", cpu.cycles())
+                                    } else {
+                                        format!("rugby-art-{}-{}.png", i + 1, cpu.cycles())
+                                    };
+                                    export_art(cpu, &config.photo, &path);
+                                }
+                            }
+                            Keycode::F4 if !repeat => toggle_heatmap(heatmap, cpus[0]),
+                            Keycode::F6 if !repeat => {
+                                let audio = &mut cpus[0].audio;
+                                audio.scope = if audio.scope.is_some() { None } else { Some(Scope::new()) };
+                                redraw = true;
+                            }
+                            Keycode::F7 if !repeat && !barcodes.is_empty() => {
+                                let barcode = &barcodes[next_barcode];
+                                next_barcode = (next_barcode + 1) % barcodes.len();
+                                scan_barcode(cpus[0], barcode);
+                            }
+                            Keycode::F9 if !repeat => {
+                                if let ReproMode::Record(r) = repro {
+                                    let cpu = &cpus[0];
+                                    *r = Repro::new(
+                                        cpu.cart.rom(),
+                                        cpu.save_state(),
+                                        cpu.cycles() as u64,
+                                        cpu.joypad.pressed(),
+                                    );
+                                    println!("{}", tr("messages.recording_restarted"));
+                                }
                             }
                             _ => {}
                         }
@@ -145,19 +1066,13 @@ fn run_emulator(
                         Mod::RCTRLMOD | Mod::LALTMOD | Mod::RALTMOD | Mod::LGUIMOD |
                         Mod::RGUIMOD;
                     if !keymod.intersects(modifiers) {
+                        for (cpu, keys) in cpus.iter_mut().zip(&config.keys.players()) {
+                            set_key(keys, &mut cpu.joypad, keycode, false);
+                        }
+                        tilts[0].set_key(&config.keys.tilt, keycode, false);
                         match keycode {
-                            Keycode::W => cpu.joypad.dir_key_up(DirKey::Up),
-                            Keycode::A => cpu.joypad.dir_key_up(DirKey::Left),
-                            Keycode::S => cpu.joypad.dir_key_up(DirKey::Down),
-                            Keycode::D => cpu.joypad.dir_key_up(DirKey::Right),
-                            Keycode::Return => cpu.joypad.button_key_up(ButtonKey::Start),
-                            Keycode::Tab => cpu.joypad.button_key_up(ButtonKey::Select),
-                            Keycode::K => cpu.joypad.button_key_up(ButtonKey::A),
-                            Keycode::J => cpu.joypad.button_key_up(ButtonKey::B),
-                            Keycode::RightBracket =>
-                                speed_multiplier = (speed_multiplier * 2.0).min(4.0),
-                            Keycode::LeftBracket =>
-                                speed_multiplier = (speed_multiplier / 2.0).max(0.25),
+                            Keycode::RightBracket => run_command(&mut session, cpus, Command::SpeedUp),
+                            Keycode::LeftBracket => run_command(&mut session, cpus, Command::SlowDown),
                             _ => {}
                         }
                     }
@@ -177,35 +1092,27 @@ fn run_emulator(
                     info!("Removed controller with index {}", which);
                 }
 
-                Event::ControllerButtonDown { button, .. } => {
-                    match button {
-                        Button::A => cpu.joypad.button_key_down(ButtonKey::A),
-                        Button::X => cpu.joypad.button_key_down(ButtonKey::B),
-                        Button::Start => cpu.joypad.button_key_down(ButtonKey::Start),
-                        Button::Back => cpu.joypad.button_key_down(ButtonKey::Select),
-                        Button::DPadLeft => cpu.joypad.dir_key_down(DirKey::Left),
-                        Button::DPadRight => cpu.joypad.dir_key_down(DirKey::Right),
-                        Button::DPadUp => cpu.joypad.dir_key_down(DirKey::Up),
-                        Button::DPadDown => cpu.joypad.dir_key_down(DirKey::Down),
-                        _ => {}
+                Event::ControllerButtonDown { which, button, .. } => {
+                    // Each controller drives its own instance, in the order they were connected.
+                    let cpu = &mut cpus[controller_player(controllers, which, cpus.len())];
+                    if !set_controller_button(&config.controller, &mut cpu.joypad, button, true) && button == Button::Guide {
+                        browser = Some(open_browser(cpus, save_path));
+                        redraw = true;
                     }
                 }
 
-                Event::ControllerButtonUp { button, .. } => {
-                    match button {
-                        Button::A => cpu.joypad.button_key_up(ButtonKey::A),
-                        Button::X => cpu.joypad.button_key_up(ButtonKey::B),
-                        Button::Start => cpu.joypad.button_key_up(ButtonKey::Start),
-                        Button::Back => cpu.joypad.button_key_up(ButtonKey::Select),
-                        Button::DPadLeft => cpu.joypad.dir_key_up(DirKey::Left),
-                        Button::DPadRight => cpu.joypad.dir_key_up(DirKey::Right),
-                        Button::DPadUp => cpu.joypad.dir_key_up(DirKey::Up),
-                        Button::DPadDown => cpu.joypad.dir_key_up(DirKey::Down),
-                        Button::RightShoulder =>
-                            speed_multiplier = (speed_multiplier * 2.0).min(4.0),
-                        Button::LeftShoulder =>
-                            speed_multiplier = (speed_multiplier / 2.0).max(0.25),
-                        _ => {}
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    tilts[controller_player(controllers, which, cpus.len())].set_stick(axis, value);
+                }
+
+                Event::ControllerButtonUp { which, button, .. } => {
+                    let cpu = &mut cpus[controller_player(controllers, which, cpus.len())];
+                    if !set_controller_button(&config.controller, &mut cpu.joypad, button, false) {
+                        match button {
+                            Button::RightShoulder => run_command(&mut session, cpus, Command::SpeedUp),
+                            Button::LeftShoulder => run_command(&mut session, cpus, Command::SlowDown),
+                            _ => {}
+                        }
                     }
                 }
 
@@ -213,55 +1120,331 @@ fn run_emulator(
             }
         }
 
+        if replaying {
+            cpus[0].joypad = joypad_before_events;
+        }
+        let (input_frame, offset) = emulated_frame(emulated);
+        if let Some(pressed) = input.as_mut().and_then(|i| i.poll(input_frame, offset)) {
+            cpus[0].joypad.set_pressed(pressed);
+        }
+        if let ReproMode::Record(r) = repro {
+            r.record_input(cpus[0].cycles() as u64, cpus[0].joypad.pressed());
+        }
+        for (cpu, tilt) in cpus.iter_mut().zip(&tilts) {
+            let (x, y) = tilt.value();
+            cpu.cart.set_tilt(x, y);
+        }
+        if let Some(image) = camera.as_mut().and_then(CameraImage::poll) {
+            cpus[0].cart.set_camera_image(&image);
+        }
+
+        // Whether this frame played any sound, so the audio queue can pace the next one. Otherwise,
+        // e.g. while the CPU is stopped, frames are paced by the clock.
+        let mut paced_by_audio = false;
         match num_instrs {
             Some(n) => {
-                cpu.step_n(n, watches);
-                break 'main;
+                let start = cpus[0].cycles();
+                cpus[0].step_n(n, watches);
+                emulated += (cpus[0].cycles() - start) as u64;
+                stop_after_draw = true;
+                redraw = true;
+                continue 'main;
             },
             None => {
-                if !paused {
-                    let should_break = cpu.step_cycles(
-                        (CYCLES_PER_FRAME as f32 * speed_multiplier) as usize,
-                        audio_queue,
-                        watches,
-                    );
+                if !cpus[0].pause.is_paused() {
+                    if let Some(heatmap) = &mut cpus[0].heatmap {
+                        heatmap.decay();
+                    }
+                    let cycles = session.cycles_per_frame();
+                    // Scaling the sample period by the speed too makes as many samples per frame at
+                    // any speed, so the audio queue paces the game at that speed.
+                    let scale = rate_control::sample_period_scale(audio_queue);
+                    cpus[0].audio.set_sample_period_scale(scale * f64::from(session.speed_multiplier()));
+                    let samples_before = cpus[0].audio.samples_output();
+                    if let Some(link) = link {
+                        link.start_frame(cpus[0], cycles);
+                    }
+                    let mut should_break = false;
+                    let mut reached_limit = false;
+                    let end = emulated + cycles as u64;
+                    // Frame outputs go by emulated frames, which at other speeds don't line up with
+                    // frames of real time, so stop at the end of each one to write them.
+                    while emulated < end && !should_break && !reached_limit {
+                        let (emulated_frame_number, offset) = emulated_frame(emulated);
+                        let piece = (end - emulated).min(CYCLES_PER_FRAME as u64 - offset) as usize;
+                        let start = cpus[0].cycles();
+                        for (i, cpu) in cpus.iter_mut().enumerate() {
+                            // Only the first instance is audible.
+                            let mut sink = QueueSink(&mut *audio_queue);
+                            let audio_queue: Option<&mut dyn AudioSink> = if i == 0 { Some(&mut sink) } else { None };
+                            should_break |= match repro {
+                                ReproMode::Play { repro, next } =>
+                                    step_cycles_replaying(cpu, piece, &repro.inputs, next, audio_queue, watches),
+                                ReproMode::Record(repro) if i == 0 =>
+                                    step_cycles_with_input(cpu, piece, input, emulated, audio_queue, watches, Some(repro)),
+                                _ if i == 0 => step_cycles_with_input(cpu, piece, input, emulated, audio_queue, watches, None),
+                                _ => cpu.step_cycles(piece, audio_queue, watches),
+                            };
+                        }
+                        emulated += (cpus[0].cycles() - start) as u64;
+                        if !should_break && emulated_frame(emulated).0 > emulated_frame_number {
+                            write_frame_outputs(frame_hashes, audio_dump, vgm, emulated_frame_number, cpus[0]);
+                            reached_limit = frame_limit.map_or(false, |limit| emulated_frame_number + 1 >= limit);
+                        }
+                    }
+                    if let Some(link) = link {
+                        link.end_frame(cpus[0]);
+                    }
                     if should_break {
+                        set_paused(cpus, PauseReason::Breakpoint, true);
+                        stop_after_draw = true;
+                        redraw = true;
+                        continue 'main;
+                    }
+                    paced_by_audio = cpus[0].audio.samples_output() > samples_before;
+                    for (cpu, lcd_off_frames) in cpus.iter().zip(&mut lcd_off_frames) {
+                        count_lcd_off_frames(cpu, lcd_off_frames);
+                    }
+                    clip.push(&cpus[0].gpu.screen_buffer);
+                    frame += 1;
+                    if reached_limit {
                         break 'main;
                     }
                 }
             },
         }
+        update_save_file(save_file, cpus[0]);
+
+        if low_power {
+            if !idle {
+                limiter.wait();
+            }
+        } else if paced_by_audio {
+            rate_control::wait_for_queue(audio_queue);
+        } else if !idle {
+            sdl_fps.delay();
+        }
+    }
+}
 
-        sdl_fps.delay();
+/// Pause or resume every instance for `reason`, keeping them in step.
+fn set_paused(cpus: &mut [&mut Cpu], reason: PauseReason, paused: bool) {
+    for cpu in cpus.iter_mut() {
+        if paused { cpu.pause.pause(reason) } else { cpu.pause.resume(reason) }
     }
 }
 
+/// Open the savestate browser, pausing the game behind it.
+fn open_browser(cpus: &mut [&mut Cpu], save_path: &Path) -> StateBrowser {
+    let browser = StateBrowser::open(cpus[0], save_path);
+    set_paused(cpus, PauseReason::User, true);
+    browser
+}
+
+/// Do what a key or button pressed in the savestate browser asks. Loading a state closes the
+/// browser, and closing it resumes the game unless the user had paused it.
+fn browser_action(browser: &mut Option<StateBrowser>, action: Action, session: &mut EmulatorSession, cpus: &mut [&mut Cpu]) {
+    let open = match browser.as_mut() {
+        Some(open) => open,
+        None => return,
+    };
+    let close = match action {
+        Action::Move(columns, rows) => {
+            open.move_selection(columns, rows);
+            false
+        }
+        Action::Save => {
+            run_command(session, cpus, Command::SaveState(open.selected().path.clone()));
+            open.reload_selected(cpus[0]);
+            false
+        }
+        Action::Load => match open.selected().contents {
+            Contents::Saved { .. } => {
+                run_command(session, cpus, Command::LoadState(open.selected().path.clone()));
+                true
+            }
+            _ => false,
+        },
+        Action::Close => true,
+    };
+    if close {
+        if !open.was_paused {
+            set_paused(cpus, PauseReason::User, false);
+        }
+        *browser = None;
+    }
+}
+
+/// Run a command from a hotkey. There's nowhere to reply to, so errors are printed.
+fn run_command(session: &mut EmulatorSession, cpus: &mut [&mut Cpu], command: Command) {
+    if let Err(e) = session.execute(cpus, command) {
+        println!("{}", e);
+    }
+}
+
+/// Swipe a card from the `--barcodes` list through the Barcode Boy.
+fn scan_barcode(cpu: &mut Cpu, line: &str) {
+    if let Some(barcode_boy) = cpu.serial.device_mut::<BarcodeBoy>() {
+        // The list was checked when it was read.
+        let barcode = line.split_whitespace().next().unwrap();
+        barcode_boy.scan(barcode).expect("invalid barcode");
+        println!("{}", tr_with("messages.barcode_scanned", &[("barcode", &line)]));
+    }
+}
+
+fn is_function_key(keycode: Keycode) -> bool {
+    match keycode {
+        Keycode::F1 | Keycode::F2 | Keycode::F3 | Keycode::F4 | Keycode::F5 | Keycode::F6 |
+        Keycode::F7 | Keycode::F8 | Keycode::F9 | Keycode::F10 | Keycode::F11 | Keycode::F12 => true,
+        _ => false,
+    }
+}
+
+/// The WorkBoy key for a key that doesn't type text. Text arrives separately, as `TextInput`. The
+/// application keys are ctrl + 1-9.
+fn workboy_key(keycode: Keycode, keymod: Mod) -> Option<workboy::Key> {
+    use crate::workboy::Key;
+    if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+        let app = (keycode as i32).checked_sub(Keycode::Num1 as i32)?;
+        return Key::APPS.get(app as usize).cloned();
+    }
+    match keycode {
+        Keycode::Return | Keycode::KpEnter => Some(Key::Enter),
+        Keycode::Backspace => Some(Key::Backspace),
+        Keycode::Escape => Some(Key::Escape),
+        Keycode::Left => Some(Key::Left),
+        Keycode::Right => Some(Key::Right),
+        Keycode::Up => Some(Key::Up),
+        Keycode::Down => Some(Key::Down),
+        _ => None,
+    }
+}
+
+/// Which instance a controller drives.
+fn controller_player(controllers: &[GameController], which: i32, players: usize) -> usize {
+    controllers.iter()
+        .position(|c| c.instance_id() == which)
+        .filter(|&i| i < players)
+        .unwrap_or(0)
+}
+
+/// Plays the first instance's sound through SDL.
+struct QueueSink<'a>(&'a mut AudioQueue<u8>);
+
+impl AudioSink for QueueSink<'_> {
+    fn queue(&mut self, samples: &[u8]) {
+        self.0.queue(samples);
+    }
+}
+
+/// Like `Cpu::step_cycles`, but stop at the cycle of each recorded input to apply it. Inputs are
+/// recorded between calls to `step_cycles`, which always end on an instruction boundary, so this
+/// applies each input exactly where it was recorded.
+fn step_cycles_replaying(
+    cpu: &mut Cpu, cycles: usize, inputs: &[InputEvent], next: &mut usize,
+    mut audio_queue: Option<&mut dyn AudioSink>, watches: &HashSet<Watch>,
+) -> bool {
+    let end = cpu.cycles() + cycles;
+    while let Some(event) = inputs.get(*next) {
+        let event_cycle = event.cycle as usize;
+        if event_cycle >= end {
+            break;
+        }
+        let queue = audio_queue.as_mut().map(|q| &mut **q as &mut dyn AudioSink);
+        if event_cycle > cpu.cycles() && cpu.step_cycles(event_cycle - cpu.cycles(), queue, watches) {
+            return true;
+        }
+        cpu.joypad.set_pressed(event.pressed);
+        *next += 1;
+        if *next == inputs.len() {
+            println!("{}", tr_with("messages.replay_finished", &[("cycle", &cpu.cycles())]));
+        }
+    }
+    end > cpu.cycles() && cpu.step_cycles(end - cpu.cycles(), audio_queue, watches)
+}
+
+/// The emulated frame `emulated` cycles into a run falls in, and how far into it.
+fn emulated_frame(emulated: u64) -> (u64, u64) {
+    (emulated / CYCLES_PER_FRAME as u64, emulated % CYCLES_PER_FRAME as u64)
+}
+
+/// Like `Cpu::step_cycles`, but stop at each change `input` has for the cycles being emulated to
+/// apply it, recording it in `repro` if given. `emulated` is how many cycles have been emulated
+/// since the input started, since its frame numbers count emulated frames: at other speeds, a
+/// frame of real time emulates more or less than one.
+fn step_cycles_with_input(
+    cpu: &mut Cpu, cycles: usize, input: &mut Option<InputStream>, emulated: u64,
+    mut audio_queue: Option<&mut dyn AudioSink>, watches: &HashSet<Watch>, mut repro: Option<&mut Repro>,
+) -> bool {
+    let start = cpu.cycles();
+    let now = |cpu: &Cpu| emulated + (cpu.cycles() - start) as u64;
+    let end = emulated + cycles as u64;
+    let frame_cycles = CYCLES_PER_FRAME as u64;
+    // Changes are looked up by frame, so stop at the end of each frame this crosses.
+    while now(cpu) < end {
+        let frame = now(cpu) / frame_cycles;
+        let frame_start = frame * frame_cycles;
+        let piece_end = end.min(frame_start + frame_cycles);
+        while let Some(offset) = input.as_mut().and_then(|i| i.next_offset(frame)) {
+            let change = frame_start + offset;
+            if change >= piece_end {
+                break;
+            }
+            let queue = audio_queue.as_mut().map(|q| &mut **q as &mut dyn AudioSink);
+            if change > now(cpu) && cpu.step_cycles((change - now(cpu)) as usize, queue, watches) {
+                return true;
+            }
+            if let Some(pressed) = input.as_mut().and_then(|i| i.poll(frame, now(cpu) - frame_start)) {
+                cpu.joypad.set_pressed(pressed);
+                if let Some(repro) = repro.as_mut() {
+                    repro.record_input(cpu.cycles() as u64, pressed);
+                }
+            }
+        }
+        let queue = audio_queue.as_mut().map(|q| &mut **q as &mut dyn AudioSink);
+        if piece_end > now(cpu) && cpu.step_cycles((piece_end - now(cpu)) as usize, queue, watches) {
+            return true;
+        }
+    }
+    false
+}
+
 const COMMANDS: &str = "\
 h:                      Display commands
 p:                      Play emulator (Press again to pause)
-wm <addr> [end_addr]:   Watch writes to a memory address 'addr'. Specifying 'end_addr' will watch a range. Hex format
+wm <addr> [end_addr]:   Watch writes to a memory address 'addr'. Specifying 'end_addr' will watch a range. Banks are ignored
 wr <reg>:               Watch writes to register 'reg'. Supports 8 and 16 bit registers. e.g. HL, AF, A, B, etc
-rm <addr> [end_addr]:   Read memory address 'addr'. Specifying 'end_addr' will read a range. Hex format
+w-vram [bank]:          Watch writes to VRAM (0x8000:0x9FFF). Only bank 0 exists so far
+w-oam:                  Watch writes to OAM (0xFE00:0xFE9F). OAM DMA transfers aren't caught
+w-tile <n> [signed]:    Watch writes to tile 'n' (0-17F from 0x8000), or with 'signed' to tile map byte 'n' in LCDC's 0x8800 mode. Numbers as in addresses
+rm <addr> [end_addr]:   Read memory address 'addr'. Specifying 'end_addr' will read a range, in the bank of 'addr'
+                        Addresses are hex like C000, decimal like #49152, symbols, with a bank like 03:4000, or sums like Main+4
 rr:                     Read registers
+x <expr>:               Evaluate an expression, e.g. '[hl] + 1', 'pc == Main && a != 0', or '[03:4000] == #10'. Numbers and symbols as in addresses
+bw [expr]:              Break when 'expr' becomes true after an instruction. No 'expr' clears it
 l:                      List watches
-dm <addr> [end_addr]:   Delete memory address watch. Hex format
+dm <addr> [end_addr]:   Delete memory address watch
 dr <reg>:               Delete register watch.
 s [n]:                  Step forward 'n' instructions (defaults to 1). n = 1 will pass over breaks.
+dumpstate <file>:       Write a report of the complete emulator state to 'file'
+status:                 Summarize the frame, PC, LCD, interrupts, timer, and mapper banks
+mbc:                    Show the cartridge mapper's banking state
+regions:                List the memory regions and their banks
+art <file> [options]:   Export the screen as a PNG. Options: scale=N, palette=dmg|pocket|gray|sepia, grid, ghost
+indices <file>:         Export the screen's palette indices, as an indexed PNG for .png files or else raw bytes
+profile start|stop:     Start (resetting any previous counts) or stop counting cycles per instruction
+profile [file]:         Print a profile report, by function and section if a map file was given
+stats irq start|stop:   Start (resetting any previous counts) or stop collecting interrupt latencies
+stats irq:              Print the latency from request to service of each interrupt type
 e:                      Exit debugger";
 
-pub fn start_frontend_debug(cpu: &mut Cpu) {
+pub fn start_frontend_debug(cpu: &mut Cpu, config: &Config) {
     let sdl = sdl2::init().expect("Failed to initialize SDL");
 
-    let sdl_video = sdl.video().expect("Failed to access SDL video subsystem");
-    let window = sdl_video
-        .window(
-            "Rugby",
-            (SCREEN_WIDTH * WINDOW_SCALE) as u32,
-            (SCREEN_HEIGHT * WINDOW_SCALE) as u32,
-        )
-        .build()
-        .expect("Failed to create SDL window");
-    let mut canvas = window.into_canvas().build().expect("Failed to get SDL window canvas");
+    let mut canvas = create_window(&sdl, 1);
+    let heatmap_canvas = create_heatmap_window(&sdl);
+    let heatmap_textures = heatmap_canvas.texture_creator();
+    let mut heatmap = HeatmapWindow::new(heatmap_canvas, &heatmap_textures);
     let mut sdl_events = sdl.event_pump().expect("Failed to get SDL event pump");
 
     let mut sdl_fps = FPSManager::new();
@@ -271,18 +1454,19 @@ pub fn start_frontend_debug(cpu: &mut Cpu) {
     let mut controllers = vec![];
 
     let sdl_audio = sdl.audio().expect("Failed to access SDL audio subsystem");
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1), // mono
-        samples: Some(SAMPLE_BUFFER_SIZE as u16),
-    };
-    let mut audio_queue = sdl_audio.open_queue(None, &desired_spec).expect("Failed to open audio queue");
+    let mut audio_queue = sdl_audio.open_queue(None, &config.audio.desired_spec()).expect("Failed to open audio queue");
     audio_queue.resume();
+    cpu.audio.set_sample_rate(audio_queue.spec().freq as u32);
 
     let reader = Interface::new("rugby-interactive-debugger").expect("Failed to create interactive terminal");
     println!("\nWelcome to the rugby debugger! Press h for help");
+    if !crate::cpu::INSTRUMENTATION {
+        println!("NOTE: this build has no instrumentation, so watches will never be hit");
+    }
     reader.set_prompt("rugby> ").expect("Failed to set terminal prompt");
     let mut watches = HashSet::new();
+    let mut options = FrontendOptions::new();
+    cpu.pause.pause(PauseReason::Debugger);
 
     while let Some(ReadResult::Input(input)) = reader.read_line().ok() {
         let (cmd, args) = split_first_word(&input);
@@ -292,11 +1476,15 @@ pub fn start_frontend_debug(cpu: &mut Cpu) {
                 println!("{}", COMMANDS);
             }
             "p" => {
-                run_emulator(cpu, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, None, &watches)
+                for &reason in &[PauseReason::User, PauseReason::Breakpoint, PauseReason::Debugger] {
+                    cpu.pause.resume(reason);
+                }
+                run_emulator(&mut [&mut *cpu], &mut canvas, &mut heatmap, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, None, &watches, &mut options, config);
+                cpu.pause.pause(PauseReason::Debugger);
             }
             "s" => {
                 let n= if let Some(x) = args.parse::<usize>().ok() { x } else { 1 };
-                run_emulator(cpu, &mut canvas, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, Some(n), &watches)
+                run_emulator(&mut [&mut *cpu], &mut canvas, &mut heatmap, &mut sdl_events, &mut sdl_fps, &sdl_controllers, &mut controllers, &mut audio_queue, true, Some(n), &watches, &mut options, config)
             }
             "rr" => {
                 cpu.print_regs();
@@ -305,20 +1493,57 @@ pub fn start_frontend_debug(cpu: &mut Cpu) {
                 print_mem(cpu, args)
             }
             "wm" => {
-                add_mem_watch(&mut watches, args)
+                add_mem_watch(cpu, &mut watches, args)
             }
             "wr" => {
                 add_reg_watch(&mut watches, args)
             }
+            "w-vram" | "w-oam" | "w-tile" => {
+                add_region_watch(&mut watches, cmd, args)
+            }
             "l" => {
                 print_watches(&watches)
             }
             "dm" => {
-                delete_mem_watch(&mut watches, args)
+                delete_mem_watch(cpu, &mut watches, args)
             }
             "dr" => {
                 delete_reg_watch(&mut watches, args)
             }
+            "dumpstate" => {
+                if args.is_empty() {
+                    println!("usage: dumpstate <file>");
+                } else {
+                    dump_state(cpu, args);
+                }
+            }
+            "status" => {
+                let _ = cpu.write_status(std::io::stdout());
+            }
+            "mbc" => {
+                print_mbc(cpu)
+            }
+            "regions" => {
+                print_regions(cpu)
+            }
+            "profile" => {
+                profile_command(cpu, args)
+            }
+            "stats" => {
+                stats_command(cpu, args)
+            }
+            "art" => {
+                art_command(cpu, &config.photo, args)
+            }
+            "indices" => {
+                indices_command(cpu, args)
+            }
+            "x" => {
+                eval_command(cpu, args)
+            }
+            "bw" => {
+                break_when_command(cpu, args)
+            }
             "e" => {
                 println!("Happy debugging :)");
                 break
@@ -328,6 +1553,205 @@ pub fn start_frontend_debug(cpu: &mut Cpu) {
     }
 }
 
+fn dump_state(cpu: &Cpu, path: &str) {
+    let result = std::fs::File::create(path)
+        .and_then(|file| cpu.write_state_report(std::io::BufWriter::new(file)));
+    match result {
+        Ok(()) => println!("Wrote emulator state to {}", path),
+        Err(e) => println!("Failed to write emulator state to {}: {}", path, e),
+    }
+}
+
+/// The index in `Audio::muted` of the channel a number key mutes.
+fn audio_channel_key(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        _ => None,
+    }
+}
+
+fn save_clip(clip: &ClipRecorder, options: &ClipOptions, path: &str) {
+    if clip.is_empty() {
+        println!("No frames recorded for a clip yet");
+        return;
+    }
+    match clip.write_gif(Path::new(path), options) {
+        Ok(()) => println!("Saved clip to {}", path),
+        Err(e) => println!("Failed to save clip to {}: {}", path, e),
+    }
+}
+
+fn export_art(cpu: &Cpu, options: &PhotoOptions, path: &str) {
+    match photo::export(&cpu.gpu, options, Path::new(path)) {
+        Ok(()) => println!("Exported art to {}", path),
+        Err(e) => println!("Failed to export art to {}: {}", path, e),
+    }
+}
+
+/// Export art with the config's photo options, overridden by any given on the command line.
+fn art_command(cpu: &Cpu, defaults: &PhotoOptions, args: &str) {
+    let mut words = args.split_whitespace();
+    let path = match words.next() {
+        Some(path) => path,
+        None => {
+            println!("usage: art <file> [scale=N] [palette=NAME] [grid] [ghost]");
+            return;
+        }
+    };
+    let mut options = defaults.clone();
+    for word in words {
+        match word {
+            "grid" => options.grid = true,
+            "ghost" => options.ghosting = true,
+            _ if word.starts_with("scale=") => match word["scale=".len()..].parse() {
+                Ok(scale @ 1..=photo::MAX_SCALE) => options.scale = scale,
+                _ => {
+                    println!("invalid scale: {:?} (must be 1 to {})", word, photo::MAX_SCALE);
+                    return;
+                }
+            },
+            _ if word.starts_with("palette=") => match word["palette=".len()..].parse() {
+                Ok(palette) => options.palette = palette,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            },
+            _ => {
+                println!("unknown option: {:?}", word);
+                return;
+            }
+        }
+    }
+    export_art(cpu, &options, path);
+}
+
+fn indices_command(cpu: &Cpu, path: &str) {
+    if path.is_empty() {
+        println!("usage: indices <file>");
+        return;
+    }
+    match palette_indices::export(&cpu.gpu, Path::new(path)) {
+        Ok(()) => println!("Exported palette indices to {}", path),
+        Err(e) => println!("Failed to export palette indices to {}: {}", path, e),
+    }
+}
+
+fn print_mbc(cpu: &Cpu) {
+    let mut out = tabwriter::TabWriter::new(std::io::stdout());
+    let _ = write!(out, "{}", cpu.cart.debug_state());
+    let _ = writeln!(out, "ROM banks:\t{}", cpu.cart.rom().len() / 0x4000);
+    let _ = writeln!(out, "RAM banks:\t{}", (cpu.cart.ram().len() + 0x1FFF) / 0x2000);
+    let _ = out.flush();
+}
+
+fn print_regions(cpu: &Cpu) {
+    let mut out = tabwriter::TabWriter::new(std::io::stdout());
+    let _ = writeln!(out, "Region\tRange\tBanks\tMapped\tAccess");
+    for region in memory_map::regions(cpu) {
+        let access = match (region.readable, region.writable) {
+            (true, true) => "read/write",
+            (true, false) => "read only",
+            (false, _) => "none",
+        };
+        let _ = writeln!(
+            out,
+            "{}\t{:04X}-{:04X}\t{}\t{}\t{}",
+            region.name,
+            region.start,
+            region.end,
+            region.banks,
+            memory_map::mapped_bank(cpu, region.start),
+            access,
+        );
+    }
+    let _ = out.flush();
+}
+
+fn eval_command(cpu: &Cpu, args: &str) {
+    match Expr::parse(args).and_then(|expr| expr.check_names(cpu).map(|()| expr)) {
+        Ok(expr) => {
+            let val = expr.eval(cpu);
+            println!("{}\t0x{:X}", val, val);
+        }
+        Err(e) => println!("invalid expression: {}", e),
+    }
+}
+
+fn break_when_command(cpu: &mut Cpu, args: &str) {
+    if args.trim().is_empty() {
+        cpu.break_when = None;
+        println!("Break condition cleared");
+        return;
+    }
+    match Expr::parse(args).and_then(|expr| expr.check_names(cpu).map(|()| expr)) {
+        Ok(expr) => cpu.break_when = Some(expr),
+        Err(e) => println!("invalid expression: {}", e),
+    }
+}
+
+fn profile_command(cpu: &mut Cpu, args: &str) {
+    match args {
+        "start" => {
+            cpu.profile = Some(Profile::new());
+            println!("Profiling started");
+        }
+        "stop" => {
+            if cpu.profile.take().is_none() {
+                println!("Not profiling");
+            }
+        }
+        _ => {
+            let profile = match &cpu.profile {
+                Some(profile) => profile,
+                None => {
+                    println!("Not profiling. Use 'profile start' first");
+                    return;
+                }
+            };
+            let result = if args.is_empty() {
+                profile.write_report(cpu.debug_map.as_ref(), std::io::stdout())
+            } else {
+                std::fs::File::create(args).and_then(|file| {
+                    profile.write_report(cpu.debug_map.as_ref(), std::io::BufWriter::new(file))
+                })
+            };
+            if let Err(e) = result {
+                println!("Failed to write profile: {}", e);
+            }
+        }
+    }
+}
+
+fn stats_command(cpu: &mut Cpu, args: &str) {
+    let (kind, args) = split_first_word(args);
+    if kind != "irq" {
+        println!("usage: stats irq [start|stop]");
+        return;
+    }
+    match args {
+        "start" => {
+            cpu.irq_stats = Some(IrqStats::new());
+            println!("Collecting interrupt latencies");
+        }
+        "stop" => {
+            if cpu.irq_stats.take().is_none() {
+                println!("Not collecting interrupt latencies");
+            }
+        }
+        "" => match &cpu.irq_stats {
+            Some(irq_stats) => {
+                let _ = irq_stats.write_report(std::io::stdout());
+            }
+            None => println!("Not collecting interrupt latencies. Use 'stats irq start' first"),
+        },
+        _ => println!("usage: stats irq [start|stop]"),
+    }
+}
+
 fn split_first_word(s: &str) -> (&str, &str) {
     let s = s.trim();
 
@@ -338,47 +1762,37 @@ fn split_first_word(s: &str) -> (&str, &str) {
 }
 
 fn print_mem(cpu: &mut Cpu, args: &str) -> () {
-    let addrs = args.trim().split(" ").collect::<Vec<&str>>();
-    match addrs.len() {
-        1 => {
-            let r = parse_hex(args);
-            match r {
-                Ok(addr) => {
-                    let val = cpu.read_mem_debug(addr);
-                    println!("{:04X}:\t{}\t0x{:02X}", addr, val, val);
-                },
-                Err(_) => println!("invalid memory address: {:?}", args)
-            }
+    let addrs = args.split_whitespace().collect::<Vec<&str>>();
+    let (bank, start, end) = match addrs.len() {
+        1 => match memory_map::parse_banked_address(cpu, addrs[0]) {
+            Ok((bank, addr)) => (bank, addr, addr),
+            Err(e) => return println!("{}", e),
         },
-        2 => {
-            match parse_range(addrs) {
-                Ok((start, end)) => {
-                    for i in start..end {
-                        let val = cpu.read_mem_debug(i);
-                        println!("{:04X}:\t{}\t0x{:02X}", i, val, val);
-                    }
-                    let val = cpu.read_mem_debug(end);
-                    println!("{:04X}:\t{}\t0x{:02X}", end, val, val);
-                },
-                Err(e) => println!("{}", e),
-            }
+        2 => match (memory_map::parse_banked_address(cpu, addrs[0]), parse_address(cpu, addrs[1])) {
+            (Ok((bank, start)), Ok(end)) => (bank, start, end),
+            (Err(e), _) | (_, Err(e)) => return println!("{}", e),
         },
-        _ => println!("invalid memory address: {:?}", args),
+        _ => return println!("invalid memory address: {:?}", args),
+    };
+    for i in start..=end {
+        match memory_map::read(cpu, bank, i) {
+            Ok(val) => println!("{:04X}:\t{}\t0x{:02X}", i, val, val),
+            Err(e) => return println!("{:04X}:\t{}", i, e),
+        }
     }
 }
 
-fn add_mem_watch(watches: &mut HashSet<Watch>, args: &str) {
-    let addrs = args.trim().split(" ").collect::<Vec<&str>>();
+fn add_mem_watch(cpu: &Cpu, watches: &mut HashSet<Watch>, args: &str) {
+    let addrs = args.split_whitespace().collect::<Vec<&str>>();
     match addrs.len() {
         1 => {
-            let r = parse_hex(args);
-            match r {
+            match parse_address(cpu, addrs[0]) {
                 Ok(addr) => { watches.insert(Watch::Mem(addr)); },
-                Err(_) => println!("invalid memory address: {:?}", args)
+                Err(e) => println!("{}", e),
             }
         },
         2 => {
-            match parse_range(addrs) {
+            match parse_range(cpu, addrs) {
                 Ok((start, end)) => {
                     if start >= end {
                         println!("invalid memory range. start must be less than end");
@@ -393,6 +1807,52 @@ fn add_mem_watch(watches: &mut HashSet<Watch>, args: &str) {
     }
 }
 
+/// `w-vram`, `w-oam`, and `w-tile`: watch the addresses of a region of video memory, which are
+/// then listed and deleted like any other range.
+fn add_region_watch(watches: &mut HashSet<Watch>, cmd: &str, args: &str) {
+    let (start, end) = match cmd {
+        "w-vram" => match args.trim() {
+            "" | "0" => (0x8000, 0x9FFF),
+            "1" => {
+                println!("VRAM bank 1 isn't emulated yet, so it can't be watched");
+                return;
+            }
+            _ => {
+                println!("usage: w-vram [bank]");
+                return;
+            }
+        },
+        "w-oam" => (0xFE00, 0xFE9F),
+        _ => {
+            let (n, mode) = split_first_word(args);
+            let tile = match memory_map::parse_number(n) {
+                Some(tile) => tile,
+                None => {
+                    println!("usage: w-tile <n> [signed]");
+                    return;
+                }
+            };
+            let start = match mode {
+                "" if tile < 0x180 => 0x8000 + tile * 16,
+                // Tile map bytes 0x00-0x7F pick tiles from 0x9000, and 0x80-0xFF count back
+                // from it as negative numbers.
+                "signed" if tile < 0x100 => 0x9000u16.wrapping_add((tile as u8 as i8 as i16 * 16) as u16),
+                "" | "signed" => {
+                    println!("tile number out of range: 0x{:X}", tile);
+                    return;
+                }
+                _ => {
+                    println!("usage: w-tile <n> [signed]");
+                    return;
+                }
+            };
+            (start, start + 15)
+        }
+    };
+    watches.insert(Watch::MemRange(start, end));
+    println!("Watching 0x{:04X}:0x{:04X}", start, end);
+}
+
 fn add_reg_watch(watches: &mut HashSet<Watch>, args: &str) {
     let reg = args.trim();
     match reg.len() {
@@ -435,18 +1895,17 @@ fn print_watches(watches: &HashSet<Watch>) {
     }
 }
 
-fn delete_mem_watch(watches: &mut HashSet<Watch>, args: &str) {
-    let addrs = args.trim().split(" ").collect::<Vec<&str>>();
+fn delete_mem_watch(cpu: &Cpu, watches: &mut HashSet<Watch>, args: &str) {
+    let addrs = args.split_whitespace().collect::<Vec<&str>>();
     match addrs.len() {
         1 => {
-            let r = parse_hex(args);
-            match r {
+            match parse_address(cpu, addrs[0]) {
                 Ok(addr) => { watches.remove(&Watch::Mem(addr)); },
-                Err(_) => println!("invalid memory address: {:?}", args)
+                Err(e) => println!("{}", e),
             }
         },
         2 => {
-            match parse_range(addrs) {
+            match parse_range(cpu, addrs) {
                 Ok((start, end)) => { watches.remove(&Watch::MemRange(start, end)); },
                 Err(e) => println!("{}", e),
             }
@@ -483,42 +1942,14 @@ fn delete_reg_watch(watches: &mut HashSet<Watch>, args: &str) {
     };
 }
 
-fn parse_hex(inp: &str) -> Result<u16, &str> {
-    let mut hex_str = inp.trim();
-    if hex_str.is_empty() {
-        return Result::Err("invalid input hex string");
-    }
-    if hex_str.starts_with("0x") {
-        hex_str = &hex_str[2..];
-    }
-    let mut zero = "0".to_owned();
-    if hex_str.len() % 2 != 0 {
-        zero.push_str(hex_str);
-        hex_str = &zero[..];
-    }
-    let addr_bytes = Vec::from_hex(hex_str).map_err(|_| "failed to parse hex")?;
-    if addr_bytes.len() > 1 {
-        Result::Ok((addr_bytes[0] as u16) << 8 | (addr_bytes[1] as u16))
-    } else {
-        Result::Ok(addr_bytes[0] as u16)
-    }
+/// An address for a watch. Watches catch writes whatever bank is mapped, so any bank is dropped.
+fn parse_address(cpu: &Cpu, s: &str) -> Result<u16, String> {
+    memory_map::parse_address(cpu, s).map(|(_, addr)| addr)
 }
 
-fn parse_range(addrs: Vec<&str>) -> Result<(u16, u16), String> {
+fn parse_range(cpu: &Cpu, addrs: Vec<&str>) -> Result<(u16, u16), String> {
     if addrs.len() != 2 {
         return Err("Invalid length for range".to_owned());
     }
-    let start = match parse_hex(addrs[0]) {
-        Ok(addr) => addr,
-        Err(_) => {
-            return Err(format!("invalid memory address: {:?}", addrs[0]));
-        }
-    };
-    let end = match parse_hex(addrs[1]) {
-        Ok(addr) => addr,
-        Err(_) => {
-            return Err(format!("invalid memory address: {:?}", addrs[1]));
-        }
-    };
-    return Ok((start, end))
+    Ok((parse_address(cpu, addrs[0])?, parse_address(cpu, addrs[1])?))
 }
@@ -1,12 +1,13 @@
-use enumflags2::BitFlags;
-use std::collections::BinaryHeap;
-use crate::interrupts::Interrupt;
+use crate::interrupts::{Interrupt, InterruptController};
+use crate::io_register::{IoDevice, IoRegister};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 mod sprite;
 
-const HORIZONTAL_BLANK_CYCLES: usize = 204; // Horizontal blank phase takes 201-207 cycles.
 const OAM_READ_CYCLES: usize = 80; // OAM read phase takes 77-83 cycles.
-const VRAM_READ_CYCLES: usize = 172; // VRAM read phase takes 169-175 cycles.
+const VRAM_READ_CYCLES: usize = 172; // VRAM read phase takes at least 172 cycles, see `mode_3_cycles`.
+const WINDOW_PENALTY_CYCLES: usize = 6; // Extra VRAM read cycles for starting to fetch the window.
+const SPRITE_PENALTY_CYCLES: usize = 6; // Extra VRAM read cycles for each sprite fetched.
 const SCAN_LINE_CYCLES: usize = 456; // One scan line takes 456 cycles.
 const VERTICAL_BLANK_START_LINE: u8 = 144; // The scan line at which we enter the vertical blank phase
 const VERTICAL_BLANK_END_LINE: u8 = 154; // The scan line at which the vertical blank phase ends
@@ -16,10 +17,12 @@ const TILE_MAP_0_START: usize = 0x1800; // The starting address of tile map 0
 const SPRITE_RAM_SIZE: usize = 160; // For the address range 0xFE00-0xFE9F (inclusive).
 const TOTAL_SPRITES: usize = 40; // The number of sprites in sprite ram
 const BYTES_PER_SPRITE: usize = 4;
+const SPRITES_PER_LINE: usize = 10; // The most sprites the hardware draws on one scan line
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
+pub const CYCLES_PER_FRAME: usize = 69905; // The number of cycles in 1/60th of a second
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Mode {
     HorizontalBlank = 0,
     VerticalBlank = 1,
@@ -27,6 +30,28 @@ enum Mode {
     VRamRead = 3,
 }
 
+/// How many sprites are drawn on each scan line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum SpriteLimit {
+    /// Like real hardware, only the first 10 sprites in OAM order on a line are drawn. Games that
+    /// put more sprites on a line rely on this, often cycling which ones are hidden, which flickers.
+    Hardware,
+
+    /// Draw every sprite on the line, so nothing flickers. Inaccurate, and shows sprites some
+    /// games deliberately hide behind the limit.
+    Unlimited,
+}
+
+impl SpriteLimit {
+    fn per_line(self) -> usize {
+        match self {
+            SpriteLimit::Hardware => SPRITES_PER_LINE,
+            SpriteLimit::Unlimited => TOTAL_SPRITES,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 enum TileMapLocation {
     X9800 = 0,
@@ -86,6 +111,14 @@ pub struct Gpu {
     /// Current screen
     pub screen_buffer: Box<[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
 
+    /// The last complete frame before the one in `screen_buffer`, for effects like LCD ghosting.
+    pub previous_screen_buffer: Box<[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
+
+    /// The current screen before the palettes were applied: each pixel's 2-bit color index within
+    /// the tile it came from. Unlike the shades in `screen_buffer`, these don't change when a game
+    /// fades the palette, which makes them better for telling game states apart.
+    pub palette_index_buffer: Box<[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
+
     /// The current background
     background: Box<[[u8; 256]; 256]>,
 
@@ -170,6 +203,31 @@ pub struct Gpu {
 
     /// Second sprite palette register
     obj_palette_1: u8,
+
+    /// How long the VRAM read phase of the current line lasts. Horizontal blank takes the rest of
+    /// the line.
+    vram_read_cycles: usize,
+
+    /// Whether WY has matched the scan line yet this frame. The window only shows up on lines
+    /// after that, even if WY changes again later in the frame.
+    window_y_triggered: bool,
+
+    /// The window's own line counter: the row of the window drawn on its next line. It only
+    /// advances on lines where the window was actually drawn, so hiding the window for a few lines
+    /// mid-frame resumes it where it left off instead of skipping rows.
+    window_line: u8,
+
+    /// Set when the window was drawn at WX=166, which on hardware makes it cover the whole of the
+    /// following line.
+    window_spills_to_next_line: bool,
+
+    /// Set when the LCD is turned back on. The hardware doesn't show the first frame after that,
+    /// so it isn't drawn and the screen stays blank until the next one.
+    skipping_frame: bool,
+
+    /// Whether to drop sprites beyond the hardware's per-line limit. A user setting, not part of
+    /// the emulated state.
+    pub sprite_limit: SpriteLimit,
 }
 
 impl Gpu {
@@ -178,6 +236,8 @@ impl Gpu {
             // TODO(solson): Figure out a clean way to allocate 2D arrays like these directly on
             // the heap (without giving up the `arr[i][j]` multidimensional indexing).
             screen_buffer: Box::new([[0u8; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            previous_screen_buffer: Box::new([[0u8; SCREEN_WIDTH]; SCREEN_HEIGHT]),
+            palette_index_buffer: Box::new([[0u8; SCREEN_WIDTH]; SCREEN_HEIGHT]),
             background: Box::new([[0u8; 256]; 256]),
             window: Box::new([[0u8; 256]; 256]),
             video_ram: vec![0; VIDEO_RAM_SIZE].into_boxed_slice(),
@@ -185,6 +245,12 @@ impl Gpu {
             sprite_ram: vec![0; SPRITE_RAM_SIZE].into_boxed_slice(),
             sprites: vec![sprite::Sprite::new(); TOTAL_SPRITES].into_boxed_slice(),
             cycles: 0,
+            vram_read_cycles: VRAM_READ_CYCLES,
+            // WY starts at 0, so it matches on the first line.
+            window_y_triggered: true,
+            window_line: 0,
+            window_spills_to_next_line: false,
+            skipping_frame: false,
             scan_line: 0,
             scan_line_compare: 0,
             lcd_enabled: true,
@@ -207,6 +273,7 @@ impl Gpu {
             background_palette: 0,
             obj_palette_0: 0,
             obj_palette_1: 1,
+            sprite_limit: SpriteLimit::Hardware,
         };
         for i in 0..TOTAL_SPRITES {
             gpu.sprites[i].index = i;
@@ -214,6 +281,41 @@ impl Gpu {
         gpu
     }
 
+    /// The raw contents of video RAM.
+    pub fn vram(&self) -> &[u8] {
+        &self.video_ram
+    }
+
+    /// The raw contents of sprite RAM (OAM).
+    pub fn oam(&self) -> &[u8] {
+        &self.sprite_ram
+    }
+
+    /// Whether LCDC has the display turned on.
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcd_enabled
+    }
+
+    /// Where the beam is in the visible part of the frame: the line it's on (LY), and how many lines
+    /// of `screen_buffer` already hold this frame. `None` in vertical blank or with the LCD off.
+    pub fn beam(&self) -> Option<(usize, usize)> {
+        if !self.lcd_enabled || self.mode == Mode::VerticalBlank {
+            return None;
+        }
+        let line = usize::from(self.scan_line);
+        Some((line, if self.mode == Mode::HorizontalBlank { line + 1 } else { line }))
+    }
+
+    /// Whether the CPU is currently locked out of video RAM on real hardware.
+    pub fn vram_blocked(&self) -> bool {
+        self.lcd_enabled && self.mode == Mode::VRamRead
+    }
+
+    /// Whether the CPU is currently locked out of sprite RAM on real hardware.
+    pub fn oam_blocked(&self) -> bool {
+        self.lcd_enabled && (self.mode == Mode::OamRead || self.mode == Mode::VRamRead)
+    }
+
     pub fn read_sprite_ram(&self, addr: usize) -> u8 {
         self.sprite_ram[addr]
     }
@@ -271,29 +373,31 @@ impl Gpu {
     /// `Mode::OamRead` -> `Mode::VRamRead` -> `Mode::HorizontalBlank` on each line.
     /// For lines 144-153, we stay in `Mode::VerticalBlank` for the whole line, after which
     /// we go back to line 0
-    pub fn step(&mut self, cycles: usize) -> BitFlags<Interrupt> {
-        let mut interrupts = BitFlags::empty();
-        if !self.lcd_enabled { return interrupts; }
+    pub fn step(&mut self, cycles: usize, interrupts: &mut InterruptController) {
+        if !self.lcd_enabled { return; }
         self.cycles += cycles;
 
         match self.mode {
             Mode::HorizontalBlank => {
-                if self.cycles >= HORIZONTAL_BLANK_CYCLES {
-                    self.cycles %= HORIZONTAL_BLANK_CYCLES;
+                let horizontal_blank_cycles =
+                    (SCAN_LINE_CYCLES - OAM_READ_CYCLES).saturating_sub(self.vram_read_cycles);
+                if self.cycles >= horizontal_blank_cycles {
+                    self.cycles -= horizontal_blank_cycles;
                     self.scan_line += 1;
 
                     if self.scan_line >= VERTICAL_BLANK_START_LINE {
                         self.mode = Mode::VerticalBlank;
                         if self.vertical_blank_interrupt {
-                            interrupts.insert(Interrupt::Lcd)
+                            interrupts.request(Interrupt::Lcd)
                         }
-                        interrupts.insert(Interrupt::VBlank);
+                        interrupts.request(Interrupt::VBlank);
                     } else {
                         self.mode = Mode::OamRead;
+                        self.check_window_y();
                     }
 
                     if self.coincidence_interrupt && self.scan_line == self.scan_line_compare {
-                        interrupts.insert(Interrupt::Lcd)
+                        interrupts.request(Interrupt::Lcd)
                     }
                 }
             }
@@ -304,15 +408,21 @@ impl Gpu {
                     self.scan_line += 1;
 
                     if self.scan_line >= VERTICAL_BLANK_END_LINE {
+                        *self.previous_screen_buffer = *self.screen_buffer;
                         self.scan_line = 0;
                         self.mode = Mode::OamRead;
+                        self.window_y_triggered = false;
+                        self.window_line = 0;
+                        self.window_spills_to_next_line = false;
+                        self.skipping_frame = false;
+                        self.check_window_y();
                         if self.oam_interrupt {
-                            interrupts.insert(Interrupt::Lcd);
+                            interrupts.request(Interrupt::Lcd);
                         }
                     }
 
                     if self.coincidence_interrupt && self.scan_line == self.scan_line_compare {
-                        interrupts.insert(Interrupt::Lcd)
+                        interrupts.request(Interrupt::Lcd)
                     }
                 }
             }
@@ -321,31 +431,95 @@ impl Gpu {
                 if self.cycles > OAM_READ_CYCLES {
                     self.cycles %= OAM_READ_CYCLES;
                     self.mode = Mode::VRamRead;
+                    self.vram_read_cycles = self.mode_3_cycles();
                 }
             }
 
             Mode::VRamRead => {
-                if self.cycles > VRAM_READ_CYCLES {
-                    self.cycles %= VRAM_READ_CYCLES;
+                if self.cycles > self.vram_read_cycles {
+                    self.cycles -= self.vram_read_cycles;
                     self.mode = Mode::HorizontalBlank;
-                    self.render_scan_line();
+                    if !self.skipping_frame {
+                        self.render_scan_line();
+                    }
 
                     if self.horizontal_blank_interrupt {
-                        interrupts.insert(Interrupt::Lcd)
+                        interrupts.request(Interrupt::Lcd)
                     }
                 }
             }
         }
+    }
+
+    /// How long the VRAM read phase lasts on the current line. The pixel FIFO stalls to discard
+    /// the pixels scrolled off by SCX's low bits, to start fetching the window, and to fetch each
+    /// sprite, so games that poll STAT for horizontal blank see it start later on busy lines. This
+    /// follows the Pan Docs' description of the penalties. Only the timing is modeled: lines are
+    /// still drawn all at once at the end.
+    fn mode_3_cycles(&self) -> usize {
+        let mut cycles = VRAM_READ_CYCLES + (self.scan_x % 8) as usize;
+
+        if self.window_visible() {
+            cycles += WINDOW_PENALTY_CYCLES;
+        }
 
-        interrupts
+        if self.obj_display_enabled {
+            let height = match self.obj_size {
+                ObjSize::EightBySixteen => 16,
+                ObjSize::EightByEight => 8,
+            };
+            // The hardware limit applies here even with `SpriteLimit::Unlimited`, which only
+            // changes what's drawn.
+            let sprites = self.sprites_on_line(height, SpriteLimit::Hardware);
+            // Each sprite waits for the background tile under its leftmost pixel to finish
+            // fetching, but only the first sprite over each tile does.
+            let mut tiles_waited_for = Vec::new();
+            for sprite in sprites {
+                // Sprites entirely off the right edge of the screen aren't fetched.
+                let oam_x = sprite.x.wrapping_add(8);
+                if oam_x >= 168 {
+                    continue;
+                }
+                cycles += SPRITE_PENALTY_CYCLES;
+                if oam_x == 0 {
+                    cycles += 5;
+                    continue;
+                }
+                let background_x = sprite.x.wrapping_add(self.scan_x);
+                let tile = background_x / 8;
+                if !tiles_waited_for.contains(&tile) {
+                    tiles_waited_for.push(tile);
+                    cycles += 5usize.saturating_sub((background_x % 8) as usize);
+                }
+            }
+        }
+
+        cycles
+    }
+
+    /// WY is compared against the scan line at the start of each line, and a match enables the
+    /// window for the rest of the frame.
+    fn check_window_y(&mut self) {
+        if self.scan_line == self.window_y {
+            self.window_y_triggered = true;
+        }
+    }
+
+    /// Whether the window is drawn on the current line. WX values past 166 put it entirely off
+    /// screen, unless the previous line spilled it over.
+    fn window_visible(&self) -> bool {
+        let wx = self.window_x.wrapping_add(7);
+        self.window_enabled && self.window_y_triggered && (wx <= 166 || self.window_spills_to_next_line)
     }
 
     fn render_scan_line(&mut self) {
         if self.background_enabled {
             self.render_background_line();
         }
-        if self.window_enabled {
+        if self.window_visible() {
             self.render_window_line();
+        } else {
+            self.window_spills_to_next_line = false;
         }
         if self.obj_display_enabled {
             self.render_sprite_line();
@@ -378,8 +552,9 @@ impl Gpu {
         for i in 0..SCREEN_WIDTH {
             let pixel_x = (self.scan_x as usize + i) % 256;
             let pixel_y = (self.scan_line as usize + self.scan_y as usize) % 256;
-            let color = get_palette_color(self.background[pixel_y][pixel_x], self.background_palette);
-            self.screen_buffer[self.scan_line as usize][i] = color;
+            let index = self.background[pixel_y][pixel_x];
+            self.palette_index_buffer[self.scan_line as usize][i] = index;
+            self.screen_buffer[self.scan_line as usize][i] = get_palette_color(index, self.background_palette);
         }
     }
 
@@ -406,13 +581,39 @@ impl Gpu {
             }
         }
 
-        for screen_x in (self.window_x as usize)..SCREEN_WIDTH {
-            let (y, overflow) = self.scan_line.overflowing_sub(self.window_y);
-            if !overflow && (y as usize) < SCREEN_HEIGHT {
-                let color = get_palette_color(self.window[y as usize][screen_x - (self.window_x as usize)], self.background_palette);
-                self.screen_buffer[self.scan_line as usize][screen_x] = color;
-            }
+        // The window's left edge is at WX - 7. Below 7, it starts at the screen's left edge with
+        // its first columns cut off, and at WX=0 the pixels SCX discards for fine scrolling are
+        // cut from the window as well.
+        let wx = self.window_x.wrapping_add(7);
+        let (screen_start, window_start) = if self.window_spills_to_next_line {
+            (0, 0)
+        } else if wx < 7 {
+            let stutter = if wx == 0 { self.scan_x % 8 } else { 0 };
+            (0, (7 - wx + stutter) as usize)
+        } else {
+            ((wx - 7) as usize, 0)
+        };
+
+        let y = self.window_line as usize;
+        for screen_x in screen_start..SCREEN_WIDTH {
+            let index = self.window[y][screen_x - screen_start + window_start];
+            self.palette_index_buffer[self.scan_line as usize][screen_x] = index;
+            self.screen_buffer[self.scan_line as usize][screen_x] = get_palette_color(index, self.background_palette);
         }
+
+        self.window_line = self.window_line.wrapping_add(1);
+        self.window_spills_to_next_line = wx == 166;
+    }
+
+    /// The OAM search: find the sprites overlapping the current scan line, in OAM order, up to
+    /// the sprite limit.
+    fn sprites_on_line(&self, height: u8, limit: SpriteLimit) -> Vec<sprite::Sprite> {
+        self.sprites.iter()
+            // Sprites at y = 0 or y >= 160 are off screen
+            .filter(|s| self.scan_line.wrapping_sub(s.y) < height)
+            .take(limit.per_line())
+            .cloned()
+            .collect()
     }
 
     fn render_sprite_line(&mut self) {
@@ -421,16 +622,10 @@ impl Gpu {
             ObjSize::EightByEight => 8,
         };
 
-        let mut heap = BinaryHeap::new();
-        for i in 0..TOTAL_SPRITES {
-            let s = self.sprites[i];
-            // Sprites at y = 0 or y >= 160 are off screen
-            if self.scan_line.wrapping_sub(s.y) < height {
-                heap.push(s);
-            }
-        }
-        let mut sprites_to_render = heap.into_sorted_vec();
-        sprites_to_render.truncate(10);
+        // Sprites with lower x positions, then lower OAM indices, are drawn on top, so draw them
+        // last.
+        let mut sprites_to_render = self.sprites_on_line(height, self.sprite_limit);
+        sprites_to_render.sort();
         for s in sprites_to_render.iter().rev() {
             let mut tile_num = s.tile_num as usize;
             let mut line = if s.flip_y {
@@ -461,48 +656,38 @@ impl Gpu {
                         } else {
                             self.obj_palette_1
                         };
-                        let color = get_palette_color(tile[line as usize][tile_x], palette);
-                        self.screen_buffer[self.scan_line as usize][target_x as usize] = color;
+                        let index = tile[line as usize][tile_x];
+                        self.palette_index_buffer[self.scan_line as usize][target_x as usize] = index;
+                        self.screen_buffer[self.scan_line as usize][target_x as usize] = get_palette_color(index, palette);
                     }
                 }
             }
         }
     }
 
-    pub fn read_reg(&self, addr: u8) -> u8 {
-        match addr {
-            0x40 => self.read_lcd_control(),
-            0x41 => self.read_lcd_stat(),
-            0x42 => self.scan_y,
-            0x43 => self.scan_x,
-            0x44 => self.scan_line,
-            0x45 => self.scan_line_compare,
-            // 0x46 is handled in the CPU.
-            0x47 => self.background_palette,
-            0x48 => self.obj_palette_0,
-            0x49 => self.obj_palette_1,
-            0x4A => self.window_y,
-            0x4B => self.window_x.wrapping_add(7),
-            _ => panic!("Invalid read address for GPU"),
+    /// Check internal consistency for `--paranoid` mode.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.scan_line >= VERTICAL_BLANK_END_LINE {
+            return Err(format!("scan line {} is past the end of the frame", self.scan_line));
         }
-    }
-
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
-        match addr {
-            0x40 => self.write_lcd_control(val),
-            0x41 => self.write_lcd_stat(val),
-            0x42 => self.scan_y = val,
-            0x43 => self.scan_x = val,
-            0x44 => self.scan_line = 0,
-            0x45 => self.scan_line_compare = val,
-            // 0x46 is handled in the CPU.
-            0x47 => self.background_palette = val,
-            0x48 => self.obj_palette_0 = val,
-            0x49 => self.obj_palette_1 = val,
-            0x4A => self.window_y = val,
-            0x4B => self.window_x = val.wrapping_sub(7),
-            _ => panic!("Invalid write address for GPU"),
+        let in_vblank = self.scan_line >= VERTICAL_BLANK_START_LINE;
+        if in_vblank != (self.mode == Mode::VerticalBlank) {
+            return Err(format!("mode {:?} is inconsistent with scan line {}", self.mode, self.scan_line));
+        }
+        if self.cycles > SCAN_LINE_CYCLES * 2 {
+            return Err(format!("{} cycles accumulated in mode {:?}", self.cycles, self.mode));
         }
+        if self.sprite_ram.len() != SPRITE_RAM_SIZE || self.sprites.len() != TOTAL_SPRITES {
+            return Err(format!(
+                "OAM has {} bytes and {} sprites",
+                self.sprite_ram.len(),
+                self.sprites.len(),
+            ));
+        }
+        if let Some((i, s)) = self.sprites.iter().enumerate().find(|(i, s)| s.index != *i) {
+            return Err(format!("sprite {} has OAM index {}", i, s.index));
+        }
+        Ok(())
     }
 
     fn read_lcd_control(&self) -> u8 {
@@ -518,6 +703,28 @@ impl Gpu {
         lcd_control
     }
 
+    /// A write to LCDC from the CPU. Turning the LCD off stops the PPU at the start of line 0 and
+    /// blanks the screen to white, and turning it back on starts a new frame that isn't shown.
+    fn set_lcd_control(&mut self, val: u8) {
+        let was_enabled = self.lcd_enabled;
+        self.write_lcd_control(val);
+        if was_enabled && !self.lcd_enabled {
+            self.scan_line = 0;
+            self.cycles = 0;
+            // STAT reports mode 0 while the LCD is off.
+            self.mode = Mode::HorizontalBlank;
+            *self.screen_buffer = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+            *self.palette_index_buffer = [[0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        } else if !was_enabled && self.lcd_enabled {
+            self.mode = Mode::OamRead;
+            self.window_y_triggered = false;
+            self.window_line = 0;
+            self.window_spills_to_next_line = false;
+            self.skipping_frame = true;
+            self.check_window_y();
+        }
+    }
+
     fn write_lcd_control(&mut self, val: u8) {
         self.lcd_enabled = (val >> 7) == 1;
         self.window_tile_map = TileMapLocation::from((val >> 6) & 1);
@@ -531,7 +738,7 @@ impl Gpu {
     }
 
     fn read_lcd_stat(&self) -> u8 {
-        let mut lcd_stat = 0x80; // bit 7 is always 1
+        let mut lcd_stat = 0;
         lcd_stat |= (self.coincidence_interrupt as u8) << 6;
         lcd_stat |= (self.oam_interrupt as u8) << 5;
         lcd_stat |= (self.vertical_blank_interrupt as u8) << 4;
@@ -542,8 +749,6 @@ impl Gpu {
     }
 
     /// Write the LCD stat register 0xFF41
-    ///
-    /// Bits 0-2 are read only
     fn write_lcd_stat(&mut self, val: u8) {
         self.coincidence_interrupt = (val >> 6) & 1 == 1;
         self.oam_interrupt = (val >> 5) & 1 == 1;
@@ -552,6 +757,198 @@ impl Gpu {
     }
 }
 
+impl IoDevice for Gpu {
+    // 0xFF46 (DMA) is handled in the CPU.
+    const REGISTERS: &'static [IoRegister] = &[
+        IoRegister::new(0x40, "LCDC"),
+        IoRegister::new(0x41, "STAT").unused(0b1000_0000).read_only(0b0000_0111),
+        IoRegister::new(0x42, "SCY"),
+        IoRegister::new(0x43, "SCX"),
+        IoRegister::new(0x44, "LY").read_only(0xFF),
+        IoRegister::new(0x45, "LYC"),
+        IoRegister::new(0x47, "BGP"),
+        IoRegister::new(0x48, "OBP0"),
+        IoRegister::new(0x49, "OBP1"),
+        IoRegister::new(0x4A, "WY"),
+        IoRegister::new(0x4B, "WX"),
+    ];
+
+    fn read_raw(&self, addr: u8) -> u8 {
+        match addr {
+            0x40 => self.read_lcd_control(),
+            0x41 => self.read_lcd_stat(),
+            0x42 => self.scan_y,
+            0x43 => self.scan_x,
+            0x44 => self.scan_line,
+            0x45 => self.scan_line_compare,
+            0x47 => self.background_palette,
+            0x48 => self.obj_palette_0,
+            0x49 => self.obj_palette_1,
+            0x4A => self.window_y,
+            0x4B => self.window_x.wrapping_add(7),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_raw(&mut self, addr: u8, val: u8) {
+        match addr {
+            0x40 => self.set_lcd_control(val),
+            0x41 => self.write_lcd_stat(val),
+            0x42 => self.scan_y = val,
+            0x43 => self.scan_x = val,
+            // LY can't be set, but any write resets it.
+            0x44 => self.scan_line = 0,
+            0x45 => self.scan_line_compare = val,
+            0x47 => self.background_palette = val,
+            0x48 => self.obj_palette_0 = val,
+            0x49 => self.obj_palette_1 = val,
+            0x4A => self.window_y = val,
+            0x4B => self.window_x = val.wrapping_sub(7),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl SaveState for Gpu {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.video_ram);
+        w.bytes(&self.sprite_ram);
+        for row in self.screen_buffer.iter() {
+            w.bytes(row);
+        }
+        w.u8(self.scan_line);
+        w.u8(self.scan_line_compare);
+        w.usize(self.cycles);
+        w.u8(self.mode as u8);
+        w.u8(self.read_lcd_control());
+        w.u8(self.read_lcd_stat());
+        w.u8(self.scan_x);
+        w.u8(self.scan_y);
+        w.u8(self.window_x);
+        w.u8(self.window_y);
+        w.u8(self.background_palette);
+        w.u8(self.obj_palette_0);
+        w.u8(self.obj_palette_1);
+        for row in self.palette_index_buffer.iter() {
+            w.bytes(row);
+        }
+        w.usize(self.vram_read_cycles);
+        w.bool(self.window_y_triggered);
+        w.u8(self.window_line);
+        w.bool(self.window_spills_to_next_line);
+        w.bool(self.skipping_frame);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        // Go through the usual write paths so the decoded tile set and sprites are rebuilt.
+        let mut video_ram = vec![0; VIDEO_RAM_SIZE];
+        r.bytes_into(&mut video_ram)?;
+        for (addr, &val) in video_ram.iter().enumerate() {
+            self.write_vram(addr, val);
+        }
+        let mut sprite_ram = vec![0; SPRITE_RAM_SIZE];
+        r.bytes_into(&mut sprite_ram)?;
+        for (addr, &val) in sprite_ram.iter().enumerate() {
+            self.write_sprite_ram(addr, val);
+        }
+        for row in self.screen_buffer.iter_mut() {
+            r.bytes_into(row)?;
+        }
+        *self.previous_screen_buffer = *self.screen_buffer;
+        self.scan_line = r.u8()?;
+        self.scan_line_compare = r.u8()?;
+        self.cycles = r.usize()?;
+        self.mode = match r.u8()? {
+            0 => Mode::HorizontalBlank,
+            1 => Mode::VerticalBlank,
+            2 => Mode::OamRead,
+            3 => Mode::VRamRead,
+            _ => return Err(SaveStateError::InvalidValue("LCD mode")),
+        };
+        // Lines past the last would never end, and the drawing modes index the screen by line.
+        if self.scan_line >= VERTICAL_BLANK_END_LINE {
+            return Err(SaveStateError::InvalidValue("LCD scan line"));
+        }
+        if (self.scan_line >= VERTICAL_BLANK_START_LINE) != (self.mode == Mode::VerticalBlank) {
+            return Err(SaveStateError::InvalidValue("LCD mode for the scan line"));
+        }
+        let lcd_control = r.u8()?;
+        self.write_lcd_control(lcd_control);
+        let lcd_stat = r.u8()?;
+        self.write_lcd_stat(lcd_stat);
+        self.scan_x = r.u8()?;
+        self.scan_y = r.u8()?;
+        self.window_x = r.u8()?;
+        self.window_y = r.u8()?;
+        self.background_palette = r.u8()?;
+        self.obj_palette_0 = r.u8()?;
+        self.obj_palette_1 = r.u8()?;
+        for row in self.palette_index_buffer.iter_mut() {
+            r.bytes_into(row)?;
+        }
+        self.vram_read_cycles = r.usize()?;
+        // `mode_3_cycles` never leaves the line less than the OAM read phase and the shortest VRAM
+        // read phase, or more than the whole line.
+        if self.vram_read_cycles < VRAM_READ_CYCLES || self.vram_read_cycles > SCAN_LINE_CYCLES - OAM_READ_CYCLES {
+            return Err(SaveStateError::InvalidValue("VRAM read phase length"));
+        }
+        self.window_y_triggered = r.bool()?;
+        self.window_line = r.u8()?;
+        self.window_spills_to_next_line = r.bool()?;
+        self.skipping_frame = r.bool()?;
+        Ok(())
+    }
+}
+
 fn get_palette_color(color_num: u8, palette: u8) -> u8 {
     (palette >> (2 * color_num)) & 3
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::savestate::StateChunks;
+
+    const MODES: [Mode; 4] = [Mode::HorizontalBlank, Mode::VerticalBlank, Mode::OamRead, Mode::VRamRead];
+
+    /// Save `gpu` and load the state into a new `Gpu`.
+    fn reload(gpu: &Gpu) -> Result<Gpu, SaveStateError> {
+        let mut w = StateWriter::new();
+        w.chunk(b"GPU ", gpu);
+        let state = w.finish();
+        let mut loaded = Gpu::new();
+        StateChunks::parse(&state)?.load(b"GPU ", &mut loaded)?;
+        Ok(loaded)
+    }
+
+    #[test]
+    fn test_savestate_rejects_impossible_scan_lines() {
+        for line in 0..=255 {
+            for &mode in &MODES {
+                let mut gpu = Gpu::new();
+                gpu.scan_line = line;
+                gpu.mode = mode;
+                let possible = line < 154 && (line >= 144) == (mode == Mode::VerticalBlank);
+                assert_eq!(reload(&gpu).is_ok(), possible, "LY {}, mode {:?}", line, mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_savestate_rejects_impossible_mode_3_lengths() {
+        for &cycles in &[0, 171, 172, 250, 376, 377, 456, usize::max_value()] {
+            let mut gpu = Gpu::new();
+            gpu.vram_read_cycles = cycles;
+            let possible = cycles >= 172 && cycles <= 456 - 80;
+            assert_eq!(reload(&gpu).is_ok(), possible, "{} cycles", cycles);
+        }
+    }
+
+    #[test]
+    fn test_writing_ly_resets_it() {
+        let mut gpu = Gpu::new();
+        gpu.scan_line = 100;
+        assert_eq!(gpu.read_reg(0x44), 100);
+        gpu.write_reg(0x44, 0x55);
+        assert_eq!(gpu.read_reg(0x44), 0);
+    }
+}
@@ -0,0 +1,117 @@
+//! Where in memory the CPU has been reading and writing lately, for the heatmap window (F4). Each
+//! address has a read and a write intensity, which every access raises and `decay` lowers once a
+//! frame, so the map shows what the game is touching now rather than everything it ever touched.
+//! Instruction fetches count as reads, so running code lights up too.
+//!
+//! Only the CPU's accesses are counted. The PPU reading VRAM and OAM to draw, and OAM DMA copying,
+//! don't show up.
+
+use std::cell::Cell;
+
+/// The whole address space, one entry per byte.
+pub const SIZE: usize = 0x10000;
+
+/// How much an access raises an intensity, which tops out at 255. A handful of accesses in a frame
+/// light an address up fully.
+const HIT: u8 = 0x60;
+
+/// How much of its intensity an address keeps each frame, in 256ths. An address lit up fully fades
+/// out in about half a second.
+const DECAY: u16 = 0xE0;
+
+#[derive(Clone)]
+pub struct Heatmap {
+    // Reads are recorded from `Cpu::read_mem`, which only has a shared reference.
+    reads: Box<[Cell<u8>]>,
+    writes: Box<[u8]>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Heatmap { reads: vec![Cell::new(0); SIZE].into_boxed_slice(), writes: vec![0; SIZE].into_boxed_slice() }
+    }
+
+    pub fn record_read(&self, addr: u16) {
+        let read = &self.reads[addr as usize];
+        read.set(read.get().saturating_add(HIT));
+    }
+
+    pub fn record_write(&mut self, addr: u16) {
+        let write = &mut self.writes[addr as usize];
+        *write = write.saturating_add(HIT);
+    }
+
+    /// Fade every address, once a frame.
+    pub fn decay(&mut self) {
+        let fade = |intensity: u8| (u16::from(intensity) * DECAY >> 8) as u8;
+        for read in self.reads.iter() {
+            read.set(fade(read.get()));
+        }
+        for write in self.writes.iter_mut() {
+            *write = fade(*write);
+        }
+    }
+
+    /// How much `addr` has been read and written lately, from 0 to 255 each.
+    pub fn intensity(&self, addr: u16) -> (u8, u8) {
+        (self.reads[addr as usize].get(), self.writes[addr as usize])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    quickcheck! {
+        // The CPU only records accesses with instrumentation built in.
+        #[cfg(feature = "instrumentation")]
+        fn test_heatmap_counts_only_the_cpu(offset: u16) -> bool {
+            use crate::cart::{Cart, CartConfig};
+            use crate::cart_header::CartType;
+            use crate::cpu::Cpu;
+            use std::collections::HashSet;
+
+            // ld a, [addr]; ld [addr + 1], a, in work RAM. Tools peeking and poking, and OAM DMA,
+            // don't count.
+            let addr = 0xC000 + offset % 0x1000;
+            let [low, high] = addr.to_le_bytes();
+            let [low_1, high_1] = (addr + 1).to_le_bytes();
+            let mut rom = vec![0; 0x8000];
+            rom[0x100..0x106].copy_from_slice(&[0xFA, low, high, 0xEA, low_1, high_1]);
+            let config = CartConfig { cart_type: CartType::NoMbc, rom_size: rom.len(), ram_size: 0 };
+            let mut cpu = Cpu::new(Cart::new(rom.into_boxed_slice(), None, &config).unwrap());
+            cpu.heatmap = Some(Heatmap::new());
+            cpu.read_mem_debug(0xDFFF);
+            cpu.write_mem_debug(0xDFFF, 1);
+            crate::expr::Expr::parse("[0xDFFF]").unwrap().eval(&cpu);
+            cpu.write_mem_debug(0xFF46, 0xD0);
+            cpu.step_n(2, &HashSet::new());
+            let heatmap = cpu.heatmap.as_ref().unwrap();
+            let (read, _) = heatmap.intensity(addr);
+            let (_, written) = heatmap.intensity(addr + 1);
+            let (fetched, _) = heatmap.intensity(0x0101);
+            read > 0 && written > 0 && fetched > 0
+                && heatmap.intensity(0xDFFF) == (0, 0)
+                && heatmap.intensity(0xD000) == (0, 0)
+        }
+
+        fn test_heatmap_decays(addr: u16, hits: u8) -> bool {
+            let mut heatmap = Heatmap::new();
+            for _ in 0..hits % 8 {
+                heatmap.record_read(addr);
+                heatmap.record_write(addr);
+            }
+            let mut last = heatmap.intensity(addr);
+            for _ in 0..64 {
+                heatmap.decay();
+                let now = heatmap.intensity(addr);
+                if now.0 > last.0 || now.1 > last.1 || (last.0 > 0 && now.0 == last.0) {
+                    return false;
+                }
+                last = now;
+            }
+            last == (0, 0)
+        }
+    }
+}
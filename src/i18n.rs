@@ -0,0 +1,114 @@
+//! Translations of the text Rugby shows to players: the on-screen display, the window title,
+//! messages from hotkeys, and the subcommand descriptions in `--help`. Messages live in one TOML
+//! file per language under `locales/`, compiled into the binary, and may contain placeholders like
+//! `{seconds}`. Anything a translation is missing falls back to English.
+//!
+//! The language is `language` under `[ui]` in the config file, or else the one named by the usual
+//! `LC_ALL`, `LC_MESSAGES`, or `LANG` environment variables. Replies on the control socket are
+//! meant for programs, so they stay in English.
+
+use crate::config::Config;
+use crate::pause::{PauseReason, PauseState};
+use lazy_static::lazy_static;
+use log::info;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.toml")),
+    ("de", include_str!("../locales/de.toml")),
+];
+
+struct Catalog {
+    messages: HashMap<String, String>,
+    english: HashMap<String, String>,
+}
+
+lazy_static! {
+    static ref CATALOG: Catalog = Catalog::new(&language());
+}
+
+impl Catalog {
+    fn new(language: &str) -> Self {
+        let english = parse(LOCALES[0].1);
+        let messages = match LOCALES.iter().find(|&&(name, _)| name == language) {
+            Some(&(_, source)) => parse(source),
+            None => {
+                if !language.is_empty() && language != "c" && language != "posix" {
+                    info!("No translation for language {:?}, using English", language);
+                }
+                HashMap::new()
+            }
+        };
+        Catalog { messages, english }
+    }
+}
+
+/// The message with the given id, like `osd.anarchy`, in the user's language.
+pub fn tr(id: &'static str) -> &'static str {
+    let catalog: &'static Catalog = &CATALOG;
+    catalog.messages.get(id)
+        .or_else(|| catalog.english.get(id))
+        .map_or(id, |message| message.as_str())
+}
+
+/// Like `tr`, with each `{name}` placeholder replaced by its value from `args`.
+pub fn tr_with(id: &'static str, args: &[(&str, &dyn Display)]) -> String {
+    let mut message = tr(id).to_string();
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    message
+}
+
+/// `running`, or `paused` followed by the reasons, like `PauseState`'s `Display` but translated.
+pub fn pause_state(state: PauseState) -> String {
+    if !state.is_paused() {
+        return tr("state.running").to_string();
+    }
+    let reasons: Vec<_> = state.reasons().iter().map(|reason| tr(match reason {
+        PauseReason::User => "state.user",
+        PauseReason::Breakpoint => "state.breakpoint",
+        PauseReason::FocusLost => "state.focus_lost",
+        PauseReason::Debugger => "state.debugger",
+    })).collect();
+    tr_with("state.paused", &[("reasons", &reasons.join(", "))])
+}
+
+/// The user's language as a lowercase code like `de`, or an empty string if there's no setting.
+/// Errors in the config file are ignored here, since every subcommand reports them anyway.
+fn language() -> String {
+    let configured = Config::load().ok().and_then(|config| config.ui.language);
+    let from_env = || {
+        ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+    };
+    let locale = configured.or_else(from_env).unwrap_or_default();
+    // Locales look like `de_DE.UTF-8` or `sr_RS@latin`; only the language matters.
+    let code = locale.split(|c| c == '_' || c == '-' || c == '.' || c == '@').next().unwrap_or("");
+    code.to_lowercase()
+}
+
+/// Flatten a TOML file of tables of strings into ids like `table.key`.
+fn parse(source: &str) -> HashMap<String, String> {
+    let value: toml::Value = toml::from_str(source).expect("built-in translation isn't valid TOML");
+    let mut messages = HashMap::new();
+    flatten(&value, String::new(), &mut messages);
+    messages
+}
+
+fn flatten(value: &toml::Value, prefix: String, messages: &mut HashMap<String, String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let id = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(value, id, messages);
+            }
+        }
+        toml::Value::String(message) => {
+            messages.insert(prefix, message.clone());
+        }
+        _ => panic!("built-in translation {} isn't a string", prefix),
+    }
+}
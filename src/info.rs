@@ -0,0 +1,276 @@
+//! `rugby info`, which describes cartridges from their headers. Text output is for people: on a
+//! terminal it's highlighted and goes through the pager. JSON and TOML output are for scripts, with
+//! one object per ROM (an array in JSON, `[[rom]]` tables in TOML) and sizes in bytes.
+//!
+//! Given a DAT file, each ROM is also looked up by checksum to name the game it is, since headers
+//! only hold a shortened title and say nothing about regions or most revisions.
+//!
+//! The Nintendo logo and both header checksums are checked too, since a failure usually means a
+//! bad dump or a corrupted file.
+
+use crate::cart_header::{CartHardware, CartHeader, Checksum, GbcFlag, MemSize, SgbFlag};
+use crate::checksum::crc32;
+use crate::rom_db::{Entry, RomDb};
+use failure::ResultExt;
+use serde_derive::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Toml,
+}
+
+/// What `info` knows about one ROM, in the shape of the JSON and TOML output.
+#[derive(Debug, Serialize)]
+struct RomInfo {
+    path: String,
+    title: String,
+    version: u8,
+    mapper: String,
+    hardware: Vec<String>,
+    rom_size: Option<usize>,
+    ram_size: Option<usize>,
+    gbc: &'static str,
+    sgb: bool,
+    manufacturer_code: Option<String>,
+    licensee_code: String,
+    publisher: Option<&'static str>,
+    destination: String,
+    crc32: String,
+    logo_valid: bool,
+    header_checksum_valid: bool,
+    global_checksum_valid: bool,
+
+    /// The game's name, region, and revision in the ROM database, if it's there.
+    game: Option<String>,
+    region: Option<String>,
+    revision: Option<String>,
+}
+
+/// A ROM and what's known about it.
+struct Rom<'a> {
+    path: &'a Path,
+    header: CartHeader,
+    crc32: u32,
+    entry: Option<Entry>,
+}
+
+#[derive(Serialize)]
+struct TomlInfo<'a> {
+    rom: &'a [RomInfo],
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!("unknown format {:?}, expected text, json, or toml", s)),
+        }
+    }
+}
+
+impl RomInfo {
+    fn new(rom: &Rom) -> Self {
+        let (path, header) = (rom.path, &rom.header);
+        let bytes = |size| match size {
+            MemSize::Bytes(n) => Some(n),
+            MemSize::Unknown(_) => None,
+        };
+        RomInfo {
+            path: path.display().to_string(),
+            title: title(header),
+            version: header.rom_version,
+            mapper: header.cart_type.to_string(),
+            hardware: header.hardware.iter().map(|h| format!("{:?}", h)).collect(),
+            rom_size: bytes(header.rom_size),
+            ram_size: bytes(header.ram_size),
+            gbc: match header.gbc_flag {
+                GbcFlag::Unsupported => "unsupported",
+                GbcFlag::Supported => "supported",
+                GbcFlag::Required => "required",
+            },
+            sgb: header.sgb_flag == SgbFlag::Supported,
+            manufacturer_code: header.manufacturer_code.clone(),
+            licensee_code: header.licensee_code.to_string(),
+            publisher: header.licensee_code.publisher(),
+            destination: header.destination_code.to_string(),
+            crc32: format!("{:08X}", rom.crc32),
+            logo_valid: header.checks.logo,
+            header_checksum_valid: header.checks.header_checksum.is_valid(),
+            global_checksum_valid: header.checks.global_checksum.is_valid(),
+            game: rom.entry.as_ref().map(|entry| entry.name.clone()),
+            region: rom.entry.as_ref().and_then(|entry| entry.region.clone()),
+            revision: rom.entry.as_ref().and_then(|entry| entry.revision.clone()),
+        }
+    }
+}
+
+/// Describe each ROM, naming the game from `db` if given. `table` puts text output in one row per
+/// ROM.
+pub fn print(paths: &[PathBuf], format: Format, table: bool, db: Option<&RomDb>) -> Result<(), failure::Error> {
+    let mut roms = Vec::new();
+    for path in paths {
+        let data = std::fs::read(path)
+            .with_context(|_| format!("Failed to read ROM file: {}", path.display()))?;
+        let header = CartHeader::from_rom(&data)
+            .with_context(|_| format!("Failed to parse cartridge header: {}", path.display()))?;
+        let crc32 = crc32(&data);
+        let entry = db.and_then(|db| db.lookup(data.len(), crc32)).cloned();
+        roms.push(Rom { path: path.as_path(), header, crc32, entry });
+    }
+
+    match format {
+        Format::Json => {
+            let infos: Vec<_> = roms.iter().map(RomInfo::new).collect();
+            println!("{}", serde_json::to_string_pretty(&infos)?);
+        }
+        Format::Toml => {
+            let infos: Vec<_> = roms.iter().map(RomInfo::new).collect();
+            print!("{}", toml::to_string(&TomlInfo { rom: &infos })?);
+        }
+        Format::Text => {
+            let terminal = atty::is(atty::Stream::Stdout);
+            let color = terminal && std::env::var_os("NO_COLOR").is_none();
+            let mut out = tabwriter::TabWriter::new(Vec::new());
+            if table {
+                write_table(&mut out, &roms, db.is_some())?;
+            } else {
+                write_records(&mut out, &roms, db.is_some(), color)?;
+            }
+            let text = out.into_inner()?;
+            if terminal { page(&text)? } else { io::stdout().write_all(&text)? }
+        }
+    }
+    Ok(())
+}
+
+/// One row per ROM, with the database's name for it if `with_db`.
+fn write_table(out: &mut impl Write, roms: &[Rom], with_db: bool) -> io::Result<()> {
+    write!(out, "File path\t")?;
+    if with_db {
+        write!(out, "Game\t")?;
+    }
+    writeln!(out, "Title\tVersion\tMapper\tHardware\tROM size\tRAM size\tGBC\tSGB\tPublisher\tDestination\tManufacturer\tCRC-32\tChecks")?;
+    for Rom { path, header, crc32, entry } in roms {
+        write!(out, "{}\t", path.display())?;
+        if with_db {
+            write!(out, "{}\t", entry.as_ref().map_or("-", |entry| entry.name.as_str()))?;
+        }
+        write!(out, "{}\t", title(header))?;
+        write!(out, "{}\t", header.rom_version)?;
+        write!(out, "{}\t", header.cart_type)?;
+        write!(out, "{}\t", CartHardware::flags_to_string(header.hardware))?;
+        write!(out, "{}\t", header.rom_size)?;
+        write!(out, "{}\t", header.ram_size)?;
+        write!(out, "{}\t", header.gbc_flag)?;
+        write!(out, "{}\t", header.sgb_flag)?;
+        write!(out, "{}\t", publisher(header))?;
+        write!(out, "{}\t", header.destination_code)?;
+        write!(out, "{}\t", header.manufacturer_code.as_ref().map_or("-", |code| code.as_str()))?;
+        write!(out, "{:08X}\t", crc32)?;
+        writeln!(out, "{}", pass_fail(header.checks.all_passed()))?;
+    }
+    out.flush()
+}
+
+/// A list of key-value records, one per ROM, with what the database says about it if `with_db`.
+/// Every label gets the same escape codes, so they don't throw off the alignment.
+fn write_records(out: &mut impl Write, roms: &[Rom], with_db: bool, color: bool) -> io::Result<()> {
+    let (bold, reset) = if color { ("\x1b[1m", "\x1b[0m") } else { ("", "") };
+    for Rom { path, header, crc32, entry } in roms {
+        let mut fields = vec![
+            ("File path", path.display().to_string()),
+            ("Title", title(header)),
+            ("Version", header.rom_version.to_string()),
+            ("Mapper", header.cart_type.to_string()),
+            ("Hardware", CartHardware::flags_to_string(header.hardware)),
+            ("ROM size", header.rom_size.to_string()),
+            ("RAM size", header.ram_size.to_string()),
+            ("GBC support", header.gbc_flag.to_string()),
+            ("SGB support", header.sgb_flag.to_string()),
+            ("Manufacturer code", header.manufacturer_code.clone().unwrap_or_else(|| String::from("none"))),
+            ("Publisher", publisher(header)),
+            ("Destination", header.destination_code.to_string()),
+            ("CRC-32", format!("{:08X}", crc32)),
+            ("Nintendo logo", pass_fail(header.checks.logo).to_string()),
+            ("Header checksum", checksum(header.checks.header_checksum, 2)),
+            ("Global checksum", checksum(header.checks.global_checksum, 4)),
+        ];
+        if with_db {
+            let none = || String::from("none");
+            match entry {
+                Some(entry) => fields.extend(vec![
+                    ("Game", entry.title.clone()),
+                    ("Region", entry.region.clone().unwrap_or_else(none)),
+                    ("Revision", entry.revision.clone().unwrap_or_else(none)),
+                ]),
+                None => fields.push(("Game", String::from("not in the ROM database"))),
+            }
+        }
+        for (label, value) in fields.iter() {
+            writeln!(out, "{}{}:{}\t{}", bold, label, reset, value)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// The title with the padding removed, or the raw bytes if it isn't text.
+fn title(header: &CartHeader) -> String {
+    match std::str::from_utf8(&header.title) {
+        Ok(title) => title.trim_end_matches('\0').to_string(),
+        Err(_) => format!("{:x?}", header.title),
+    }
+}
+
+fn pass_fail(passed: bool) -> &'static str {
+    if passed { "ok" } else { "FAILED" }
+}
+
+/// A checksum from the header and whether it's right, like "0x3C ok" or "0x3C FAILED (computed
+/// 0x3D)", with `digits` hex digits.
+fn checksum<T: PartialEq + fmt::UpperHex>(checksum: Checksum<T>, digits: usize) -> String {
+    let expected = format!("0x{:0width$X}", checksum.expected, width = digits);
+    if checksum.is_valid() {
+        format!("{} ok", expected)
+    } else {
+        format!("{} FAILED (computed 0x{:0width$X})", expected, checksum.actual, width = digits)
+    }
+}
+
+/// The publisher's name and licensee code, like "Nintendo (01)".
+fn publisher(header: &CartHeader) -> String {
+    let code = header.licensee_code;
+    match code.publisher() {
+        Some(name) => format!("{} ({})", name, code),
+        None => format!("unknown ({})", code),
+    }
+}
+
+/// Show `text` through the user's pager (`$PAGER`, or `less`), like `git log` does. Without a
+/// working pager, print it directly.
+fn page(text: &[u8]) -> io::Result<()> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| String::from("less -FRX"));
+    let mut words = pager.split_whitespace();
+    let child = words.next().and_then(|program| {
+        Command::new(program).args(words).stdin(Stdio::piped()).spawn().ok()
+    });
+    let mut child = match child {
+        Some(child) => child,
+        None => return io::stdout().write_all(text),
+    };
+    // The user quitting the pager early closes the pipe, which is fine.
+    let _ = child.stdin.take().unwrap().write_all(text);
+    child.wait()?;
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! Joypad input read from a simple line protocol, so external programs can drive the emulator
+//! through stdin or a named pipe.
+//!
+//! Each line holds a frame number and a bitmask of the keys to hold from that frame on, separated
+//! by whitespace, e.g. `120 0x08` to press Start on frame 120. The bitmask uses the layout of
+//! `Joypad::pressed`: bits 0-3 are A, B, Select, and Start, and bits 4-7 are Right, Left, Up, and
+//! Down. Numbers may be decimal or `0x`-prefixed hex. Blank lines and lines starting with `#` are
+//! ignored. Lines must be in order.
+//!
+//! A change can also happen partway through a frame, for TAS tricks and comparisons against real
+//! hardware, by adding a cycle offset to the frame number: `120+34952 0x08` presses Start halfway
+//! through frame 120. Offsets count CPU clock cycles and take effect on the first instruction
+//! boundary at or after them. Frames count emulated time, not real time, so the same input does
+//! the same thing at any speed. They're the emulator's frames, a 60th of a second or 69905 cycles,
+//! rather than the LCD's slightly longer 70224-cycle ones.
+//!
+//! A recording can name the build it was made with in a `# Rugby <build>` comment, using the text
+//! `rugby --version` prints. Playing it in another build warns that the game may not go the same
+//! way, as loading a savestate from another build does.
+
+use crate::gpu::CYCLES_PER_FRAME;
+use rugby::version;
+use log::warn;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct InputLine {
+    frame: u64,
+
+    /// Cycles into the frame.
+    offset: u64,
+
+    pressed: u8,
+}
+
+pub struct InputStream {
+    lines: Receiver<InputLine>,
+
+    /// A line read ahead of time, waiting for its frame.
+    next: Option<InputLine>,
+}
+
+impl InputStream {
+    /// Start reading input from the given file, or from stdin if the path is `-`. Reading happens
+    /// on a separate thread so a slow writer doesn't stall emulation.
+    pub fn open(path: &Path) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            let result = if path.as_os_str() == "-" {
+                let stdin = io::stdin();
+                let lock = stdin.lock();
+                read_lines(lock, &tx)
+            } else {
+                File::open(&path).and_then(|file| read_lines(BufReader::new(file), &tx))
+            };
+            if let Err(e) = result {
+                warn!("Failed to read input from {}: {}", path.display(), e);
+            }
+        });
+        InputStream { lines: rx, next: None }
+    }
+
+    /// Return the keys to hold from `offset` cycles into `frame`, if any line for then or earlier
+    /// has arrived since the last call.
+    pub fn poll(&mut self, frame: u64, offset: u64) -> Option<u8> {
+        let mut pressed = None;
+        while let Some(line) = self.peek() {
+            if (line.frame, line.offset) > (frame, offset) {
+                break;
+            }
+            pressed = Some(line.pressed);
+            self.next = None;
+        }
+        pressed
+    }
+
+    /// How many cycles into `frame` the next change is, if one for it has arrived. Lines that
+    /// arrived late, for an earlier frame, are due right away.
+    pub fn next_offset(&mut self, frame: u64) -> Option<u64> {
+        let line = self.peek()?;
+        if line.frame < frame {
+            Some(0)
+        } else if line.frame == frame {
+            Some(line.offset)
+        } else {
+            None
+        }
+    }
+
+    fn peek(&mut self) -> Option<InputLine> {
+        if self.next.is_none() {
+            match self.lines.try_recv() {
+                Ok(line) => self.next = Some(line),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        self.next
+    }
+}
+
+fn read_lines(input: impl BufRead, tx: &mpsc::Sender<InputLine>) -> io::Result<()> {
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.starts_with("# Rugby ") {
+            let build = line["# Rugby ".len()..].trim();
+            if build != version::BUILD {
+                warn!(
+                    "Input was recorded with Rugby {}, but this is {}, so the game may not go the same way",
+                    build, version::BUILD,
+                );
+            }
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Some(input) => {
+                if tx.send(input).is_err() {
+                    // The emulator has exited.
+                    return Ok(());
+                }
+            }
+            None => warn!("Ignoring invalid input line {}: {:?}", i + 1, line),
+        }
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Option<InputLine> {
+    let mut words = line.split_whitespace();
+    let mut time = words.next()?.splitn(2, '+');
+    let frame = parse_number(time.next()?)?;
+    let offset = match time.next() {
+        Some(offset) => parse_number(offset)?,
+        None => 0,
+    };
+    let pressed = parse_number(words.next()?)?;
+    if words.next().is_some() || pressed > 0xFF || offset >= CYCLES_PER_FRAME as u64 {
+        return None;
+    }
+    Some(InputLine { frame, offset, pressed: pressed as u8 })
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
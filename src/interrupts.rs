@@ -1,4 +1,7 @@
+use crate::cpu::INSTRUMENTATION;
+use enumflags2::BitFlags;
 use enumflags2_derive::EnumFlags;
+use log::debug;
 
 #[derive(Copy, Clone, Debug, EnumFlags, Eq, PartialEq)]
 #[repr(u8)]
@@ -21,4 +24,79 @@ impl Interrupt {
             Interrupt::Joypad => 0x0060,
         }
     }
-}
\ No newline at end of file
+}
+/// The order interrupts are checked in, from highest to lowest priority.
+pub const PRIORITY: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::Lcd,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+/// The interrupt controller, which holds the `IF` and `IE` registers. The CPU owns it and lends it
+/// to each component's `step`, which raises its interrupt lines with `request`.
+#[derive(Clone, Debug)]
+pub struct InterruptController {
+    /// The `IF` Interrupt Flags register accessed via I/O port 0xFF0F.
+    requested: BitFlags<Interrupt>,
+
+    /// The `IE` Interrupt Enable register accessed via I/O port 0xFFFF. Unlike `IF`, its 3 unused
+    /// bits are nonetheless read/write-able on the Game Boy, so the whole byte is kept.
+    enable_register: u8,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController {
+            requested: BitFlags::from(Interrupt::VBlank),
+            enable_register: 0,
+        }
+    }
+
+    /// Raise an interrupt line, setting its bit in `IF`.
+    pub fn request(&mut self, interrupt: Interrupt) {
+        if INSTRUMENTATION {
+            debug!("Requesting interrupt {:?}", interrupt);
+        }
+        self.requested.insert(interrupt);
+    }
+
+    /// Clear an interrupt's bit in `IF` once the CPU has dispatched it.
+    pub fn acknowledge(&mut self, interrupt: Interrupt) {
+        self.requested.remove(interrupt);
+    }
+
+    pub fn requested(&self) -> BitFlags<Interrupt> {
+        self.requested
+    }
+
+    pub fn enabled(&self) -> BitFlags<Interrupt> {
+        BitFlags::from_bits_truncate(self.enable_register)
+    }
+
+    pub fn is_requested(&self, interrupt: Interrupt) -> bool {
+        self.requested.contains(interrupt)
+    }
+
+    pub fn is_enabled(&self, interrupt: Interrupt) -> bool {
+        self.enabled().contains(interrupt)
+    }
+
+    /// Read `IF`. The top 3 bits are unused and always 1.
+    pub fn read_flags(&self) -> u8 {
+        0b1110_0000 | self.requested.bits()
+    }
+
+    pub fn write_flags(&mut self, val: u8) {
+        self.requested = BitFlags::from_bits_truncate(val);
+    }
+
+    pub fn read_enable(&self) -> u8 {
+        self.enable_register
+    }
+
+    pub fn write_enable(&mut self, val: u8) {
+        self.enable_register = val;
+    }
+}
@@ -0,0 +1,126 @@
+//! Uniform behavior for memory-mapped I/O registers.
+//!
+//! Most registers don't use all 8 bits. Unused bits always read as 1 and ignore writes, and some
+//! bits (like the LCD mode in STAT) are set by the hardware and can't be written by the CPU.
+//! Components describe each register's bits once with an `IoRegister`, and implement `IoDevice`
+//! to get reads and writes with that masking applied, instead of masking by hand per register.
+
+/// The layout of one I/O register at 0xFF00 + `addr`.
+#[derive(Clone, Copy, Debug)]
+pub struct IoRegister {
+    pub addr: u8,
+
+    /// The name from the Pan Docs, e.g. `STAT`.
+    pub name: &'static str,
+
+    /// Bits which don't exist. They always read as 1 and ignore writes.
+    pub unused: u8,
+
+    /// Bits which only the hardware can change. Writes leave them as they are.
+    pub read_only: u8,
+}
+
+impl IoRegister {
+    pub const fn new(addr: u8, name: &'static str) -> Self {
+        IoRegister { addr, name, unused: 0, read_only: 0 }
+    }
+
+    pub const fn unused(self, unused: u8) -> Self {
+        IoRegister { unused, ..self }
+    }
+
+    pub const fn read_only(self, read_only: u8) -> Self {
+        IoRegister { read_only, ..self }
+    }
+}
+
+/// Marks an address without a register in `IoDevice::REGISTER_INDEX`.
+const NO_REGISTER: u8 = 0xFF;
+
+/// Where each address's register is in `registers`, or `NO_REGISTER`.
+const fn index_registers(registers: &[IoRegister]) -> [u8; 256] {
+    let mut index = [NO_REGISTER; 256];
+    let mut i = 0;
+    while i < registers.len() {
+        index[registers[i].addr as usize] = i as u8;
+        i += 1;
+    }
+    index
+}
+
+/// A component owning some I/O registers.
+pub trait IoDevice {
+    /// Every register this component handles.
+    const REGISTERS: &'static [IoRegister];
+
+    /// Where each address's register is in `REGISTERS`, worked out at compile time so accesses
+    /// don't search for it.
+    const REGISTER_INDEX: [u8; 256] = index_registers(Self::REGISTERS);
+
+    /// The state of the register's used bits. Unused bits are ignored.
+    fn read_raw(&self, addr: u8) -> u8;
+
+    /// Handle a write of `val`, which has unused bits cleared and read-only bits replaced with
+    /// their current values. This is also where writes trigger side effects, like resetting `DIV`.
+    fn write_raw(&mut self, addr: u8, val: u8);
+
+    fn register(addr: u8) -> &'static IoRegister {
+        match Self::REGISTER_INDEX[addr as usize] {
+            NO_REGISTER => panic!("no I/O register at FF{:02X} in this component", addr),
+            i => &Self::REGISTERS[i as usize],
+        }
+    }
+
+    fn read_reg(&self, addr: u8) -> u8 {
+        let reg = Self::register(addr);
+        self.read_raw(addr) | reg.unused
+    }
+
+    fn write_reg(&mut self, addr: u8, val: u8) {
+        let reg = Self::register(addr);
+        let kept = self.read_raw(addr) & reg.read_only;
+        self.write_raw(addr, (val & !reg.read_only | kept) & !reg.unused);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gpu::Gpu;
+    use crate::joypad::Joypad;
+    use crate::serial::Serial;
+    use crate::timer::Timer;
+    use quickcheck::{quickcheck, TestResult};
+
+    /// Write `val` to each of `device`'s registers but those named in `skip`, and check that only
+    /// the writable bits changed.
+    fn check_read_back<D: IoDevice>(device: &mut D, val: u8, skip: &[&str]) -> Result<(), String> {
+        for &IoRegister { addr, name, unused, read_only } in D::REGISTERS {
+            if skip.contains(&name) {
+                continue;
+            }
+            let before = device.read_reg(addr);
+            device.write_reg(addr, val);
+            let expected = val & !read_only & !unused | before & read_only | unused;
+            let actual = device.read_reg(addr);
+            if actual != expected {
+                return Err(format!("{}: expected 0x{:02X}, got 0x{:02X}", name, expected, actual));
+            }
+        }
+        Ok(())
+    }
+
+    quickcheck! {
+        fn registers_read_back(val: u8) -> TestResult {
+            // Writing DIV or LY resets it, whatever the value.
+            let result = check_read_back(&mut Gpu::new(), val, &["LY"])
+                .and_then(|()| check_read_back(&mut Joypad::new(), val, &[]))
+                .and_then(|()| check_read_back(&mut Serial::new(), val, &[]))
+                .and_then(|()| check_read_back(&mut Timer::new(), val, &["DIV"]));
+            match result {
+                Ok(()) => TestResult::passed(),
+                Err(e) => TestResult::error(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Interrupt latency statistics: how many cycles pass between each interrupt being requested and
+//! the CPU jumping to its handler, per interrupt type. Long or uneven latencies mean the game spent
+//! that time with interrupts disabled or in a higher priority handler, which is what eats into a
+//! vblank budget.
+
+use crate::interrupts::{Interrupt, PRIORITY};
+use enumflags2::BitFlags;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[derive(Clone, Debug, Default)]
+pub struct IrqStats {
+    /// The cycle each currently requested interrupt was requested on, in `PRIORITY` order.
+    requested_at: [Option<usize>; 5],
+
+    /// For each interrupt, how many times it was serviced after each latency in cycles.
+    latencies: [BTreeMap<usize, u64>; 5],
+
+    /// Requests cleared without being serviced, e.g. by a game polling `IF`.
+    dropped: [u64; 5],
+}
+
+impl IrqStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Note which interrupts are requested in `IF` at `cycle`. Call before each instruction.
+    pub fn observe(&mut self, requested: BitFlags<Interrupt>, cycle: usize) {
+        for (i, &interrupt) in PRIORITY.iter().enumerate() {
+            match (requested.contains(interrupt), self.requested_at[i]) {
+                (true, None) => self.requested_at[i] = Some(cycle),
+                (false, Some(_)) => {
+                    self.requested_at[i] = None;
+                    self.dropped[i] += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Forget when the interrupts requested now were requested, e.g. because a savestate was
+    /// loaded and the clock went back. They're timed again from when they're next seen.
+    pub fn forget_requests(&mut self) {
+        self.requested_at = [None; 5];
+    }
+
+    /// Record that the CPU dispatched `interrupt` at `cycle`.
+    pub fn serviced(&mut self, interrupt: Interrupt, cycle: usize) {
+        let i = index(interrupt);
+        if let Some(requested_at) = self.requested_at[i].take() {
+            *self.latencies[i].entry(cycle - requested_at).or_insert(0) += 1;
+        }
+    }
+
+    pub fn write_report(&self, out: impl Write) -> io::Result<()> {
+        let mut out = tabwriter::TabWriter::new(out);
+        writeln!(out, "Interrupt\tServiced\tMin\tMedian\t99th %\tMax\tMean\tCleared unserviced")?;
+        for (i, interrupt) in PRIORITY.iter().enumerate() {
+            let latencies = &self.latencies[i];
+            let count: u64 = latencies.values().sum();
+            write!(out, "{:?}\t{}\t", interrupt, count)?;
+            if count == 0 {
+                writeln!(out, "-\t-\t-\t-\t-\t{}", self.dropped[i])?;
+                continue;
+            }
+            let total: u64 = latencies.iter().map(|(&latency, &n)| latency as u64 * n).sum();
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{:.1}\t{}",
+                latencies.keys().next().unwrap(),
+                percentile(latencies, count, 50),
+                percentile(latencies, count, 99),
+                latencies.keys().next_back().unwrap(),
+                total as f64 / count as f64,
+                self.dropped[i],
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "Latencies are in cycles, from the request to the jump to the handler.")?;
+        out.flush()
+    }
+}
+
+fn index(interrupt: Interrupt) -> usize {
+    PRIORITY.iter().position(|&i| i == interrupt).unwrap()
+}
+
+/// The smallest latency at least `percent` of the `count` services were as fast as.
+fn percentile(latencies: &BTreeMap<usize, u64>, count: u64, percent: u64) -> usize {
+    let target = (count * percent + 99) / 100;
+    let mut seen = 0;
+    for (&latency, &n) in latencies {
+        seen += n;
+        if seen >= target {
+            return latency;
+        }
+    }
+    *latencies.keys().next_back().unwrap()
+}
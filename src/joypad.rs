@@ -1,6 +1,8 @@
 use enumflags2::BitFlags;
 use enumflags2_derive::EnumFlags;
-use crate::interrupts::Interrupt;
+use crate::interrupts::{Interrupt, InterruptController};
+use crate::io_register::{IoDevice, IoRegister};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 #[derive(Copy, Clone, Debug, EnumFlags)]
 #[repr(u8)]
@@ -50,11 +52,11 @@ impl Joypad {
     }
 
     pub fn button_key_down(&mut self, button: ButtonKey) {
-        let before = self.read_reg();
+        let before = self.read_raw(0x00);
         self.button_keys_pressed.insert(button);
-        let after = self.read_reg();
+        let after = self.read_raw(0x00);
         // Request an interrupt if a 1 bit in `before` became a 0 bit in `after`.
-        self.should_interrupt = before & !after != 0;
+        self.should_interrupt |= before & !after != 0;
     }
 
     pub fn button_key_up(&mut self, button: ButtonKey) {
@@ -62,24 +64,60 @@ impl Joypad {
     }
 
     pub fn dir_key_down(&mut self, dir: DirKey) {
-        let before = self.read_reg();
+        let before = self.read_raw(0x00);
         self.dir_keys_pressed.insert(dir);
-        let after = self.read_reg();
+        let after = self.read_raw(0x00);
         // Request an interrupt if a 1 bit in `before` became a 0 bit in `after`.
-        self.should_interrupt = before & !after != 0;
+        self.should_interrupt |= before & !after != 0;
     }
 
     pub fn dir_key_up(&mut self, dir: DirKey) {
         self.dir_keys_pressed.remove(dir);
     }
 
-    pub fn read_reg(&self) -> u8 {
+    /// All currently pressed keys as a single byte, with the button keys in the low nibble and the
+    /// direction keys in the high nibble. Used for recording and replaying input.
+    pub fn pressed(&self) -> u8 {
+        self.button_keys_pressed.bits() | self.dir_keys_pressed.bits() << 4
+    }
+
+    /// Press and release keys so that exactly those in `pressed` (in the format returned by
+    /// `pressed`) are held down.
+    pub fn set_pressed(&mut self, pressed: u8) {
+        let before = self.read_raw(0x00);
+        self.button_keys_pressed = BitFlags::from_bits_truncate(pressed);
+        self.dir_keys_pressed = BitFlags::from_bits_truncate(pressed >> 4);
+        let after = self.read_raw(0x00);
+        // Request an interrupt if a 1 bit in `before` became a 0 bit in `after`.
+        self.should_interrupt |= before & !after != 0;
+    }
+
+    /// Whether any key in a selected group is held, pulling one of P1's key lines low. This is
+    /// what wakes the CPU from STOP.
+    pub fn key_line_low(&self) -> bool {
+        self.read_raw(0x00) & 0b1111 != 0b1111
+    }
+
+    /// Called by the CPU when executing an instruction, to request a Joypad interrupt if a key
+    /// was pressed since the last step.
+    pub fn step(&mut self, interrupts: &mut InterruptController) {
+        if self.should_interrupt {
+            self.should_interrupt = false;
+            interrupts.request(Interrupt::Joypad);
+        }
+    }
+}
+
+impl IoDevice for Joypad {
+    // Bits 0-3 are the key states and bits 6-7 are unused, according to Mooneye.
+    const REGISTERS: &'static [IoRegister] = &[
+        IoRegister::new(0x00, "P1").unused(0b1100_0000).read_only(0b0000_1111),
+    ];
+
+    fn read_raw(&self, _addr: u8) -> u8 {
         // For all the used bits in this register, 0 actually represents `true` values of the
         // corresponding fields. I found it easiest to construct the opposite and then negate at
         // the end.
-        //
-        // NOTE: The top two bits of this register are unused and should always set to 1 according
-        // to Mooneye tests.
         let mut bits = 0;
         bits |= (self.select_button_keys as u8) << 5;
         bits |= (self.select_dir_keys as u8) << 4;
@@ -92,24 +130,31 @@ impl Joypad {
         !bits
     }
 
-    pub fn write_reg(&mut self, bits: u8) {
-        // Bits 0-3 are read-only and bits 6-7 are unused and unwritable according to Mooneye.
-        // Also, the meaning of these bits is negated (0 means `true`).
+    fn write_raw(&mut self, _addr: u8, bits: u8) {
+        // The meaning of these bits is negated (0 means `true`).
         self.select_button_keys = bits >> 5 & 1 == 0;
         self.select_dir_keys = bits >> 4 & 1 == 0;
         // TODO(solson): Enabling these bits can trigger the Joypad interrupt if some keys were
         // already being held, so we should handle interrupts here, too. (Or, more likely, in a way
         // that lets us do the check in a single place.)
     }
+}
 
-    /// Called by the CPU when executing an instruction. Returns whether to request a Joypad
-    /// interrupt.
-    pub fn step(&mut self) -> BitFlags<Interrupt> {
-        if self.should_interrupt {
-            self.should_interrupt = false;
-            BitFlags::from(Interrupt::Joypad)
-        } else {
-            BitFlags::empty()
-        }
+impl SaveState for Joypad {
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.select_button_keys);
+        w.bool(self.select_dir_keys);
+        w.u8(self.pressed());
+        w.bool(self.should_interrupt);
     }
-}
\ No newline at end of file
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.select_button_keys = r.bool()?;
+        self.select_dir_keys = r.bool()?;
+        let pressed = r.u8()?;
+        self.button_keys_pressed = BitFlags::from_bits_truncate(pressed);
+        self.dir_keys_pressed = BitFlags::from_bits_truncate(pressed >> 4);
+        self.should_interrupt = r.bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! The Rugby Game Boy emulator core, without any frontend.
+//!
+//! Load a cartridge with `cart::Cart::from_rom`, set up a `cpu::Cpu` with `Cpu::builder`, and call
+//! `Cpu::run_frame` once per frame, or `Cpu::run_frame_with_audio` to hear it too. The screen is
+//! `Cpu::screen`, input goes through `Cpu::joypad_mut`, and `Cpu::save_state`/`Cpu::load_state`
+//! snapshot everything. See the `examples` directory for complete frontends.
+//!
+//! Everything a frontend needs is in the `prelude`, which follows semver: anything it exports
+//! only changes in a new major version. That covers the methods of the types it exports, not
+//! their fields; `Cpu`'s fields are public for Rugby's own frontend and debugger only. Enums that
+//! are likely to grow, like the cartridge types and errors, are `#[non_exhaustive]`, so matching
+//! on them needs a `_` arm. The rest of the crate may change in any release.
+
+pub mod audio;
+pub mod barcode_boy;
+pub mod cart;
+pub mod cart_header;
+pub mod cheat;
+pub mod checksum;
+pub mod cpu;
+pub mod debug;
+pub mod emulator_session;
+pub mod expr;
+pub mod gpu;
+pub mod heatmap;
+pub mod interrupts;
+pub mod io_register;
+pub mod irq_stats;
+pub mod joypad;
+pub mod memory_map;
+pub mod pause;
+pub mod profile;
+pub mod repro;
+pub mod rgbds_map;
+pub mod savestate;
+pub mod serial;
+pub mod timer;
+pub mod version;
+pub mod wla_symbols;
+pub mod workboy;
+
+/// The stable API for frontends: `use rugby::prelude::*;`.
+pub mod prelude {
+    pub use crate::audio::AudioSink;
+    pub use crate::cart::{Cart, CartError};
+    pub use crate::cart_header::{CartType, HeaderParseError};
+    pub use crate::cpu::{Cpu, CpuBuilder, Model};
+    pub use crate::gpu::{SpriteLimit, SCREEN_HEIGHT, SCREEN_WIDTH};
+    pub use crate::joypad::{ButtonKey, DirKey, Joypad};
+    pub use crate::savestate::SaveStateError;
+}
@@ -0,0 +1,433 @@
+//! Link cable play over the network.
+//!
+//! Waiting for the other side on every bit, or even every byte, like the real cable does would
+//! stall the game for a network round trip on each byte, which makes link games unplayably slow
+//! over the internet. Instead, each side tells the other whenever its game writes `SB`, and the
+//! side clocking a transfer finishes it right away with the last byte it heard of. The peer
+//! answers with the byte it really shifted out, and if that's not what was guessed, the clocking
+//! side rolls back to a savestate from before the transfer and emulates the frames since again
+//! with the right byte and the same input.
+//!
+//! A guessed transfer is only sent to the peer once the guesses before it are confirmed, so the
+//! peer never sees a transfer that gets rolled back. Games send link bytes with a pause between
+//! them that's usually longer than the round trip, so rollbacks are rare and short.
+
+use crate::cpu::Cpu;
+use crate::serial::{Clocking, LinkEvent, LinkPort};
+use log::{info, warn};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"RGLK";
+const VERSION: u8 = 1;
+
+/// The most frames to keep savestates for. A guess that takes longer than this to confirm can't
+/// be rolled back anymore.
+const MAX_ROLLBACK_FRAMES: usize = 180;
+
+/// How long the handshake, or sending a message, may block before the peer counts as gone.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message {
+    /// The sender's game wrote `SB`.
+    Data(u8),
+
+    /// The sender clocked a transfer, shifting out this byte.
+    Transfer { number: u32, byte: u8 },
+
+    /// The sender shifted out this byte in a transfer the receiver clocked.
+    Answer { number: u32, byte: u8 },
+}
+
+impl Message {
+    const LEN: usize = 6;
+
+    fn encode(self) -> [u8; Message::LEN] {
+        let (tag, number, byte) = match self {
+            Message::Data(byte) => (0, 0, byte),
+            Message::Transfer { number, byte } => (1, number, byte),
+            Message::Answer { number, byte } => (2, number, byte),
+        };
+        let n = number.to_le_bytes();
+        [tag, n[0], n[1], n[2], n[3], byte]
+    }
+
+    fn decode(buf: &[u8; Message::LEN]) -> io::Result<Message> {
+        let number = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        let byte = buf[5];
+        match buf[0] {
+            0 => Ok(Message::Data(byte)),
+            1 => Ok(Message::Transfer { number, byte }),
+            2 => Ok(Message::Answer { number, byte }),
+            tag => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown link message {}", tag))),
+        }
+    }
+}
+
+/// A connection to the peer that never blocks the frame loop.
+pub trait Transport {
+    fn send(&mut self, message: Message) -> io::Result<()>;
+
+    /// The next message from the peer, if one has arrived.
+    fn try_recv(&mut self) -> io::Result<Option<Message>>;
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+
+    /// A partly received message.
+    buf: [u8; Message::LEN],
+    len: usize,
+}
+
+impl TcpTransport {
+    /// Wait for the peer to connect to `addr`.
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Waiting for the other player to connect to {}", listener.local_addr()?);
+        let (stream, peer) = listener.accept()?;
+        info!("Link connection from {}", peer);
+        Self::handshake(stream)
+    }
+
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        Self::handshake(TcpStream::connect(addr)?)
+    }
+
+    fn handshake(mut stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.write_all(MAGIC)?;
+        stream.write_all(&[VERSION])?;
+        let mut hello = [0; 5];
+        stream.read_exact(&mut hello)?;
+        if &hello[..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the other end isn't a Rugby link"));
+        }
+        if hello[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the other player's link protocol is version {}, but ours is {}", hello[4], VERSION),
+            ));
+        }
+        stream.set_nonblocking(true)?;
+        Ok(TcpTransport { stream, buf: [0; Message::LEN], len: 0 })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        // Messages are tiny and sent a few at a time, so the socket buffer never fills up in
+        // practice. Briefly blocking is better than dropping one if it does, and the write timeout
+        // keeps a peer that stopped reading from hanging the game.
+        self.stream.set_nonblocking(false)?;
+        let result = self.stream.write_all(&message.encode());
+        self.stream.set_nonblocking(true)?;
+        result
+    }
+
+    fn try_recv(&mut self) -> io::Result<Option<Message>> {
+        while self.len < Message::LEN {
+            match self.stream.read(&mut self.buf[self.len..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the other player disconnected")),
+                Ok(n) => self.len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        self.len = 0;
+        Message::decode(&self.buf).map(Some)
+    }
+}
+
+/// The state at the start of a frame, to roll back to.
+struct Snapshot {
+    state: Vec<u8>,
+    pressed: u8,
+    cycles: usize,
+    started: u32,
+    clocking: Option<Clocking>,
+    peer_data: u8,
+}
+
+/// Connects a `Cpu`'s serial port to a peer over a `Transport`. Call `start_frame` before
+/// emulating each frame and `end_frame` after.
+pub struct Link {
+    transport: Box<dyn Transport>,
+
+    /// Snapshots from the start of each frame since the oldest unconfirmed guess, oldest first.
+    history: VecDeque<Snapshot>,
+
+    /// The byte shifted in for each transfer this side clocked, by number, until the peer answers.
+    guesses: BTreeMap<u32, u8>,
+
+    /// Transfers this side clocked but hasn't sent yet, because an earlier guess is unconfirmed.
+    held: BTreeMap<u32, u8>,
+
+    /// The last transfer sent to the peer, and the answers sent, so emulating frames again after a
+    /// rollback doesn't repeat them.
+    last_sent: Option<u32>,
+    answered: BTreeMap<u32, u8>,
+}
+
+impl Link {
+    /// Start linking `cpu` to the peer at the other end of `transport`.
+    pub fn new(transport: Box<dyn Transport>, cpu: &mut Cpu) -> Self {
+        cpu.serial.link = Some(LinkPort::default());
+        Link {
+            transport,
+            history: VecDeque::new(),
+            guesses: BTreeMap::new(),
+            held: BTreeMap::new(),
+            last_sent: None,
+            answered: BTreeMap::new(),
+        }
+    }
+
+    /// Take in what the peer sent, rolling back if it shows a guess was wrong, and snapshot the
+    /// state to roll back to later. `cycles` is how long the frame about to be emulated is.
+    pub fn start_frame(&mut self, cpu: &mut Cpu, cycles: usize) {
+        if cpu.serial.link.is_none() {
+            return;
+        }
+        let mut wrong_guess = None;
+        loop {
+            let message = match self.transport.try_recv() {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(e) => return self.disconnect(cpu, e),
+            };
+            let port = cpu.serial.link.as_mut().unwrap();
+            match message {
+                Message::Data(byte) => port.peer_data = byte,
+                Message::Transfer { number, byte } => {
+                    port.incoming.insert(number, byte);
+                }
+                Message::Answer { number, byte } => {
+                    port.answers.insert(number, byte);
+                    if self.guesses.remove(&number).map_or(false, |guess| guess != byte) {
+                        wrong_guess = Some(wrong_guess.map_or(number, |n: u32| n.min(number)));
+                    }
+                }
+            }
+        }
+        if let Some(number) = wrong_guess {
+            self.roll_back(cpu, number);
+        }
+        if let Err(e) = self.send_held() {
+            return self.disconnect(cpu, e);
+        }
+        self.snapshot(cpu, cycles);
+    }
+
+    /// Tell the peer what happened on the link during the frame.
+    pub fn end_frame(&mut self, cpu: &mut Cpu) {
+        if let Err(e) = self.send_events(cpu) {
+            return self.disconnect(cpu, e);
+        }
+        self.forget_confirmed(cpu);
+        // The peer's transfers are kept after the game takes them in, since emulating the frames
+        // again after a rollback takes them in again, until no rollback can go back before them.
+        if let Some(port) = &mut cpu.serial.link {
+            let oldest = self.history.front().map_or(port.started, |snapshot| snapshot.started);
+            port.incoming = port.incoming.split_off(&oldest);
+        }
+    }
+
+    /// Drop the snapshots from before the oldest unconfirmed guess, which nothing can be rolled
+    /// back to anymore.
+    fn forget_confirmed(&mut self, cpu: &mut Cpu) {
+        match self.guesses.keys().next() {
+            None => self.history.clear(),
+            Some(&oldest) => {
+                while self.history.len() > 1 && self.history[1].started <= oldest {
+                    self.history.pop_front();
+                }
+                if self.history.len() > MAX_ROLLBACK_FRAMES {
+                    warn!("No answer to link transfer {} in {} frames, so it can't be corrected", oldest, MAX_ROLLBACK_FRAMES);
+                    self.history.clear();
+                    self.guesses.clear();
+                    if let Err(e) = self.send_held() {
+                        self.disconnect(cpu, e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn snapshot(&mut self, cpu: &Cpu, cycles: usize) {
+        let port = cpu.serial.link.as_ref().unwrap();
+        self.history.push_back(Snapshot {
+            state: cpu.save_state(),
+            pressed: cpu.joypad.pressed(),
+            cycles,
+            started: port.started,
+            clocking: port.clocking,
+            peer_data: port.peer_data,
+        });
+    }
+
+    fn send_events(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        let events = match &mut cpu.serial.link {
+            Some(port) => std::mem::replace(&mut port.events, Vec::new()),
+            None => return Ok(()),
+        };
+        let port = cpu.serial.link.as_ref().unwrap();
+        for event in events {
+            match event {
+                LinkEvent::Data(byte) => self.transport.send(Message::Data(byte))?,
+                LinkEvent::Clocked { number, sent, received } => {
+                    if port.answers.get(&number).map_or(true, |&answer| answer != received) {
+                        self.guesses.insert(number, received);
+                    }
+                    if self.last_sent.map_or(true, |last| number > last) {
+                        self.held.insert(number, sent);
+                    }
+                }
+                LinkEvent::Answered { number, sent } => {
+                    // After a rollback, only answers that came out differently are sent again,
+                    // which rolls the peer back in turn.
+                    if self.answered.get(&number) != Some(&sent) {
+                        self.answered.insert(number, sent);
+                        self.transport.send(Message::Answer { number, byte: sent })?;
+                    }
+                }
+            }
+        }
+        self.send_held()
+    }
+
+    /// Send the held transfers that no longer come after an unconfirmed guess.
+    fn send_held(&mut self) -> io::Result<()> {
+        while let Some((&number, &byte)) = self.held.iter().next() {
+            if self.guesses.keys().next().map_or(false, |&oldest| oldest < number) {
+                break;
+            }
+            self.held.remove(&number);
+            self.transport.send(Message::Transfer { number, byte })?;
+            self.last_sent = Some(number);
+        }
+        Ok(())
+    }
+
+    /// Go back to the frame transfer `number` was clocked in, and emulate every frame since again
+    /// with the peer's real answers.
+    fn roll_back(&mut self, cpu: &mut Cpu, number: u32) {
+        let start = match self.history.iter().rposition(|s| s.started <= number) {
+            Some(start) => start,
+            None => {
+                warn!("Link transfer {} was guessed wrong too long ago to roll back", number);
+                return;
+            }
+        };
+        info!("Rolling back {} frames for link transfer {}", self.history.len() - start, number);
+        let pressed = cpu.joypad.pressed();
+        let peer_data = cpu.serial.link.as_ref().unwrap().peer_data;
+        let frames: Vec<Snapshot> = self.history.drain(start..).collect();
+        if let Err(e) = cpu.load_state(&frames[0].state) {
+            warn!("Failed to roll back for the link: {}", e);
+            return;
+        }
+        {
+            let port = cpu.serial.link.as_mut().unwrap();
+            port.started = frames[0].started;
+            port.clocking = frames[0].clocking;
+            port.events.clear();
+            let started = port.started;
+            self.guesses.retain(|&n, _| n < started);
+            self.held.retain(|&n, _| n < started);
+        }
+        // Each frame guesses with the byte it heard of the first time around, so transfers the peer
+        // already answered come out the same.
+        for frame in frames {
+            cpu.joypad.set_pressed(frame.pressed);
+            cpu.serial.link.as_mut().unwrap().peer_data = frame.peer_data;
+            self.snapshot(cpu, frame.cycles);
+            cpu.step_cycles(frame.cycles, None, &HashSet::new());
+            if let Err(e) = self.send_events(cpu) {
+                return self.disconnect(cpu, e);
+            }
+        }
+        cpu.joypad.set_pressed(pressed);
+        cpu.serial.link.as_mut().unwrap().peer_data = peer_data;
+    }
+
+    fn disconnect(&mut self, cpu: &mut Cpu, e: io::Error) {
+        warn!("Link cable disconnected: {}", e);
+        cpu.serial.link = None;
+        self.history.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cart::{Cart, CartConfig};
+    use crate::cart_header::CartType;
+    use crate::gpu::CYCLES_PER_FRAME;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// The peer's end is the test: it queues up what the peer sends, and collects what this side
+    /// sends.
+    #[derive(Clone, Default)]
+    struct MemoryTransport {
+        inbox: Rc<RefCell<VecDeque<Message>>>,
+        sent: Rc<RefCell<Vec<Message>>>,
+    }
+
+    impl Transport for MemoryTransport {
+        fn send(&mut self, message: Message) -> io::Result<()> {
+            self.sent.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn try_recv(&mut self) -> io::Result<Option<Message>> {
+            Ok(self.inbox.borrow_mut().pop_front())
+        }
+    }
+
+    fn frame(link: &mut Link, cpu: &mut Cpu) {
+        link.start_frame(cpu, CYCLES_PER_FRAME);
+        cpu.step_cycles(CYCLES_PER_FRAME, None, &HashSet::new());
+        link.end_frame(cpu);
+    }
+
+    #[test]
+    fn rollback_takes_in_the_peers_transfers_again() {
+        let mut rom = vec![0; 0x8000];
+        let code = [
+            0x3E, 0x42, 0xE0, 0x01, // ld a, $42; ldh [SB], a
+            0x3E, 0x81, 0xE0, 0x02, // ld a, $81; ldh [SC], a: transfer 0, clocked here
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait for it
+            0xF0, 0x01, 0xE0, 0x80, // ldh a, [SB]; ldh [$FF80], a
+            0x3E, 0x80, 0xE0, 0x02, // ld a, $80; ldh [SC], a: transfer 1, clocked by the peer
+            0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, // wait for it
+            0xF0, 0x01, 0xE0, 0x81, // ldh a, [SB]; ldh [$FF81], a
+            0x18, 0xFE, // jr @
+        ];
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        let config = CartConfig { cart_type: CartType::NoMbc, rom_size: rom.len(), ram_size: 0 };
+        let mut cpu = Cpu::new(Cart::new(rom.into_boxed_slice(), None, &config).unwrap());
+        let transport = MemoryTransport::default();
+        let mut link = Link::new(Box::new(transport.clone()), &mut cpu);
+
+        // Transfer 0 goes out with a guess, then the peer clocks transfer 1, and only then answers
+        // transfer 0 with something else, so this side rolls back to before both.
+        frame(&mut link, &mut cpu);
+        assert!(transport.sent.borrow().contains(&Message::Transfer { number: 0, byte: 0x42 }));
+        transport.inbox.borrow_mut().push_back(Message::Transfer { number: 1, byte: 0x55 });
+        frame(&mut link, &mut cpu);
+        assert_eq!(cpu.peek(0xFF81), 0x55);
+        transport.inbox.borrow_mut().push_back(Message::Answer { number: 0, byte: 0x77 });
+        frame(&mut link, &mut cpu);
+
+        assert_eq!(cpu.peek(0xFF80), 0x77);
+        assert_eq!(cpu.peek(0xFF81), 0x55);
+        assert!(transport.sent.borrow().contains(&Message::Answer { number: 1, byte: 0x77 }));
+    }
+}
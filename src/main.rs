@@ -1,40 +1,129 @@
 extern crate sdl2;
 
-use crate::cart::{Cart, CartConfig};
-use crate::cart_header::{CartHardware, CartHeader};
-use crate::cpu::Cpu;
-use crate::frontend::{start_frontend, start_frontend_debug};
+use crate::cart::{Cart, CartConfig, CartError};
+use crate::cart_header::{CartHardware, CartHeader, CartType};
+use crate::config::Config;
+use crate::control::ControlServer;
+use crate::cpu::{Cpu, Model};
+use crate::expr::Expr;
+use crate::audio_dump::AudioDump;
+use crate::camera_image::CameraImage;
+use crate::cheat::CheatCode;
+use crate::cheat_file::CheatFile;
+use crate::barcode_boy::BarcodeBoy;
+use crate::frame_hash::FrameHashes;
+use crate::frontend::{
+    run_headless, run_service, start_frontend, start_frontend_debug, FrontendOptions, ReproMode,
+};
+use crate::gpu::SpriteLimit;
+use crate::input_stream::InputStream;
+use crate::link::{Link, TcpTransport};
+use crate::profile::Profile;
+use crate::repro::Repro;
+use crate::rom_db::RomDb;
+use crate::save_file::{SaveFile, RTC_FOOTER_SIZES};
+use crate::rgbds_map::RgbdsMap;
+use crate::session::Session;
+use crate::sweep::Outcome;
+use crate::vgm::VgmRecorder;
+use crate::votes::VoteBox;
 use crate::wla_symbols::WlaSymbols;
+use crate::workboy::WorkBoy;
 use failure::ResultExt;
-use log::info;
+use log::{info, log_enabled, warn};
+use rayon::prelude::*;
 use std::fs::File;
 use std::io::{BufReader, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-mod audio;
-mod cart;
-mod cart_header;
-mod cpu;
-mod debug;
+// The emulator core lives in the library. Importing its modules here keeps `crate::` paths in
+// the frontend modules working.
+use rugby::{
+    audio, barcode_boy, cart, cart_header, cheat, checksum, cpu, debug, emulator_session, expr, gpu, heatmap, irq_stats,
+    joypad, memory_map, pause, profile, repro, rgbds_map, savestate, serial, wla_symbols, workboy,
+};
+
+mod audio_dump;
+mod bisect;
+mod camera_image;
+mod cheat_file;
+mod clip;
+mod config;
+mod control;
+mod doctor;
+mod frame_hash;
+mod frame_limiter;
 mod frontend;
-mod gpu;
-mod interrupts;
-mod joypad;
-mod timer;
-mod wla_symbols;
+mod i18n;
+mod info;
+mod input_stream;
+mod link;
+mod palette_indices;
+mod patch;
+mod photo;
+mod rate_control;
+mod rom_db;
+mod rom_tools;
+mod save_file;
+mod selftest;
+mod state_browser;
+mod state_diff;
+mod session;
+mod setup;
+mod sweep;
+mod vgm;
+mod votes;
+
+/// The exit status of `run` when the `--exit-when` condition was met, as opposed to 0 when the
+/// emulator quit for any other reason.
+const EXIT_CONDITION_MET: i32 = 3;
+
+/// The CPU's clock rate, for turning cycles into seconds of game time.
+const CLOCK_RATE: usize = 4_194_304;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "Rugby", about = "Rust Game Boy? Yes!")]
+#[structopt(name = "Rugby", raw(about = "i18n::tr(\"cli.about\")", version = "rugby::version::BUILD"))]
 enum Opts {
-    #[structopt(name = "run", about = "Runs the given Game Boy ROM file")]
+    #[structopt(name = "run", raw(about = "i18n::tr(\"cli.run\")"))]
     Run(RunOpts),
 
-    #[structopt(name = "debug", about = "Runs the given Game Boy ROM file in debug mode")]
+    #[structopt(name = "debug", raw(about = "i18n::tr(\"cli.debug\")"))]
     Debug(DebugOpts),
 
-    #[structopt(name = "info", about = "Prints information about the given Game Boy ROMs")]
+    #[structopt(name = "serve", raw(about = "i18n::tr(\"cli.serve\")"))]
+    Serve(ServeOpts),
+
+    #[structopt(name = "info", raw(about = "i18n::tr(\"cli.info\")"))]
     Info(InfoOpts),
+
+    #[structopt(name = "sweep", raw(about = "i18n::tr(\"cli.sweep\")"))]
+    Sweep(SweepOpts),
+
+    #[structopt(name = "repro", raw(about = "i18n::tr(\"cli.repro\")"))]
+    Repro(ReproCmd),
+
+    #[structopt(name = "bisect", raw(about = "i18n::tr(\"cli.bisect\")"))]
+    Bisect(BisectOpts),
+
+    #[structopt(name = "rom", raw(about = "i18n::tr(\"cli.rom\")"))]
+    Rom(RomCmd),
+
+    #[structopt(name = "state", raw(about = "i18n::tr(\"cli.state\")"))]
+    State(StateCmd),
+
+    #[structopt(name = "cheats", raw(about = "i18n::tr(\"cli.cheats\")"))]
+    Cheats(CheatsCmd),
+
+    #[structopt(name = "doctor", raw(about = "i18n::tr(\"cli.doctor\")"))]
+    Doctor,
+
+    #[structopt(name = "setup", raw(about = "i18n::tr(\"cli.setup\")"))]
+    Setup,
+
+    #[structopt(name = "selftest", raw(about = "i18n::tr(\"cli.selftest\")"))]
+    Selftest,
 }
 
 #[derive(Debug, StructOpt)]
@@ -50,6 +139,145 @@ struct RunOpts {
     /// Load symbol file for debugging (in the WLA DX assembler's format
     #[structopt(short = "S", long = "symbol-file", name = "SYMBOLS", parse(from_os_str))]
     symbols_path: Option<PathBuf>,
+
+    /// Load an RGBDS linker map file, so profiles can be aggregated by function and section
+    #[structopt(short = "M", long = "map-file", name = "MAP", parse(from_os_str))]
+    map_path: Option<PathBuf>,
+
+    /// Check internal emulator invariants on every memory access and abort on violations
+    #[structopt(long = "paranoid")]
+    paranoid: bool,
+
+    /// Pause (or quit, if headless) when the ROM reads uninitialized RAM, writes to ROM outside any
+    /// mapper register, or accesses VRAM or OAM while the LCD has them locked
+    #[structopt(long = "strict")]
+    strict: bool,
+
+    /// Print bytes the ROM sends over the serial port or writes to 0xFF7F, for homebrew
+    /// development and test ROMs
+    #[structopt(long = "debug-output")]
+    debug_output: bool,
+
+    /// Draw every sprite instead of only 10 per line like real hardware, which removes flicker
+    #[structopt(long = "no-sprite-limit")]
+    no_sprite_limit: bool,
+
+    /// The sample rate to ask the sound card for, in Hz, instead of the config's
+    #[structopt(long = "sample-rate", name = "HZ", parse(try_from_str = "config::parse_sample_rate"))]
+    sample_rate: Option<u32>,
+
+    /// The sound card's buffer size in samples, instead of the config's. Bigger buffers add
+    /// latency but crackle less on slow machines
+    #[structopt(long = "audio-buffer", name = "SAMPLES")]
+    audio_buffer: Option<u16>,
+
+    #[structopt(flatten)]
+    hardware: HardwareOpts,
+
+    /// Run a second instance of ROM2 side by side with the first, with its own controls
+    #[structopt(long = "dual", requires = "ROM2")]
+    dual: bool,
+
+    /// Read joypad input for the first instance from this file or named pipe (or stdin if `-`).
+    /// Each line is a frame number and a bitmask of keys to hold from then on.
+    #[structopt(long = "input", name = "INPUT", parse(from_os_str))]
+    input_path: Option<PathBuf>,
+
+    /// Write a hash of each frame's screen to this file, one line per frame
+    #[structopt(long = "frame-hashes", name = "HASHES", parse(from_os_str))]
+    frame_hashes_path: Option<PathBuf>,
+
+    /// Also hash each frame's audio samples in the `--frame-hashes` output
+    #[structopt(long = "hash-audio", requires = "HASHES")]
+    hash_audio: bool,
+
+    /// Write the sound output to this WAV file
+    #[structopt(long = "dump-audio", name = "WAV", parse(from_os_str))]
+    audio_dump_path: Option<PathBuf>,
+
+    /// Record the game's writes to the sound registers to this VGM file
+    #[structopt(long = "dump-vgm", name = "VGM", parse(from_os_str))]
+    vgm_path: Option<PathBuf>,
+
+    /// Count the cycles spent at each instruction and write a report to this file on exit
+    #[structopt(long = "profile", name = "PROFILE", parse(from_os_str))]
+    profile_path: Option<PathBuf>,
+
+    /// Quit after emulating this many frames
+    #[structopt(long = "frames", name = "FRAMES")]
+    frame_limit: Option<u64>,
+
+    /// Quit after emulating this many seconds of game time
+    #[structopt(long = "seconds", name = "SECONDS", conflicts_with = "FRAMES")]
+    seconds: Option<f64>,
+
+    /// Run without a window or sound, as fast as possible
+    #[structopt(long = "headless", conflicts_with = "dual")]
+    headless: bool,
+
+    /// Save battery by sleeping between frames, like `low_power` in the config
+    #[structopt(long = "low-power", conflicts_with = "headless")]
+    low_power: bool,
+
+    /// Quit as soon as this debugger expression is true, e.g. "pc == 0x1234" or "[0xA000] == 5",
+    /// and exit with status 3
+    #[structopt(long = "exit-when", name = "EXPR")]
+    exit_when: Option<Expr>,
+
+    /// Resume where the last session of this ROM left off, and save the session again on quit
+    #[structopt(long = "continue")]
+    continue_session: bool,
+
+    /// Wait for another Rugby to connect its link cable to this HOST:PORT
+    #[structopt(long = "link-listen", name = "ADDR", conflicts_with = "dual")]
+    link_listen: Option<String>,
+
+    /// Connect the link cable to another Rugby listening at this HOST:PORT
+    #[structopt(long = "link-connect", name = "PEER", conflicts_with = "dual", conflicts_with = "ADDR")]
+    link_connect: Option<String>,
+
+    /// Plug in a Barcode Boy, and scan the barcodes in this file with F7, one after another. Each
+    /// line is a 13 digit barcode, optionally followed by a description
+    #[structopt(long = "barcodes", name = "BARCODES", parse(from_os_str), conflicts_with = "ADDR", conflicts_with = "PEER")]
+    barcodes_path: Option<PathBuf>,
+
+    /// Show the Pocket Camera this PNG, reloading it whenever it changes, e.g. as a webcam tool
+    /// keeps overwriting it
+    #[structopt(long = "camera-image", name = "PNG", parse(from_os_str))]
+    camera_image_path: Option<PathBuf>,
+
+    /// Plug in a WorkBoy, and type on its keyboard with the host's
+    #[structopt(long = "workboy", conflicts_with = "BARCODES", conflicts_with = "ADDR", conflicts_with = "PEER")]
+    workboy: bool,
+
+    /// The second game ROM file path, for `--dual`
+    #[structopt(name = "ROM2", parse(from_os_str), requires = "dual")]
+    second_rom_path: Option<PathBuf>,
+}
+
+/// Changes to the cartridge: a patch to apply to the ROM, and overrides for what the header says,
+/// for homebrew with a wrong header and for compatibility experiments.
+#[derive(Debug, Default, StructOpt)]
+struct HardwareOpts {
+    /// Apply this IPS, UPS, or BPS patch to the ROM when loading it, instead of one next to the ROM
+    #[structopt(long = "patch", name = "PATCH", parse(from_os_str))]
+    patch: Option<PathBuf>,
+
+    /// Use this mapper instead of the one the header names, e.g. mbc1, mbc5, or wisdom-tree
+    #[structopt(long = "mapper", name = "MAPPER")]
+    mapper: Option<CartType>,
+
+    /// Give the cartridge this much RAM instead of what the header says, in bytes or KiB (e.g. 8K)
+    #[structopt(long = "ram-size", name = "SIZE", parse(try_from_str = "parse_size"))]
+    ram_size: Option<usize>,
+
+    /// Start as a Game Boy Color, even if the game doesn't require one
+    #[structopt(long = "force-cgb", conflicts_with = "force_dmg")]
+    force_cgb: bool,
+
+    /// Start as an original Game Boy, even if the game requires a Game Boy Color
+    #[structopt(long = "force-dmg")]
+    force_dmg: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -61,8 +289,226 @@ struct DebugOpts {
     /// Load symbol file for debugging (in the WLA DX assembler's format
     #[structopt(short = "S", long = "symbol-file", name = "SYMBOLS", parse(from_os_str))]
     symbols_path: Option<PathBuf>,
+
+    /// Load an RGBDS linker map file, so profiles can be aggregated by function and section
+    #[structopt(short = "M", long = "map-file", name = "MAP", parse(from_os_str))]
+    map_path: Option<PathBuf>,
+
+    /// Check internal emulator invariants on every memory access and abort on violations
+    #[structopt(long = "paranoid")]
+    paranoid: bool,
+
+    /// Stop in the debugger when the ROM reads uninitialized RAM, writes to ROM outside any mapper
+    /// register, or accesses VRAM or OAM while the LCD has them locked
+    #[structopt(long = "strict")]
+    strict: bool,
+
+    /// Print bytes the ROM sends over the serial port or writes to 0xFF7F, for homebrew
+    /// development and test ROMs
+    #[structopt(long = "debug-output")]
+    debug_output: bool,
+
+    #[structopt(flatten)]
+    hardware: HardwareOpts,
+}
+
+
+#[derive(Debug, StructOpt)]
+enum ReproCmd {
+    #[structopt(name = "record", raw(about = "i18n::tr(\"cli.repro_record\")"))]
+    Record(ReproRecordOpts),
+
+    #[structopt(name = "play", raw(about = "i18n::tr(\"cli.repro_play\")"))]
+    Play(ReproPlayOpts),
+}
+
+#[derive(Debug, StructOpt)]
+enum RomCmd {
+    #[structopt(name = "pad", raw(about = "i18n::tr(\"cli.rom_pad\")"))]
+    Pad(RomPadOpts),
+
+    #[structopt(name = "trim", raw(about = "i18n::tr(\"cli.rom_trim\")"))]
+    Trim(RomTrimOpts),
+}
+
+#[derive(Debug, StructOpt)]
+enum StateCmd {
+    #[structopt(name = "info", raw(about = "i18n::tr(\"cli.state_info\")"))]
+    Info(StateInfoOpts),
+
+    #[structopt(name = "diff", raw(about = "i18n::tr(\"cli.state_diff\")"))]
+    Diff(StateDiffOpts),
 }
 
+#[derive(Debug, StructOpt)]
+enum CheatsCmd {
+    #[structopt(name = "import", raw(about = "i18n::tr(\"cli.cheats_import\")"))]
+    Import(CheatsImportOpts),
+
+    #[structopt(name = "list", raw(about = "i18n::tr(\"cli.cheats_list\")"))]
+    List(CheatsListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+struct CheatsImportOpts {
+    /// The game ROM file path, which names the game's cheat file
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// A RetroArch .cht file, or a list with one code and its name per line
+    #[structopt(name = "FILE", parse(from_os_str))]
+    cheats_path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct CheatsListOpts {
+    /// The game ROM file path, which names the game's cheat file
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct StateInfoOpts {
+    /// The savestate file path
+    #[structopt(name = "STATE", parse(from_os_str))]
+    state_path: PathBuf,
+
+    /// Write the screen at the time the state was saved to this PNG file
+    #[structopt(long = "thumbnail", name = "PNG", parse(from_os_str))]
+    thumbnail_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct StateDiffOpts {
+    /// The game ROM file path, which both savestates must be for
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// The first savestate
+    #[structopt(name = "A", parse(from_os_str))]
+    a_path: PathBuf,
+
+    /// The savestate to compare with the first
+    #[structopt(name = "B", parse(from_os_str))]
+    b_path: PathBuf,
+
+    /// Write the screens of both savestates to this PNG file, side by side
+    #[structopt(long = "image", name = "PNG", parse(from_os_str))]
+    image_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct RomPadOpts {
+    /// The game ROM file path
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// Write the result here instead of changing the ROM file in place
+    #[structopt(short = "o", long = "output", name = "OUTPUT", parse(from_os_str))]
+    out_path: Option<PathBuf>,
+
+    /// The byte to pad with, e.g. 0x00
+    #[structopt(long = "fill", name = "BYTE", default_value = "0xFF", parse(try_from_str = "parse_byte"))]
+    fill: u8,
+}
+
+#[derive(Debug, StructOpt)]
+struct RomTrimOpts {
+    /// The game ROM file path
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// Write the result here instead of changing the ROM file in place
+    #[structopt(short = "o", long = "output", name = "OUTPUT", parse(from_os_str))]
+    out_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ReproRecordOpts {
+    /// The game ROM file path
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// Where to write the bundle when the emulator exits
+    #[structopt(name = "BUNDLE", parse(from_os_str))]
+    bundle_path: PathBuf,
+
+    /// Load cartridge RAM from this file before recording (it is never written)
+    #[structopt(short = "s", long = "save-file", name = "SAVE", parse(from_os_str))]
+    save_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ReproPlayOpts {
+    /// The bundle file path
+    #[structopt(name = "BUNDLE", parse(from_os_str))]
+    bundle_path: PathBuf,
+
+    /// The game ROM file path. Must be the ROM the bundle was recorded with.
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// Check internal emulator invariants on every memory access and abort on violations
+    #[structopt(long = "paranoid")]
+    paranoid: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct BisectOpts {
+    /// The game ROM file path. Must be the ROM the bundle was recorded with.
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// The bundle to replay, recorded with `repro record`
+    #[structopt(long = "movie", name = "BUNDLE", parse(from_os_str))]
+    bundle_path: PathBuf,
+
+    /// The debugger expression to find the first frame it's true after, e.g. "[C0A2] > #99"
+    #[structopt(long = "check", name = "EXPR")]
+    check: Expr,
+
+    /// Search this many frames instead of up to the bundle's last input
+    #[structopt(long = "frames", name = "FRAMES")]
+    frames: Option<u64>,
+
+    /// Where to write the savestate from the start of the frame found
+    #[structopt(short = "o", long = "output", name = "STATE", default_value = "rugby-bisect.state", parse(from_os_str))]
+    state_path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct ServeOpts {
+    /// The game ROM file path
+    #[structopt(name = "ROM", parse(from_os_str))]
+    rom_path: PathBuf,
+
+    /// Load and save cartridge RAM to this file. RAM is saved when the server quits
+    #[structopt(short = "s", long = "save-file", name = "SAVE", parse(from_os_str))]
+    save_path: Option<PathBuf>,
+
+    /// Listen for commands on this Unix socket path, or on a TCP socket if given as HOST:PORT
+    #[structopt(long = "control-socket", name = "SOCKET")]
+    control_socket: String,
+
+    /// Show the game and the vote tally in a window. Closing it stops the server
+    #[structopt(long = "window")]
+    window: bool,
+
+    /// Plug in a Barcode Boy, to swipe cards with the `scan` command
+    #[structopt(long = "barcode-boy")]
+    barcode_boy: bool,
+
+    /// Load a WLA DX symbol file, so `peek` and `poke` take symbol names
+    #[structopt(short = "S", long = "symbol-file", name = "SYMBOLS", parse(from_os_str))]
+    symbols_path: Option<PathBuf>,
+
+    /// Load an RGBDS linker map file, so `peek` and `poke` take symbol names
+    #[structopt(short = "M", long = "map-file", name = "MAP", parse(from_os_str))]
+    map_path: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    hardware: HardwareOpts,
+}
 
 #[derive(Debug, StructOpt)]
 struct InfoOpts {
@@ -73,31 +519,127 @@ struct InfoOpts {
     /// Show results in a table
     #[structopt(short = "t", long = "table")]
     table: bool,
+
+    /// Print as text, json, or toml
+    #[structopt(long = "format", name = "FORMAT", default_value = "text")]
+    format: info::Format,
+
+    /// Name each game from this DAT file, like No-Intro's, instead of the one in the config
+    #[structopt(long = "dat", name = "DAT", parse(from_os_str))]
+    dat_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct SweepOpts {
+    /// The game ROM file paths
+    #[structopt(name = "ROM", parse(from_os_str), required = true)]
+    rom_paths: Vec<PathBuf>,
+
+    /// Give up on each ROM after emulating this many frames
+    #[structopt(long = "frames", name = "FRAMES", default_value = "3600")]
+    frame_limit: u64,
+
+    /// Stop each ROM as soon as this debugger expression is true
+    #[structopt(long = "exit-when", name = "EXPR")]
+    exit_when: Option<Expr>,
+
+    /// Write each ROM's final screen and serial output to this directory
+    #[structopt(short = "o", long = "out", name = "DIR", parse(from_os_str))]
+    out_dir: Option<PathBuf>,
+
+    /// Run this many ROMs at once (by default, one per CPU core)
+    #[structopt(short = "j", long = "jobs", name = "JOBS")]
+    jobs: Option<usize>,
+
+    #[structopt(flatten)]
+    hardware: HardwareOpts,
 }
 
-fn main() -> Result<(), failure::Error> {
+fn main() {
     let env = env_logger::Env::new().filter("RUGBY_LOG").write_style("RUGBY_LOG_STYLE");
     env_logger::Builder::from_env(env)
         .default_format_timestamp(false)
         .init();
+    // Heads trace logs, so logs from two builds aren't compared by mistake. A trace of just the CPU
+    // (`RUGBY_LOG=rugby::cpu=trace`) leaves out this module, so it gets the line under its own name.
+    if log_enabled!(log::Level::Info) {
+        info!("Rugby {}", rugby::version::BUILD);
+    } else {
+        info!(target: "rugby::cpu", "Rugby {}", rugby::version::BUILD);
+    }
+
+    // Print errors as their messages, outermost first, rather than `Debug` output.
+    if let Err(e) = run_command(&Opts::from_args()) {
+        let mut causes = e.iter_chain();
+        if let Some(error) = causes.next() {
+            eprintln!("error: {}", error);
+        }
+        for cause in causes {
+            eprintln!("  caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
+}
 
-    match &Opts::from_args() {
+fn run_command(opts: &Opts) -> Result<(), failure::Error> {
+    match opts {
         Opts::Run(run_opts) => run(run_opts),
         Opts::Debug(debug_opts) => debug(debug_opts),
+        Opts::Serve(serve_opts) => serve(serve_opts),
         Opts::Info(info_opts) => info(info_opts),
+        Opts::Sweep(sweep_opts) => sweep(sweep_opts),
+        Opts::Repro(ReproCmd::Record(record_opts)) => repro_record(record_opts),
+        Opts::Repro(ReproCmd::Play(play_opts)) => repro_play(play_opts),
+        Opts::Bisect(bisect_opts) => bisect(bisect_opts),
+        Opts::Rom(RomCmd::Pad(pad_opts)) => rom_pad(pad_opts),
+        Opts::Rom(RomCmd::Trim(trim_opts)) => rom_trim(trim_opts),
+        Opts::State(StateCmd::Info(info_opts)) => state_info(info_opts),
+        Opts::State(StateCmd::Diff(diff_opts)) => state_diff(diff_opts),
+        Opts::Cheats(CheatsCmd::Import(import_opts)) => cheats_import(import_opts),
+        Opts::Cheats(CheatsCmd::List(list_opts)) => cheats_list(list_opts),
+        Opts::Doctor => {
+            if doctor::run(std::io::stdout())? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Opts::Setup => {
+            let stdin = std::io::stdin();
+            setup::run(stdin.lock(), std::io::stdout())
+        }
+        Opts::Selftest => {
+            if !selftest::run(std::io::stdout())? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
     }
 }
 
 fn run(opts: &RunOpts) -> Result<(), failure::Error> {
-    let rom = std::fs::read(&opts.rom_path)
-        .context("Failed to read ROM file")?
-        .into_boxed_slice();
-    let cart_header = CartHeader::from_rom(&rom).context("Failed to parse cartridge header")?;
-    let cart_config = CartConfig::from_cart_header(&cart_header)?;
+    if !opts.headless && setup::is_first_run() {
+        let stdin = std::io::stdin();
+        setup::run(stdin.lock(), std::io::stdout())?;
+    }
+    let mut config = Config::load()?;
+    if let Some(rate) = opts.sample_rate {
+        config.audio.sample_rate = rate;
+    }
+    if let Some(samples) = opts.audio_buffer {
+        config.audio.buffer_samples = samples;
+    }
 
     // TODO(solson): Include some kind of game-identifying information in the save file to
     // prevent loading a save file with the wrong game.
-    let ram = opts.save_path
+    // Headless runs, replays, and hash runs are for testing, and shouldn't depend on or clobber
+    // the save the game is normally played with, or on the cheats it's played with.
+    let testing = opts.headless || opts.input_path.is_some() || opts.frame_hashes_path.is_some();
+    let save_path = if testing {
+        opts.save_path.clone()
+    } else {
+        default_save_path(&opts.rom_path, opts.save_path.as_ref(), &config)?
+    };
+    let ram = save_path
         .as_ref()
         .and_then(|path| std::fs::read(path).ok())
         .map(|r| r.into_boxed_slice());
@@ -105,109 +647,673 @@ fn run(opts: &RunOpts) -> Result<(), failure::Error> {
         info!("Initialized cartridge RAM from file");
     }
 
-    let cart = Cart::new(rom, ram, &cart_config).context("Failed to initialize cartridge")?;
-    let mut cpu = Cpu::new(cart);
+    let mut cpu = Cpu::new(load_cart(&opts.rom_path, ram, &opts.hardware)?);
+    set_model(&mut cpu, &opts.hardware);
+    cpu.paranoid = opts.paranoid;
+    cpu.strict = opts.strict;
+    cpu.serial.echo = opts.debug_output;
+    cpu.debug_register = opts.debug_output;
+    cpu.break_when = opts.exit_when.clone();
+    cpu.gpu.sprite_limit = sprite_limit(&config, opts.no_sprite_limit);
+    if !testing {
+        cpu.cheats = load_cheats(&opts.rom_path)?;
+    }
+    cpu.audio.set_sample_rate(config.audio.sample_rate);
 
-    if let Some(path) = &opts.symbols_path {
-        let file = File::open(path).context("Failed to open symbol file")?;
-        cpu.debug_symbols = Some(WlaSymbols::parse(BufReader::new(file))
-            .context("Failed to parse WLA DX symbol file")?);
+    load_debug_info(&mut cpu, opts.symbols_path.as_ref(), opts.map_path.as_ref())?;
+    if let Some(expr) = &opts.exit_when {
+        expr.check_names(&cpu).context("Invalid --exit-when expression")?;
+    }
+
+    if opts.profile_path.is_some() {
+        cpu.profile = Some(Profile::new());
+    }
+
+    let session_path = Session::path(&opts.rom_path);
+    if opts.continue_session {
+        if let Some(path) = &session_path {
+            resume_session(&mut cpu, path)?;
+        }
+    }
+
+    let mut options = FrontendOptions::new();
+    options.frame_limit = opts.frame_limit
+        .or(opts.seconds.map(|s| (s * CLOCK_RATE as f64 / gpu::CYCLES_PER_FRAME as f64).round() as u64));
+    options.input = opts.input_path.as_ref().map(|path| InputStream::open(path));
+    options.low_power = opts.low_power;
+    if let Some(path) = save_path.clone().or_else(|| config.save.path_for(&opts.rom_path)) {
+        options.save_path = path;
+    }
+    options.save_file = match &save_path {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|_| format!("Failed to create save directory: {}", dir.display()))?;
+            }
+            Some(SaveFile::open(path, &mut cpu.cart, config.save.flush_interval())?)
+        }
+        None => None,
+    };
+    if let Some(path) = &opts.frame_hashes_path {
+        options.frame_hashes = Some(FrameHashes::create(path, opts.hash_audio)
+            .context("Failed to create frame hash file")?);
+    }
+    if let Some(path) = &opts.audio_dump_path {
+        options.audio_dump = Some(AudioDump::create(path).context("Failed to create audio dump file")?);
+    }
+    if let Some(path) = &opts.vgm_path {
+        options.vgm = Some(VgmRecorder::create(path).context("Failed to create VGM file")?);
+    }
+    let transport = match (&opts.link_listen, &opts.link_connect) {
+        (Some(addr), _) => Some(TcpTransport::listen(addr).context("Failed to accept a link connection")?),
+        (_, Some(addr)) => Some(TcpTransport::connect(addr).context("Failed to connect the link cable")?),
+        _ => None,
+    };
+    options.link = transport.map(|transport| Link::new(Box::new(transport), &mut cpu));
+    if let Some(path) = &opts.barcodes_path {
+        options.barcodes = read_barcodes(path)?;
+        cpu.serial.device = Some(Box::new(BarcodeBoy::new()));
+    }
+    if opts.workboy {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+        cpu.serial.device = Some(Box::new(WorkBoy::new(now)));
+    }
+    if let Some(path) = &opts.camera_image_path {
+        cpu.cart.set_camera_image(&camera_image::load(path)?);
+        options.camera = Some(CameraImage::new(path.clone()));
     }
 
-    start_frontend(&mut cpu);
+    if opts.headless {
+        run_headless(&mut cpu, &mut options);
+    } else if opts.dual {
+        // structopt guarantees ROM2 is given along with `--dual`.
+        let path = opts.second_rom_path.as_ref().unwrap();
+        let hardware = HardwareOpts::default();
+        let mut second_cpu = Cpu::new(load_cart(path, None, &hardware)?);
+        set_model(&mut second_cpu, &hardware);
+        second_cpu.paranoid = opts.paranoid;
+        second_cpu.gpu.sprite_limit = cpu.gpu.sprite_limit;
+        second_cpu.audio.set_sample_rate(config.audio.sample_rate);
+        start_frontend(&mut [&mut cpu, &mut second_cpu], &mut options, &config);
+    } else {
+        start_frontend(&mut [&mut cpu], &mut options, &config);
+    }
+
+    // Like while the game runs, failing to save doesn't stop the rest of the shutdown.
+    if let Some(save_file) = &mut options.save_file {
+        if let Err(e) = save_file.flush(&cpu.cart) {
+            println!("Failed to write save file: {}", e);
+        }
+    }
+    if let Some(audio_dump) = options.audio_dump.take() {
+        audio_dump.finish().context("Failed to write audio dump file")?;
+    }
+    if let Some(vgm) = options.vgm.take() {
+        vgm.finish().context("Failed to write VGM file")?;
+    }
+    if opts.continue_session || config.session.sleep {
+        sleep(&cpu, session_path.as_ref().map(|p| p.as_path()))?;
+    }
+
+    if let (Some(path), Some(profile)) = (&opts.profile_path, &cpu.profile) {
+        let file = File::create(path).context("Failed to create profile file")?;
+        profile.write_report(cpu.debug_map.as_ref(), std::io::BufWriter::new(file))
+            .context("Failed to write profile")?;
+    }
+
+    if cpu.break_condition_met() {
+        println!("Exit condition met: {}", cpu.break_when.as_ref().unwrap());
+        std::process::exit(EXIT_CONDITION_MET);
+    }
 
     Ok(())
 }
 
-fn debug(opts: &DebugOpts) -> Result<(), failure::Error> {
-    let rom = std::fs::read(&opts.rom_path)
-        .context("Failed to read ROM file")?
-        .into_boxed_slice();
-    let cart_header = CartHeader::from_rom(&rom).context("Failed to parse cartridge header")?;
-    let cart_config = CartConfig::from_cart_header(&cart_header)?;
+fn sprite_limit(config: &Config, no_sprite_limit: bool) -> SpriteLimit {
+    if no_sprite_limit || !config.video.sprite_limit {
+        SpriteLimit::Unlimited
+    } else {
+        SpriteLimit::Hardware
+    }
+}
 
-    let cart = Cart::new(rom, None, &cart_config).context("Failed to initialize cartridge")?;
-    let mut cpu = Cpu::new(cart);
+fn serve(opts: &ServeOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    let save_path = default_save_path(&opts.rom_path, opts.save_path.as_ref(), &config)?;
+    let ram = save_path
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .map(|r| r.into_boxed_slice());
+    let mut cpu = Cpu::new(load_cart(&opts.rom_path, ram, &opts.hardware)?);
+    set_model(&mut cpu, &opts.hardware);
+    cpu.gpu.sprite_limit = sprite_limit(&config, false);
+    cpu.cheats = load_cheats(&opts.rom_path)?;
+    load_debug_info(&mut cpu, opts.symbols_path.as_ref(), opts.map_path.as_ref())?;
+    if opts.barcode_boy {
+        cpu.serial.device = Some(Box::new(BarcodeBoy::new()));
+    }
+
+    let votes = VoteBox::new(config.votes.clone());
+    let mut control = ControlServer::bind(&opts.control_socket, votes)
+        .with_context(|_| format!("Failed to listen on control socket: {}", opts.control_socket))?;
+    let mut save_file = match &save_path {
+        Some(path) => {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|_| format!("Failed to create save directory: {}", dir.display()))?;
+            }
+            Some(SaveFile::open(path, &mut cpu.cart, config.save.flush_interval())?)
+        }
+        None => None,
+    };
+    run_service(&mut cpu, &mut control, &mut save_file, &config, opts.window);
+
+    if let Some(save_file) = &mut save_file {
+        if let Err(e) = save_file.flush(&cpu.cart) {
+            println!("Failed to write save file: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Read a list of barcodes for `--barcodes`, skipping blank lines and `#` comments.
+fn read_barcodes(path: &Path) -> Result<Vec<String>, failure::Error> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|_| format!("Failed to read barcode list: {}", path.display()))?;
+    let mut barcodes = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let barcode = line.split_whitespace().next().unwrap();
+        barcode_boy::parse_barcode(barcode)
+            .with_context(|_| format!("Invalid barcode on line {} of {}", i + 1, path.display()))?;
+        barcodes.push(line.to_string());
+    }
+    if barcodes.is_empty() {
+        failure::bail!("No barcodes in {}", path.display());
+    }
+    Ok(barcodes)
+}
+
+/// Load the saved session at `path` into `cpu`, unless there is none or it was saved with a
+/// different ROM.
+fn resume_session(cpu: &mut Cpu, path: &Path) -> Result<(), failure::Error> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No saved session for this ROM, starting fresh");
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("Failed to read session file")?,
+    };
+    let session = Session::from_bytes(&data).context("Failed to parse session file")?;
+    if !session.matches_rom(cpu.cart.rom()) {
+        println!("The ROM changed since the last session was saved, starting fresh");
+        return Ok(());
+    }
+    cpu.load_state(&session.state).context("Failed to load the session's savestate")?;
+    info!("Resumed session from {}", path.display());
+    Ok(())
+}
 
-    if let Some(path) = &opts.symbols_path {
+/// Save the session for the next `--continue`.
+fn sleep(cpu: &Cpu, session_path: Option<&Path>) -> Result<(), failure::Error> {
+    if let Some(path) = session_path {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create session directory")?;
+        }
+        let session = Session::new(cpu.cart.rom(), cpu.save_state());
+        std::fs::write(path, session.to_bytes()).context("Failed to write session file")?;
+        info!("Saved session to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Where to keep the game's save file: `-s` if given, and otherwise, for carts with a battery, the
+/// save directory or next to the ROM. Carts without one forget their RAM when switched off.
+fn default_save_path(rom_path: &Path, save_path: Option<&PathBuf>, config: &Config)
+    -> Result<Option<PathBuf>, failure::Error>
+{
+    if let Some(path) = save_path {
+        return Ok(Some(path.clone()));
+    }
+    let rom = std::fs::read(rom_path)
+        .with_context(|_| format!("Failed to read ROM file: {}", rom_path.display()))?;
+    let has_battery = CartHeader::from_rom(&rom)
+        .map_or(false, |header| header.hardware.contains(CartHardware::Battery));
+    Ok(if has_battery { config.save.path_for(rom_path) } else { None })
+}
+
+/// Read a ROM file and set up its cartridge, with the given RAM contents if any, and with any
+/// overrides for what the header says.
+fn load_cart(rom_path: &Path, ram: Option<Box<[u8]>>, hardware: &HardwareOpts) -> Result<Cart, failure::Error> {
+    let rom = read_rom(rom_path, hardware.patch.as_ref())?;
+    let header = CartHeader::from_rom(&rom).context("Failed to parse cartridge header")?;
+    // Emulation doesn't depend on these, but real hardware would refuse the cartridge, and a bad
+    // dump explains a lot of bug reports.
+    for failure in header.checks.failures() {
+        warn!("Cartridge header check failed: {}", failure);
+    }
+    let config = cart_config(&header, hardware)?;
+    // MBC3 saves may end with a clock footer, which `SaveFile` reads. Older versions sized
+    // cartridge RAM after the ROM, so their saves are padded past the end of the real RAM.
+    let ram = ram.map(|ram| {
+        let footer = ram.len().checked_sub(config.ram_size);
+        if config.cart_type == CartType::Mbc3 && footer.map_or(false, |size| RTC_FOOTER_SIZES.contains(&size)) {
+            ram[..config.ram_size].to_vec().into_boxed_slice()
+        } else if ram.len() > config.ram_size && ram.len() == config.rom_size {
+            warn!("Save file is {} bytes but the cartridge has {}, ignoring the rest", ram.len(), config.ram_size);
+            ram[..config.ram_size].to_vec().into_boxed_slice()
+        } else {
+            ram
+        }
+    });
+    let cart = Cart::new(rom, ram, &config);
+    let context = match cart {
+        Err(CartError::UnsupportedMapper { .. }) | Err(CartError::UnknownMapper(_)) => {
+            "Failed to initialize cartridge; `--mapper none` runs it without a mapper, which may get far enough to be useful"
+        }
+        _ => "Failed to initialize cartridge",
+    };
+    Ok(cart.context(context)?)
+}
+
+/// The cartridge's configuration from its header, with the overrides in `hardware`. The mapper
+/// is replaced first, since the MBC2, MBC6, and MBC7 decide the RAM size themselves.
+fn cart_config(header: &CartHeader, hardware: &HardwareOpts) -> Result<CartConfig, CartError> {
+    let mut header = header.clone();
+    if let Some(mapper) = hardware.mapper {
+        header.cart_type = mapper;
+    }
+    let mut config = CartConfig::from_cart_header(&header)?;
+    if let Some(ram_size) = hardware.ram_size {
+        config.ram_size = ram_size;
+    }
+    Ok(config)
+}
+
+/// Read a ROM and apply `patch` to it, or the patch next to it if there is one.
+fn read_rom(rom_path: &Path, patch: Option<&PathBuf>) -> Result<Box<[u8]>, failure::Error> {
+    let mut rom = std::fs::read(rom_path)
+        .with_context(|_| format!("Failed to read ROM file: {}", rom_path.display()))?;
+    if let Some(path) = patch.cloned().or_else(|| patch::sibling(rom_path)) {
+        let data = std::fs::read(&path)
+            .with_context(|_| format!("Failed to read patch file: {}", path.display()))?;
+        patch::apply(&mut rom, &data)
+            .with_context(|_| format!("Failed to apply patch: {}", path.display()))?;
+        info!("Applied patch {}", path.display());
+    }
+    Ok(rom.into_boxed_slice())
+}
+
+/// Load the WLA DX symbol file and RGBDS map file given on the command line, if any.
+fn load_debug_info(cpu: &mut Cpu, symbols_path: Option<&PathBuf>, map_path: Option<&PathBuf>) -> Result<(), failure::Error> {
+    if let Some(path) = symbols_path {
         let file = File::open(path).context("Failed to open symbol file")?;
         cpu.debug_symbols = Some(WlaSymbols::parse(BufReader::new(file))
             .context("Failed to parse WLA DX symbol file")?);
     }
 
-    start_frontend_debug(&mut cpu);
-
+    if let Some(path) = map_path {
+        let file = File::open(path).context("Failed to open map file")?;
+        cpu.debug_map = Some(RgbdsMap::parse(BufReader::new(file))
+            .context("Failed to parse RGBDS map file")?);
+    }
     Ok(())
 }
 
-fn info(opts: &InfoOpts) -> Result<(), failure::Error> {
-    if opts.table {
-        info_table(opts)
+/// The codes of the enabled cheats in the game's cheat file, if it has one.
+fn load_cheats(rom_path: &Path) -> Result<Vec<CheatCode>, failure::Error> {
+    let path = match CheatFile::path_for(rom_path) {
+        Some(path) => path,
+        None => return Ok(Vec::new()),
+    };
+    let codes = CheatFile::load(&path)?.enabled_codes()?;
+    if !codes.is_empty() {
+        info!("Loaded {} cheat codes from {}", codes.len(), path.display());
+    }
+    Ok(codes)
+}
+
+/// Emulate the model the game expects, unless overridden.
+fn set_model(cpu: &mut Cpu, hardware: &HardwareOpts) {
+    let model = if hardware.force_cgb {
+        Model::Cgb
+    } else if hardware.force_dmg {
+        Model::Dmg
     } else {
-        info_records(opts)
+        match CartHeader::from_rom(cpu.cart.rom()) {
+            Ok(header) => Model::from_header(&header),
+            Err(_) => Model::Dmg,
+        }
+    };
+    if model != cpu.model() {
+        info!("Emulating a {:?}", model);
+        cpu.set_model(model);
+    }
+}
+
+/// Parse a size in bytes, with an optional `K` or `KiB` suffix for kibibytes. Cartridge RAM is
+/// always a power of two, or none at all.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let lower = s.trim().to_lowercase();
+    let (digits, multiplier) = if lower.ends_with("kib") {
+        (&lower[..lower.len() - 3], 1024)
+    } else if lower.ends_with('k') {
+        (&lower[..lower.len() - 1], 1024)
+    } else {
+        (&lower[..], 1)
+    };
+    let n: usize = digits.trim().parse().map_err(|_| format!("invalid size: {}", s))?;
+    match n.checked_mul(multiplier) {
+        Some(size) if size == 0 || size.is_power_of_two() => Ok(size),
+        _ => Err(format!("size isn't a power of two: {}", s)),
+    }
+}
+
+/// Parse a byte in decimal or hex (with `0x`).
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let result = if s.starts_with("0x") || s.starts_with("0X") {
+        u8::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+    result.map_err(|_| format!("invalid byte: {}", s))
+}
+
+fn rom_pad(opts: &RomPadOpts) -> Result<(), failure::Error> {
+    let mut rom = std::fs::read(&opts.rom_path).context("Failed to read ROM file")?;
+    let old_len = rom.len();
+    rom_tools::pad(&mut rom, opts.fill)?;
+    let out_path = opts.out_path.as_ref().unwrap_or(&opts.rom_path);
+    std::fs::write(out_path, &rom).context("Failed to write ROM file")?;
+    println!("Padded {} bytes to {} ({} banks)", old_len, rom.len(), rom.len() / 0x4000);
+    Ok(())
+}
+
+fn rom_trim(opts: &RomTrimOpts) -> Result<(), failure::Error> {
+    let mut rom = std::fs::read(&opts.rom_path).context("Failed to read ROM file")?;
+    let old_len = rom.len();
+    rom_tools::trim(&mut rom)?;
+    let out_path = opts.out_path.as_ref().unwrap_or(&opts.rom_path);
+    std::fs::write(out_path, &rom).context("Failed to write ROM file")?;
+    println!("Trimmed {} bytes to {}", old_len, rom.len());
+    Ok(())
+}
+
+fn cheats_import(opts: &CheatsImportOpts) -> Result<(), failure::Error> {
+    let path = match CheatFile::path_for(&opts.rom_path) {
+        Some(path) => path,
+        None => failure::bail!("There's no config directory to keep cheats in"),
+    };
+    let contents = std::fs::read_to_string(&opts.cheats_path).context("Failed to read cheat list")?;
+    let imported = cheat_file::import(&contents).context("Failed to import cheat list")?;
+    let mut file = CheatFile::load(&path)?;
+    let total = imported.len();
+    let added = file.merge(imported);
+    file.save(&path)?;
+    println!("Imported {} of {} cheats into {}", added, total, path.display());
+    if added < total {
+        println!("The other {} were already there", total - added);
     }
+    Ok(())
 }
 
-/// Print the ROM info in a table with one ROM per row
-fn info_table(opts: &InfoOpts) -> Result<(), failure::Error> {
+fn cheats_list(opts: &CheatsListOpts) -> Result<(), failure::Error> {
+    let path = match CheatFile::path_for(&opts.rom_path) {
+        Some(path) => path,
+        None => failure::bail!("There's no config directory to keep cheats in"),
+    };
+    let file = CheatFile::load(&path)?;
     let mut out = tabwriter::TabWriter::new(std::io::stdout());
-    writeln!(out, "File path\tTitle\tVersion\tType\tHardware\tROM size\tRAM size\tGBC\tSGB\tLicensee\tDestination\tManufacturer")?;
-
-    for path in &opts.rom_paths {
-        let rom = std::fs::read(path)
-            .with_context(|_| format!("Failed to read ROM file: {}", path.display()))?;
-        let cart = cart_header::CartHeader::from_rom(&rom)
-            .with_context(|_| format!("Failed to parse cartridge header: {}", path.display()))?;
-
-        write!(out, "{}\t", path.display())?;
-        match std::str::from_utf8(&cart.title) {
-            Ok(title) => write!(out, "{}\t", title)?,
-            Err(_) => write!(out, "{:x?}\t", cart.title)?,
-        }
-        write!(out, "{}\t", cart.rom_version)?;
-        write!(out, "{:?}\t", cart.cart_type)?;
-        write!(out, "{}\t", CartHardware::flags_to_string(cart.hardware))?;
-        write!(out, "{}\t", cart.rom_size)?;
-        write!(out, "{}\t", cart.ram_size)?;
-        write!(out, "{}\t", cart.gbc_flag)?;
-        write!(out, "{}\t", cart.sgb_flag)?;
-        write!(out, "{:?}\t", cart.licensee_code)?;
-        write!(out, "{:?}\t", cart.destination_code)?;
-        write!(out, "{:?}", cart.manufacturer_code)?;
-        writeln!(out, "")?;
+    for cheat in &file.cheats {
+        writeln!(out, "{}\t{}\t{}", if cheat.enabled { "on" } else { "off" }, cheat.code, cheat.name)?;
     }
+    out.flush()?;
+    println!("{} cheats in {}", file.cheats.len(), path.display());
+    Ok(())
+}
+
+fn state_info(opts: &StateInfoOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    let state = std::fs::read(&opts.state_path).context("Failed to read savestate")?;
+    let info = Cpu::state_info(&state).context("Failed to read savestate")?;
 
+    let mut out = tabwriter::TabWriter::new(std::io::stdout());
+    writeln!(out, "Format version:\t{}", info.header.version)?;
+    writeln!(out, "ROM CRC-32:\t{:08X}", info.header.rom_crc32)?;
+    writeln!(out, "Title:\t{}", info.title.as_ref().map_or("not recorded", |title| title.as_str()))?;
+    writeln!(out, "Made by:\t{}", info.build.as_ref().map_or("not recorded", |build| build.as_str()))?;
+    let frames = info.cycles / gpu::CYCLES_PER_FRAME;
+    writeln!(out, "Frame:\t{}", frames)?;
+    let seconds = info.cycles / CLOCK_RATE;
+    writeln!(out, "Play time:\t{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)?;
     out.flush()?;
+
+    if let Some(path) = &opts.thumbnail_path {
+        photo::export(&info.gpu, &config.photo, path).context("Failed to write thumbnail")?;
+    }
     Ok(())
 }
 
-/// Print the ROM info in the style of a list of separate key-value records.
-fn info_records(opts: &InfoOpts) -> Result<(), failure::Error> {
-    for path in &opts.rom_paths {
-        let rom = std::fs::read(path)
-            .with_context(|_| format!("Failed to read ROM file: {}", path.display()))?;
-        let cart = cart_header::CartHeader::from_rom(&rom)
-            .with_context(|_| format!("Failed to parse cartridge header: {}", path.display()))?;
-        let mut out = tabwriter::TabWriter::new(std::io::stdout());
+fn state_diff(opts: &StateDiffOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    let rom = read_rom(&opts.rom_path, None)?;
+    let load = |path: &Path| -> Result<Cpu, failure::Error> {
+        let state = std::fs::read(path)
+            .with_context(|_| format!("Failed to read savestate: {}", path.display()))?;
+        let mut cpu = Cpu::new(Cart::from_rom(rom.clone(), None)?);
+        cpu.load_state(&state)
+            .with_context(|_| format!("Failed to load savestate: {}", path.display()))?;
+        Ok(cpu)
+    };
+    let (a, b) = (load(&opts.a_path)?, load(&opts.b_path)?);
+
+    if let Some(path) = &opts.image_path {
+        photo::export_side_by_side(&[&a.gpu, &b.gpu], &config.photo, path)
+            .context("Failed to write image")?;
+    }
+    let differences = state_diff::write_diff(&a, &b, std::io::stdout())?;
+    if differences > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn debug(opts: &DebugOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    let mut cpu = Cpu::new(load_cart(&opts.rom_path, None, &opts.hardware)?);
+    set_model(&mut cpu, &opts.hardware);
+    cpu.paranoid = opts.paranoid;
+    cpu.strict = opts.strict;
+    cpu.serial.echo = opts.debug_output;
+    cpu.debug_register = opts.debug_output;
+    cpu.gpu.sprite_limit = sprite_limit(&config, false);
+    cpu.cheats = load_cheats(&opts.rom_path)?;
+
+    load_debug_info(&mut cpu, opts.symbols_path.as_ref(), opts.map_path.as_ref())?;
+
+    start_frontend_debug(&mut cpu, &config);
+
+    Ok(())
+}
 
-        writeln!(out, "File path:\t{}", path.display())?;
-        match std::str::from_utf8(&cart.title) {
-            Ok(title) => writeln!(out, "Title:\t{}", title)?,
-            Err(_) => writeln!(out, "Title:\t{:x?}", cart.title)?,
+fn repro_record(opts: &ReproRecordOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    let ram = match &opts.save_path {
+        Some(path) => Some(std::fs::read(path).context("Failed to read save file")?.into_boxed_slice()),
+        None => None,
+    };
+
+    let mut cpu = Cpu::new(load_cart(&opts.rom_path, ram, &HardwareOpts::default())?);
+    let repro = Repro::new(cpu.cart.rom(), cpu.save_state(), cpu.cycles() as u64, cpu.joypad.pressed());
+    let mut options = FrontendOptions::new();
+    options.repro = ReproMode::Record(repro);
+    if let Some(path) = config.save.path_for(&opts.rom_path) {
+        options.save_path = path;
+    }
+
+    start_frontend(&mut [&mut cpu], &mut options, &config);
+
+    if let ReproMode::Record(repro) = options.repro {
+        std::fs::write(&opts.bundle_path, repro.to_bytes()).context("Failed to write bundle")?;
+        println!(
+            "Wrote {} input changes to {}",
+            repro.inputs.len() - 1,
+            opts.bundle_path.display(),
+        );
+    }
+    Ok(())
+}
+
+fn repro_play(opts: &ReproPlayOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    let bundle = std::fs::read(&opts.bundle_path).context("Failed to read bundle")?;
+    let repro = Repro::from_bytes(&bundle).context("Failed to parse bundle")?;
+    let rom = read_rom(&opts.rom_path, None)?;
+    repro.check_rom(&rom)?;
+    let mut cpu = Cpu::new(Cart::from_rom(rom, None)?);
+    cpu.paranoid = opts.paranoid;
+    cpu.load_state(&repro.state).context("Failed to load the bundle's savestate")?;
+
+    let mut options = FrontendOptions::new();
+    options.repro = ReproMode::Play { repro, next: 0 };
+    if let Some(path) = config.save.path_for(&opts.rom_path) {
+        options.save_path = path;
+    }
+    start_frontend(&mut [&mut cpu], &mut options, &config);
+
+    Ok(())
+}
+
+fn bisect(opts: &BisectOpts) -> Result<(), failure::Error> {
+    let bundle = std::fs::read(&opts.bundle_path).context("Failed to read bundle")?;
+    let repro = Repro::from_bytes(&bundle).context("Failed to parse bundle")?;
+    let rom = read_rom(&opts.rom_path, None)?;
+    repro.check_rom(&rom)?;
+    let mut cpu = Cpu::new(Cart::from_rom(rom, None)?);
+    opts.check.check_names(&cpu).context("Invalid --check expression")?;
+
+    let outcome = bisect::bisect(&mut cpu, &repro, &opts.check, opts.frames)
+        .context("Failed to load the bundle's savestate")?;
+    match outcome {
+        bisect::Outcome::TrueAtStart => println!("The expression is already true at the start of the bundle"),
+        bisect::Outcome::NeverTrue => println!("The expression is never true"),
+        bisect::Outcome::Found { frame, cycle, state } => {
+            std::fs::write(&opts.state_path, state).context("Failed to write savestate")?;
+            println!("The expression becomes true during frame {} (from cycle {})", frame, cycle);
+            println!("Wrote the state from the start of that frame to {}", opts.state_path.display());
         }
-        writeln!(out, "Version:\t{}", cart.rom_version)?;
-        writeln!(out, "MBC type:\t{:?}", cart.cart_type)?;
-        writeln!(out, "Hardware:\t{}", CartHardware::flags_to_string(cart.hardware))?;
-        writeln!(out, "ROM size:\t{}", cart.rom_size)?;
-        writeln!(out, "RAM size:\t{}", cart.ram_size)?;
-        writeln!(out, "GBC support:\t{}", cart.gbc_flag)?;
-        writeln!(out, "SGB support:\t{}", cart.sgb_flag)?;
-        writeln!(out, "Manufacturer code:\t{:?}", cart.manufacturer_code)?;
-        writeln!(out, "Licensee code:\t{:?}", cart.licensee_code)?;
-        writeln!(out, "Destination code:\t{:?}", cart.destination_code)?;
-        writeln!(out, "")?;
+    }
+    Ok(())
+}
 
-        out.flush()?;
+fn sweep(opts: &SweepOpts) -> Result<(), failure::Error> {
+    let config = Config::load()?;
+    if let Some(dir) = &opts.out_dir {
+        std::fs::create_dir_all(dir).context("Failed to create output directory")?;
+    }
+
+    let mut pool = rayon::ThreadPoolBuilder::new();
+    if let Some(jobs) = opts.jobs {
+        pool = pool.num_threads(jobs);
+    }
+    let pool = pool.build()?;
+    let reports: Vec<_> = pool.install(|| {
+        opts.rom_paths.par_iter().enumerate()
+            .map(|(i, path)| sweep_rom(opts, &config, i, path))
+            .collect()
+    });
+
+    let mut out = tabwriter::TabWriter::new(std::io::stdout());
+    writeln!(out, "File path\tResult\tFrames\tScreen CRC")?;
+    for (path, report) in opts.rom_paths.iter().zip(&reports) {
+        writeln!(out, "{}\t{}\t{}\t{:08x}", path.display(), report.outcome, report.frames, report.screen_crc)?;
+    }
+    out.flush()?;
+
+    if reports.iter().any(|report| report.outcome.is_failure()) {
+        std::process::exit(1);
     }
     Ok(())
 }
+
+/// Run the `index`th ROM of a sweep and write its output files. Called on a worker thread.
+fn sweep_rom(opts: &SweepOpts, config: &Config, index: usize, path: &Path) -> sweep::Report {
+    let error = |e: failure::Error| sweep::Report {
+        outcome: Outcome::Error(e.to_string()),
+        frames: 0,
+        screen_crc: 0,
+        serial: Vec::new(),
+    };
+    let mut cpu = match load_cart(path, None, &opts.hardware) {
+        Ok(cart) => Cpu::new(cart),
+        Err(e) => return error(e),
+    };
+    set_model(&mut cpu, &opts.hardware);
+    cpu.gpu.sprite_limit = sprite_limit(config, false);
+    if let Some(Err(e)) = opts.exit_when.as_ref().map(|expr| expr.check_names(&cpu)) {
+        return error(e.into());
+    }
+    let mut report = sweep::run(&mut cpu, opts.frame_limit, opts.exit_when.as_ref());
+
+    if let Some(dir) = &opts.out_dir {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = format!("{:03}-{}", index, stem);
+        let result = photo::export(&cpu.gpu, &config.photo, &dir.join(format!("{}.png", name)))
+            .and_then(|()| Ok(std::fs::write(dir.join(format!("{}.serial.txt", name)), &report.serial)?));
+        if let Err(e) = result {
+            report.outcome = Outcome::Error(format!("failed to write output: {}", e));
+        }
+    }
+    report
+}
+
+fn info(opts: &InfoOpts) -> Result<(), failure::Error> {
+    // Describing ROMs doesn't need the config, so a broken one only costs the database it names.
+    let dat_path = match &opts.dat_path {
+        Some(path) => Some(path.clone()),
+        None => match Config::load() {
+            Ok(config) => config.info.dat,
+            Err(e) => {
+                warn!("Not using a ROM database from the config file, which failed to load: {}", e);
+                None
+            }
+        },
+    };
+    let db = match dat_path {
+        Some(path) => Some(RomDb::load(&path)?),
+        None => None,
+    };
+    info::print(&opts.rom_paths, opts.format, opts.table, db.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("8K"), Ok(8192));
+        assert_eq!(parse_size(" 32 KiB "), Ok(32768));
+        assert!(parse_size("3K").is_err());
+        assert!(parse_size("1000").is_err());
+        assert!(parse_size("big").is_err());
+        assert!(parse_size(&format!("{}K", usize::max_value())).is_err());
+    }
+
+    #[test]
+    fn test_mapper_override_sets_built_in_ram() {
+        // A ROM-only header, which says there's no RAM.
+        let header = CartHeader::from_rom(&[0; 0x8000]).unwrap();
+        let hardware = HardwareOpts { mapper: Some(CartType::Mbc2), ..HardwareOpts::default() };
+        let config = cart_config(&header, &hardware).unwrap();
+        assert_eq!(config.cart_type, CartType::Mbc2);
+        // One byte for each of the MBC2's 512 nibbles.
+        assert_eq!(config.ram_size, 512);
+    }
+}
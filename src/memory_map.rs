@@ -0,0 +1,273 @@
+//! One addressing model for every tool that looks at memory: the address space split into named
+//! regions, some of them banked, with any bank of any region readable by bank number and address
+//! regardless of what the game currently has mapped in. Region names follow RGBDS's section types,
+//! so `ROMX` bank 3 at 0x4000 means the same thing here as in a linker map.
+
+use crate::cpu::Cpu;
+use failure_derive::Fail;
+
+/// A range of the address space backed by one kind of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub start: u16,
+
+    /// The last address in the region, inclusive.
+    pub end: u16,
+
+    /// How many banks can be addressed in this region. Regions without banking have one bank,
+    /// number 0, and cartridges without RAM have no `SRAM` banks at all.
+    pub banks: u16,
+
+    pub readable: bool,
+    pub writable: bool,
+}
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum MemoryError {
+    #[fail(display = "{} has no bank {} (it has {})", region, bank, banks)]
+    NoSuchBank {
+        region: &'static str,
+        bank: u16,
+        banks: u16,
+    },
+
+    #[fail(display = "{} can't be written", _0)]
+    NotWritable(&'static str),
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
+
+/// The regions of `cpu`'s address space, in address order. Together they cover every address.
+pub fn regions(cpu: &Cpu) -> impl Iterator<Item = MemoryRegion> {
+    let region = |name, start, end, banks, readable, writable| {
+        MemoryRegion { name, start, end, banks, readable, writable }
+    };
+    let ram_banks = cpu.cart.ram_banks();
+    vec![
+        region("ROM0", 0x0000, 0x3FFF, 1, true, false),
+        // Any ROM bank can be mapped in here, including bank 0 on some mappers.
+        region("ROMX", 0x4000, 0x7FFF, cpu.cart.rom_banks(), true, false),
+        region("VRAM", 0x8000, 0x9FFF, 1, true, true),
+        region("SRAM", 0xA000, 0xBFFF, ram_banks, ram_banks > 0, ram_banks > 0),
+        region("WRAM0", 0xC000, 0xCFFF, 1, true, true),
+        region("WRAMX", 0xD000, 0xDFFF, 1, true, true),
+        region("ECHO", 0xE000, 0xFDFF, 1, true, true),
+        region("OAM", 0xFE00, 0xFE9F, 1, true, true),
+        region("UNUSABLE", 0xFEA0, 0xFEFF, 1, true, false),
+        region("IO", 0xFF00, 0xFF7F, 1, true, true),
+        region("HRAM", 0xFF80, 0xFFFE, 1, true, true),
+        region("IE", 0xFFFF, 0xFFFF, 1, true, true),
+    ].into_iter()
+}
+
+/// The region containing `addr`.
+pub fn region_at(cpu: &Cpu, addr: u16) -> MemoryRegion {
+    regions(cpu).find(|region| region.contains(addr)).expect("regions cover every address")
+}
+
+/// Read `addr` in the given bank of its region. Outside the cartridge's banked regions, this is
+/// the same as reading from the CPU, so I/O registers read as the game would see them.
+pub fn read(cpu: &Cpu, bank: u16, addr: u16) -> Result<u8, MemoryError> {
+    let region = checked_region(cpu, bank, addr)?;
+    Ok(match region.name {
+        "ROM0" | "ROMX" => cpu.cart.read_rom_bank(bank, addr),
+        "SRAM" => cpu.cart.read_ram_bank(bank, addr),
+        _ => cpu.peek(addr),
+    })
+}
+
+/// Write `val` to `addr` in the given bank of its region. Outside the cartridge's banked regions,
+/// this is the same as a write from the CPU, including any side effects on I/O registers.
+pub fn write(cpu: &mut Cpu, bank: u16, addr: u16, val: u8) -> Result<(), MemoryError> {
+    let region = checked_region(cpu, bank, addr)?;
+    if !region.writable {
+        return Err(MemoryError::NotWritable(region.name));
+    }
+    match region.name {
+        "SRAM" => cpu.cart.write_ram_bank(bank, addr, val),
+        _ => cpu.write_mem_debug(addr, val),
+    }
+    Ok(())
+}
+
+/// The bank of `addr`'s region that the game currently has mapped in.
+pub fn mapped_bank(cpu: &Cpu, addr: u16) -> u16 {
+    match addr {
+        0x4000...0x7FFF => cpu.cart.debug_state().rom_bank,
+        0xA000...0xBFFF => cpu.cart.debug_state().ram_bank,
+        _ => 0,
+    }
+}
+
+/// Parse an address, in the syntax every tool that takes one shares:
+///
+/// - a number, in hex with an optional `0x` or `$` (`C000`, `0xC000`, `$C000`), or in decimal
+///   after `#` (`#49152`),
+/// - or a symbol from the WLA DX symbol file or RGBDS map file loaded into `cpu`, like `Main` or
+///   `Main.loop`,
+/// - followed by any number of offsets to add or subtract, like `Main+4` or `wBuffer-#10`,
+/// - all optionally after a bank number and a colon, like `03:4000` or `3:Main+4`.
+///
+/// A symbol in a switchable bank means that bank, unless another is given. Without a bank, the
+/// currently mapped one is meant, see `mapped_bank`. A name that's also a hex number, like `Face`,
+/// means the symbol if there is one.
+pub fn parse_address(cpu: &Cpu, s: &str) -> Result<(Option<u16>, u16), String> {
+    let s = s.trim();
+    let (bank, expr) = match s.find(':') {
+        Some(i) => {
+            let bank = parse_number(s[..i].trim()).ok_or_else(|| format!("invalid bank: {:?}", &s[..i]))?;
+            (Some(bank), &s[i + 1..])
+        }
+        None => (None, s),
+    };
+
+    let mut terms = Vec::new();
+    let (mut sign, mut start) = (1, 0);
+    for (i, c) in expr.char_indices() {
+        if c == '+' || c == '-' {
+            terms.push((sign, &expr[start..i]));
+            sign = if c == '+' { 1 } else { -1 };
+            start = i + 1;
+        }
+    }
+    terms.push((sign, &expr[start..]));
+
+    let (mut symbol_bank, mut addr) = (None, 0i64);
+    for (sign, term) in terms {
+        let term = term.trim();
+        let value = match symbol_address(cpu, term) {
+            Some((bank, value)) => {
+                symbol_bank = symbol_bank.or(bank);
+                value
+            }
+            None if term.is_empty() => return Err(format!("invalid address: {:?}", s)),
+            None => parse_number(term).ok_or_else(|| format!("unknown symbol or invalid number: {:?}", term))?,
+        };
+        addr += sign * i64::from(value);
+    }
+    if addr < 0 || addr > 0xFFFF {
+        return Err(format!("address out of range: {:?}", s));
+    }
+    Ok((bank.or(symbol_bank), addr as u16))
+}
+
+/// Parse an address with `parse_address`, picking the mapped bank if it doesn't say.
+pub fn parse_banked_address(cpu: &Cpu, s: &str) -> Result<(u16, u16), String> {
+    let (bank, addr) = parse_address(cpu, s)?;
+    Ok((bank.unwrap_or_else(|| mapped_bank(cpu, addr)), addr))
+}
+
+/// Where a symbol from the loaded WLA DX symbols or RGBDS map is, with its bank if it's in a
+/// switchable region.
+pub fn symbol_address(cpu: &Cpu, name: &str) -> Option<(Option<u16>, u16)> {
+    let wla = cpu.debug_symbols.as_ref()
+        .and_then(|symbols| symbols.symbols.get(name))
+        .map(|rom_addr| (u16::from(rom_addr.bank), rom_addr.addr));
+    let rgbds = || cpu.debug_map.as_ref().and_then(|map| {
+        map.sections.iter().find_map(|section| {
+            section.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| (section.bank, symbol.addr))
+        })
+    });
+    let (bank, addr) = wla.or_else(rgbds)?;
+    match addr {
+        0x4000...0x7FFF | 0xA000...0xBFFF => Some((Some(bank), addr)),
+        _ => Some((None, addr)),
+    }
+}
+
+/// A number in an address: hex, with an optional `0x` or `$`, or decimal after `#`.
+pub fn parse_number(s: &str) -> Option<u16> {
+    if s.starts_with('#') {
+        s[1..].parse().ok()
+    } else {
+        let digits = s.trim_start_matches("0x").trim_start_matches("0X").trim_start_matches('$');
+        u16::from_str_radix(digits, 16).ok()
+    }
+}
+
+fn checked_region(cpu: &Cpu, bank: u16, addr: u16) -> Result<MemoryRegion, MemoryError> {
+    let region = region_at(cpu, addr);
+    if bank >= region.banks {
+        return Err(MemoryError::NoSuchBank { region: region.name, bank, banks: region.banks });
+    }
+    Ok(region)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::cart::Cart;
+    use crate::rgbds_map::RgbdsMap;
+
+    /// A CPU with a 64 KiB MBC1 cartridge, whose bank 3 has 0x42 at 0x4010, and a map with symbols
+    /// in ROM0, ROMX bank 3, and WRAM0. The game starts with `ld a, 5` and `ld bc, 0x1234`.
+    pub fn cpu_with_symbols() -> Cpu {
+        let mut rom = vec![0; 0x10000];
+        rom[0x100..0x105].copy_from_slice(&[0x3E, 0x05, 0x01, 0x34, 0x12]);
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        rom[3 * 0x4000 + 0x10] = 0x42;
+        let mut cpu = Cpu::new(Cart::from_rom(rom.into_boxed_slice(), None).unwrap());
+        let map = "\
+ROM0 bank #0:
+  SECTION: $0150-$0157 ($0008 bytes) [\"Main\"]
+           $0150 = Main
+           $0154 = Main.loop
+ROMX bank #3:
+  SECTION: $4000-$40FF ($0100 bytes) [\"Data\"]
+           $4010 = Face
+WRAM0 bank #0:
+  SECTION: $C000-$C0FF ($0100 bytes) [\"Vars\"]
+           $C000 = wBuffer
+";
+        cpu.debug_map = Some(RgbdsMap::parse(map.as_bytes()).unwrap());
+        cpu
+    }
+
+    #[test]
+    fn parses_numbers() {
+        let cpu = cpu_with_symbols();
+        for s in &["C000", "c000", "0xC000", "$C000", "#49152", " C000 "] {
+            assert_eq!(parse_address(&cpu, s), Ok((None, 0xC000)), "{}", s);
+        }
+        assert_eq!(parse_address(&cpu, "03:4000"), Ok((Some(3), 0x4000)));
+        assert_eq!(parse_address(&cpu, "#3:4000"), Ok((Some(3), 0x4000)));
+    }
+
+    #[test]
+    fn parses_symbols_and_offsets() {
+        let cpu = cpu_with_symbols();
+        assert_eq!(parse_address(&cpu, "Main"), Ok((None, 0x150)));
+        assert_eq!(parse_address(&cpu, "Main.loop"), Ok((None, 0x154)));
+        assert_eq!(parse_address(&cpu, "Main+4"), Ok((None, 0x154)));
+        assert_eq!(parse_address(&cpu, "Main + 10 - #2"), Ok((None, 0x15E)));
+        assert_eq!(parse_address(&cpu, "wBuffer-#10"), Ok((None, 0xBFF6)));
+        // A symbol wins over the hex number with the same name, and brings its bank.
+        assert_eq!(parse_address(&cpu, "Face"), Ok((Some(3), 0x4010)));
+        assert_eq!(parse_address(&cpu, "Face+1"), Ok((Some(3), 0x4011)));
+        assert_eq!(parse_address(&cpu, "1:Face"), Ok((Some(1), 0x4010)));
+        assert_eq!(parse_banked_address(&cpu, "Face"), Ok((3, 0x4010)));
+        assert_eq!(parse_banked_address(&cpu, "4010"), Ok((1, 0x4010)));
+    }
+
+    #[test]
+    fn rejects_bad_addresses() {
+        let cpu = cpu_with_symbols();
+        for s in &["", "Nope", "main", "-1", "Main+", "FFFF+1", "10000", "x:4000", "#", "$"] {
+            assert!(parse_address(&cpu, s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn reads_any_bank() {
+        let cpu = cpu_with_symbols();
+        assert_eq!(read(&cpu, 3, 0x4010), Ok(0x42));
+        assert_eq!(read(&cpu, 1, 0x4010), Ok(0x00));
+        assert_eq!(read(&cpu, 4, 0x4010), Err(MemoryError::NoSuchBank { region: "ROMX", bank: 4, banks: 4 }));
+    }
+}
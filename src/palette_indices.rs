@@ -0,0 +1,47 @@
+//! Exporting the screen as palette indices rather than colors, for tools that compare frames or
+//! feed them to a program: each pixel is the 2-bit color number from its tile, before the game's
+//! palettes turned it into a shade. See `Gpu::palette_index_buffer`.
+//!
+//! Files ending in `.png` are written as indexed PNGs whose pixel values are the indices, with a
+//! gray palette so they can still be viewed. Anything else gets the raw indices, one byte per
+//! pixel, row by row.
+
+use crate::gpu::{Gpu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// The palette of exported PNGs, index 0 lightest like the default palettes.
+const PNG_PALETTE: [u8; 12] = [255, 255, 255, 170, 170, 170, 85, 85, 85, 0, 0, 0];
+
+/// Write the GPU's current palette indices to `path`.
+pub fn export(gpu: &Gpu, path: &Path) -> Result<(), failure::Error> {
+    let is_png = path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("png"));
+    if !is_png {
+        let data: Vec<u8> = gpu.palette_index_buffer.iter().flat_map(|row| row.iter().cloned()).collect();
+        std::fs::write(path, data)?;
+        return Ok(());
+    }
+
+    // Four 2-bit pixels per byte, leftmost in the high bits.
+    let data: Vec<u8> = gpu.palette_index_buffer.iter()
+        .flat_map(|row| row.chunks(4))
+        .map(|pixels| pixels.iter().fold(0, |byte, &index| byte << 2 | index & 0b11))
+        .collect();
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Two);
+    encoder.set_palette(&PNG_PALETTE[..]);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// The palette indices as one digit per pixel, row by row, for the control socket.
+pub fn digits(gpu: &Gpu) -> String {
+    gpu.palette_index_buffer.iter()
+        .flat_map(|row| row.iter())
+        .map(|&index| char::from(b'0' + index))
+        .collect()
+}
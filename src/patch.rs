@@ -0,0 +1,465 @@
+//! Soft-patching: applying a ROM hack or translation patch to the ROM in memory when it's loaded,
+//! so the original file stays untouched. Three formats are read, told apart by their first bytes:
+//!
+//! - IPS (`PATCH`): records of a 3-byte offset and 2-byte length followed by that many bytes (or,
+//!   with length zero, a 2-byte count and one byte to repeat), then `EOF`. Some tools add a 3-byte
+//!   size to truncate the ROM to after the `EOF`. Numbers are big endian.
+//! - UPS (`UPS1`): the ROM sizes before and after, then runs of bytes to XOR into the ROM, each
+//!   after a number of bytes to skip and ending at a zero byte.
+//! - BPS (`BPS1`): the sizes and some metadata, then actions that build the patched ROM from runs
+//!   of the original, of the patch, or of what's been built so far.
+//!
+//! UPS and BPS use variable-length numbers, and end with the CRC-32s (little endian) of the ROM
+//! they're for, the patched ROM, and the patch itself. Applying one to the wrong ROM is an error,
+//! since that silently produces garbage. IPS has no checksums, so that can't be caught there.
+
+use crate::checksum::crc32;
+use failure_derive::Fail;
+use std::path::{Path, PathBuf};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// The three CRC-32s at the end of UPS and BPS patches.
+const FOOTER_SIZE: usize = 12;
+
+/// The biggest cartridge ROM, 8 MiB. Sizes and offsets in a patch can be anything, and one past
+/// this is a broken patch rather than a reason to allocate that much.
+const MAX_TARGET_SIZE: usize = 8 * 1024 * 1024;
+
+/// The extensions of the patches looked for next to a ROM, in order of preference.
+const EXTENSIONS: [&str; 3] = ["bps", "ups", "ips"];
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum PatchError {
+    #[fail(display = "not an IPS, UPS, or BPS patch")]
+    UnknownFormat,
+
+    #[fail(display = "patch ends in the middle of a record at byte {}", _0)]
+    Truncated(usize),
+
+    #[fail(display = "patch file is corrupted: its CRC-32 is {:08X}, but it says {:08X}", actual, expected)]
+    PatchMismatch { expected: u32, actual: u32 },
+
+    #[fail(
+        display = "patch is for a ROM with CRC-32 {:08X}, but this one's is {:08X}; it may already be patched, or be another version of the game",
+        expected, actual,
+    )]
+    SourceMismatch { expected: u32, actual: u32 },
+
+    #[fail(display = "patched ROM has CRC-32 {:08X}, but the patch says it should be {:08X}", actual, expected)]
+    TargetMismatch { expected: u32, actual: u32 },
+
+    #[fail(display = "patch reads outside the ROM at byte {}", _0)]
+    OutOfBounds(usize),
+
+    #[fail(display = "patch has a number too big to be a size or offset at byte {}", _0)]
+    NumberTooBig(usize),
+
+    #[fail(display = "patched ROM would be {} bytes, but cartridges hold at most 8 MiB", _0)]
+    TooLarge(usize),
+}
+
+/// The patch `rugby` applies to `rom_path` without being told to: a file next to it with the same
+/// name and a `.bps`, `.ups`, or `.ips` extension, if there is one.
+pub fn sibling(rom_path: &Path) -> Option<PathBuf> {
+    EXTENSIONS.iter().map(|extension| rom_path.with_extension(extension)).find(|path| path.is_file())
+}
+
+/// Apply `patch` to `rom`, in whichever format it's in. On error, `rom` may be partly patched.
+pub fn apply(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Records past the end of the ROM grow it, filling any gap with zeros.
+fn apply_ips(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    let mut r = Reader { data: patch, pos: IPS_MAGIC.len() };
+    loop {
+        let offset = r.take(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = number(offset);
+        match number(r.take(2)?) {
+            0 => {
+                let count = number(r.take(2)?);
+                let fill = r.byte()?;
+                grow(rom, offset + count)?;
+                for byte in &mut rom[offset..offset + count] {
+                    *byte = fill;
+                }
+            }
+            len => {
+                let data = r.take(len)?;
+                grow(rom, offset + len)?;
+                rom[offset..offset + len].copy_from_slice(data);
+            }
+        }
+    }
+
+    if let Ok(size) = r.take(3) {
+        rom.truncate(number(size));
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    let footer = Footer::check(rom, patch)?;
+    let mut r = Reader { data: &patch[..patch.len() - FOOTER_SIZE], pos: UPS_MAGIC.len() };
+    let _source_size = r.varint()?;
+    let target_size = r.varint()?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::TooLarge(target_size));
+    }
+    rom.resize(target_size, 0);
+
+    let mut pos: usize = 0;
+    while r.pos < r.data.len() {
+        pos = pos.checked_add(r.varint()?).ok_or(PatchError::OutOfBounds(r.pos))?;
+        loop {
+            match r.byte()? {
+                0 => break,
+                xor => {
+                    let byte = rom.get_mut(pos).ok_or(PatchError::OutOfBounds(pos))?;
+                    *byte ^= xor;
+                    pos += 1;
+                }
+            }
+        }
+        // The zero byte ending a run stands for an unchanged byte too.
+        pos += 1;
+    }
+    footer.check_target(rom)
+}
+
+fn apply_bps(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    let footer = Footer::check(rom, patch)?;
+    let mut r = Reader { data: &patch[..patch.len() - FOOTER_SIZE], pos: BPS_MAGIC.len() };
+    let _source_size = r.varint()?;
+    let target_size = r.varint()?;
+    let metadata_size = r.varint()?;
+    r.take(metadata_size)?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::TooLarge(target_size));
+    }
+
+    let source = std::mem::replace(rom, Vec::with_capacity(target_size));
+    let target = rom;
+    // Where the next `SourceCopy` and `TargetCopy` read from. Each action moves them relative to
+    // where the last one left off.
+    let (mut source_pos, mut target_pos) = (0, 0);
+    while r.pos < r.data.len() {
+        let action = r.varint()?;
+        let len = (action >> 2) + 1;
+        // Whatever the patch says, the target can't grow past the size it gave.
+        if len > target_size - target.len() {
+            return Err(PatchError::OutOfBounds(target.len()));
+        }
+        match action & 3 {
+            // SourceRead: the original ROM's bytes at the same place.
+            0 => {
+                let start = target.len();
+                let bytes = slice(&source, start, len).ok_or(PatchError::OutOfBounds(start))?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead: bytes from the patch.
+            1 => target.extend_from_slice(r.take(len)?),
+            // SourceCopy: the original ROM's bytes from anywhere.
+            2 => {
+                source_pos = r.relative(source_pos)?;
+                let bytes = slice(&source, source_pos, len).ok_or(PatchError::OutOfBounds(source_pos))?;
+                target.extend_from_slice(bytes);
+                source_pos += len;
+            }
+            // TargetCopy: bytes already written, one at a time, since the run may overlap itself.
+            _ => {
+                target_pos = r.relative(target_pos)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_pos).ok_or(PatchError::OutOfBounds(target_pos))?;
+                    target.push(byte);
+                    target_pos += 1;
+                }
+            }
+        }
+    }
+    footer.check_target(target)
+}
+
+/// The checksums at the end of a UPS or BPS patch.
+struct Footer {
+    target_crc32: u32,
+}
+
+impl Footer {
+    /// Read the footer of `patch`, checking the patch itself and that it's for `rom`.
+    fn check(rom: &[u8], patch: &[u8]) -> Result<Footer, PatchError> {
+        if patch.len() < 4 + FOOTER_SIZE {
+            return Err(PatchError::Truncated(patch.len()));
+        }
+        let footer = &patch[patch.len() - FOOTER_SIZE..];
+        let crc = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+        let (source_crc32, target_crc32, patch_crc32) = (crc(0), crc(4), crc(8));
+
+        let actual = crc32(&patch[..patch.len() - 4]);
+        if actual != patch_crc32 {
+            return Err(PatchError::PatchMismatch { expected: patch_crc32, actual });
+        }
+        let actual = crc32(rom);
+        if actual != source_crc32 {
+            return Err(PatchError::SourceMismatch { expected: source_crc32, actual });
+        }
+        Ok(Footer { target_crc32 })
+    }
+
+    fn check_target(&self, rom: &[u8]) -> Result<(), PatchError> {
+        let actual = crc32(rom);
+        if actual != self.target_crc32 {
+            return Err(PatchError::TargetMismatch { expected: self.target_crc32, actual });
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = slice(self.data, self.pos, len).ok_or(PatchError::Truncated(self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// A UPS or BPS number: seven bits per byte, least significant first, with the top bit set on
+    /// the last byte. Each byte after the first also adds one to what it stands for, so every
+    /// number has exactly one encoding.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let start = self.pos;
+        let too_big = PatchError::NumberTooBig(start);
+        let (mut value, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.byte()?;
+            let digit = ((byte & 0x7F) as usize).checked_mul(shift).ok_or_else(|| too_big.clone())?;
+            value = value.checked_add(digit).ok_or_else(|| too_big.clone())?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(|| too_big.clone())?;
+            value = value.checked_add(shift).ok_or_else(|| too_big.clone())?;
+        }
+    }
+
+    /// A BPS offset from `pos`: the magnitude shifted left once, with the sign in the low bit.
+    fn relative(&mut self, pos: usize) -> Result<usize, PatchError> {
+        let offset = self.varint()?;
+        let pos = if offset & 1 == 0 { pos.checked_add(offset >> 1) } else { pos.checked_sub(offset >> 1) };
+        pos.ok_or(PatchError::OutOfBounds(self.pos))
+    }
+}
+
+/// `len` bytes of `data` from `start`, if it's that long. Lengths come from the patch, so they can
+/// be anything.
+fn slice(data: &[u8], start: usize, len: usize) -> Option<&[u8]> {
+    data.get(start..)?.get(..len)
+}
+
+/// A big-endian number.
+fn number(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &byte| n << 8 | byte as usize)
+}
+
+fn grow(rom: &mut Vec<u8>, len: usize) -> Result<(), PatchError> {
+    if len > MAX_TARGET_SIZE {
+        return Err(PatchError::TooLarge(len));
+    }
+    if rom.len() < len {
+        rom.resize(len, 0);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let digit = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | digit);
+                return;
+            }
+            out.push(digit);
+            n -= 1;
+        }
+    }
+
+    /// End a UPS or BPS patch with its checksums.
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    fn ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = UPS_MAGIC.to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        let xor = |i: usize| source.get(i).cloned().unwrap_or(0) ^ target[i];
+        let (mut pos, mut i) = (0, 0);
+        while i < target.len() {
+            if xor(i) == 0 {
+                i += 1;
+                continue;
+            }
+            varint(i - pos, &mut patch);
+            while i < target.len() && xor(i) != 0 {
+                patch.push(xor(i));
+                i += 1;
+            }
+            patch.push(0);
+            pos = i + 1;
+            i += 1;
+        }
+        finish(patch, source, target)
+    }
+
+    /// A BPS patch that keeps what `source` and `target` start with, and has the rest in the patch.
+    fn bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        let same = source.iter().zip(target).take_while(|(a, b)| a == b).count();
+        if same > 0 {
+            varint((same - 1) << 2, &mut patch);
+        }
+        if same < target.len() {
+            varint((target.len() - same - 1) << 2 | 1, &mut patch);
+            patch.extend_from_slice(&target[same..]);
+        }
+        finish(patch, source, target)
+    }
+
+    fn rom() -> Vec<u8> {
+        (0..0x8000).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn ups_round_trip() {
+        let source = rom();
+        let mut target = source.clone();
+        target[0x134..0x140].copy_from_slice(b"TRANSLATION!");
+        target[0x7FFF] ^= 0xFF;
+        target.extend_from_slice(&[1, 2, 3, 0, 5]);
+        let mut patched = source.clone();
+        apply(&mut patched, &ups(&source, &target)).unwrap();
+        assert!(patched == target);
+    }
+
+    #[test]
+    fn bps_round_trip() {
+        let source = rom();
+        let mut target = source[..0x4000].to_vec();
+        target.extend_from_slice(&[0xAA; 0x5000]);
+        let mut patched = source.clone();
+        apply(&mut patched, &bps(&source, &target)).unwrap();
+        assert!(patched == target);
+    }
+
+    #[test]
+    fn ips_round_trip() {
+        let source = rom();
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x01, 0x34, 0x00, 0x04]);
+        patch.extend_from_slice(b"HACK");
+        // Fill 0x10 bytes past the end of the ROM with 0xFF.
+        patch.extend_from_slice(&[0x00, 0x80, 0x10, 0x00, 0x00, 0x00, 0x10, 0xFF]);
+        patch.extend_from_slice(IPS_EOF);
+        let mut patched = source.clone();
+        apply(&mut patched, &patch).unwrap();
+
+        let mut target = source.clone();
+        target[0x134..0x138].copy_from_slice(b"HACK");
+        target.resize(0x8010, 0);
+        target.extend_from_slice(&[0xFF; 0x10]);
+        assert!(patched == target);
+    }
+
+    #[test]
+    fn rejects_the_wrong_rom() {
+        let source = rom();
+        let mut target = source.clone();
+        target[0x150] ^= 1;
+        let mut other = source.clone();
+        other[0] ^= 1;
+        match apply(&mut other, &ups(&source, &target)) {
+            Err(PatchError::SourceMismatch { .. }) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_patches() {
+        let source = rom();
+        let check = |patch: Vec<u8>, expected: fn(&PatchError) -> bool| {
+            let error = apply(&mut source.clone(), &patch).unwrap_err();
+            assert!(expected(&error), "{:?}", error);
+        };
+
+        check(b"NOT A PATCH".to_vec(), |e| *e == PatchError::UnknownFormat);
+        check(b"PATCH\x00\x01\x00\x00\x10AB".to_vec(), |e| *e == PatchError::Truncated(10));
+
+        // A record past 8 MiB.
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x00, 0x01, 0x00]);
+        patch.extend_from_slice(IPS_EOF);
+        check(patch, |e| *e == PatchError::TooLarge(0x100_0000));
+
+        // A number that doesn't fit in a `usize`.
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0; 12]);
+        patch.push(0x80);
+        check(finish(patch, &source, &source), |e| *e == PatchError::NumberTooBig(4));
+
+        let mut patch = UPS_MAGIC.to_vec();
+        varint(source.len(), &mut patch);
+        varint(1 << 40, &mut patch);
+        check(finish(patch, &source, &source), |e| *e == PatchError::TooLarge(1 << 40));
+
+        // A `TargetCopy` longer than the target.
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(source.len(), &mut patch);
+        varint(1, &mut patch);
+        varint(0, &mut patch);
+        varint(1 << 2 | 1, &mut patch);
+        patch.extend_from_slice(&[0, 0]);
+        check(finish(patch, &source, &[0]), |e| *e == PatchError::OutOfBounds(0));
+
+        let mut corrupted = ups(&source, &source);
+        corrupted[4] ^= 1;
+        check(corrupted, |e| match e {
+            PatchError::PatchMismatch { .. } => true,
+            _ => false,
+        });
+    }
+}
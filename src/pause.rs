@@ -0,0 +1,74 @@
+//! Why emulation is paused. Several parts of a frontend can pause the emulator independently (the
+//! user's pause key, a breakpoint, the window losing focus, the debugger), and it only runs again
+//! once every one of them has resumed. Pausing for a reason that is already set does nothing, so
+//! e.g. regaining focus doesn't undo the user's own pause.
+
+use enumflags2::BitFlags;
+use enumflags2_derive::EnumFlags;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, EnumFlags, PartialEq)]
+#[repr(u8)]
+pub enum PauseReason {
+    /// The pause key, or a `pause` command from the control socket.
+    User       = 1 << 0,
+
+    /// A watch or `break_when` condition was hit.
+    Breakpoint = 1 << 1,
+
+    /// The window lost focus.
+    FocusLost  = 1 << 2,
+
+    /// The debugger prompt is waiting for a command.
+    Debugger   = 1 << 3,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PauseState {
+    reasons: BitFlags<PauseReason>,
+}
+
+impl PauseReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            PauseReason::User => "user",
+            PauseReason::Breakpoint => "breakpoint",
+            PauseReason::FocusLost => "focus lost",
+            PauseReason::Debugger => "debugger",
+        }
+    }
+}
+
+impl PauseState {
+    pub fn pause(&mut self, reason: PauseReason) {
+        self.reasons.insert(reason);
+    }
+
+    /// Withdraw one reason for pausing. Emulation stays paused if there are others.
+    pub fn resume(&mut self, reason: PauseReason) {
+        self.reasons.remove(reason);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+
+    pub fn is_paused_for(&self, reason: PauseReason) -> bool {
+        self.reasons.contains(reason)
+    }
+
+    pub fn reasons(&self) -> BitFlags<PauseReason> {
+        self.reasons
+    }
+}
+
+/// `running`, or `paused` followed by the reasons, like `paused (user, focus lost)`.
+impl fmt::Display for PauseState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_paused() {
+            return write!(f, "running");
+        }
+        let reasons: Vec<_> = self.reasons.iter().map(PauseReason::name).collect();
+        write!(f, "paused ({})", reasons.join(", "))
+    }
+}
@@ -0,0 +1,138 @@
+//! Photo mode: export the current frame as a PNG for thumbnails and wallpapers. Unlike a raw
+//! screenshot, the frame is upscaled, drawn with a choice of palettes, and can imitate the look of
+//! the original LCD with a pixel grid and ghosting from the previous frame.
+
+use crate::gpu::{Gpu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use serde_derive::Deserialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The largest `scale`, which keeps a side-by-side image of two frames under 100 MB.
+pub const MAX_SCALE: u32 = 16;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    /// The green shades of the original Game Boy, as shown in the window.
+    Dmg,
+
+    /// The grey shades of the Game Boy Pocket.
+    Pocket,
+
+    /// Plain greyscale, from white to black.
+    Gray,
+
+    /// Warm brown shades, like an old photograph.
+    Sepia,
+}
+
+/// Settings for `export`. Also the `[photo]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PhotoOptions {
+    /// The size of each Game Boy pixel in the image, from 1 to `MAX_SCALE`.
+    pub scale: u32,
+
+    pub palette: Palette,
+
+    /// Darken the edges of each pixel, like the gaps between LCD cells. Needs a scale of 3 or more.
+    pub grid: bool,
+
+    /// Blend in the previous frame, like the slow LCD of the original Game Boy. This also shows
+    /// sprites which games flicker on alternate frames.
+    pub ghosting: bool,
+}
+
+impl Palette {
+    /// The RGB colors of the four shades, lightest first.
+    pub fn colors(self) -> [[u8; 3]; 4] {
+        match self {
+            Palette::Dmg => [[155, 188, 15], [139, 172, 15], [48, 98, 48], [15, 56, 15]],
+            Palette::Pocket => [[196, 207, 161], [139, 149, 109], [77, 83, 60], [31, 31, 31]],
+            Palette::Gray => [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]],
+            Palette::Sepia => [[236, 220, 190], [178, 146, 106], [108, 78, 52], [44, 28, 16]],
+        }
+    }
+
+    /// The name of the palette in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Palette::Dmg => "dmg",
+            Palette::Pocket => "pocket",
+            Palette::Gray => "gray",
+            Palette::Sepia => "sepia",
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Palette, String> {
+        match s {
+            "dmg" => Ok(Palette::Dmg),
+            "pocket" => Ok(Palette::Pocket),
+            "gray" => Ok(Palette::Gray),
+            "sepia" => Ok(Palette::Sepia),
+            _ => Err(format!("unknown palette '{}', expected dmg, pocket, gray, or sepia", s)),
+        }
+    }
+}
+
+impl Default for PhotoOptions {
+    fn default() -> Self {
+        PhotoOptions { scale: 4, palette: Palette::Dmg, grid: false, ghosting: false }
+    }
+}
+
+/// Write the GPU's current frame to a PNG file at `path`.
+pub fn export(gpu: &Gpu, options: &PhotoOptions, path: &Path) -> Result<(), failure::Error> {
+    export_side_by_side(&[gpu], options, path)
+}
+
+/// Write the current frames of several GPUs to one PNG file, left to right with a gap between
+/// them, e.g. to compare two savestates.
+pub fn export_side_by_side(gpus: &[&Gpu], options: &PhotoOptions, path: &Path) -> Result<(), failure::Error> {
+    let scale = options.scale.max(1).min(MAX_SCALE) as usize;
+    let colors = options.palette.colors();
+    let frame_width = SCREEN_WIDTH * scale;
+    let gap = 4 * scale;
+    let width = gpus.len() * (frame_width + gap) - gap;
+    let height = SCREEN_HEIGHT * scale;
+
+    // The gap is drawn in the lightest shade, like the space around the screen.
+    let mut data: Vec<u8> = colors[0].iter().cloned().cycle().take(width * height * 3).collect();
+    for (i, gpu) in gpus.iter().enumerate() {
+        let left = i * (frame_width + gap);
+        for y in 0..height {
+            for x in 0..frame_width {
+                let (screen_x, screen_y) = (x / scale, y / scale);
+                let mut color = colors[gpu.screen_buffer[screen_y][screen_x] as usize];
+                if options.ghosting {
+                    let previous = colors[gpu.previous_screen_buffer[screen_y][screen_x] as usize];
+                    for (c, p) in color.iter_mut().zip(&previous) {
+                        *c = ((*c as u16 + *p as u16) / 2) as u8;
+                    }
+                }
+                let on_grid_line = x % scale == scale - 1 || y % scale == scale - 1;
+                if options.grid && scale >= 3 && on_grid_line {
+                    for c in color.iter_mut() {
+                        *c = (*c as u16 * 3 / 4) as u8;
+                    }
+                }
+                let offset = (y * width + left + x) * 3;
+                data[offset..offset + 3].copy_from_slice(&color);
+            }
+        }
+    }
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
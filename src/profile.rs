@@ -0,0 +1,137 @@
+//! A simple execution profiler which counts the cycles spent at each instruction address.
+//! With an RGBDS map file, reports are aggregated by function and section, along with how many
+//! distinct instructions in each were executed at all. Cycles the CPU spends halted or stopped
+//! aren't at any instruction, so they're counted separately.
+
+use crate::rgbds_map::RgbdsMap;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// The number of hottest addresses to show when there is no map file.
+const TOP_ADDRESSES: usize = 20;
+
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// Total cycles per (ROM bank, address) of each executed instruction.
+    cycles: HashMap<(u16, u16), u64>,
+
+    /// Cycles spent halted, stopped, or switching speed, running no instructions.
+    idle_cycles: u64,
+}
+
+#[derive(Default)]
+struct Totals {
+    cycles: u64,
+    instructions: usize,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, bank: u16, addr: u16, cycles: usize) {
+        *self.cycles.entry((bank, addr)).or_insert(0) += cycles as u64;
+    }
+
+    pub fn record_idle(&mut self, cycles: usize) {
+        self.idle_cycles += cycles as u64;
+    }
+
+    pub fn write_report(&self, map: Option<&RgbdsMap>, out: impl Write) -> io::Result<()> {
+        let mut out = tabwriter::TabWriter::new(out);
+        let total: u64 = self.cycles.values().sum();
+        writeln!(out, "Total cycles:\t{}", total + self.idle_cycles)?;
+        writeln!(
+            out,
+            "Idle cycles:\t{}\t{:.1}%",
+            self.idle_cycles,
+            percent(self.idle_cycles, total + self.idle_cycles)
+        )?;
+        writeln!(out, "Instruction cycles:\t{}", total)?;
+        writeln!(out, "Distinct instructions executed:\t{}", self.cycles.len())?;
+        writeln!(out)?;
+
+        let map = match map {
+            Some(map) => map,
+            None => {
+                let mut addrs: Vec<_> = self.cycles.iter().collect();
+                addrs.sort_by(|a, b| b.1.cmp(a.1));
+                writeln!(out, "Address\tCycles\t%")?;
+                for (&(bank, addr), &cycles) in addrs.into_iter().take(TOP_ADDRESSES) {
+                    writeln!(out, "{:02X}:{:04X}\t{}\t{:.1}", bank, addr, cycles, percent(cycles, total))?;
+                }
+                return out.flush();
+            }
+        };
+
+        let mut sections: HashMap<String, Totals> = HashMap::new();
+        let mut functions: HashMap<(String, String), Totals> = HashMap::new();
+        for (&(bank, addr), &cycles) in &self.cycles {
+            let (section, function) = match map.section_at(bank, addr) {
+                Some(section) => {
+                    let function = match section.symbol_at(addr) {
+                        Some(sym) => sym.name.clone(),
+                        None => format!("{:02X}:{:04X}", bank, addr),
+                    };
+                    (section.name.clone(), function)
+                }
+                None => (String::from("(unknown)"), format!("{:02X}:{:04X}", bank, addr)),
+            };
+            let entry = sections.entry(section.clone()).or_default();
+            entry.cycles += cycles;
+            entry.instructions += 1;
+            let entry = functions.entry((function, section)).or_default();
+            entry.cycles += cycles;
+            entry.instructions += 1;
+        }
+
+        let mut sections: Vec<_> = sections.into_iter().collect();
+        sections.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles));
+        writeln!(out, "Section\tCycles\t%\tInstructions")?;
+        for (name, totals) in sections {
+            writeln!(out, "{}\t{}\t{:.1}\t{}", name, totals.cycles, percent(totals.cycles, total), totals.instructions)?;
+        }
+        writeln!(out)?;
+
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles));
+        writeln!(out, "Function\tSection\tCycles\t%\tInstructions")?;
+        for ((function, section), totals) in functions {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{:.1}\t{}",
+                function,
+                section,
+                totals.cycles,
+                percent(totals.cycles, total),
+                totals.instructions,
+            )?;
+        }
+
+        out.flush()
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idle_cycles_reported_separately() {
+        let mut profile = Profile::default();
+        profile.record(0, 0x150, 12);
+        profile.record_idle(4);
+        let mut report = Vec::new();
+        profile.write_report(None, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<Vec<&str>> = report.lines().take(3).map(|l| l.split_whitespace().collect()).collect();
+        assert_eq!(lines[0], ["Total", "cycles:", "16"]);
+        assert_eq!(lines[1], ["Idle", "cycles:", "4", "25.0%"]);
+        assert_eq!(lines[2], ["Instruction", "cycles:", "12"]);
+    }
+}
@@ -0,0 +1,42 @@
+//! Dynamic rate control: the window's frontend uses the sound card as its clock. Each frame waits
+//! for the audio queue to drain down to a target fill level, so emulation runs exactly as fast as
+//! the sound is played. The Game Boy's sample rate never quite matches the sound card's, so the
+//! APU's sample period is nudged by up to half a percent, too little to hear, to hold the queue
+//! near the target instead of letting it run dry (crackling) or fill up (lag).
+
+use sdl2::audio::AudioQueue;
+use std::time::{Duration, Instant};
+
+/// How much audio to keep queued, in bytes: two of the sound card's buffers of 8-bit stereo
+/// samples. A bigger buffer means more latency, but more slack when a frame takes long.
+fn target_queued_bytes(queue: &AudioQueue<u8>) -> u32 {
+    2 * u32::from(queue.spec().samples) * 2
+}
+
+/// The furthest the sample period is stretched or shrunk from its nominal length.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Give up waiting for the queue after this long, e.g. if the audio device stalled, so the game
+/// doesn't freeze with it.
+const MAX_WAIT: Duration = Duration::from_millis(50);
+
+/// How much to scale the APU's sample period by for the next frame, given how much audio is
+/// queued. Above the target, samples are spread out so fewer are made, and below it the opposite.
+pub fn sample_period_scale(queue: &AudioQueue<u8>) -> f64 {
+    let fill = f64::from(queue.size()) / f64::from(target_queued_bytes(queue));
+    1.0 + MAX_RATE_DELTA * (fill - 1.0).max(-1.0).min(1.0)
+}
+
+/// Wait until the queue has drained to the target fill level. Audio far beyond it, e.g. from a
+/// stall, is dropped instead of lagging behind the picture.
+pub fn wait_for_queue(queue: &AudioQueue<u8>) {
+    let target = target_queued_bytes(queue);
+    if queue.size() > 4 * target {
+        queue.clear();
+        return;
+    }
+    let deadline = Instant::now() + MAX_WAIT;
+    while queue.size() > target && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
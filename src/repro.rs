@@ -0,0 +1,92 @@
+//! Reproduction bundles: a starting savestate plus every input change after it, tied to the ROM
+//! they were recorded with. Replaying a bundle runs the emulator through exactly the same states,
+//! which makes emulation bugs easy to report and to reproduce.
+
+use crate::checksum::crc32;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use failure_derive::Fail;
+
+const MAGIC: &[u8; 8] = b"RUGBYREP";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Fail)]
+pub enum ReproError {
+    #[fail(display = "bundle was recorded with ROM {:08X}, but this ROM is {:08X}", expected, actual)]
+    RomMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+/// A change to the set of pressed keys, as returned by `Joypad::pressed`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    /// The CPU cycle count at which the change happened.
+    pub cycle: u64,
+    pub pressed: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Repro {
+    /// CRC-32 of the ROM the bundle was recorded with.
+    pub rom_crc32: u32,
+
+    /// The savestate to start playback from.
+    pub state: Vec<u8>,
+
+    /// Input changes after the starting state, in order.
+    pub inputs: Vec<InputEvent>,
+}
+
+impl Repro {
+    /// Start a bundle from a savestate taken at `cycle`, when the keys in `pressed` were held.
+    pub fn new(rom: &[u8], state: Vec<u8>, cycle: u64, pressed: u8) -> Self {
+        Repro {
+            rom_crc32: crc32(rom),
+            state,
+            // The first event repeats what the savestate already holds, so `record_input` has
+            // something to compare against.
+            inputs: vec![InputEvent { cycle, pressed }],
+        }
+    }
+
+    /// Record the keys pressed at the given cycle, if they changed since the last event.
+    pub fn record_input(&mut self, cycle: u64, pressed: u8) {
+        if self.inputs.last().map(|e| e.pressed) != Some(pressed) {
+            self.inputs.push(InputEvent { cycle, pressed });
+        }
+    }
+
+    /// Check that `rom` is the ROM this bundle was recorded with.
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), ReproError> {
+        let actual = crc32(rom);
+        if actual != self.rom_crc32 {
+            return Err(ReproError::RomMismatch { expected: self.rom_crc32, actual });
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::with_header(MAGIC, VERSION);
+        w.u32(self.rom_crc32);
+        w.bytes(&self.state);
+        w.u32(self.inputs.len() as u32);
+        for event in &self.inputs {
+            w.u64(event.cycle);
+            w.u8(event.pressed);
+        }
+        w.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SaveStateError> {
+        let mut r = StateReader::with_header(data, MAGIC, VERSION)?;
+        let rom_crc32 = r.u32()?;
+        let state = r.bytes()?.to_vec();
+        let len = r.u32()?;
+        let mut inputs = Vec::new();
+        for _ in 0..len {
+            inputs.push(InputEvent { cycle: r.u64()?, pressed: r.u8()? });
+        }
+        Ok(Repro { rom_crc32, state, inputs })
+    }
+}
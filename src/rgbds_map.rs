@@ -0,0 +1,188 @@
+//! Module for reading map files written by the RGBDS linker (`rgblink -m`).
+//!
+//! See [the rgblink documentation](https://rgbds.gbdev.io/docs/rgblink.1) for details. Map files
+//! list every section along with its bank, address range, and the labels inside it, which lets us
+//! attribute addresses to the function and section they belong to. Both the old layout and the
+//! tab-indented layout of newer RGBDS versions are accepted.
+
+use failure_derive::Fail;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::io::BufRead;
+
+#[derive(Clone, Debug)]
+pub struct RgbdsMap {
+    /// All sections, in the order they appear in the file.
+    pub sections: Vec<MapSection>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MapSection {
+    pub name: String,
+
+    /// The memory region, e.g. `ROM0`, `ROMX`, or `WRAM0`.
+    pub region: String,
+
+    pub bank: u16,
+    pub start: u16,
+
+    /// The number of bytes in the section. Empty sections take up no addresses.
+    pub size: u32,
+
+    /// The labels defined in this section, sorted by address.
+    pub symbols: Vec<MapSymbol>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MapSymbol {
+    pub addr: u16,
+    pub name: String,
+}
+
+#[derive(Debug, Fail)]
+pub enum RgbdsMapError {
+    #[fail(display = "section outside of any bank: '{}'", _0)]
+    SectionBeforeBank(String),
+
+    #[fail(display = "symbol outside of any section: '{}'", _0)]
+    SymbolBeforeSection(String),
+
+    #[fail(display = "IO error: {}", _0)]
+    Io(#[fail(cause)] std::io::Error),
+}
+
+impl std::convert::From<std::io::Error> for RgbdsMapError {
+    fn from(e: std::io::Error) -> Self { RgbdsMapError::Io(e) }
+}
+
+lazy_static! {
+    static ref BANK_REGEX: Regex =
+        Regex::new(r"^([[:alnum:]]+) bank #([[:digit:]]+):$").unwrap();
+
+    static ref SECTION_REGEX: Regex =
+        Regex::new(r#"^SECTION: \$([[:xdigit:]]{4})(?:-\$[[:xdigit:]]{4})? \(\$([[:xdigit:]]+) bytes?\) \["(.*)"\]$"#)
+            .unwrap();
+
+    static ref SYMBOL_REGEX: Regex =
+        Regex::new(r"^\$([[:xdigit:]]{4}) = (.*)$").unwrap();
+}
+
+impl MapSection {
+    /// Check whether this section covers `addr` while `bank` is mapped in. The bank only matters
+    /// for switchable regions.
+    pub fn contains(&self, bank: u16, addr: u16) -> bool {
+        let in_range = addr >= self.start && (addr as u32) < self.start as u32 + self.size;
+        in_range && (self.region != "ROMX" || self.bank == bank)
+    }
+
+    /// The last label at or before `addr`, which is usually the function containing it.
+    pub fn symbol_at(&self, addr: u16) -> Option<&MapSymbol> {
+        self.symbols.iter().take_while(|sym| sym.addr <= addr).last()
+    }
+}
+
+impl RgbdsMap {
+    pub fn parse(mut input: impl BufRead) -> Result<Self, RgbdsMapError> {
+        let mut line = String::new();
+        let mut current_bank: Option<(String, u16)> = None;
+        let mut sections: Vec<MapSection> = Vec::new();
+
+        loop {
+            line.clear();
+            input.read_line(&mut line)?;
+            if line.is_empty() { break; }
+
+            let src = line.trim();
+
+            if let Some(cap) = BANK_REGEX.captures(src) {
+                let bank = cap[2].parse().unwrap_or(0);
+                current_bank = Some((String::from(&cap[1]), bank));
+                continue;
+            }
+
+            if let Some(cap) = SECTION_REGEX.captures(src) {
+                let (region, bank) = match &current_bank {
+                    Some((region, bank)) => (region.clone(), *bank),
+                    None => return Err(RgbdsMapError::SectionBeforeBank(String::from(src))),
+                };
+                sections.push(MapSection {
+                    name: String::from(&cap[3]),
+                    region,
+                    bank,
+                    start: u16::from_str_radix(&cap[1], 16).unwrap(),
+                    size: u32::from_str_radix(&cap[2], 16).unwrap_or(0),
+                    symbols: Vec::new(),
+                });
+                continue;
+            }
+
+            if let Some(cap) = SYMBOL_REGEX.captures(src) {
+                let section = match sections.last_mut() {
+                    Some(section) => section,
+                    None => return Err(RgbdsMapError::SymbolBeforeSection(String::from(src))),
+                };
+                section.symbols.push(MapSymbol {
+                    addr: u16::from_str_radix(&cap[1], 16).unwrap(),
+                    name: String::from(&cap[2]),
+                });
+                continue;
+            }
+
+            // Everything else is summary information like `SLACK:` and `EMPTY` lines, which
+            // varies between RGBDS versions and isn't needed.
+        }
+
+        for section in &mut sections {
+            section.symbols.sort_by_key(|sym| sym.addr);
+        }
+
+        Ok(RgbdsMap { sections })
+    }
+
+    /// Find the section covering `addr` while `bank` is mapped in.
+    pub fn section_at(&self, bank: u16, addr: u16) -> Option<&MapSection> {
+        self.sections.iter().find(|section| section.contains(bank, addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_bank_header() {
+        let map = RgbdsMap::parse(&b"ROMX bank #3:\n\tSECTION: $4000-$40FF ($0100 bytes) [\"Level\"]\n"[..]).unwrap();
+        assert_eq!(map.sections.len(), 1);
+        assert_eq!(map.sections[0].region, "ROMX");
+        assert_eq!(map.sections[0].bank, 3);
+        assert!(map.section_at(3, 0x40FF).is_some());
+        assert!(map.section_at(2, 0x4000).is_none());
+    }
+
+    #[test]
+    fn test_parse_section_and_symbols() {
+        let map = RgbdsMap::parse(&br#"ROM0 bank #0:
+  SECTION: $0150-$01FF ($00B0 bytes) ["Main"]
+           $0180 = Main.loop
+           $0150 = Main
+    SLACK: $3E50 bytes
+"#[..]).unwrap();
+        let section = &map.sections[0];
+        assert_eq!((section.name.as_str(), section.start, section.size), ("Main", 0x150, 0xB0));
+        let names: Vec<_> = section.symbols.iter().map(|sym| (sym.addr, sym.name.as_str())).collect();
+        assert_eq!(names, [(0x150, "Main"), (0x180, "Main.loop")]);
+        assert_eq!(section.symbol_at(0x17F).unwrap().name, "Main");
+    }
+
+    #[test]
+    fn test_parse_rejects_lines_out_of_place() {
+        match RgbdsMap::parse(&b"  SECTION: $0000-$0007 ($0008 bytes) [\"RST0\"]\n"[..]) {
+            Err(RgbdsMapError::SectionBeforeBank(_)) => {}
+            other => panic!("expected SectionBeforeBank, got {:?}", other),
+        }
+        match RgbdsMap::parse(&b"ROM0 bank #0:\n  $0150 = Main\n"[..]) {
+            Err(RgbdsMapError::SymbolBeforeSection(_)) => {}
+            other => panic!("expected SymbolBeforeSection, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Identifying ROMs by checksum in a DAT file, the ROM databases No-Intro and other preservation
+//! groups publish. Both of their formats are read: Logiqx XML, with a `<game name="...">` element
+//! around each `<rom ... size="..." crc="..."/>`, and clrmamepro's `game ( name "..." rom ( ...
+//! size N crc XXXXXXXX ) )`. Only the game's name and each ROM's size and CRC-32 are used.
+//!
+//! Names follow the No-Intro convention of a title followed by tags in parentheses, like
+//! `Tetris (World) (Rev 1)`, where the first tag is the region and a `Rev` or `v` tag the revision.
+
+use failure::ResultExt;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
+
+lazy_static! {
+    static ref XML_GAME: Regex = Regex::new(r#"(?s)<(?:game|machine)\s[^>]*?name="([^"]*)"(.*?)</(?:game|machine)>"#).unwrap();
+    static ref XML_ROM: Regex = Regex::new(r#"<rom\s[^>]*>"#).unwrap();
+    static ref XML_ATTR: Regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+    static ref CMP_GAME: Regex = Regex::new(r#"(?s)\bgame\s*\(\s*name\s+"([^"]*)"(.*?)\n\)"#).unwrap();
+    static ref CMP_ROM: Regex = Regex::new(r#"\brom\s*\((.*?)\)"#).unwrap();
+    static ref CMP_FIELD: Regex = Regex::new(r#"(\w+)\s+("[^"]*"|\S+)"#).unwrap();
+    static ref TAG: Regex = Regex::new(r#"\(([^)]*)\)"#).unwrap();
+}
+
+/// A game as the database names it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// The full name, tags and all.
+    pub name: String,
+
+    /// The name without its tags.
+    pub title: String,
+
+    pub region: Option<String>,
+    pub revision: Option<String>,
+}
+
+pub struct RomDb {
+    /// Entries by size and CRC-32. The size tells apart the rare ROMs whose CRCs collide.
+    entries: HashMap<(usize, u32), Entry>,
+}
+
+impl RomDb {
+    pub fn load(path: &Path) -> Result<RomDb, failure::Error> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|_| format!("Failed to read ROM database: {}", path.display()))?;
+        let db = RomDb::parse(&text);
+        if db.entries.is_empty() {
+            failure::bail!("No ROMs found in ROM database: {}", path.display());
+        }
+        Ok(db)
+    }
+
+    /// Read the games in a DAT file, in either format. Games and ROMs that can't be read are
+    /// skipped, since DAT files are big and often hand-edited.
+    pub fn parse(text: &str) -> RomDb {
+        let mut entries = HashMap::new();
+        let mut add = |name: &str, size: Option<&str>, crc: Option<&str>| {
+            let size = size.and_then(|size| size.parse().ok());
+            let crc = crc.and_then(|crc| u32::from_str_radix(crc, 16).ok());
+            if let (Some(size), Some(crc)) = (size, crc) {
+                entries.insert((size, crc), Entry::from_name(&unescape_xml(name)));
+            }
+        };
+
+        for game in XML_GAME.captures_iter(text) {
+            for rom in XML_ROM.find_iter(&game[2]) {
+                let attrs: HashMap<_, _> = XML_ATTR.captures_iter(rom.as_str())
+                    .map(|attr| (attr.get(1).unwrap().as_str(), attr.get(2).unwrap().as_str()))
+                    .collect();
+                add(&game[1], attrs.get("size").cloned(), attrs.get("crc").cloned());
+            }
+        }
+        for game in CMP_GAME.captures_iter(text) {
+            for rom in CMP_ROM.captures_iter(&game[2]) {
+                let fields: HashMap<_, _> = CMP_FIELD.captures_iter(&rom[1])
+                    .map(|field| (field.get(1).unwrap().as_str(), field.get(2).unwrap().as_str().trim_matches('"')))
+                    .collect();
+                add(&game[1], fields.get("size").cloned(), fields.get("crc").cloned());
+            }
+        }
+        RomDb { entries }
+    }
+
+    pub fn lookup(&self, size: usize, crc32: u32) -> Option<&Entry> {
+        self.entries.get(&(size, crc32))
+    }
+}
+
+impl Entry {
+    fn from_name(name: &str) -> Entry {
+        let title = match name.find(" (") {
+            Some(i) => &name[..i],
+            None => name,
+        };
+        let mut tags = TAG.captures_iter(&name[title.len()..]).map(|tag| tag.get(1).unwrap().as_str());
+        let region = tags.next().map(str::to_string);
+        let revision = tags.find(|tag| {
+            tag.starts_with("Rev ") || tag.starts_with('v') && tag[1..].starts_with(|c: char| c.is_ascii_digit())
+        });
+        Entry {
+            name: name.to_string(),
+            title: title.to_string(),
+            region,
+            revision: revision.map(str::to_string),
+        }
+    }
+}
+
+/// Decode the entities DAT files use in names, like `&amp;` in `Mario &amp; Yoshi`.
+fn unescape_xml(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entry_from_name() {
+        assert_eq!(Entry::from_name("Tetris (World) (Rev 1)"), Entry {
+            name: "Tetris (World) (Rev 1)".to_string(),
+            title: "Tetris".to_string(),
+            region: Some("World".to_string()),
+            revision: Some("Rev 1".to_string()),
+        });
+        let entry = Entry::from_name("Pokemon - Red Version (USA, Europe) (SGB Enhanced) (v1.1)");
+        assert_eq!(entry.title, "Pokemon - Red Version");
+        assert_eq!(entry.region.as_ref().map(String::as_str), Some("USA, Europe"));
+        assert_eq!(entry.revision.as_ref().map(String::as_str), Some("v1.1"));
+
+        // A tag starting with v isn't a version without a number after it.
+        let entry = Entry::from_name("Game (Japan) (virtual)");
+        assert_eq!(entry.revision, None);
+        let entry = Entry::from_name("Homebrew");
+        assert_eq!((entry.title.as_str(), entry.region, entry.revision), ("Homebrew", None, None));
+    }
+
+    #[test]
+    fn test_rom_db_parse_xml() {
+        let db = RomDb::parse(r#"
+            <datafile>
+                <game name="Mario &amp; Yoshi (Europe)">
+                    <rom name="a.gb" size="65536" crc="0a1b2c3d" md5="x"/>
+                </game>
+                <game name="Broken (USA)">
+                    <rom name="b.gb" size="big" crc="12345678"/>
+                    <rom name="c.gb" size="32768"/>
+                </game>
+                <machine name="Arcade (World)">
+                    <rom name="d.gb" crc="DEADBEEF" size="32768"/>
+                </machine>
+            </datafile>
+        "#);
+        assert_eq!(db.entries.len(), 2);
+        assert_eq!(db.lookup(65536, 0x0A1B_2C3D).unwrap().title, "Mario & Yoshi");
+        assert_eq!(db.lookup(32768, 0xDEAD_BEEF).unwrap().name, "Arcade (World)");
+        assert!(db.lookup(32768, 0x0A1B_2C3D).is_none());
+    }
+
+    #[test]
+    fn test_rom_db_parse_clrmamepro() {
+        let db = RomDb::parse(concat!(
+            "clrmamepro (\n\tname \"Nintendo - Game Boy\"\n)\n\n",
+            "game (\n\tname \"Tetris (World) (Rev 1)\"\n",
+            "\trom ( name \"Tetris.gb\" size 32768 crc 46DF91AD md5 X )\n)\n\n",
+            "game (\n\tname \"Quoted (Japan)\"\n",
+            "\trom ( name \"Quoted.gb\" size \"65536\" crc \"0000ABCD\" )\n)\n",
+        ));
+        assert_eq!(db.entries.len(), 2);
+        let entry = db.lookup(32768, 0x46DF_91AD).unwrap();
+        assert_eq!(entry.revision.as_ref().map(String::as_str), Some("Rev 1"));
+        assert_eq!(db.lookup(65536, 0xABCD).unwrap().title, "Quoted");
+    }
+}
@@ -0,0 +1,117 @@
+//! `rugby rom`, for fixing up ROM files: padding a ROM to a size a real cartridge (or a flashcart
+//! that expects one) can have, and trimming the padding off again.
+
+use crate::checksum::{global_checksum, header_checksum};
+use failure_derive::Fail;
+
+/// The smallest ROM a cartridge can have, two 16 KiB banks. Header size byte 0 means this size,
+/// and each step up doubles it.
+const MIN_ROM_SIZE: usize = 0x8000;
+
+/// The largest size the header's size byte can describe (0x08).
+const MAX_ROM_SIZE: usize = MIN_ROM_SIZE << 8;
+
+/// The end of the cartridge header, which trimming never cuts into.
+const HEADER_END: usize = 0x150;
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum RomToolError {
+    #[fail(display = "ROM is {} bytes, which is too short to contain a header", _0)]
+    TooShort(usize),
+
+    #[fail(display = "ROM is {} bytes, but cartridges hold at most 8 MiB", _0)]
+    TooLarge(usize),
+}
+
+/// Pad `rom` with `fill` to the next power of two (at least 32 KiB), and fix the size byte and
+/// checksums in the header to match.
+pub fn pad(rom: &mut Vec<u8>, fill: u8) -> Result<(), RomToolError> {
+    if rom.len() < HEADER_END {
+        return Err(RomToolError::TooShort(rom.len()));
+    }
+    let size = rom.len().next_power_of_two().max(MIN_ROM_SIZE);
+    if size > MAX_ROM_SIZE {
+        return Err(RomToolError::TooLarge(rom.len()));
+    }
+    rom.resize(size, fill);
+    rom[0x148] = (size / MIN_ROM_SIZE).trailing_zeros() as u8;
+    rom[0x14D] = header_checksum(rom);
+    let global = global_checksum(rom);
+    rom[0x14E] = (global >> 8) as u8;
+    rom[0x14F] = global as u8;
+    Ok(())
+}
+
+/// Strip the run of 0xFF or 0x00 bytes at the end of `rom`, stopping at the header. The header is
+/// left alone, since it still describes the cartridge the ROM belongs on.
+pub fn trim(rom: &mut Vec<u8>) -> Result<(), RomToolError> {
+    if rom.len() < HEADER_END {
+        return Err(RomToolError::TooShort(rom.len()));
+    }
+    let fill = rom[rom.len() - 1];
+    if fill != 0xFF && fill != 0x00 {
+        return Ok(());
+    }
+    let end = rom.iter().rposition(|&byte| byte != fill).map_or(0, |i| i + 1);
+    rom.truncate(end.max(HEADER_END));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 0xFE + 1) as u8).collect()
+    }
+
+    #[test]
+    fn test_pad_to_power_of_two() {
+        let mut padded = rom(0x9000);
+        pad(&mut padded, 0xFF).unwrap();
+        assert_eq!(padded.len(), 0x10000);
+        assert!(padded[0x9000..].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(padded[0x148], 1);
+        assert_eq!(padded[0x14D], header_checksum(&padded));
+        assert_eq!(u16::from_be_bytes([padded[0x14E], padded[0x14F]]), global_checksum(&padded));
+    }
+
+    #[test]
+    fn test_pad_small_rom_to_minimum() {
+        let mut padded = rom(HEADER_END);
+        pad(&mut padded, 0x00).unwrap();
+        assert_eq!(padded.len(), MIN_ROM_SIZE);
+        assert_eq!(padded[0x148], 0);
+    }
+
+    #[test]
+    fn test_pad_errors() {
+        assert_eq!(pad(&mut rom(HEADER_END - 1), 0xFF), Err(RomToolError::TooShort(HEADER_END - 1)));
+        assert_eq!(pad(&mut rom(MAX_ROM_SIZE + 1), 0xFF), Err(RomToolError::TooLarge(MAX_ROM_SIZE + 1)));
+    }
+
+    #[test]
+    fn test_trim_undoes_pad() {
+        let original = rom(0x9000);
+        for &fill in &[0x00, 0xFF] {
+            let mut padded = original.clone();
+            pad(&mut padded, fill).unwrap();
+            trim(&mut padded).unwrap();
+            assert_eq!(padded.len(), original.len());
+            assert_eq!(padded[HEADER_END..], original[HEADER_END..]);
+        }
+    }
+
+    #[test]
+    fn test_trim_stops_at_header() {
+        let mut blank = vec![0xFF; 0x8000];
+        trim(&mut blank).unwrap();
+        assert_eq!(blank.len(), HEADER_END);
+
+        let mut unpadded = rom(0x8000);
+        trim(&mut unpadded).unwrap();
+        assert_eq!(unpadded.len(), 0x8000);
+
+        assert_eq!(trim(&mut rom(0x100)), Err(RomToolError::TooShort(0x100)));
+    }
+}
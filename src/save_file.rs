@@ -0,0 +1,305 @@
+//! Writing battery-backed cartridge RAM back to the save file while a game runs, so a crash or
+//! power loss doesn't lose progress.
+//!
+//! Games write to cartridge RAM constantly, so writes are batched: the RAM is compared against
+//! what was last written, and only rewritten if it changed, at most once per flush interval, and
+//! right away when emulation pauses or stops. This keeps writes rare on SD cards and other flash
+//! storage. Each write goes to a temporary file which is synced and then renamed over the save
+//! file, so the save file is never left half-written.
+//!
+//! Next to the save file is a small TOML sidecar with the same name and a `.rtc` extension, which
+//! holds the save format version, when the game was last played, and the state of the MBC3's or
+//! HuC3's clock. The time is stored in UTC, so a save copied to another machine's clock keeps
+//! counting from the right moment. Saves from before the sidecar existed are just the RAM, and
+//! get a sidecar the first time they're written.
+//!
+//! MBC3 saves also end with the 48-byte clock footer VBA-M, BGB, and most other emulators use, so
+//! they can be moved between emulators with the time intact. It holds the running and latched
+//! clock registers as little-endian 32-bit words, then the Unix time it was written as a 64-bit
+//! one. The 44-byte variant with a 32-bit time is read too. The save is only rewritten when the RAM
+//! or the clock registers changed, so while the clock is stopped the footer's time falls behind
+//! the sidecar's. When a save has both, the running clock comes from whichever was written later,
+//! since another emulator may have played the save without updating the sidecar.
+
+use failure::ResultExt;
+use failure_derive::Fail;
+use log::{info, warn};
+use rugby::cart::{Cart, HuC3Clock, Rtc};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The newest sidecar format this version understands. Version 2 added `huc3_clock`.
+const FORMAT_VERSION: u32 = 2;
+
+/// The sizes of the clock footer after an MBC3 save's RAM, with a 64-bit or 32-bit timestamp.
+pub const RTC_FOOTER_SIZES: [usize; 2] = [48, 44];
+
+/// The size of the running and latched clock registers at the start of the footer.
+const RTC_REGISTERS_SIZE: usize = 40;
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum SaveFileError {
+    #[fail(display = "format version {} is newer than this version of Rugby supports ({})", _0, _1)]
+    NewerFormat(u32, u32),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Metadata {
+    format_version: u32,
+
+    /// When the save was last written, in seconds since the Unix epoch.
+    last_played: u64,
+
+    rtc: Option<RtcMetadata>,
+    huc3_clock: Option<HuC3ClockMetadata>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RtcMetadata {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct HuC3ClockMetadata {
+    seconds: u8,
+    minutes: u16,
+    days: u16,
+}
+
+pub struct SaveFile {
+    path: PathBuf,
+    metadata_path: PathBuf,
+
+    /// The RAM contents last written to (or read from) the file.
+    written: Box<[u8]>,
+
+    /// The clock registers in the footer last written to (or read from) the file, for MBC3 carts.
+    written_clock: Option<Vec<u8>>,
+
+    /// How often to check for changes. `None` only flushes on pause and exit.
+    interval: Option<Duration>,
+
+    last_check: Instant,
+    was_paused: bool,
+
+    /// Whether the sidecar needs writing even if the RAM didn't change, because it's missing or
+    /// there's a clock in it.
+    metadata_stale: bool,
+}
+
+impl SaveFile {
+    /// Track the save file at `path`, whose contents are already loaded into `cart` (or which
+    /// doesn't exist yet, if the cart's RAM is freshly initialized). The clock is restored from the
+    /// sidecar, and caught up on the time since the game was last played.
+    pub fn open(path: &Path, cart: &mut Cart, interval: Option<Duration>) -> Result<Self, failure::Error> {
+        let metadata_path = path.with_extension("rtc");
+        let exists = metadata_path.exists();
+        let mut last_played = None;
+        if exists {
+            let text = std::fs::read_to_string(&metadata_path)
+                .with_context(|_| format!("Failed to read save metadata: {}", metadata_path.display()))?;
+            let metadata: Metadata = toml::from_str(&text)
+                .with_context(|_| format!("Failed to parse save metadata: {}", metadata_path.display()))?;
+            restore(&metadata, cart)
+                .with_context(|_| format!("Invalid save metadata: {}", metadata_path.display()))?;
+            last_played = Some(metadata.last_played);
+        } else if path.exists() {
+            info!("No metadata for save file {}, it will be created on the next write", path.display());
+        }
+        let mut written_clock = None;
+        if cart.rtc().is_some() {
+            let data = match std::fs::read(path) {
+                Ok(data) => data,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e).with_context(|_| format!("Failed to read save file: {}", path.display()))?,
+            };
+            if let Some(footer) = data.get(cart.ram().len()..) {
+                if RTC_FOOTER_SIZES.contains(&footer.len()) {
+                    restore_rtc_footer(footer, cart, last_played);
+                    written_clock = Some(footer[..RTC_REGISTERS_SIZE].to_vec());
+                }
+            }
+        }
+
+        Ok(SaveFile {
+            path: path.to_path_buf(),
+            metadata_path,
+            written: cart.ram().into(),
+            written_clock,
+            interval,
+            last_check: Instant::now(),
+            was_paused: false,
+            metadata_stale: !exists || cart.has_clock(),
+        })
+    }
+
+    pub fn is_dirty(&self, ram: &[u8]) -> bool {
+        *self.written != *ram
+    }
+
+    /// Call once per frame. Flushes if the interval has passed or emulation just paused.
+    pub fn update(&mut self, cart: &Cart, paused: bool) -> io::Result<()> {
+        let just_paused = paused && !self.was_paused;
+        self.was_paused = paused;
+        let due = self.interval.map_or(false, |interval| self.last_check.elapsed() >= interval);
+        if !due && !just_paused {
+            return Ok(());
+        }
+        self.last_check = Instant::now();
+        self.flush(cart)
+    }
+
+    /// Write the cart's RAM to the save file if it or the MBC3 clock changed since the last write,
+    /// and the sidecar along with it. Carts with a clock always get a fresh sidecar, since the time
+    /// it was written moves on even when the clock is stopped.
+    pub fn flush(&mut self, cart: &Cart) -> io::Result<()> {
+        let ram = cart.ram();
+        let dirty = self.is_dirty(ram);
+        if !dirty && !self.metadata_stale {
+            return Ok(());
+        }
+
+        let footer = rtc_footer(cart);
+        let clock = footer.as_ref().map(|footer| footer[..RTC_REGISTERS_SIZE].to_vec());
+        if dirty || clock != self.written_clock {
+            match &footer {
+                Some(footer) => write_atomically(&self.path, &[ram, footer].concat())?,
+                None => write_atomically(&self.path, ram)?,
+            }
+            if self.written.len() == ram.len() {
+                self.written.copy_from_slice(ram);
+            } else {
+                self.written = ram.into();
+            }
+            self.written_clock = clock;
+        }
+
+        let metadata = Metadata {
+            format_version: FORMAT_VERSION,
+            last_played: unix_time(),
+            rtc: cart.rtc().map(|rtc| RtcMetadata {
+                seconds: rtc.seconds,
+                minutes: rtc.minutes,
+                hours: rtc.hours,
+                days: rtc.days,
+                halted: rtc.halted,
+                day_carry: rtc.day_carry,
+            }),
+            huc3_clock: cart.huc3_clock().map(|clock| HuC3ClockMetadata {
+                seconds: clock.seconds,
+                minutes: clock.minutes,
+                days: clock.days,
+            }),
+        };
+        let text = toml::to_string(&metadata).expect("save metadata is always valid TOML");
+        write_atomically(&self.metadata_path, text.as_bytes())?;
+        self.metadata_stale = cart.has_clock();
+        Ok(())
+    }
+}
+
+/// Load the clock from the sidecar into `cart`, advanced by the time since it was written.
+fn restore(metadata: &Metadata, cart: &mut Cart) -> Result<(), SaveFileError> {
+    if metadata.format_version > FORMAT_VERSION {
+        return Err(SaveFileError::NewerFormat(metadata.format_version, FORMAT_VERSION));
+    }
+    let now = unix_time();
+    if now < metadata.last_played && cart.has_clock() {
+        warn!("Save was last played in the future, is this computer's clock wrong? Not advancing the clock");
+    }
+    let elapsed = now.saturating_sub(metadata.last_played);
+    if let Some(saved) = &metadata.rtc {
+        let mut rtc = Rtc {
+            seconds: saved.seconds,
+            minutes: saved.minutes,
+            hours: saved.hours,
+            days: saved.days & 0x1FF,
+            halted: saved.halted,
+            day_carry: saved.day_carry,
+        };
+        rtc.advance(elapsed);
+        cart.set_rtc(rtc);
+    }
+    if let Some(saved) = &metadata.huc3_clock {
+        let mut clock = HuC3Clock { seconds: saved.seconds % 60, minutes: saved.minutes, days: saved.days & 0xFFF };
+        clock.advance(elapsed);
+        cart.set_huc3_clock(clock);
+    }
+    Ok(())
+}
+
+/// Load the MBC3 clock from a save's footer, advanced by the time since it was written. The running
+/// clock is only taken from the footer if it's newer than the sidecar's, written at `last_played`.
+fn restore_rtc_footer(footer: &[u8], cart: &mut Cart, last_played: Option<u64>) {
+    let word = |i: usize| footer[i * 4];
+    let registers = |first: usize| [word(first), word(first + 1), word(first + 2), word(first + 3), word(first + 4)];
+    let mut time = [0; 8];
+    time[..footer.len() - RTC_REGISTERS_SIZE].copy_from_slice(&footer[RTC_REGISTERS_SIZE..]);
+    let saved_at = u64::from_le_bytes(time);
+    cart.set_rtc_latched(registers(5));
+    if last_played.map_or(false, |last_played| saved_at <= last_played) {
+        return;
+    }
+
+    let now = unix_time();
+    if now < saved_at {
+        warn!("Save's clock was written in the future, is this computer's clock wrong? Not advancing the clock");
+    }
+    let mut rtc = Rtc::from_registers(registers(0));
+    rtc.advance(now.saturating_sub(saved_at));
+    cart.set_rtc(rtc);
+}
+
+/// The footer holding the MBC3 clock as it is now, for carts with one.
+fn rtc_footer(cart: &Cart) -> Option<Vec<u8>> {
+    let clock = cart.rtc()?.registers();
+    let latched = cart.debug_state().rtc.unwrap_or(clock);
+    let mut footer = Vec::with_capacity(RTC_FOOTER_SIZES[0]);
+    for &register in clock.iter().chain(&latched) {
+        footer.extend_from_slice(&u32::from(register).to_le_bytes());
+    }
+    footer.extend_from_slice(&unix_time().to_le_bytes());
+    Some(footer)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Replace the file at `path` with `data` through a temporary file, so it's never half-written.
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    sync_dir(path)
+}
+
+/// Make a rename in the file's directory durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened as files outside Unix, so there's no portable way to do this.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
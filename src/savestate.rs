@@ -0,0 +1,300 @@
+//! Savestates: snapshots of the complete emulator state which can be restored later.
+//!
+//! A savestate is a series of chunks, one per emulated component. Each chunk has a 4-byte tag and
+//! a length, so readers can skip chunks they don't recognize and new components can be added
+//! without breaking old states.
+//!
+//! On disk, the chunks are compressed with zstd behind a short header holding a CRC-32 of the ROM
+//! the state was made with and a CRC-32 of the compressed chunks, so states for the wrong game
+//! and corrupted files are rejected before anything is loaded.
+
+use crate::checksum::crc32;
+use failure_derive::Fail;
+use log::warn;
+use std::io::Read;
+
+const MAGIC: &[u8; 8] = b"RUGBYSAV";
+const VERSION: u8 = 2;
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The most a state may unpack to, far more than any real one, so a crafted file can't make
+/// loading it allocate without bound.
+pub const MAX_UNPACKED_SIZE: usize = 16 << 20;
+
+#[derive(Clone, Debug, Fail, PartialEq)]
+#[non_exhaustive]
+pub enum SaveStateError {
+    #[fail(display = "unrecognized file format")]
+    BadMagic,
+
+    #[fail(display = "unsupported file format version {}", _0)]
+    UnsupportedVersion(u8),
+
+    #[fail(display = "file ended unexpectedly")]
+    UnexpectedEof,
+
+    #[fail(display = "savestate is missing the {} chunk", _0)]
+    MissingChunk(String),
+
+    #[fail(display = "invalid value in savestate: {}", _0)]
+    InvalidValue(&'static str),
+
+    #[fail(display = "savestate is corrupted")]
+    Corrupted,
+
+    #[fail(display = "savestate unpacks to more than {} bytes", _0)]
+    TooLarge(usize),
+
+    #[fail(display = "savestate was made with ROM {:08X}, but this ROM is {:08X}", expected, actual)]
+    RomMismatch {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+/// Implemented by every component that is part of a savestate.
+pub trait SaveState {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+/// Serializes values into a savestate. All integers are little-endian.
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Start a savestate. Write its chunks, then call `finish_savestate`.
+    pub fn new() -> Self {
+        StateWriter { buf: Vec::new() }
+    }
+
+    /// Start a file in some other format built out of the same primitives.
+    pub fn with_header(magic: &[u8; 8], version: u8) -> Self {
+        let mut buf = magic.to_vec();
+        buf.push(version);
+        StateWriter { buf }
+    }
+
+    /// Write a chunk containing whatever `component` saves.
+    pub fn chunk(&mut self, tag: &[u8; 4], component: &dyn SaveState) {
+        self.buf.extend_from_slice(tag);
+        let len_pos = self.buf.len();
+        self.u32(0);
+        component.save(self);
+        let len = (self.buf.len() - len_pos - 4) as u32;
+        self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Compress the chunks and add the savestate header, tying the state to the given ROM.
+    pub fn finish_savestate(self, rom: &[u8]) -> Vec<u8> {
+        let compressed = zstd::encode_all(&self.buf[..], COMPRESSION_LEVEL)
+            .expect("zstd compression into memory failed");
+        let mut w = StateWriter::with_header(MAGIC, VERSION);
+        w.u32(crc32(rom));
+        w.u32(crc32(&compressed));
+        w.buf.extend_from_slice(&compressed);
+        w.buf
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, val: usize) {
+        self.u64(val as u64);
+    }
+
+    /// Write a length-prefixed byte slice.
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.buf.extend_from_slice(val);
+    }
+}
+
+/// Deserializes values written by a `StateWriter`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Check the header of a file written with `StateWriter::with_header`.
+    pub fn with_header(data: &'a [u8], magic: &[u8; 8], version: u8) -> Result<Self, SaveStateError> {
+        if !data.starts_with(magic) {
+            return Err(SaveStateError::BadMagic);
+        }
+        let mut r = StateReader { data: &data[magic.len()..] };
+        let actual_version = r.u8()?;
+        if actual_version != version {
+            return Err(SaveStateError::UnsupportedVersion(actual_version));
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < len {
+            return Err(SaveStateError::UnexpectedEof);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, SaveStateError> {
+        Ok(self.u64()? as usize)
+    }
+
+    /// Whether everything has been read, e.g. to tell older chunks from ones with fields added
+    /// at the end.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Read a length-prefixed byte slice.
+    pub fn bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Read a length-prefixed byte slice into `dest`, which must have the same length.
+    pub fn bytes_into(&mut self, dest: &mut [u8]) -> Result<(), SaveStateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(SaveStateError::InvalidValue("memory size mismatch"));
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// What a savestate's header says.
+#[derive(Clone, Copy, Debug)]
+pub struct StateHeader {
+    pub version: u8,
+
+    /// The CRC-32 of the ROM the state was made with.
+    pub rom_crc32: u32,
+}
+
+/// Check a savestate's header and integrity, and return its uncompressed chunks.
+pub fn unpack(data: &[u8], rom: &[u8]) -> Result<Vec<u8>, SaveStateError> {
+    let (header, chunks) = unpack_any_rom(data)?;
+    let actual = crc32(rom);
+    if actual != header.rom_crc32 {
+        return Err(SaveStateError::RomMismatch { expected: header.rom_crc32, actual });
+    }
+    Ok(chunks)
+}
+
+/// Like `unpack`, but without checking which ROM the state is for, and returning the header.
+pub fn unpack_any_rom(data: &[u8]) -> Result<(StateHeader, Vec<u8>), SaveStateError> {
+    if !data.starts_with(MAGIC) {
+        return Err(SaveStateError::BadMagic);
+    }
+    let mut r = StateReader { data: &data[MAGIC.len()..] };
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let rom_crc32 = r.u32()?;
+    let chunks_crc32 = r.u32()?;
+    if crc32(r.data) != chunks_crc32 {
+        return Err(SaveStateError::Corrupted);
+    }
+    let mut chunks = Vec::new();
+    zstd::Decoder::new(r.data)
+        .and_then(|decoder| decoder.take(MAX_UNPACKED_SIZE as u64 + 1).read_to_end(&mut chunks))
+        .map_err(|_| SaveStateError::Corrupted)?;
+    if chunks.len() > MAX_UNPACKED_SIZE {
+        return Err(SaveStateError::TooLarge(MAX_UNPACKED_SIZE));
+    }
+    Ok((StateHeader { version: VERSION, rom_crc32 }, chunks))
+}
+
+/// The chunks of a savestate, keyed by tag.
+pub struct StateChunks<'a> {
+    chunks: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> StateChunks<'a> {
+    /// Split data unpacked by `unpack` into chunks.
+    pub fn parse(data: &'a [u8]) -> Result<Self, SaveStateError> {
+        let mut r = StateReader { data };
+        let mut chunks = Vec::new();
+        while !r.data.is_empty() {
+            let mut tag = [0; 4];
+            tag.copy_from_slice(r.take(4)?);
+            let len = r.u32()? as usize;
+            chunks.push((tag, r.take(len)?));
+        }
+        Ok(StateChunks { chunks })
+    }
+
+    /// Load `component` from the chunk with the given tag, which must be present.
+    pub fn load(&self, tag: &[u8; 4], component: &mut dyn SaveState) -> Result<(), SaveStateError> {
+        match self.chunks.iter().find(|(t, _)| t == tag) {
+            Some(&(_, data)) => component.load(&mut StateReader { data }),
+            None => Err(SaveStateError::MissingChunk(String::from_utf8_lossy(tag).into_owned())),
+        }
+    }
+
+    /// Whether there's a chunk with the given tag.
+    pub fn contains(&self, tag: &[u8; 4]) -> bool {
+        self.chunks.iter().any(|(t, _)| t == tag)
+    }
+
+    /// Warn about any chunks not in `known`, which probably come from a newer version.
+    pub fn warn_unknown(&self, known: &[&[u8; 4]]) {
+        for (tag, _) in &self.chunks {
+            if !known.contains(&tag) {
+                warn!("ignoring unknown savestate chunk {:?}", String::from_utf8_lossy(tag));
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! `rugby selftest`, which checks that a build emulates correctly and fast enough, for users to run
+//! before filing an issue. It runs a small test program for a few seconds of game time without a
+//! window or sound, compares hashes of the picture and sound it made against known ones, and times
+//! it. Nothing is written or sent anywhere; the results are only printed.
+//!
+//! The test program is built here rather than shipped as a ROM file, so it can't go missing. It
+//! fills VRAM with a pattern computed on the CPU, then scrolls it one pixel per vblank interrupt
+//! while changing the pitch of a square wave, which covers the CPU, interrupts, the PPU, and the
+//! APU.
+
+use crate::cart::Cart;
+use crate::checksum::{header_checksum, Crc32};
+use crate::cpu::Cpu;
+use crate::gpu::CYCLES_PER_FRAME;
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// How many frames to run: ten seconds of game time.
+const FRAMES: u32 = 600;
+
+/// The hashes of the last frame and of every sample over the whole run, as made by a correct
+/// build. Update these along with any change to the test program or intended change to the output.
+const EXPECTED_VIDEO: u32 = 0xFB93_B81B;
+const EXPECTED_AUDIO: u32 = 0x904E_5162;
+
+/// The Game Boy's frame rate.
+const GAME_BOY_FPS: f64 = 4_194_304.0 / CYCLES_PER_FRAME as f64;
+
+/// Run the test and print a summary. Returns whether everything passed.
+pub fn run(out: impl Write) -> Result<bool, failure::Error> {
+    let mut cpu = Cpu::new(Cart::from_rom(rom(), None)?);
+    cpu.audio.capture = Some(Vec::new());
+
+    let mut audio = Crc32::new();
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        cpu.run_frame();
+        if let Some(samples) = &mut cpu.audio.capture {
+            audio.update(samples);
+            samples.clear();
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mut video = Crc32::new();
+    for row in cpu.gpu.screen_buffer.iter() {
+        video.update(row);
+    }
+    let (video, audio) = (video.finish(), audio.finish());
+
+    let mut out = tabwriter::TabWriter::new(out);
+    write_result(&mut out, "video", video, EXPECTED_VIDEO)?;
+    write_result(&mut out, "audio", audio, EXPECTED_AUDIO)?;
+    let fps = f64::from(FRAMES) / elapsed;
+    writeln!(out, "speed\t{:.0} fps\t{:.1}x a Game Boy", fps, fps / GAME_BOY_FPS)?;
+    if cfg!(debug_assertions) {
+        writeln!(out, "\t\t-> This is a debug build. Build with --release for full speed.")?;
+    }
+    let passed = video == EXPECTED_VIDEO && audio == EXPECTED_AUDIO;
+    writeln!(out)?;
+    writeln!(out, "{}", if passed { "Self-test passed." } else { "Self-test FAILED." })?;
+    out.flush()?;
+    Ok(passed)
+}
+
+fn write_result(out: &mut impl Write, name: &str, actual: u32, expected: u32) -> io::Result<()> {
+    if actual == expected {
+        writeln!(out, "{}\tpass\t{:08x}", name, actual)
+    } else {
+        writeln!(out, "{}\tFAIL\t{:08x}, expected {:08x}", name, actual, expected)
+    }
+}
+
+/// The test program, as a 32 KiB ROM with no mapper.
+fn rom() -> Box<[u8]> {
+    const PROGRAM: &[u8] = &[
+        0xF3,                   //     di
+        0x31, 0xFE, 0xFF,       //     ld sp, $FFFE
+        0xF0, 0x44,             // .vblank: ldh a, [rLY]
+        0xFE, 0x90,             //     cp 144
+        0x38, 0xFA,             //     jr c, .vblank
+        0xAF,                   //     xor a
+        0xE0, 0x40,             //     ldh [rLCDC], a
+
+        0x21, 0x00, 0x80,       //     ld hl, $8000
+        0x7D,                   // .tiles: ld a, l
+        0x47,                   //     ld b, a
+        0x87,                   //     add a, a
+        0x87,                   //     add a, a
+        0x87,                   //     add a, a
+        0x80,                   //     add a, b
+        0xAC,                   //     xor h
+        0x22,                   //     ld [hl+], a
+        0x7C,                   //     ld a, h
+        0xFE, 0x81,             //     cp $81
+        0x20, 0xF3,             //     jr nz, .tiles
+
+        0x21, 0x00, 0x98,       //     ld hl, $9800
+        0x7D,                   // .map: ld a, l
+        0xE6, 0x0F,             //     and $0F
+        0x22,                   //     ld [hl+], a
+        0x7C,                   //     ld a, h
+        0xFE, 0x9C,             //     cp $9C
+        0x20, 0xF7,             //     jr nz, .map
+
+        0x3E, 0x80, 0xE0, 0x26, //     sound on
+        0x3E, 0x77, 0xE0, 0x24, //     full volume
+        0x3E, 0x11, 0xE0, 0x25, //     channel 1 to both sides
+        0x3E, 0x80, 0xE0, 0x11, //     50% duty
+        0x3E, 0xF0, 0xE0, 0x12, //     volume 15, no envelope
+        0x3E, 0x00, 0xE0, 0x13, //     frequency low
+        0x3E, 0x87, 0xE0, 0x14, //     trigger, frequency high
+
+        0x3E, 0xE4, 0xE0, 0x47, //     ld a, %11100100; ldh [rBGP], a
+        0x3E, 0x91, 0xE0, 0x40, //     LCD and background on, tiles at $8000
+        0x3E, 0x01, 0xE0, 0xFF, //     enable the vblank interrupt
+        0xFB,                   //     ei
+
+        0x76,                   // .loop: halt
+        0x00,                   //     nop
+        0xF0, 0x43,             //     ldh a, [rSCX]
+        0x3C,                   //     inc a
+        0xE0, 0x43,             //     ldh [rSCX], a
+        0xE0, 0x13,             //     ldh [rNR13], a
+        0x18, 0xF5,             //     jr .loop
+    ];
+    const VBLANK_HANDLER: usize = 0x40;
+    const ENTRY: usize = 0x100;
+    const START: usize = 0x150;
+
+    let mut rom = vec![0; 0x8000];
+    rom[VBLANK_HANDLER] = 0xD9; // reti
+    rom[ENTRY..ENTRY + 4].copy_from_slice(&[0x00, 0xC3, START as u8, (START >> 8) as u8]); // nop; jp START
+    rom[0x134..0x13C].copy_from_slice(b"SELFTEST");
+    rom[START..START + PROGRAM.len()].copy_from_slice(PROGRAM);
+    rom[0x14D] = header_checksum(&rom);
+    rom.into_boxed_slice()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        let mut out = Vec::new();
+        let passed = run(&mut out).unwrap();
+        assert!(passed, "{}", String::from_utf8_lossy(&out));
+    }
+}
@@ -0,0 +1,404 @@
+use crate::interrupts::{Interrupt, InterruptController};
+use crate::io_register::{IoDevice, IoRegister};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+use crate::timer;
+use log::warn;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// The bit of the system counter (see `Timer::system_counter`) whose falling edges clock the
+/// internal 8192 Hz serial clock, and the Game Boy Color's 262144 Hz fast clock.
+const CLOCK_BIT: u32 = 8;
+const FAST_CLOCK_BIT: u32 = 3;
+
+/// The serial link port. Unless `link` connects it to another emulator or a `device` is plugged
+/// in, every transfer shifts in 1 bits, as if the cable were unplugged.
+///
+/// With the internal clock, a bit is shifted out on each falling edge of a bit of the system
+/// counter, so like on hardware the first bit of a transfer can come early depending on where the
+/// counter is. With an external clock, the transfer stalls until the other side clocks each bit,
+/// which never happens without a partner.
+#[derive(Clone)]
+pub struct Serial {
+    /// The serial transfer data `SB` register 0xFF01
+    data: u8,
+
+    /// The serial control `SC` register 0xFF02 bit 7
+    transferring: bool,
+
+    /// The serial control `SC` register 0xFF02 bit 0
+    internal_clock: bool,
+
+    /// The serial control `SC` register 0xFF02 bit 1, selecting the fast clock. Game Boy Color
+    /// only.
+    fast_clock: bool,
+
+    /// Bits left to shift in the current transfer
+    bits_left: u8,
+
+    /// Whether this is a Game Boy Color, which has the fast clock.
+    pub cgb: bool,
+
+    /// If true, print every byte the game sends to stdout. Homebrew and test ROMs commonly use
+    /// this as a `printf` port.
+    pub echo: bool,
+
+    /// If set, every byte the game sends is also appended here, so several emulators printing at
+    /// once don't interleave their output on stdout.
+    pub capture: Option<Vec<u8>>,
+
+    /// The link cable to another emulator, if one is connected.
+    pub link: Option<LinkPort>,
+
+    /// The peripheral plugged into the port, if any.
+    pub device: Option<Box<dyn SerialDevice>>,
+
+    /// The byte `device` is shifting in during a transfer this side clocks.
+    device_byte: u8,
+}
+
+/// A peripheral plugged into the link port, like the Barcode Boy. It trades whole bytes with the
+/// Game Boy, one per transfer.
+pub trait SerialDevice: Send {
+    /// Take the byte the Game Boy shifts out in a transfer it clocks, and return the byte the
+    /// device shifts back.
+    fn exchange(&mut self, sent: u8) -> u8;
+
+    /// Advance by `cycles`.
+    fn step(&mut self, _cycles: usize) {}
+
+    /// While the game waits for a transfer on the external clock, clock in a byte if the device
+    /// has one to send. `sent` is what the game shifts out in return.
+    fn clock_in(&mut self, _sent: u8) -> Option<u8> {
+        None
+    }
+
+    /// For frontends to reach the concrete device, e.g. to scan a barcode.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// Identifies the kind of device in savestates, so one device's state isn't loaded into
+    /// another.
+    fn name(&self) -> &'static str;
+
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError>;
+
+    fn box_clone(&self) -> Box<dyn SerialDevice>;
+}
+
+impl Clone for Box<dyn SerialDevice> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// The "SDEV" savestate chunk, saved only while a device is plugged in. A state for another kind
+/// of device is skipped with a warning, leaving the plugged in one as it was.
+impl SaveState for Box<dyn SerialDevice> {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(self.name().as_bytes());
+        SerialDevice::save(&**self, w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let name = r.bytes()?;
+        if name != self.name().as_bytes() {
+            warn!(
+                "Not loading the savestate's {} state into the plugged in {}",
+                String::from_utf8_lossy(name), self.name(),
+            );
+            return Ok(());
+        }
+        SerialDevice::load(&mut **self, r)
+    }
+}
+
+/// The serial port's end of a link cable to another emulator, which the frontend carries over the
+/// network. Bytes cross the link whole, and both sides number the transfers they take part in, so
+/// they agree on which byte belongs to which transfer.
+#[derive(Clone, Default)]
+pub struct LinkPort {
+    /// How many transfers have started since the link was connected. The next one gets this
+    /// number.
+    pub started: u32,
+
+    /// The transfer this side is clocking, if any.
+    pub clocking: Option<Clocking>,
+
+    /// The byte the peer last put in `SB`. When this side clocks a transfer, the peer's answer
+    /// can't arrive in time, so this is shifted in as a guess.
+    pub peer_data: u8,
+
+    /// The peer's answers to transfers this side clocked, by number. They take the place of the
+    /// guess when a transfer is emulated again.
+    pub answers: BTreeMap<u32, u8>,
+
+    /// Bytes the peer clocked out, by transfer number, waiting for the game to be ready for them
+    /// on the external clock. They stay after the game takes them in, until the frontend is sure
+    /// it won't roll back and take them in again.
+    pub incoming: BTreeMap<u32, u8>,
+
+    /// What happened on the link since the frontend last took these, for it to tell the peer.
+    pub events: Vec<LinkEvent>,
+}
+
+/// A transfer in progress on the internal clock.
+#[derive(Clone, Copy, Debug)]
+pub struct Clocking {
+    pub number: u32,
+    pub sent: u8,
+    pub received: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkEvent {
+    /// The game wrote `SB`.
+    Data(u8),
+
+    /// This side clocked a transfer, shifting out `sent` and shifting in `received`.
+    Clocked { number: u32, sent: u8, received: u8 },
+
+    /// This side shifted out `sent` in a transfer the peer clocked.
+    Answered { number: u32, sent: u8 },
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            transferring: false,
+            internal_clock: false,
+            fast_clock: false,
+            bits_left: 0,
+            cgb: false,
+            echo: false,
+            capture: None,
+            link: None,
+            device: None,
+            device_byte: 0xFF,
+        }
+    }
+
+    /// The plugged in device, if it's a `T`.
+    pub fn device_mut<T: SerialDevice + 'static>(&mut self) -> Option<&mut T> {
+        self.device.as_mut().and_then(|device| device.as_any_mut().downcast_mut())
+    }
+
+    /// Advance by `cycles`, starting from the system counter value `counter`.
+    pub fn step(&mut self, cycles: usize, counter: u16, interrupts: &mut InterruptController) {
+        if let Some(device) = &mut self.device {
+            device.step(cycles);
+        }
+        if !self.transferring {
+            return;
+        }
+        if !self.internal_clock {
+            self.receive_link_transfer(interrupts);
+            self.receive_device_transfer(interrupts);
+            return;
+        }
+        let bit = if self.fast_clock { FAST_CLOCK_BIT } else { CLOCK_BIT };
+        for _ in 0..timer::falling_edges(counter, cycles, bit) {
+            if !self.transferring {
+                break;
+            }
+            let received = match (self.link.as_ref().and_then(|link| link.clocking), &self.device) {
+                (Some(clocking), _) => clocking.received,
+                (None, Some(_)) => self.device_byte,
+                (None, None) => 0xFF,
+            };
+            self.shift(received >> (self.bits_left - 1) & 1 != 0, interrupts);
+        }
+    }
+
+    /// Shift in a whole byte the peer clocked, if it's arrived and is the next transfer.
+    fn receive_link_transfer(&mut self, interrupts: &mut InterruptController) {
+        let link = match &mut self.link {
+            Some(link) => link,
+            None => return,
+        };
+        let number = link.started;
+        // The frontend drops the byte once it can't roll back to before this transfer.
+        let byte = match link.incoming.get(&number) {
+            Some(&byte) => byte,
+            None => return,
+        };
+        link.started += 1;
+        link.events.push(LinkEvent::Answered { number, sent: self.data });
+        for i in (0..8).rev() {
+            self.shift(byte >> i & 1 != 0, interrupts);
+        }
+    }
+
+    /// Shift in a whole byte the plugged in device clocks, if it has one.
+    fn receive_device_transfer(&mut self, interrupts: &mut InterruptController) {
+        let sent = self.data;
+        if let Some(byte) = self.device.as_mut().and_then(|device| device.clock_in(sent)) {
+            for i in (0..8).rev() {
+                self.shift(byte >> i & 1 != 0, interrupts);
+            }
+        }
+    }
+
+    /// Start clocking a transfer to the linked peer, shifting in its answer if it's known, or else
+    /// the guess.
+    fn start_link_transfer(&mut self) {
+        if let Some(link) = &mut self.link {
+            // Restarting a transfer partway through keeps its number.
+            let number = match link.clocking {
+                Some(clocking) => clocking.number,
+                None => {
+                    link.started += 1;
+                    link.started - 1
+                }
+            };
+            let received = link.answers.get(&number).cloned().unwrap_or(link.peer_data);
+            link.clocking = Some(Clocking { number, sent: self.data, received });
+        }
+    }
+
+    /// Clock one bit in from a partner driving the clock, if a transfer on the external clock is
+    /// waiting for it. Returns the bit shifted out.
+    pub fn clock_external(&mut self, bit_in: bool, interrupts: &mut InterruptController) -> Option<bool> {
+        if !self.transferring || self.internal_clock {
+            return None;
+        }
+        Some(self.shift(bit_in, interrupts))
+    }
+
+    /// Shift `bit_in` into SB, finishing the transfer after the eighth bit. Returns the bit
+    /// shifted out.
+    fn shift(&mut self, bit_in: bool, interrupts: &mut InterruptController) -> bool {
+        let bit_out = self.data & 0x80 != 0;
+        self.data = self.data << 1 | bit_in as u8;
+        self.bits_left -= 1;
+        if self.bits_left == 0 {
+            self.transferring = false;
+            interrupts.request(Interrupt::Serial);
+            if let Some(link) = &mut self.link {
+                if let Some(Clocking { number, sent, received }) = link.clocking.take() {
+                    link.events.push(LinkEvent::Clocked { number, sent, received });
+                }
+            }
+        }
+        bit_out
+    }
+}
+
+impl IoDevice for Serial {
+    const REGISTERS: &'static [IoRegister] = &[
+        IoRegister::new(0x01, "SB"),
+        // Bit 1 only exists on the Game Boy Color, see `read_reg` and `write_reg`.
+        IoRegister::new(0x02, "SC").unused(0b0111_1110),
+    ];
+
+    fn read_raw(&self, addr: u8) -> u8 {
+        match addr {
+            0x01 => self.data,
+            0x02 => (self.transferring as u8) << 7 | self.internal_clock as u8,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_raw(&mut self, addr: u8, val: u8) {
+        match addr {
+            0x01 => {
+                self.data = val;
+                if let Some(link) = &mut self.link {
+                    link.events.push(LinkEvent::Data(val));
+                }
+            }
+            0x02 => {
+                self.transferring = val & (1 << 7) != 0;
+                self.internal_clock = val & 1 != 0;
+                if self.transferring {
+                    self.bits_left = 8;
+                    if self.internal_clock {
+                        self.start_link_transfer();
+                        if let Some(device) = &mut self.device {
+                            self.device_byte = device.exchange(self.data);
+                        }
+                    }
+                    if self.echo {
+                        print_debug_byte(self.data);
+                    }
+                    if let Some(capture) = &mut self.capture {
+                        capture.push(self.data);
+                    }
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_reg(&self, addr: u8) -> u8 {
+        let val = self.read_raw(addr) | Self::register(addr).unused;
+        if addr == 0x02 && self.cgb && !self.fast_clock { val & !(1 << 1) } else { val }
+    }
+
+    fn write_reg(&mut self, addr: u8, val: u8) {
+        // Set the clock speed first, since the same write can start a transfer.
+        if addr == 0x02 && self.cgb {
+            self.fast_clock = val & (1 << 1) != 0;
+        }
+        self.write_raw(addr, val & !Self::register(addr).unused);
+    }
+}
+
+/// Print a byte sent by the game to one of the debug output ports.
+pub fn print_debug_byte(val: u8) {
+    let stdout = io::stdout();
+    let mut lock = stdout.lock();
+    let _ = lock.write_all(&[val]);
+    let _ = lock.flush();
+}
+
+impl SaveState for Serial {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.bool(self.transferring);
+        w.bool(self.internal_clock);
+        w.bool(self.fast_clock);
+        w.u8(self.bits_left);
+        w.u8(self.device_byte);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.data = r.u8()?;
+        self.transferring = r.bool()?;
+        self.internal_clock = r.bool()?;
+        self.fast_clock = r.bool()?;
+        self.bits_left = r.u8()?;
+        self.device_byte = r.u8()?;
+        // A transfer in progress shifts `bits_left` more bits, so it has to have some left.
+        if self.bits_left > 8 || self.transferring && self.bits_left == 0 {
+            return Err(SaveStateError::InvalidValue("serial transfer bits left"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::savestate::StateChunks;
+
+    #[test]
+    fn test_savestate_rejects_impossible_transfers() {
+        for bits_left in 0..=255 {
+            for &transferring in &[false, true] {
+                let mut serial = Serial::new();
+                serial.bits_left = bits_left;
+                serial.transferring = transferring;
+                let mut w = StateWriter::new();
+                w.chunk(b"SERL", &serial);
+                let state = w.finish();
+
+                let possible = bits_left <= 8 && (bits_left > 0 || !transferring);
+                let loaded = StateChunks::parse(&state).unwrap().load(b"SERL", &mut Serial::new());
+                assert_eq!(loaded.is_ok(), possible, "{} bits left", bits_left);
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Sleep mode: on quit, the complete emulator state is saved so the next `run --continue` of the
+//! same ROM picks up exactly where it left off, like closing the lid of a handheld.
+//!
+//! Sessions are stored per ROM file name in the platform's data directory (e.g.
+//! `~/.local/share/rugby/sessions` on Linux), along with a CRC-32 of the ROM so a state saved
+//! with a different build of the game is never loaded.
+
+use crate::checksum::crc32;
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"RUGBYSES";
+const VERSION: u8 = 1;
+
+pub struct Session {
+    /// CRC-32 of the ROM the session was saved with.
+    pub rom_crc32: u32,
+
+    pub state: Vec<u8>,
+}
+
+impl Session {
+    pub fn new(rom: &[u8], state: Vec<u8>) -> Self {
+        Session { rom_crc32: crc32(rom), state }
+    }
+
+    /// The session file for the given ROM, if there is a data directory.
+    pub fn path(rom_path: &Path) -> Option<PathBuf> {
+        let name = rom_path.file_name()?;
+        let mut file_name = name.to_os_string();
+        file_name.push(".session");
+        dirs::data_dir().map(|dir| dir.join("rugby").join("sessions").join(file_name))
+    }
+
+    /// Whether this session was saved with the given ROM. If not, the ROM was changed (e.g.
+    /// rebuilt) and the state is stale.
+    pub fn matches_rom(&self, rom: &[u8]) -> bool {
+        crc32(rom) == self.rom_crc32
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::with_header(MAGIC, VERSION);
+        w.u32(self.rom_crc32);
+        w.bytes(&self.state);
+        w.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SaveStateError> {
+        let mut r = StateReader::with_header(data, MAGIC, VERSION)?;
+        let rom_crc32 = r.u32()?;
+        let state = r.bytes()?.to_vec();
+        Ok(Session { rom_crc32, state })
+    }
+}
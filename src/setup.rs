@@ -0,0 +1,199 @@
+//! `rugby setup`, a few questions in the terminal that write a config file, so nobody has to learn
+//! TOML or SDL's key names before playing. It also runs by itself the first time a game is started
+//! from a terminal without a config file.
+//!
+//! Each question suggests the current setting, which Enter keeps. The answers are merged into an
+//! existing file, so settings and comments the wizard doesn't ask about are left as they were.
+
+use crate::config::Config;
+use crate::photo::Palette;
+use failure::ResultExt;
+use sdl2::controller::Button;
+use sdl2::keyboard::Keycode;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use toml_edit::{Document, Item, Table, TableLike};
+
+/// The joypad keys in the order they're asked about, with their config names.
+const JOYPAD_KEYS: [(&str, &str); 8] = [
+    ("up", "Up"), ("down", "Down"), ("left", "Left"), ("right", "Right"),
+    ("a", "A"), ("b", "B"), ("start", "Start"), ("select", "Select"),
+];
+
+/// Whether to run the wizard before starting a game: there's nowhere the config could be yet, and
+/// someone at a terminal to answer.
+pub fn is_first_run() -> bool {
+    let missing = Config::path().map_or(false, |path| !path.exists());
+    missing && atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout)
+}
+
+/// Ask the questions and write the answers to the config file, keeping everything else in it.
+pub fn run(input: impl BufRead, out: impl Write) -> Result<(), failure::Error> {
+    let path = match Config::path() {
+        Some(path) => path,
+        None => failure::bail!("There's no config directory to write the config file to"),
+    };
+    let current = Config::load().unwrap_or_default();
+    let existing = path.exists();
+    let mut doc = if existing {
+        let text = std::fs::read_to_string(&path)
+            .with_context(|_| format!("Failed to read config file: {}", path.display()))?;
+        text.parse::<Document>()
+            .with_context(|_| format!("Failed to parse config file, fix or remove it first: {}", path.display()))?
+    } else {
+        Document::new()
+    };
+    let mut wizard = Wizard { input, out, finished: false };
+    wizard.ask_all(&path, &current, &mut doc)?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|_| format!("Failed to create config directory: {}", dir.display()))?;
+    }
+    let text = if existing { doc.to_string() } else { format!("{}\n{}", HEADER, doc) };
+    std::fs::write(&path, text)
+        .with_context(|_| format!("Failed to write config file: {}", path.display()))?;
+    writeln!(wizard.out, "Wrote {}. Run `rugby setup` to change it again.", path.display())?;
+    Ok(())
+}
+
+/// The start of a config file the wizard writes from scratch.
+const HEADER: &str = "# Written by `rugby setup`. See the README for every setting.\n";
+
+struct Wizard<R, W> {
+    input: R,
+    out: W,
+
+    /// Whether the input ended, so the remaining questions take their suggestions.
+    finished: bool,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    /// Ask every question and put the answers in `doc`.
+    fn ask_all(&mut self, path: &Path, current: &Config, doc: &mut Document) -> Result<(), failure::Error> {
+        writeln!(self.out, "Let's set up Rugby. Press Enter to keep the suggestion in brackets.")?;
+        writeln!(self.out, "Everything can be changed later in {}.", path.display())?;
+        writeln!(self.out)?;
+
+        let palette = self.ask("Screen colors (dmg, pocket, gray, or sepia)", current.video.palette.name(), |answer| {
+            let palette: Palette = answer.to_lowercase().parse()?;
+            Ok(palette.name().to_string())
+        })?;
+        section(doc, &["video"])?.insert("palette", toml_edit::value(palette));
+
+        writeln!(self.out)?;
+        writeln!(self.out, "Keyboard controls, by key name, like W, Return, Space, or Left:")?;
+        let keys = &current.keys.player1;
+        let current_keys = [keys.up, keys.down, keys.left, keys.right, keys.a, keys.b, keys.start, keys.select];
+        for (&(name, label), keycode) in JOYPAD_KEYS.iter().zip(&current_keys) {
+            let key = self.ask(&format!("  {}", label), &keycode.name(), |answer| {
+                Keycode::from_name(answer)
+                    .map(|keycode| keycode.name())
+                    .ok_or_else(|| format!("there's no key called {:?}", answer))
+            })?;
+            section(doc, &["keys", "player1"])?.insert(name, toml_edit::value(key));
+        }
+
+        writeln!(self.out)?;
+        writeln!(self.out, "Game controller buttons, like a, b, x, y, back, start, leftshoulder, or rightshoulder.")?;
+        writeln!(self.out, "The d-pad is always the d-pad:")?;
+        let controller = &current.controller;
+        let current_buttons = [controller.a, controller.b, controller.start, controller.select];
+        for (&(name, label), button) in JOYPAD_KEYS[4..].iter().zip(&current_buttons) {
+            let button = self.ask(&format!("  {}", label), &button.string(), |answer| {
+                Button::from_string(&answer.to_lowercase())
+                    .map(|button| button.string())
+                    .ok_or_else(|| format!("there's no button called {:?}", answer))
+            })?;
+            section(doc, &["controller"])?.insert(name, toml_edit::value(button));
+        }
+
+        writeln!(self.out)?;
+        writeln!(self.out, "A folder to keep battery saves in. Without one, they're kept next to the ROM.")?;
+        let current_dir = current.save.directory.as_ref().map_or("-".to_string(), |dir| dir.display().to_string());
+        let dir = self.ask("Save folder (- for next to the ROM)", &current_dir, |answer| Ok(answer.to_string()))?;
+        let save = section(doc, &["save"])?;
+        if dir == "-" {
+            save.remove("directory");
+        } else {
+            save.insert("directory", toml_edit::value(dir));
+        }
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    /// Ask until `parse` accepts the answer, which it turns into what to write to the file. An
+    /// empty answer picks `suggestion`.
+    fn ask(
+        &mut self, question: &str, suggestion: &str, parse: impl Fn(&str) -> Result<String, String>,
+    ) -> io::Result<String> {
+        loop {
+            write!(self.out, "{} [{}]: ", question, suggestion)?;
+            self.out.flush()?;
+            let mut line = String::new();
+            if !self.finished && self.input.read_line(&mut line)? == 0 {
+                self.finished = true;
+            }
+            if self.finished {
+                writeln!(self.out)?;
+            }
+            let answer = line.trim();
+            if answer.is_empty() {
+                return Ok(suggestion.to_string());
+            }
+            match parse(answer) {
+                Ok(value) => return Ok(value),
+                Err(e) => writeln!(self.out, "  Sorry, {}.", e)?,
+            }
+        }
+    }
+}
+
+/// The table at `path` in `doc`, added if it's missing.
+fn section<'a>(doc: &'a mut Document, path: &[&str]) -> Result<&'a mut dyn TableLike, failure::Error> {
+    let mut table: &mut dyn TableLike = doc.as_table_mut();
+    for (i, name) in path.iter().enumerate() {
+        let item = table.entry(name).or_insert_with(|| {
+            // Parent tables with nothing of their own don't get a header.
+            let mut new = Table::new();
+            new.set_implicit(true);
+            Item::Table(new)
+        });
+        table = match item.as_table_like_mut() {
+            Some(table) => table,
+            None => failure::bail!("The config file's {} setting isn't a table", path[..=i].join(".")),
+        };
+    }
+    Ok(table)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Answer every question with its suggestion, and return the merged file.
+    fn keep_everything(text: &str) -> String {
+        let mut doc = text.parse::<Document>().unwrap();
+        let mut wizard = Wizard { input: io::empty(), out: Vec::new(), finished: false };
+        wizard.ask_all(Path::new("config.toml"), &Config::default(), &mut doc).unwrap();
+        doc.to_string()
+    }
+
+    #[test]
+    fn test_setup_keeps_other_settings() {
+        let text = keep_everything("# Mine\n[power]\nlow_power = true\n\n[video]\nscanline_marker = true\npalette = \"gray\"\n");
+        assert!(text.starts_with("# Mine\n[power]\nlow_power = true\n"), "{}", text);
+        assert!(text.contains("scanline_marker = true\n"), "{}", text);
+        assert!(text.contains("[keys.player1]\n"), "{}", text);
+        assert!(!text.contains("[keys]\n"), "{}", text);
+        let config: Config = toml::from_str(&text).unwrap();
+        assert!(config.power.low_power && config.video.scanline_marker);
+    }
+
+    #[test]
+    fn test_setup_rejects_a_setting_where_a_section_goes() {
+        let mut doc = "video = 1\n".parse::<Document>().unwrap();
+        let mut wizard = Wizard { input: io::empty(), out: Vec::new(), finished: false };
+        assert!(wizard.ask_all(Path::new("config.toml"), &Config::default(), &mut doc).is_err());
+    }
+}
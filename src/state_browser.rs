@@ -0,0 +1,170 @@
+//! The savestate browser, a grid of savestate slots drawn over the game. Each slot shows the screen
+//! it was saved at and how long ago, so players can pick a state to load by sight rather than by
+//! number. The first slot is the one F5 and F8 use, and the rest are only reachable from here.
+//! Slots are kept next to the game's save file and named after it, so each game has its own.
+//!
+//! The browser pauses the game while it's open, and is driven by the player's own joypad
+//! bindings: the d-pad moves, A loads, Start saves over the selected slot, and B closes it.
+
+use crate::checksum::crc32;
+use crate::cpu::Cpu;
+use crate::gpu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::i18n::{tr, tr_with};
+use crate::joypad::{ButtonKey, DirKey};
+use crate::pause::PauseReason;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const COLUMNS: usize = 3;
+pub const ROWS: usize = 3;
+pub const SLOTS: usize = COLUMNS * ROWS;
+
+pub struct Slot {
+    pub path: PathBuf,
+    pub contents: Contents,
+}
+
+pub enum Contents {
+    Empty,
+
+    /// The file isn't a savestate, or is corrupted.
+    Unreadable,
+
+    /// A state for a ROM other than the one running, which can't be loaded.
+    OtherGame,
+
+    Saved {
+        screen: Box<[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT]>,
+
+        /// When the file was last written, if the filesystem knows.
+        modified: Option<SystemTime>,
+    },
+}
+
+/// What a key or button does in the browser.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Move the selection by this many columns and rows, wrapping around the edges.
+    Move(isize, isize),
+    Load,
+    Save,
+    Close,
+}
+
+pub struct StateBrowser {
+    pub slots: Vec<Slot>,
+    pub selected: usize,
+
+    /// Whether the user had paused the game before opening the browser, so closing it doesn't
+    /// resume a game they paused themselves.
+    pub was_paused: bool,
+}
+
+impl StateBrowser {
+    /// Read every slot, for the game running in `cpu` whose save file is `save_path`. Call before
+    /// pausing for the browser.
+    pub fn open(cpu: &Cpu, save_path: &Path) -> Self {
+        let rom_crc32 = crc32(cpu.cart.rom());
+        let slots = (0..SLOTS).map(|i| Slot::read(slot_path(save_path, i), rom_crc32)).collect();
+        StateBrowser { slots, selected: 0, was_paused: cpu.pause.is_paused_for(PauseReason::User) }
+    }
+
+    pub fn selected(&self) -> &Slot {
+        &self.slots[self.selected]
+    }
+
+    /// Read the selected slot again, after saving over it.
+    pub fn reload_selected(&mut self, cpu: &Cpu) {
+        let rom_crc32 = crc32(cpu.cart.rom());
+        let path = self.slots[self.selected].path.clone();
+        self.slots[self.selected] = Slot::read(path, rom_crc32);
+    }
+
+    pub fn move_selection(&mut self, columns: isize, rows: isize) {
+        let column = (self.selected % COLUMNS) as isize + columns;
+        let row = (self.selected / COLUMNS) as isize + rows;
+        let column = column.rem_euclid(COLUMNS as isize) as usize;
+        let row = row.rem_euclid(ROWS as isize) as usize;
+        self.selected = row * COLUMNS + column;
+    }
+}
+
+impl Slot {
+    fn read(path: PathBuf, rom_crc32: u32) -> Self {
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => return Slot { path, contents: Contents::Empty },
+        };
+        let contents = match Cpu::state_info(&data) {
+            Ok(ref info) if info.header.rom_crc32 != rom_crc32 => Contents::OtherGame,
+            Ok(info) => Contents::Saved {
+                screen: info.gpu.screen_buffer,
+                modified: std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok(),
+            },
+            Err(_) => Contents::Unreadable,
+        };
+        Slot { path, contents }
+    }
+
+    /// The slot's name, like "Quick" or "Slot 3".
+    pub fn name(index: usize) -> String {
+        if index == 0 {
+            tr("osd.quick_slot").to_string()
+        } else {
+            tr_with("osd.slot", &[("number", &index)])
+        }
+    }
+
+    /// What to show under the slot's name: how long ago it was saved, or why there's nothing to
+    /// load.
+    pub fn status(&self, now: SystemTime) -> String {
+        match &self.contents {
+            Contents::Empty => tr("osd.slot_empty").to_string(),
+            Contents::Unreadable => tr("osd.slot_unreadable").to_string(),
+            Contents::OtherGame => tr("osd.slot_other_game").to_string(),
+            Contents::Saved { modified: None, .. } => String::new(),
+            Contents::Saved { modified: Some(modified), .. } => {
+                let seconds = now.duration_since(*modified).map_or(0, |age| age.as_secs());
+                match seconds {
+                    0...59 => tr("osd.saved_just_now").to_string(),
+                    60...3599 => tr_with("osd.saved_minutes_ago", &[("minutes", &(seconds / 60))]),
+                    3600...86399 => tr_with("osd.saved_hours_ago", &[("hours", &(seconds / 3600))]),
+                    _ => tr_with("osd.saved_days_ago", &[("days", &(seconds / 86400))]),
+                }
+            }
+        }
+    }
+}
+
+/// The file behind slot `index`, for the game whose save file is `save_path`. The first slot is
+/// where F5 saves the state and F8 loads it from.
+pub fn slot_path(save_path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        save_path.with_extension("quick.state")
+    } else {
+        save_path.with_extension(format!("slot-{}.state", index))
+    }
+}
+
+/// What pressing the joypad keys in `pressed` (as from `Joypad::pressed`) does in the browser.
+pub fn action(pressed: u8) -> Option<Action> {
+    let (buttons, dirs) = (pressed & 0xF, pressed >> 4);
+    let action = if dirs & DirKey::Left as u8 != 0 {
+        Action::Move(-1, 0)
+    } else if dirs & DirKey::Right as u8 != 0 {
+        Action::Move(1, 0)
+    } else if dirs & DirKey::Up as u8 != 0 {
+        Action::Move(0, -1)
+    } else if dirs & DirKey::Down as u8 != 0 {
+        Action::Move(0, 1)
+    } else if buttons & ButtonKey::A as u8 != 0 {
+        Action::Load
+    } else if buttons & ButtonKey::Start as u8 != 0 {
+        Action::Save
+    } else if buttons & ButtonKey::B as u8 != 0 {
+        Action::Close
+    } else {
+        return None;
+    };
+    Some(action)
+}
@@ -0,0 +1,82 @@
+//! `rugby state diff`, which compares two savestates of the same game, for bug reports where one
+//! state works and a later one is broken. It lists the CPU registers, mapper banks, and memory
+//! bytes that differ, grouped by memory region. The ROM is left out, since both states have to be
+//! for the same one, and so is echo RAM, which only mirrors work RAM.
+
+use crate::cpu::registers::Reg16;
+use crate::cpu::Cpu;
+use crate::memory_map;
+use std::io::{self, Write};
+
+/// The most differing bytes to list in each region before just counting the rest.
+const MAX_LISTED_BYTES: usize = 32;
+
+const SKIPPED_REGIONS: &[&str] = &["ROM0", "ROMX", "ECHO", "UNUSABLE"];
+
+/// Write a report of how the state of `b` differs from `a`. Returns the number of differences,
+/// counting each register and memory byte once.
+pub fn write_diff(a: &Cpu, b: &Cpu, out: impl Write) -> io::Result<usize> {
+    let mut out = tabwriter::TabWriter::new(out);
+    let mut differences = 0;
+
+    let mut registers = vec![("Cycles".to_string(), a.cycles() as u64, b.cycles() as u64, 0)];
+    for &reg in &[Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP, Reg16::PC] {
+        let (val_a, val_b) = (a.regs().get_16(reg), b.regs().get_16(reg));
+        registers.push((format!("{:?}", reg), u64::from(val_a), u64::from(val_b), 4));
+    }
+    let (mbc_a, mbc_b) = (a.cart.debug_state(), b.cart.debug_state());
+    registers.push(("ROM bank".to_string(), u64::from(mbc_a.rom_bank), u64::from(mbc_b.rom_bank), 2));
+    registers.push(("RAM bank".to_string(), u64::from(mbc_a.ram_bank), u64::from(mbc_b.ram_bank), 2));
+    registers.push(("RAM enabled".to_string(), mbc_a.ram_enabled as u64, mbc_b.ram_enabled as u64, 0));
+    let changed: Vec<_> = registers.iter().filter(|(_, val_a, val_b, _)| val_a != val_b).collect();
+    if !changed.is_empty() {
+        writeln!(out, "== Registers ==")?;
+        for (name, val_a, val_b, hex_digits) in changed {
+            if *hex_digits == 0 {
+                writeln!(out, "{}:\t{}\t{}", name, val_a, val_b)?;
+            } else {
+                writeln!(out, "{}:\t0x{:03$X}\t0x{:03$X}", name, val_a, val_b, *hex_digits)?;
+            }
+            differences += 1;
+        }
+        writeln!(out)?;
+    }
+
+    for region in memory_map::regions(a) {
+        if !region.readable || SKIPPED_REGIONS.contains(&region.name) {
+            continue;
+        }
+        let mut changed = Vec::new();
+        for bank in 0..region.banks {
+            for addr in region.start..=region.end {
+                let val_a = memory_map::read(a, bank, addr).expect("bank is in range");
+                let val_b = memory_map::read(b, bank, addr).expect("bank is in range");
+                if val_a != val_b {
+                    changed.push((bank, addr, val_a, val_b));
+                }
+            }
+        }
+        if changed.is_empty() {
+            continue;
+        }
+        let plural = if changed.len() == 1 { "" } else { "s" };
+        writeln!(out, "== {} ({} byte{} differ) ==", region.name, changed.len(), plural)?;
+        for &(bank, addr, val_a, val_b) in changed.iter().take(MAX_LISTED_BYTES) {
+            if region.banks > 1 {
+                write!(out, "{:02X}:", bank)?;
+            }
+            writeln!(out, "{:04X}:\t0x{:02X}\t0x{:02X}", addr, val_a, val_b)?;
+        }
+        if changed.len() > MAX_LISTED_BYTES {
+            writeln!(out, "... and {} more", changed.len() - MAX_LISTED_BYTES)?;
+        }
+        writeln!(out)?;
+        differences += changed.len();
+    }
+
+    if differences == 0 {
+        writeln!(out, "The states are identical.")?;
+    }
+    out.flush()?;
+    Ok(differences)
+}
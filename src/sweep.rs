@@ -0,0 +1,124 @@
+//! `rugby sweep`, which runs a batch of ROMs headless in parallel and reports how each one ended,
+//! for test ROM suites and for checking that an emulator change doesn't break a game library.
+//!
+//! Each ROM gets its own `Cpu` on a rayon worker thread. The core keeps no global mutable state
+//! (its only statics are the immutable regexes of the symbol file parsers), so runs can't affect
+//! each other. Anything a run would print or write is kept per run instead: serial output is
+//! captured rather than echoed, and files in the output directory are named after the ROM's
+//! position in the batch, so two ROMs with the same file name don't overwrite each other.
+//!
+//! A run ends at the frame limit, when the `--exit-when` expression is true, or when the ROM
+//! reports a result the way common test ROMs do. Blargg's tests print "Passed" or "Failed" over
+//! the serial port. Mooneye's tests load the Fibonacci numbers 3, 5, 8, 13, 21, 34 into `B`-`L`
+//! on success, or 0x42 into each of them on failure, and then loop forever.
+
+use crate::checksum::Crc32;
+use crate::cpu::Cpu;
+use crate::expr::Expr;
+use crate::gpu::CYCLES_PER_FRAME;
+use std::collections::HashSet;
+use std::fmt;
+
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAILED: [u8; 6] = [0x42; 6];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    ExitConditionMet,
+    FrameLimit,
+
+    /// The ROM couldn't be loaded or run, or its output couldn't be written.
+    Error(String),
+}
+
+pub struct Report {
+    pub outcome: Outcome,
+    pub frames: u64,
+
+    /// The CRC-32 of the final screen, in the format of `--frame-hashes`.
+    pub screen_crc: u32,
+
+    /// Everything the ROM sent over the serial port.
+    pub serial: Vec<u8>,
+}
+
+impl Outcome {
+    /// Whether this outcome should make the sweep as a whole fail.
+    pub fn is_failure(&self) -> bool {
+        match self {
+            Outcome::Failed | Outcome::Error(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::Failed => write!(f, "failed"),
+            Outcome::ExitConditionMet => write!(f, "exit condition met"),
+            Outcome::FrameLimit => write!(f, "frame limit"),
+            Outcome::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// Run `cpu` until it reports a result, `exit_when` is true, or `frame_limit` frames have passed.
+pub fn run(cpu: &mut Cpu, frame_limit: u64, exit_when: Option<&Expr>) -> Report {
+    cpu.serial.capture = Some(Vec::new());
+    cpu.break_when = exit_when.cloned();
+
+    let mut frames = 0;
+    let outcome = loop {
+        if frames == frame_limit {
+            break Outcome::FrameLimit;
+        }
+        let hit_break = cpu.step_cycles(CYCLES_PER_FRAME, None, &HashSet::new());
+        frames += 1;
+        if hit_break && cpu.break_condition_met() {
+            break Outcome::ExitConditionMet;
+        }
+        if let Some(outcome) = test_result(cpu) {
+            break outcome;
+        }
+    };
+
+    let mut screen = Crc32::new();
+    for row in cpu.gpu.screen_buffer.iter() {
+        screen.update(row);
+    }
+    Report {
+        outcome,
+        frames,
+        screen_crc: screen.finish(),
+        serial: cpu.serial.capture.take().unwrap_or_default(),
+    }
+}
+
+/// The result the ROM reported so far, if it's a test ROM that reports results in a known way.
+fn test_result(cpu: &Cpu) -> Option<Outcome> {
+    let serial = cpu.serial.capture.as_ref().map_or(&[][..], |c| &c[..]);
+    if contains(serial, b"Passed") {
+        return Some(Outcome::Passed);
+    }
+    if contains(serial, b"Failed") {
+        return Some(Outcome::Failed);
+    }
+
+    let regs = cpu.regs();
+    let values = [
+        regs.bc.high(), regs.bc.low(), regs.de.high(), regs.de.low(), regs.hl.high(), regs.hl.low(),
+    ];
+    match values {
+        MOONEYE_PASSED => Some(Outcome::Passed),
+        MOONEYE_FAILED => Some(Outcome::Failed),
+        _ => None,
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
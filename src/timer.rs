@@ -1,5 +1,6 @@
-use crate::interrupts::Interrupt;
-use enumflags2::BitFlags;
+use crate::interrupts::{Interrupt, InterruptController};
+use crate::io_register::{IoDevice, IoRegister};
+use crate::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 const CYCLES_PER_DIVIDE_INC: usize = 256; // The number of cycles between increments of the divide register
 
@@ -71,9 +72,9 @@ impl Timer {
         }
     }
 
-    pub fn step(&mut self, cycles: usize) -> BitFlags<Interrupt> {
+    pub fn step(&mut self, cycles: usize, interrupts: &mut InterruptController) {
         self.update_divider(cycles);
-        self.update_counter(cycles)
+        self.update_counter(cycles, interrupts);
     }
 
     fn update_divider(&mut self, cycles: usize) {
@@ -84,9 +85,9 @@ impl Timer {
         }
     }
 
-    fn update_counter(&mut self, cycles: usize) -> BitFlags<Interrupt> {
+    fn update_counter(&mut self, cycles: usize, interrupts: &mut InterruptController) {
         if !self.counter_running {
-            return BitFlags::empty();
+            return;
         }
         self.counter_cycle_counter += cycles;
         if self.counter_cycle_counter >= usize::from(self.counter_speed) {
@@ -94,38 +95,98 @@ impl Timer {
             let (new_counter, overflow) = self.counter.overflowing_add(1);
             if overflow {
                 self.counter = self.modulo;
-                return BitFlags::from(Interrupt::Timer);
+                interrupts.request(Interrupt::Timer);
             } else {
                 self.counter = new_counter;
             }
         }
-        BitFlags::empty()
     }
 
-    pub fn read_reg(&self, addr: u8) -> u8 {
+    /// The 16-bit counter DIV is the upper half of, which also clocks the serial port.
+    pub fn system_counter(&self) -> u16 {
+        u16::from(self.divider) << 8 | self.div_cycle_counter as u16
+    }
+
+    /// Reset DIV to 0, as writing to it or executing STOP does.
+    pub fn reset_divider(&mut self) {
+        self.div_cycle_counter = 0;
+        self.divider = 0;
+    }
+
+    /// Check internal consistency for `--paranoid` mode.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.div_cycle_counter >= CYCLES_PER_DIVIDE_INC {
+            return Err(format!("DIV cycle counter is {}", self.div_cycle_counter));
+        }
+        // TAC writes can switch to a faster speed between steps, so only the slowest speed is a
+        // hard bound here.
+        if self.counter_cycle_counter >= usize::from(CounterSpeed::S4096) {
+            return Err(format!("TIMA cycle counter is {}", self.counter_cycle_counter));
+        }
+        Ok(())
+    }
+}
+
+/// How many falling edges `bit` of the system counter has while it counts up `cycles` from
+/// `counter`.
+pub fn falling_edges(counter: u16, cycles: usize, bit: u32) -> u32 {
+    let period = 1 << (bit + 1);
+    let start = u32::from(counter);
+    (start + cycles as u32) / period - start / period
+}
+
+impl IoDevice for Timer {
+    const REGISTERS: &'static [IoRegister] = &[
+        IoRegister::new(0x04, "DIV"),
+        IoRegister::new(0x05, "TIMA"),
+        IoRegister::new(0x06, "TMA"),
+        IoRegister::new(0x07, "TAC").unused(0b1111_1000),
+    ];
+
+    fn read_raw(&self, addr: u8) -> u8 {
         match addr {
             0x04 => self.divider,
             0x05 => self.counter,
             0x06 => self.modulo,
-            // The upper 5 bits are unused and always 1.
-            0x07 => 0b1111_1000 | (self.counter_running as u8) << 2 | self.counter_speed as u8,
-            _ => panic!("Invalid read address for timer")
+            0x07 => (self.counter_running as u8) << 2 | self.counter_speed as u8,
+            _ => unreachable!(),
         }
     }
 
-    pub fn write_reg(&mut self, addr: u8, val: u8) {
+    fn write_raw(&mut self, addr: u8, val: u8) {
         match addr {
-            0x04 => {
-                self.div_cycle_counter = 0;
-                self.divider = 0
-            },
+            // Any write resets the divider.
+            0x04 => self.reset_divider(),
             0x05 => self.counter = val,
             0x06 => self.modulo = val,
             0x07 => {
                 self.counter_speed = CounterSpeed::from(val & 3);
                 self.counter_running = ((val >> 2) & 1) == 1;
             },
-            _ => panic!("Invalid write address for timer")
+            _ => unreachable!(),
         }
     }
-}
\ No newline at end of file
+}
+
+impl SaveState for Timer {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.divider);
+        w.usize(self.div_cycle_counter);
+        w.u8(self.counter);
+        w.usize(self.counter_cycle_counter);
+        w.u8(self.modulo);
+        w.bool(self.counter_running);
+        w.u8(self.counter_speed as u8);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.divider = r.u8()?;
+        self.div_cycle_counter = r.usize()?;
+        self.counter = r.u8()?;
+        self.counter_cycle_counter = r.usize()?;
+        self.modulo = r.u8()?;
+        self.counter_running = r.bool()?;
+        self.counter_speed = CounterSpeed::from(r.u8()? & 0b11);
+        Ok(())
+    }
+}
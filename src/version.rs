@@ -0,0 +1,15 @@
+//! Which build of Rugby is running, so savestates, bundles, and logs from another build can be
+//! told apart. Emulation changes between commits, so replaying something recorded by a different
+//! build may not go the same way.
+
+/// The crate version.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The commit Rugby was built from, or "unknown" outside a git checkout.
+pub const GIT_HASH: &str = env!("RUGBY_GIT_HASH");
+
+/// The Cargo profile, "debug" or "release".
+pub const PROFILE: &str = env!("RUGBY_PROFILE");
+
+/// All of the above, like "0.1.0 (1a2b3c4d5e, release)".
+pub const BUILD: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("RUGBY_GIT_HASH"), ", ", env!("RUGBY_PROFILE"), ")");
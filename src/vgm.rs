@@ -0,0 +1,128 @@
+//! Recording the game's writes to the sound registers as a VGM file, the format chiptune players
+//! and trackers use for music ripped from consoles. Unlike a WAV dump, a VGM keeps the notes, so
+//! homebrew authors can take their music back out of Rugby and edit or remix it.
+//!
+//! VGM is a stream of timed chip register writes at 44100 samples per second. The Game Boy's APU
+//! is supported from version 1.61 on, with each write being 0xB3, the register minus 0xFF10, and
+//! the value.
+
+use crate::audio::Audio;
+use crate::cpu::Cpu;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const VERSION: u32 = 0x161;
+const SAMPLE_RATE: u64 = 44100;
+const CLOCK_RATE: u32 = 4_194_304;
+
+/// The header is padded to this size, and the commands follow.
+const HEADER_LEN: u32 = 0x100;
+
+const CMD_GB_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_END: u8 = 0x66;
+
+pub struct VgmRecorder {
+    out: BufWriter<File>,
+
+    /// Bytes of commands written so far.
+    data_len: u32,
+
+    /// How many cycles of sound have been recorded, and how many samples of waits have been
+    /// written for them.
+    cycles: u64,
+    samples: u64,
+}
+
+impl VgmRecorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut recorder = VgmRecorder { out: BufWriter::new(File::create(path)?), data_len: 0, cycles: 0, samples: 0 };
+        recorder.write_header()?;
+        Ok(recorder)
+    }
+
+    /// Start logging `cpu`'s sound register writes, after recording the current state of every
+    /// sound register, so a recording started mid-game plays right. Channels that are playing are
+    /// triggered, so held notes are heard from the start.
+    pub fn attach(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        if cpu.audio.register_log.is_none() {
+            cpu.audio.register_log = Some(Default::default());
+        }
+        let audio = &cpu.audio;
+        let status = audio.read_reg(0x26);
+        self.write_register(0x26, status)?;
+        self.write_register(0x24, audio.read_reg(0x24))?;
+        self.write_register(0x25, audio.read_reg(0x25))?;
+        // Wave RAM goes first, since the Game Boy only lets it be written while channel 3 is off.
+        for (i, &byte) in audio.channel3.wave_ram.iter().enumerate() {
+            self.write_register(0x30 + i as u8, byte)?;
+        }
+        for (addr, val) in audio.channel_registers() {
+            let trigger = match addr {
+                0x14 | 0x19 | 0x1E | 0x23 => status & (1 << ((addr - 0x14) / 5)) != 0,
+                _ => false,
+            };
+            self.write_register(addr, val | (trigger as u8) << 7)?;
+        }
+        Ok(())
+    }
+
+    /// Append the register writes logged since the last frame.
+    pub fn write_frame(&mut self, audio: &Audio) -> io::Result<()> {
+        let log = match &audio.register_log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        for write in &log.writes {
+            self.wait_until(self.cycles + write.cycle as u64)?;
+            self.write_register(write.addr, write.val)?;
+        }
+        self.cycles += log.cycles as u64;
+        Ok(())
+    }
+
+    /// End the recording and fill in the lengths in the header, leaving a complete file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.wait_until(self.cycles)?;
+        self.write_data(&[CMD_END])?;
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.flush()
+    }
+
+    fn write_register(&mut self, addr: u8, val: u8) -> io::Result<()> {
+        self.write_data(&[CMD_GB_WRITE, addr - 0x10, val])
+    }
+
+    /// Wait until `cycle`, rounded down to a whole sample.
+    fn wait_until(&mut self, cycle: u64) -> io::Result<()> {
+        let target = cycle * SAMPLE_RATE / u64::from(CLOCK_RATE);
+        while self.samples < target {
+            let wait = (target - self.samples).min(0xFFFF);
+            self.write_data(&[CMD_WAIT, wait as u8, (wait >> 8) as u8])?;
+            self.samples += wait;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.data_len += data.len() as u32;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = [0; HEADER_LEN as usize];
+        let mut put = |offset: usize, val: u32| header[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        put(0x00, u32::from_le_bytes(*b"Vgm "));
+        // Offsets are relative to the field they're in.
+        put(0x04, HEADER_LEN + self.data_len - 0x04);
+        put(0x08, VERSION);
+        put(0x18, self.samples as u32);
+        put(0x24, 60);
+        put(0x34, HEADER_LEN - 0x34);
+        put(0x80, CLOCK_RATE);
+        self.out.write_all(&header)
+    }
+}
@@ -0,0 +1,164 @@
+//! Twitch-plays style input aggregation for `rugby serve`. Viewers' bots send `vote KEY [USER]`
+//! over the control socket, and the votes become key presses according to a policy:
+//!
+//! - Anarchy: every vote is pressed as soon as it arrives.
+//! - Democracy: votes are tallied over a window of frames, with one vote per user, and only the
+//!   most popular key is pressed when the window closes.
+
+use crate::control::key_name;
+use crate::i18n::{tr, tr_with};
+use serde_derive::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+
+/// The number of recent votes shown on screen in anarchy mode.
+const RECENT_VOTES: usize = 5;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VotePolicy {
+    Anarchy,
+    Democracy,
+}
+
+/// Settings for voting, from the `[votes]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoteOptions {
+    pub policy: VotePolicy,
+
+    /// How many frames each democracy vote lasts.
+    pub window_frames: u64,
+
+    /// How many frames to hold each key that is pressed by a vote.
+    pub hold_frames: u64,
+}
+
+pub struct VoteBox {
+    options: VoteOptions,
+
+    /// Votes per key (as a `Joypad::pressed` bitmask) in the current democracy window.
+    tally: HashMap<u8, u32>,
+
+    /// Users who already voted in the current democracy window. Votes without a user name are
+    /// always counted.
+    voters: HashSet<String>,
+
+    /// The frame the current democracy window closes on.
+    window_end: u64,
+
+    /// Recent votes, oldest first, for the on-screen display.
+    recent: VecDeque<String>,
+
+    /// The outcome of the last democracy window, for the on-screen display.
+    last_result: Option<String>,
+}
+
+impl FromStr for VotePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VotePolicy, String> {
+        match s {
+            "anarchy" => Ok(VotePolicy::Anarchy),
+            "democracy" => Ok(VotePolicy::Democracy),
+            _ => Err(format!("unknown policy {:?}, expected anarchy or democracy", s)),
+        }
+    }
+}
+
+impl Default for VoteOptions {
+    fn default() -> Self {
+        VoteOptions { policy: VotePolicy::Democracy, window_frames: 2 * 60, hold_frames: 6 }
+    }
+}
+
+impl VoteBox {
+    pub fn new(options: VoteOptions) -> Self {
+        VoteBox {
+            window_end: options.window_frames,
+            options,
+            tally: HashMap::new(),
+            voters: HashSet::new(),
+            recent: VecDeque::new(),
+            last_result: None,
+        }
+    }
+
+    pub fn hold_frames(&self) -> u64 {
+        self.options.hold_frames
+    }
+
+    /// Switch policies, starting a new democracy window.
+    pub fn set_policy(&mut self, policy: VotePolicy, frame: u64) {
+        self.options.policy = policy;
+        self.tally.clear();
+        self.voters.clear();
+        self.window_end = frame + self.options.window_frames;
+    }
+
+    /// Count a vote for `keys`. Returns the keys to press right away, in anarchy mode.
+    pub fn vote(&mut self, keys: u8, user: Option<&str>) -> Result<Option<u8>, String> {
+        let democracy = self.options.policy == VotePolicy::Democracy;
+        if let Some(user) = user {
+            if democracy && !self.voters.insert(user.to_string()) {
+                return Err(format!("{} already voted", user));
+            }
+        }
+
+        if self.recent.len() == RECENT_VOTES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(match user {
+            Some(user) => format!("{}: {}", user, key_name(keys)),
+            None => key_name(keys),
+        });
+
+        if democracy {
+            *self.tally.entry(keys).or_insert(0) += 1;
+            Ok(None)
+        } else {
+            Ok(Some(keys))
+        }
+    }
+
+    /// Call once per frame. Returns the winning keys to press when a democracy window closes.
+    pub fn tick(&mut self, frame: u64) -> Option<u8> {
+        if self.options.policy != VotePolicy::Democracy || frame < self.window_end {
+            return None;
+        }
+        self.window_end = frame + self.options.window_frames;
+        self.voters.clear();
+        // Break ties in favor of the lowest bitmask, so results don't depend on hashing.
+        let winner = self.tally.drain().max_by_key(|&(keys, votes)| (votes, std::cmp::Reverse(keys)));
+        let (keys, votes) = winner?;
+        self.last_result = Some(tr_with("osd.vote_result", &[("keys", &key_name(keys)), ("votes", &votes)]));
+        Some(keys)
+    }
+
+    /// The votes in the current democracy window, most popular first.
+    pub fn tally(&self) -> Vec<(u8, u32)> {
+        let mut tally: Vec<_> = self.tally.iter().map(|(&keys, &votes)| (keys, votes)).collect();
+        tally.sort_by_key(|&(keys, votes)| (std::cmp::Reverse(votes), keys));
+        tally
+    }
+
+    /// Lines of text describing the vote, to show over the game.
+    pub fn osd_lines(&self, frame: u64) -> Vec<String> {
+        let mut lines = Vec::new();
+        match self.options.policy {
+            VotePolicy::Anarchy => {
+                lines.push(tr("osd.anarchy").to_string());
+                lines.extend(self.recent.iter().cloned());
+            }
+            VotePolicy::Democracy => {
+                let left = self.window_end.saturating_sub(frame) as f64 / 60.0;
+                lines.push(tr_with("osd.democracy", &[("seconds", &format!("{:.1}", left))]));
+                for (keys, votes) in self.tally() {
+                    lines.push(format!("{:<6} {}", key_name(keys), votes));
+                }
+                lines.extend(self.last_result.clone());
+            }
+        }
+        lines
+    }
+}
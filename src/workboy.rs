@@ -0,0 +1,410 @@
+//! The WorkBoy, Fabtek's keyboard and organizer cartridge. The keyboard plugs into the link port
+//! and also keeps the time.
+//!
+//! The game clocks every transfer. It sends a command byte, and the WorkBoy answers `D` to the
+//! ones it knows, one transfer later since it only looks at a byte once it's all shifted in:
+//!
+//! - `R` reads the clock, which the following transfers shift in as 16 bytes, with the seconds,
+//!   minutes, hours, day, month and year in BCD from the third byte on.
+//! - `W` sets the clock, with the following transfers shifting out 16 bytes laid out the same way.
+//! - `O` starts polling the keyboard. Each transfer after it shifts in the next key pressed, or
+//!   `NO_KEY`, until the game sends `R` or `W` instead.
+//!
+//! The clock starts at a time the frontend gives it and then runs on emulated time, like a
+//! cartridge's real-time clock, so replays and rewinds see the same times.
+
+use crate::savestate::{SaveStateError, StateReader, StateWriter};
+use crate::serial::SerialDevice;
+use std::any::Any;
+use std::collections::VecDeque;
+
+const ACK: u8 = b'D';
+const NO_KEY: u8 = 0xFF;
+const CLOCK_LEN: usize = 16;
+const CYCLES_PER_SECOND: usize = 4_194_304;
+
+/// The first byte of the clock the time is in, and which bytes of it are the seconds, minutes,
+/// hours, day, month and year.
+const TIME_START: usize = 2;
+const TIME_END: usize = TIME_START + 6;
+
+/// Sent before and after a key typed with shift held, since the keyboard reports each key as it's
+/// pressed.
+const SHIFT_DOWN: u8 = 0x27;
+const SHIFT_UP: u8 = 0x27 | 0x80;
+
+/// A key on the WorkBoy's keyboard.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    /// A letter, digit, space, or one of the symbols the keyboard has.
+    Char(char),
+    Enter,
+    Backspace,
+    Escape,
+    Left,
+    Right,
+    Up,
+    Down,
+
+    /// The row of keys above the keyboard that start each of the organizer's applications, in
+    /// order.
+    Clock,
+    Temperature,
+    Money,
+    Calculator,
+    Date,
+    Conversion,
+    Record,
+    World,
+    Phone,
+}
+
+impl Key {
+    /// The application keys, in order from left to right.
+    pub const APPS: [Key; 9] = [
+        Key::Clock, Key::Temperature, Key::Money, Key::Calculator, Key::Date, Key::Conversion,
+        Key::Record, Key::World, Key::Phone,
+    ];
+
+    /// The key's code and whether shift is held to type it. None for characters the keyboard
+    /// doesn't have.
+    fn code(self) -> Option<(u8, bool)> {
+        const ROWS: [&str; 3] = ["qwertyuiop", "asdfghjkl", "zxcvbnm"];
+        const ROW_START: [u8; 3] = [17, 28, 40];
+        // Shift turns the start of the letter rows into a number pad, and some of the other keys
+        // into symbols.
+        const SHIFTED: [(char, u8); 22] = [
+            ('1', 17), ('2', 18), ('3', 19), ('!', 24), ('~', 25), ('*', 26), ('#', 27),
+            ('4', 28), ('5', 29), ('6', 30), ('+', 31), ('-', 32), ('(', 35), (')', 36), (';', 37),
+            ('7', 40), ('8', 41), ('9', 42), ('0', 43), (',', 44), ('.', 45), ('?', 46),
+        ];
+        let code = match self {
+            Key::Char(c) => {
+                let c = c.to_ascii_lowercase();
+                for (&letters, &start) in ROWS.iter().zip(&ROW_START) {
+                    if let Some(i) = letters.find(c) {
+                        return Some((start + i as u8, false));
+                    }
+                }
+                if let Some(&(_, code)) = SHIFTED.iter().find(|&&(shifted, _)| shifted == c) {
+                    return Some((code, true));
+                }
+                match c {
+                    '$' => 27,
+                    ':' => 37,
+                    ' ' => 47,
+                    _ => return None,
+                }
+            }
+            Key::Escape => 0,
+            Key::Clock => 1,
+            Key::Temperature => 3,
+            Key::Money => 4,
+            Key::Calculator => 5,
+            Key::Date => 6,
+            Key::Conversion => 7,
+            Key::Record => 8,
+            Key::World => 9,
+            Key::Phone => 10,
+            Key::Backspace => 11,
+            Key::Left => 13,
+            Key::Right => 14,
+            Key::Up => 15,
+            Key::Down => 16,
+            Key::Enter => 38,
+        };
+        Some((code, false))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Idle,
+    /// Shifting the clock in, with this many bytes sent.
+    ReadClock(usize),
+    /// Shifting a new time out, with this many bytes received.
+    WriteClock(usize),
+    Keyboard,
+}
+
+#[derive(Clone)]
+pub struct WorkBoy {
+    mode: Mode,
+
+    /// The byte to shift in on the next transfer.
+    answer: u8,
+
+    /// The clock being read or written.
+    clock: [u8; CLOCK_LEN],
+
+    /// The time on the WorkBoy's clock, in seconds since 1970.
+    time: i64,
+
+    /// Cycles into the current second.
+    time_cycles: usize,
+
+    /// Key codes waiting for the game to poll them.
+    keys: VecDeque<u8>,
+}
+
+impl WorkBoy {
+    /// A WorkBoy with its clock set to `time`, in seconds since 1970.
+    pub fn new(time: i64) -> Self {
+        WorkBoy { mode: Mode::Idle, answer: 0, clock: [0; CLOCK_LEN], time, time_cycles: 0, keys: VecDeque::new() }
+    }
+
+    /// Press and release `key`. Returns false, ignoring it, if the keyboard has no such key.
+    pub fn press(&mut self, key: Key) -> bool {
+        let (code, shift) = match key.code() {
+            Some(code) => code,
+            None => return false,
+        };
+        if shift {
+            self.keys.extend(&[SHIFT_DOWN, code, SHIFT_UP]);
+        } else {
+            self.keys.push_back(code);
+        }
+        true
+    }
+
+    /// Work out the reply to a byte the game sent.
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.mode {
+            Mode::ReadClock(sent) if sent < CLOCK_LEN => {
+                self.mode = Mode::ReadClock(sent + 1);
+                return self.clock[sent];
+            }
+            Mode::WriteClock(received) if received < CLOCK_LEN => {
+                self.clock[received] = byte;
+                self.mode = if received + 1 == CLOCK_LEN {
+                    self.set_time();
+                    Mode::Idle
+                } else {
+                    Mode::WriteClock(received + 1)
+                };
+                return ACK;
+            }
+            _ => {}
+        }
+        match byte {
+            b'R' => {
+                let time = time_to_bcd(self.time);
+                self.clock = [0; CLOCK_LEN];
+                self.clock[TIME_START..TIME_END].copy_from_slice(&time);
+                self.mode = Mode::ReadClock(0);
+                ACK
+            }
+            b'W' => {
+                self.mode = Mode::WriteClock(0);
+                ACK
+            }
+            b'O' if self.mode != Mode::Keyboard => {
+                self.mode = Mode::Keyboard;
+                ACK
+            }
+            _ if self.mode == Mode::Keyboard => self.keys.pop_front().unwrap_or(NO_KEY),
+            _ => 0,
+        }
+    }
+
+    /// Set the clock to the time the game wrote.
+    fn set_time(&mut self) {
+        let mut time = [0; 6];
+        time.copy_from_slice(&self.clock[TIME_START..TIME_END]);
+        if let Some(seconds) = time_from_bcd(time) {
+            self.time = seconds;
+            self.time_cycles = 0;
+        }
+    }
+}
+
+impl SerialDevice for WorkBoy {
+    fn exchange(&mut self, sent: u8) -> u8 {
+        let answer = self.answer;
+        self.answer = self.receive(sent);
+        answer
+    }
+
+    fn step(&mut self, cycles: usize) {
+        self.time_cycles += cycles;
+        self.time += (self.time_cycles / CYCLES_PER_SECOND) as i64;
+        self.time_cycles %= CYCLES_PER_SECOND;
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn box_clone(&self) -> Box<dyn SerialDevice> {
+        Box::new(self.clone())
+    }
+
+    fn name(&self) -> &'static str {
+        "WorkBoy"
+    }
+
+    fn save(&self, w: &mut StateWriter) {
+        let (mode, bytes) = match self.mode {
+            Mode::Idle => (0, 0),
+            Mode::ReadClock(sent) => (1, sent),
+            Mode::WriteClock(received) => (2, received),
+            Mode::Keyboard => (3, 0),
+        };
+        w.u8(mode);
+        w.usize(bytes);
+        w.u8(self.answer);
+        w.bytes(&self.clock);
+        w.u64(self.time as u64);
+        w.usize(self.time_cycles);
+        let keys: Vec<u8> = self.keys.iter().cloned().collect();
+        w.bytes(&keys);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let (mode, bytes) = (r.u8()?, r.usize()?);
+        self.mode = match (mode, bytes) {
+            (0, _) => Mode::Idle,
+            (1, sent) if sent <= CLOCK_LEN => Mode::ReadClock(sent),
+            (2, received) if received < CLOCK_LEN => Mode::WriteClock(received),
+            (3, _) => Mode::Keyboard,
+            _ => return Err(SaveStateError::InvalidValue("WorkBoy mode")),
+        };
+        self.answer = r.u8()?;
+        r.bytes_into(&mut self.clock)?;
+        self.time = r.u64()? as i64;
+        self.time_cycles = r.usize()?;
+        if self.time_cycles >= CYCLES_PER_SECOND {
+            return Err(SaveStateError::InvalidValue("WorkBoy clock cycles"));
+        }
+        self.keys = r.bytes()?.iter().cloned().collect();
+        Ok(())
+    }
+}
+
+fn to_bcd(n: i64) -> u8 {
+    (n / 10 * 16 + n % 10) as u8
+}
+
+fn from_bcd(bcd: u8) -> Option<i64> {
+    let (tens, ones) = (bcd >> 4, bcd & 0xF);
+    if tens < 10 && ones < 10 {
+        Some(i64::from(tens * 10 + ones))
+    } else {
+        None
+    }
+}
+
+/// The seconds, minutes, hours, day, month and two digit year of a Unix time, in BCD.
+fn time_to_bcd(time: i64) -> [u8; 6] {
+    let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    [
+        to_bcd(seconds % 60), to_bcd(seconds / 60 % 60), to_bcd(seconds / 3600),
+        to_bcd(day), to_bcd(month), to_bcd(year.rem_euclid(100)),
+    ]
+}
+
+/// The inverse of `time_to_bcd`, taking two digit years to be in 2000-2099. None if the time
+/// isn't valid BCD.
+fn time_from_bcd(bcd: [u8; 6]) -> Option<i64> {
+    let mut fields = [0; 6];
+    for (field, &byte) in fields.iter_mut().zip(&bcd) {
+        *field = from_bcd(byte)?;
+    }
+    let [seconds, minutes, hours, day, month, year] = fields;
+    match (day, month) {
+        (1...31, 1...12) => {}
+        _ => return None,
+    }
+    Some(days_from_civil(2000 + year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// The year, month and day of a day counted from 1970-01-01, in the proleptic Gregorian calendar.
+/// From Howard Hinnant's `chrono`-compatible date algorithms.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_workboy_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 2, 29), 11016);
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in -1000..30000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_workboy_bcd_time() {
+        // 2024-02-29 23:59:58.
+        let time = 1_709_251_198;
+        assert_eq!(time_to_bcd(time), [0x58, 0x59, 0x23, 0x29, 0x02, 0x24]);
+        assert_eq!(time_from_bcd(time_to_bcd(time)), Some(time));
+        assert_eq!(time_from_bcd([0x00, 0x00, 0x00, 0x00, 0x01, 0x24]), None);
+        assert_eq!(time_from_bcd([0x0A, 0x00, 0x00, 0x01, 0x01, 0x24]), None);
+    }
+
+    #[test]
+    fn test_workboy_clock_runs_on_emulated_time() {
+        let mut workboy = WorkBoy::new(1_709_251_198);
+        workboy.step(CYCLES_PER_SECOND - 1);
+        assert_eq!(workboy.time, 1_709_251_198);
+        workboy.step(CYCLES_PER_SECOND + 1);
+        assert_eq!(workboy.time, 1_709_251_200);
+
+        // Read the clock: the command, then the 16 bytes.
+        let mut clock = Vec::new();
+        workboy.exchange(b'R');
+        for _ in 0..=CLOCK_LEN {
+            clock.push(workboy.exchange(0));
+        }
+        assert_eq!(clock[0], ACK);
+        assert_eq!(&clock[1 + TIME_START..1 + TIME_END], &[0x00, 0x00, 0x00, 0x01, 0x03, 0x24]);
+    }
+
+    #[test]
+    fn test_workboy_savestate() {
+        let mut workboy = WorkBoy::new(1_709_251_198);
+        workboy.step(1000);
+        workboy.exchange(b'O');
+        workboy.press(Key::Char('1'));
+        let mut w = StateWriter::with_header(b"WORKBOY ", 1);
+        SerialDevice::save(&workboy, &mut w);
+        let state = w.finish();
+
+        let mut loaded = WorkBoy::new(0);
+        let mut r = StateReader::with_header(&state, b"WORKBOY ", 1).unwrap();
+        SerialDevice::load(&mut loaded, &mut r).unwrap();
+        assert_eq!(loaded.mode, workboy.mode);
+        assert_eq!((loaded.time, loaded.time_cycles), (1_709_251_198, 1000));
+        assert_eq!(loaded.keys, workboy.keys);
+        assert_eq!(loaded.exchange(0), ACK);
+        assert_eq!(loaded.exchange(0), SHIFT_DOWN);
+    }
+}
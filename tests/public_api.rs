@@ -0,0 +1,116 @@
+//! Checks that the prelude keeps the API frontends rely on, and that it does what it says. If one
+//! of the signatures stops compiling, the change needs a new major version.
+
+use rugby::prelude::*;
+
+/// A 32 KiB ROM with no mapper that runs `code` at the entry point.
+fn rom(code: &[u8]) -> Box<[u8]> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    rom.into_boxed_slice()
+}
+
+/// Loops forever at the entry point.
+fn looping_rom() -> Box<[u8]> {
+    rom(&[0x18, 0xFE]) // jr -2
+}
+
+/// Keeps copying the A button's state into the background palette, so the blank background shows
+/// shade 2 while A is held, and 3 otherwise.
+fn button_rom() -> Box<[u8]> {
+    rom(&[
+        0x3E, 0x10, 0xE0, 0x00, // ld a, $10; ldh [P1], a: select the buttons
+        0xF0, 0x00, 0xE0, 0x47, // ldh a, [P1]; ldh [BGP], a
+        0x18, 0xF6, // jr to the start
+    ])
+}
+
+#[test]
+fn signatures() {
+    let _: fn(Box<[u8]>, Option<Box<[u8]>>) -> Result<Cart, CartError> = Cart::from_rom;
+    let _: fn(Cart) -> CpuBuilder = Cpu::builder;
+    let _: fn(&mut Cpu) = Cpu::run_frame;
+    let _: fn(&mut Cpu, &mut dyn AudioSink) = Cpu::run_frame_with_audio;
+    let _: fn(&Cpu) -> &[[u8; SCREEN_WIDTH]; SCREEN_HEIGHT] = Cpu::screen;
+    let _: fn(&mut Cpu) -> &mut Joypad = Cpu::joypad_mut;
+    let _: fn(&Cpu) -> Vec<u8> = Cpu::save_state;
+    let _: fn(&mut Cpu, &[u8]) -> Result<(), SaveStateError> = Cpu::load_state;
+    let _: [[u8; SCREEN_WIDTH]; SCREEN_HEIGHT] = [[0; 160]; 144];
+}
+
+#[test]
+fn builder_runs_and_restores_states() {
+    let cart = Cart::from_rom(looping_rom(), None).unwrap();
+    let mut cpu = Cpu::builder(cart).model(Model::Cgb).sprite_limit(SpriteLimit::Unlimited).sample_rate(48000).build();
+    assert_eq!(cpu.model(), Model::Cgb);
+
+    cpu.joypad_mut().button_key_down(ButtonKey::Start);
+    cpu.joypad_mut().dir_key_down(DirKey::Left);
+    cpu.run_frame();
+    let state = cpu.save_state();
+    cpu.run_frame();
+    let cycles = cpu.cycles();
+    cpu.load_state(&state).unwrap();
+    cpu.run_frame();
+    assert_eq!(cpu.cycles(), cycles);
+}
+
+#[test]
+fn joypad_reaches_the_game_and_the_screen_shows_it() {
+    let mut cpu = Cpu::builder(Cart::from_rom(button_rom(), None).unwrap()).build();
+    let all = |cpu: &Cpu, shade: u8| cpu.screen().iter().flatten().all(|&pixel| pixel == shade);
+    cpu.run_frame();
+    cpu.run_frame();
+    assert!(all(&cpu, 3));
+
+    cpu.joypad_mut().button_key_down(ButtonKey::A);
+    let state = cpu.save_state();
+    cpu.run_frame();
+    cpu.run_frame();
+    assert!(all(&cpu, 2));
+
+    // The savestate was taken before the screen changed.
+    cpu.load_state(&state).unwrap();
+    assert!(all(&cpu, 3));
+}
+
+#[test]
+fn audio_arrives_at_the_sample_rate() {
+    struct Count(usize);
+
+    impl AudioSink for Count {
+        fn queue(&mut self, samples: &[u8]) {
+            self.0 += samples.len();
+        }
+    }
+
+    let mut cpu = Cpu::builder(Cart::from_rom(looping_rom(), None).unwrap()).sample_rate(48000).build();
+    let mut count = Count(0);
+    // About a second: the Game Boy runs at 59.73 frames a second.
+    for _ in 0..60 {
+        cpu.run_frame_with_audio(&mut count);
+    }
+    let seconds = 60.0 / 59.73;
+    let expected = 48000.0 * 2.0 * seconds;
+    assert!((count.0 as f64 - expected).abs() < expected / 100.0, "{} bytes of samples", count.0);
+}
+
+#[test]
+fn errors() {
+    match Cart::from_rom(vec![0; 0x100].into_boxed_slice(), None) {
+        Err(CartError::Header(HeaderParseError::RomTooShort(0x100))) => {}
+        Err(e) => panic!("{}", e),
+        Ok(_) => panic!("loaded a ROM without a header"),
+    }
+
+    let mut unknown = looping_rom();
+    unknown[0x147] = 0xAB;
+    match Cart::from_rom(unknown, None) {
+        Err(CartError::UnknownMapper(0xAB)) => {}
+        Err(e) => panic!("{}", e),
+        Ok(_) => panic!("loaded a ROM with an unknown mapper"),
+    }
+
+    let mut cpu = Cpu::builder(Cart::from_rom(looping_rom(), None).unwrap()).build();
+    assert!(cpu.load_state(b"not a savestate").is_err());
+}